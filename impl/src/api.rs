@@ -0,0 +1,467 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+  collections::BTreeMap,
+  env,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use cargo_metadata::Metadata;
+use url::Url;
+
+use crate::{
+  checks,
+  http::HttpConfig,
+  incremental::CrateCache,
+  metadata::{
+    default_cargo_home, default_metadata_cache_dir, MetadataFetcher, RazeMetadata,
+    RazeMetadataFetcher,
+  },
+  planning::{BuildPlannerImpl, PlannedBuild},
+  rendering::{self, RenderDetails},
+  settings::{feature_profile, load_settings, GenMode, RazeSettings, SettingsMetadataFetcher},
+  util::{find_bazel_workspace_root, find_lockfile},
+};
+
+/// Configures how the Bazel files of a cargo workspace are generated, as the flags of the
+/// `cargo raze` command do. Every option defaults to the behavior of running `cargo raze` without
+/// any flags.
+#[derive(Debug, Clone)]
+pub struct RazeBuilder {
+  manifest_path: Option<PathBuf>,
+  cargo_bin_path: Option<PathBuf>,
+  cargo_target_dir: Option<PathBuf>,
+  features: Vec<String>,
+  feature_profile: Option<String>,
+  all_features: bool,
+  minimal_versions: bool,
+  offline: bool,
+  use_metadata_cache: bool,
+  generate_lockfile: bool,
+  allow_unverified: bool,
+  max_concurrent_downloads: Option<usize>,
+}
+
+impl Default for RazeBuilder {
+  fn default() -> Self {
+    Self {
+      manifest_path: None,
+      cargo_bin_path: None,
+      cargo_target_dir: None,
+      features: Vec::new(),
+      feature_profile: None,
+      all_features: false,
+      minimal_versions: false,
+      offline: false,
+      use_metadata_cache: true,
+      generate_lockfile: false,
+      allow_unverified: false,
+      max_concurrent_downloads: None,
+    }
+  }
+}
+
+impl RazeBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// The `Cargo.toml` of the workspace to generate files for, as with `--manifest-path`.
+  /// Defaults to the workspace of the current directory.
+  pub fn manifest_path<P: Into<PathBuf>>(mut self, manifest_path: P) -> Self {
+    self.manifest_path = Some(manifest_path.into());
+    self
+  }
+
+  /// The cargo binary to resolve the workspace with, as with `--cargo-bin-path`.
+  pub fn cargo_bin_path<P: Into<PathBuf>>(mut self, cargo_bin_path: P) -> Self {
+    self.cargo_bin_path = Some(cargo_bin_path.into());
+    self
+  }
+
+  /// The target directory of the internal cargo invocations, as with `--cargo-target-dir`.
+  pub fn cargo_target_dir<P: Into<PathBuf>>(mut self, cargo_target_dir: P) -> Self {
+    self.cargo_target_dir = Some(cargo_target_dir.into());
+    self
+  }
+
+  /// Features to resolve the workspace with, as with `--features`. `member/feature` enables a
+  /// feature of a single workspace member.
+  pub fn features<I: IntoIterator<Item = String>>(mut self, features: I) -> Self {
+    self.features.extend(features);
+    self
+  }
+
+  /// An entry of the `feature_profiles` raze setting to resolve with, as with
+  /// `--feature-profile`.
+  pub fn feature_profile<S: Into<String>>(mut self, profile: S) -> Self {
+    self.feature_profile = Some(profile.into());
+    self
+  }
+
+  /// Resolves with every feature of every workspace member, as with `--all-features`.
+  pub fn all_features(mut self, all_features: bool) -> Self {
+    self.all_features = all_features;
+    self
+  }
+
+  /// Resolves every dependency to its lowest allowed version, as with `--minimal-versions`.
+  pub fn minimal_versions(mut self, minimal_versions: bool) -> Self {
+    self.minimal_versions = minimal_versions;
+    self
+  }
+
+  /// Fails instead of accessing the network, as with `--offline` or the `offline` raze setting.
+  pub fn offline(mut self, offline: bool) -> Self {
+    self.offline = offline;
+    self
+  }
+
+  /// Whether the results of `cargo metadata`, and the contexts planned and BUILD files rendered
+  /// for crates, are reused for unchanged workspaces and crates. Disabling this is the same as
+  /// `--no-cache`.
+  pub fn use_metadata_cache(mut self, use_metadata_cache: bool) -> Self {
+    self.use_metadata_cache = use_metadata_cache;
+    self
+  }
+
+  /// Resolves a new lockfile instead of reusing the existing one, as with `--generate-lockfile`.
+  pub fn generate_lockfile(mut self, generate_lockfile: bool) -> Self {
+    self.generate_lockfile = generate_lockfile;
+    self
+  }
+
+  /// Skips checking downloaded crate archives against their checksums, as with
+  /// `--allow-unverified`.
+  pub fn allow_unverified(mut self, allow_unverified: bool) -> Self {
+    self.allow_unverified = allow_unverified;
+    self
+  }
+
+  /// The number of crates downloaded at a time, as with `--jobs`. This overrides the
+  /// `max_concurrent_downloads` raze setting.
+  pub fn max_concurrent_downloads(mut self, max_concurrent_downloads: usize) -> Self {
+    self.max_concurrent_downloads = Some(max_concurrent_downloads);
+    self
+  }
+
+  /// Loads the raze settings of the workspace, which requires no network access.
+  pub fn build(self) -> Result<Raze> {
+    let local_metadata = self.fetch_local_metadata()?;
+    let settings = load_settings(&local_metadata).map_err(|err| anyhow!(err.to_string()))?;
+    let bazel_workspace_root =
+      find_bazel_workspace_root(&local_metadata.workspace_root).unwrap_or(env::current_dir()?);
+
+    Ok(Raze {
+      options: self,
+      local_metadata,
+      settings,
+      bazel_workspace_root,
+    })
+  }
+
+  /// Gathers basic, offline metadata to parse settings from
+  fn fetch_local_metadata(&self) -> Result<Metadata> {
+    let mut fetcher = match &self.cargo_bin_path {
+      Some(cargo_bin_path) => SettingsMetadataFetcher {
+        cargo_bin_path: cargo_bin_path.clone(),
+        cargo_target_dir: None,
+      },
+      None => SettingsMetadataFetcher::default(),
+    };
+    fetcher.cargo_target_dir = self.cargo_target_dir.clone();
+
+    let working_directory = match &self.manifest_path {
+      Some(manifest_path) => {
+        let manifest_path = manifest_path.canonicalize()?;
+        if !manifest_path.is_file() {
+          return Err(anyhow!(
+            "manifest path `{}` is not a file.",
+            manifest_path.display()
+          ));
+        }
+        // UNWRAP: Unwrap safe due to check above.
+        PathBuf::from(manifest_path.parent().unwrap())
+      },
+      None => env::current_dir()?,
+    };
+
+    fetcher
+      .fetch_metadata(&working_directory, false)
+      .with_context(|| {
+        format!(
+          "Failed to fetch metadata for {}",
+          working_directory.display()
+        )
+      })
+  }
+}
+
+/// The Bazel files rendered for a workspace, along with everything they were rendered from.
+#[derive(Debug)]
+pub struct RazeOutput {
+  pub raze_metadata: RazeMetadata,
+  pub planned_build: PlannedBuild,
+  pub render_details: RenderDetails,
+  /// The contents of every generated file, keyed by their absolute paths
+  pub file_outputs: BTreeMap<PathBuf, String>,
+}
+
+/// Generates the Bazel files of a cargo workspace in memory, leaving writing them to the caller.
+/// Each step of `generate` is available on its own as well, eg: to inspect the plan before
+/// rendering it.
+///
+/// ```no_run
+/// use cargo_raze::Raze;
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let raze = Raze::builder()
+///   .manifest_path("third_party/cargo/Cargo.toml")
+///   .offline(true)
+///   .build()?;
+/// for (path, contents) in raze.generate()?.file_outputs {
+///   std::fs::write(path, contents)?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Raze {
+  options: RazeBuilder,
+  local_metadata: Metadata,
+  settings: RazeSettings,
+  bazel_workspace_root: PathBuf,
+}
+
+impl Raze {
+  pub fn builder() -> RazeBuilder {
+    RazeBuilder::new()
+  }
+
+  /// The effective raze settings of the workspace
+  pub fn settings(&self) -> &RazeSettings {
+    &self.settings
+  }
+
+  /// The metadata of the workspace members, without any of their dependencies
+  pub fn local_metadata(&self) -> &Metadata {
+    &self.local_metadata
+  }
+
+  /// The root of the Bazel workspace the files are generated in, or else the current directory
+  pub fn bazel_workspace_root(&self) -> &Path {
+    &self.bazel_workspace_root
+  }
+
+  /// Resolves the dependencies of the workspace and checks they are ready to be planned.
+  pub fn fetch_metadata(&self) -> Result<RazeMetadata> {
+    let options = &self.options;
+    let settings = &self.settings;
+    let mut metadata_fetcher: RazeMetadataFetcher = match &options.cargo_bin_path {
+      Some(cargo_bin_path) => RazeMetadataFetcher::new(
+        cargo_bin_path,
+        Url::parse(&settings.registry)?,
+        Url::parse(&settings.index_url)?,
+      ),
+      None => RazeMetadataFetcher::default(),
+    };
+
+    if let Some(cargo_target_dir) = &options.cargo_target_dir {
+      metadata_fetcher.set_cargo_target_dir(cargo_target_dir);
+    }
+
+    if options.allow_unverified {
+      eprintln!("WARNING: Downloaded crate archives are not checked against their checksums");
+    }
+    metadata_fetcher.set_verify_download_checksums(
+      settings.verify_download_checksums && !options.allow_unverified,
+    );
+    metadata_fetcher.set_verify_against_upstream_index(settings.verify_against_upstream_index);
+    metadata_fetcher.set_max_concurrent_downloads(
+      options
+        .max_concurrent_downloads
+        .unwrap_or(settings.max_concurrent_downloads),
+    );
+    metadata_fetcher.set_download_mirrors(
+      settings
+        .download_mirrors
+        .iter()
+        .map(|mirror| Url::parse(mirror))
+        .collect::<Result<_, _>>()?,
+    );
+    metadata_fetcher.set_git_fallback(settings.git_fallback);
+    let mut http_config =
+      HttpConfig::load(&self.local_metadata.workspace_root, &default_cargo_home())?;
+    http_config.credential_command = settings.credential_command.clone();
+    metadata_fetcher.set_http_config(http_config);
+    if let Some(timeout_secs) = settings.metadata_timeout_secs {
+      metadata_fetcher.set_metadata_timeout(Duration::from_secs(timeout_secs));
+    }
+
+    let mut features = Vec::new();
+    if let Some(profile) = &options.feature_profile {
+      features.extend(feature_profile(settings, profile)?.iter().cloned());
+    }
+    features.extend(
+      options
+        .features
+        .iter()
+        .flat_map(|features| features.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|feature| !feature.is_empty())
+        .map(str::to_owned),
+    );
+    if !features.is_empty() {
+      metadata_fetcher.set_features(features);
+    }
+    if options.all_features {
+      metadata_fetcher.set_all_features(true);
+    }
+    if options.minimal_versions {
+      metadata_fetcher.set_minimal_versions(true);
+    }
+
+    let workspace_root = &self.local_metadata.workspace_root;
+    if let Some(crate_cache_dir) = &settings.crate_cache_dir {
+      metadata_fetcher.set_crate_cache_dir(workspace_root.join(crate_cache_dir));
+    }
+    if settings.offline || options.offline {
+      metadata_fetcher.set_offline(true);
+    }
+
+    if options.use_metadata_cache {
+      metadata_fetcher.set_metadata_cache_dir(default_metadata_cache_dir());
+      metadata_fetcher.set_index_cache_dir(default_metadata_cache_dir().join("index"));
+    }
+
+    let binary_dep_info = if settings.genmode == GenMode::Remote {
+      Some(&settings.binary_deps)
+    } else {
+      None
+    };
+
+    // A lockfile is resolved from scratch with minimal versions
+    let reused_lockfile = if !options.generate_lockfile && !options.minimal_versions {
+      find_lockfile(
+        workspace_root,
+        &self
+          .bazel_workspace_root
+          .join(settings.workspace_path.trim_start_matches('/')),
+      )
+    } else {
+      None
+    };
+
+    let raze_metadata =
+      metadata_fetcher.fetch_metadata(workspace_root, binary_dep_info, reused_lockfile)?;
+
+    checks::check_metadata(&raze_metadata, settings, &self.bazel_workspace_root)?;
+    Ok(raze_metadata)
+  }
+
+  /// Plans the build of the fetched `raze_metadata`.
+  pub fn plan(&self, raze_metadata: &RazeMetadata) -> Result<PlannedBuild> {
+    let mut planner = BuildPlannerImpl::new(raze_metadata.clone(), self.settings.clone());
+    if let Some(crate_cache) = self.crate_cache() {
+      planner.set_crate_cache(crate_cache);
+    }
+    planner.plan_for_target()
+  }
+
+  /// Renders the Bazel files of `planned_build`, yielding the contents of each file by its path.
+  pub fn render(
+    &self,
+    raze_metadata: &RazeMetadata,
+    planned_build: &PlannedBuild,
+  ) -> Result<(RenderDetails, BTreeMap<PathBuf, String>)> {
+    let mut render_details = RenderDetails::new(
+      &self.settings,
+      raze_metadata.cargo_workspace_root.clone(),
+      self.bazel_workspace_root.clone(),
+    );
+    render_details.crate_cache = self.crate_cache();
+    let file_outputs = rendering::render_files(&self.settings, &render_details, planned_build)?;
+
+    Ok((render_details, file_outputs))
+  }
+
+  /// The cache of planned crate contexts and rendered BUILD files, unless caching is disabled
+  fn crate_cache(&self) -> Option<CrateCache> {
+    self
+      .options
+      .use_metadata_cache
+      .then(|| CrateCache::new(default_metadata_cache_dir().join("crates")))
+  }
+
+  /// Fetches, plans and renders the Bazel files of the workspace.
+  pub fn generate(&self) -> Result<RazeOutput> {
+    let raze_metadata = self.fetch_metadata()?;
+    let planned_build = self.plan(&raze_metadata)?;
+    let (render_details, file_outputs) = self.render(&raze_metadata, &planned_build)?;
+
+    Ok(RazeOutput {
+      raze_metadata,
+      planned_build,
+      render_details,
+      file_outputs,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use super::*;
+  use crate::testing::*;
+
+  #[test]
+  fn test_generating_files_in_memory() {
+    let dir = make_basic_workspace();
+    let manifest_path = dir.as_ref().join("Cargo.toml");
+    let manifest = fs::read_to_string(&manifest_path).unwrap();
+    fs::write(
+      &manifest_path,
+      manifest
+        + indoc::indoc! { r#"
+
+          [package.metadata.raze]
+          workspace_path = "//cargo"
+          genmode = "Remote"
+        "# },
+    )
+    .unwrap();
+    fs::write(dir.as_ref().join("WORKSPACE"), "").unwrap();
+
+    let raze = Raze::builder()
+      .manifest_path(&manifest_path)
+      .use_metadata_cache(false)
+      .offline(true)
+      .build()
+      .unwrap();
+    assert_eq!(raze.settings().workspace_path, "//cargo");
+
+    let output = raze.generate().unwrap();
+    assert_eq!(
+      output.render_details.bazel_root,
+      fs::canonicalize(dir.as_ref()).unwrap()
+    );
+    assert!(output
+      .file_outputs
+      .contains_key(&output.render_details.bazel_root.join("cargo/crates.bzl")));
+    // Nothing is written to disk
+    assert!(!dir.as_ref().join("cargo").exists());
+  }
+}
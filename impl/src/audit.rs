@@ -0,0 +1,552 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  process::Command,
+};
+
+use anyhow::{anyhow, Context, Result};
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+  metadata::{read_cargo_index_cache, source_index_url, RazeMetadata},
+  planning::PlannedBuild,
+  settings::AuditPolicy,
+};
+
+/// The repository of the RustSec advisory database
+pub const DEFAULT_ADVISORY_DB_URL: &str = "https://github.com/RustSec/advisory-db.git";
+
+/// The `audit_ignore` entry ignoring that a crate's version has been yanked
+const YANKED_ID: &str = "yanked";
+
+/// A security advisory of the RustSec advisory database
+#[derive(Debug, Clone, PartialEq)]
+pub struct Advisory {
+  /// The id of the advisory, eg: `RUSTSEC-2020-0071`
+  pub id: String,
+  pub package: String,
+  pub title: String,
+  /// The kind of an informational advisory, eg: `unmaintained`, rather than a vulnerability
+  pub informational: Option<String>,
+  pub url: Option<String>,
+  /// The versions of the crate which are not affected by the advisory, either since it was fixed
+  /// in them or since they predate it
+  pub unaffected: Vec<VersionReq>,
+}
+
+/// The TOML front matter of an advisory, eg: `crates/time/RUSTSEC-2020-0071.md`
+#[derive(Debug, Deserialize)]
+struct AdvisoryFile {
+  advisory: AdvisoryMetadata,
+  #[serde(default)]
+  versions: AdvisoryVersions,
+}
+
+#[derive(Debug, Deserialize)]
+struct AdvisoryMetadata {
+  id: String,
+  package: String,
+  /// Only set by advisories in the older, pure TOML format which has no markdown title
+  #[serde(default)]
+  title: Option<String>,
+  #[serde(default)]
+  informational: Option<String>,
+  #[serde(default)]
+  url: Option<String>,
+  /// The date at which the advisory was withdrawn, eg: for having been issued in error
+  #[serde(default)]
+  withdrawn: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AdvisoryVersions {
+  #[serde(default)]
+  patched: Vec<String>,
+  #[serde(default)]
+  unaffected: Vec<String>,
+}
+
+/// A problem `audit` found with a crate of the plan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditFinding {
+  pub name: String,
+  pub version: Version,
+  /// The id of the advisory, or `yanked` for yanked versions
+  pub id: String,
+  pub description: String,
+  /// Whether the finding is only informational, and so never fails planning
+  pub informational: bool,
+}
+
+/// Clones the advisory database into `db_dir`, or brings an existing clone up to date. A failed
+/// update is only reported, as the existing clone remains usable.
+pub fn fetch_advisory_db(db_dir: &Path, offline: bool) -> Result<()> {
+  let git_output = |args: &[&str]| -> Result<std::process::Output> {
+    Command::new("git")
+      .args(args)
+      .output()
+      .context("Failed to run `git`")
+  };
+
+  if !db_dir.join(".git").exists() {
+    if offline {
+      return Err(anyhow!(
+        "The advisory database {} can't be cloned while offline",
+        db_dir.display()
+      ));
+    }
+    let db_dir = db_dir.to_string_lossy();
+    let output = git_output(&[
+      "clone",
+      "--quiet",
+      "--depth=1",
+      DEFAULT_ADVISORY_DB_URL,
+      &db_dir,
+    ])?;
+    if !output.status.success() {
+      return Err(anyhow!(
+        "Failed to clone the advisory database {} into {}: {}",
+        DEFAULT_ADVISORY_DB_URL,
+        db_dir,
+        String::from_utf8_lossy(&output.stderr)
+      ));
+    }
+  } else if !offline {
+    let db_dir = db_dir.to_string_lossy();
+    let output = git_output(&["-C", &db_dir, "pull", "--quiet", "--ff-only"])?;
+    if !output.status.success() {
+      eprintln!(
+        "WARNING: Failed to update the advisory database in {}, auditing against the existing \
+         copy: {}",
+        db_dir,
+        String::from_utf8_lossy(&output.stderr).trim()
+      );
+    }
+  }
+
+  Ok(())
+}
+
+/// Loads the advisories of a checkout of the advisory database, which are kept in files like
+/// `crates/{package}/{id}.md`. Files in the older `.toml` format are read as well. Withdrawn
+/// advisories are skipped.
+pub fn load_advisory_db(db_dir: &Path) -> Result<Vec<Advisory>> {
+  let crates_dir = db_dir.join("crates");
+  if !crates_dir.is_dir() {
+    return Err(anyhow!(
+      "{} is not a RustSec advisory database, it has no `crates` directory",
+      db_dir.display()
+    ));
+  }
+
+  let mut paths: Vec<PathBuf> = Vec::new();
+  for extension in ["md", "toml"].iter() {
+    let pattern = crates_dir
+      .join("*")
+      .join(format!("RUSTSEC-*.{}", extension));
+    for path in glob::glob(&pattern.to_string_lossy())? {
+      paths.push(path?);
+    }
+  }
+  paths.sort();
+
+  let mut advisories = Vec::new();
+  for path in paths.iter() {
+    let content = fs::read_to_string(path)?;
+    let advisory =
+      parse_advisory(&content).with_context(|| format!("Failed to parse {}", path.display()))?;
+    advisories.extend(advisory);
+  }
+  Ok(advisories)
+}
+
+/// Parses an advisory, either as markdown with a fenced block of TOML front matter followed by
+/// its title, or as pure TOML. Yields `None` for withdrawn advisories.
+fn parse_advisory(content: &str) -> Result<Option<Advisory>> {
+  let (front_matter, markdown) = match content.trim_start().strip_prefix("```toml") {
+    Some(rest) => rest
+      .split_once("\n```")
+      .ok_or_else(|| anyhow!("The TOML front matter of the advisory is not terminated"))?,
+    None => (content, ""),
+  };
+  let advisory_file: AdvisoryFile = toml::from_str(front_matter)?;
+  let metadata = advisory_file.advisory;
+  if metadata.withdrawn.is_some() {
+    return Ok(None);
+  }
+
+  let title = markdown
+    .lines()
+    .find_map(|line| line.strip_prefix("# "))
+    .map(|title| title.trim().to_owned())
+    .or(metadata.title)
+    .unwrap_or_default();
+  let unaffected = advisory_file
+    .versions
+    .patched
+    .iter()
+    .chain(advisory_file.versions.unaffected.iter())
+    .map(|req| {
+      VersionReq::parse(req).with_context(|| format!("Invalid version requirement `{}`", req))
+    })
+    .collect::<Result<Vec<VersionReq>>>()?;
+
+  Ok(Some(Advisory {
+    id: metadata.id,
+    package: metadata.package,
+    title,
+    informational: metadata.informational,
+    url: metadata.url,
+    unaffected,
+  }))
+}
+
+/// Checks every registry crate of `planned_build` against `advisories`, and against cargo's cache
+/// of its registry index in `cargo_home` for having been yanked. Findings listed in a crate's
+/// `audit_ignore` setting are skipped.
+pub fn audit_plan(
+  planned_build: &PlannedBuild,
+  raze_metadata: &RazeMetadata,
+  advisories: &[Advisory],
+  cargo_home: &Path,
+) -> Result<Vec<AuditFinding>> {
+  let mut findings = Vec::new();
+  for package in planned_build.crate_contexts.iter().filter(|package| {
+    package.source_details.git_data.is_none() && package.source_details.local_path.is_none()
+  }) {
+    let is_ignored = |id: &str| package.raze_settings.audit_ignore.iter().any(|i| i == id);
+
+    for advisory in advisories.iter().filter(|advisory| {
+      advisory.package == package.pkg_name
+        && !advisory
+          .unaffected
+          .iter()
+          .any(|req| req.matches(&package.pkg_version))
+        && !is_ignored(&advisory.id)
+    }) {
+      let mut description = advisory.title.clone();
+      if let Some(informational) = &advisory.informational {
+        description = format!("{} ({})", description, informational);
+      }
+      if let Some(url) = &advisory.url {
+        description = format!("{}, see {}", description, url);
+      }
+      findings.push(AuditFinding {
+        name: package.pkg_name.clone(),
+        version: package.pkg_version.clone(),
+        id: advisory.id.clone(),
+        description,
+        informational: advisory.informational.is_some(),
+      });
+    }
+
+    if !is_ignored(YANKED_ID)
+      && is_yanked(
+        raze_metadata,
+        &package.pkg_name,
+        &package.pkg_version,
+        cargo_home,
+      )?
+    {
+      findings.push(AuditFinding {
+        name: package.pkg_name.clone(),
+        version: package.pkg_version.clone(),
+        id: YANKED_ID.to_owned(),
+        description: "This version has been yanked from its registry".to_owned(),
+        informational: false,
+      });
+    }
+  }
+
+  Ok(findings)
+}
+
+/// Whether cargo's cache of the registry index of the given package lists its version as yanked.
+/// Packages which cargo has not cached the index entries of are assumed not to be.
+fn is_yanked(
+  raze_metadata: &RazeMetadata,
+  name: &str,
+  version: &Version,
+  cargo_home: &Path,
+) -> Result<bool> {
+  let index_url = raze_metadata
+    .metadata
+    .packages
+    .iter()
+    .find(|package| package.name == name && &package.version == version)
+    .and_then(|package| package.source.as_ref())
+    .and_then(|source| source_index_url(&source.repr));
+  let index_url = match index_url {
+    Some(index_url) => Url::parse(index_url)?,
+    None => return Ok(false),
+  };
+
+  let version = version.to_string();
+  Ok(
+    read_cargo_index_cache(cargo_home, &index_url, name)?
+      .unwrap_or_default()
+      .iter()
+      .any(|entry| entry.yanked && entry.name.eq_ignore_ascii_case(name) && entry.vers == version),
+  )
+}
+
+/// Prints `findings`, failing if any of them are denied by `policy`.
+pub fn check_findings(findings: &[AuditFinding], policy: AuditPolicy) -> Result<()> {
+  for finding in findings.iter() {
+    eprintln!("WARNING: {}", format_finding(finding));
+  }
+
+  let denied = findings
+    .iter()
+    .filter(|finding| !finding.informational)
+    .count();
+  if policy == AuditPolicy::Deny && denied > 0 {
+    return Err(anyhow!(
+      "{} crates have security advisories or are yanked. Upgrade them, or list the findings in \
+       the `audit_ignore` setting of the crates",
+      denied
+    ));
+  }
+
+  Ok(())
+}
+
+fn format_finding(finding: &AuditFinding) -> String {
+  format!(
+    "{} {}: {}: {}",
+    finding.name, finding.version, finding.id, finding.description
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use indoc::indoc;
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::{
+    metadata::index_entry_path,
+    planning::{BuildPlanner, BuildPlannerImpl},
+    settings::{tests::dummy_raze_settings, CrateSettings, RazeSettings},
+    testing::*,
+    util::PlatformDetails,
+  };
+
+  fn write_advisory(db_dir: &Path, package: &str, id: &str, content: &str) {
+    let package_dir = db_dir.join("crates").join(package);
+    fs::create_dir_all(&package_dir).unwrap();
+    fs::write(package_dir.join(format!("{}.md", id)), content).unwrap();
+  }
+
+  fn plan(settings: RazeSettings) -> (RazeMetadata, PlannedBuild) {
+    let raze_metadata = template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    let planned_build = BuildPlannerImpl::new(raze_metadata.clone(), settings)
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    (raze_metadata, planned_build)
+  }
+
+  #[test]
+  fn test_advisories_are_parsed_from_both_formats() {
+    let advisory = parse_advisory(indoc! { r#"
+      ```toml
+      [advisory]
+      id = "RUSTSEC-2020-0071"
+      package = "time"
+      date = "2020-11-18"
+      url = "https://github.com/time-rs/time/issues/293"
+      categories = ["code-execution", "memory-corruption"]
+
+      [affected.functions]
+      "time::UtcOffset::local_offset_at" = ["< 0.2.23"]
+
+      [versions]
+      patched = [">= 0.2.23"]
+      unaffected = ["= 0.2.0", ">= 0.1.0, < 0.1.5"]
+      ```
+
+      # Potential segfault in the time crate
+
+      Details of the advisory.
+    "# })
+    .unwrap()
+    .unwrap();
+    assert_eq!(advisory.id, "RUSTSEC-2020-0071");
+    assert_eq!(advisory.package, "time");
+    assert_eq!(advisory.title, "Potential segfault in the time crate");
+    assert_eq!(advisory.informational, None);
+    assert_eq!(advisory.unaffected.len(), 3);
+
+    let advisory = parse_advisory(indoc! { r#"
+      [advisory]
+      id = "RUSTSEC-2016-0001"
+      package = "openssl"
+      title = "SSL/TLS MitM vulnerability due to insecure defaults"
+      informational = "unsound"
+
+      [versions]
+      patched = [">= 0.9.0"]
+    "# })
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+      advisory.title,
+      "SSL/TLS MitM vulnerability due to insecure defaults"
+    );
+    assert_eq!(advisory.informational.as_deref(), Some("unsound"));
+
+    // Withdrawn advisories are skipped
+    assert_eq!(
+      parse_advisory(indoc! { r#"
+        [advisory]
+        id = "RUSTSEC-2019-0001"
+        package = "foo"
+        withdrawn = "2019-02-01"
+      "# })
+      .unwrap(),
+      None
+    );
+  }
+
+  #[test]
+  fn test_vulnerable_crates_are_found_unless_ignored() {
+    let db_dir = TempDir::new().unwrap();
+    write_advisory(
+      db_dir.as_ref(),
+      "log",
+      "RUSTSEC-2099-0001",
+      indoc! { r#"
+        ```toml
+        [advisory]
+        id = "RUSTSEC-2099-0001"
+        package = "log"
+
+        [versions]
+        patched = [">= 0.4.14"]
+        unaffected = ["< 0.4.0"]
+        ```
+
+        # Log lines may be lost
+      "# },
+    );
+    write_advisory(
+      db_dir.as_ref(),
+      "log",
+      "RUSTSEC-2099-0002",
+      indoc! { r#"
+        ```toml
+        [advisory]
+        id = "RUSTSEC-2099-0002"
+        package = "log"
+
+        [versions]
+        patched = [">= 0.3.0"]
+        ```
+
+        # An advisory of an older version
+      "# },
+    );
+    let advisories = load_advisory_db(db_dir.as_ref()).unwrap();
+    assert_eq!(advisories.len(), 2);
+    let cargo_home = TempDir::new().unwrap();
+
+    let (raze_metadata, planned_build) = plan(dummy_raze_settings());
+    let findings = audit_plan(
+      &planned_build,
+      &raze_metadata,
+      &advisories,
+      cargo_home.as_ref(),
+    )
+    .unwrap();
+    assert_eq!(
+      findings,
+      vec![AuditFinding {
+        name: "log".to_owned(),
+        version: Version::parse("0.4.13").unwrap(),
+        id: "RUSTSEC-2099-0001".to_owned(),
+        description: "Log lines may be lost".to_owned(),
+        informational: false,
+      }]
+    );
+    assert!(check_findings(&findings, AuditPolicy::Warn).is_ok());
+    let message = check_findings(&findings, AuditPolicy::Deny)
+      .unwrap_err()
+      .to_string();
+    assert!(
+      message.starts_with("1 crates have security advisories"),
+      "{}",
+      message
+    );
+
+    let mut settings = dummy_raze_settings();
+    let mut versions = HashMap::new();
+    versions.insert(
+      VersionReq::parse("0.4.13").unwrap(),
+      CrateSettings {
+        audit_ignore: vec!["RUSTSEC-2099-0001".to_owned()],
+        ..Default::default()
+      },
+    );
+    settings.crates.insert("log".to_owned(), versions);
+    let (raze_metadata, planned_build) = plan(settings);
+    assert!(audit_plan(
+      &planned_build,
+      &raze_metadata,
+      &advisories,
+      cargo_home.as_ref()
+    )
+    .unwrap()
+    .is_empty());
+  }
+
+  #[test]
+  fn test_yanked_crates_are_found_in_cargos_index_cache() {
+    let (raze_metadata, planned_build) = plan(dummy_raze_settings());
+    let cargo_home = TempDir::new().unwrap();
+    let index_cache = cargo_home
+      .as_ref()
+      .join("registry/index/github.com-1ecc6299db9ec823/.cache")
+      .join(index_entry_path("log"));
+    fs::create_dir_all(index_cache.parent().unwrap()).unwrap();
+    let mut index_cache_content = vec![3, 2, 0, 0, 0];
+    index_cache_content.extend(b"some-revision\0");
+    index_cache_content.extend(b"0.4.13\0");
+    index_cache_content.extend(
+      mock_crate_index_line("log", "0.4.13", &"0".repeat(64))
+        .replace("\"yanked\":false", "\"yanked\":true")
+        .as_bytes(),
+    );
+    index_cache_content.push(0);
+    fs::write(&index_cache, index_cache_content).unwrap();
+
+    let findings = audit_plan(&planned_build, &raze_metadata, &[], cargo_home.as_ref()).unwrap();
+    assert_eq!(
+      findings
+        .iter()
+        .map(|finding| (finding.name.as_str(), finding.id.as_str()))
+        .collect::<Vec<_>>(),
+      vec![("log", "yanked")]
+    );
+  }
+}
@@ -0,0 +1,178 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashSet, fs, iter, path::PathBuf};
+
+use anyhow::{Context, Result};
+use itertools::Itertools;
+
+use crate::{planning::PlannedBuild, rendering::RenderDetails};
+
+/// The line opening the section of a `.bazelignore` file which is managed by cargo-raze
+pub const SECTION_BEGIN: &str = "# BEGIN cargo-raze vendored directories";
+
+/// The line closing the section of a `.bazelignore` file which is managed by cargo-raze
+pub const SECTION_END: &str = "# END cargo-raze vendored directories";
+
+/// Lists the directories of the vendor tree which no BUILD file is generated for, eg: crates
+/// `cargo vendor` fetched for platforms outside of `targets`, relative to the Bazel workspace root
+/// and in the format of `.bazelignore` entries.
+pub fn unplanned_vendored_directories(
+  render_details: &RenderDetails,
+  planned_build: &PlannedBuild,
+) -> Result<Vec<String>> {
+  let vendor_dir = render_details.path_prefix.join("vendor");
+  let planned_dirs: HashSet<PathBuf> = planned_build
+    .crate_contexts
+    .iter()
+    .filter(|ctx| ctx.expected_build_path.starts_with("vendor/"))
+    .filter_map(|ctx| {
+      PathBuf::from(&ctx.expected_build_path)
+        .parent()
+        .map(|dir| render_details.path_prefix.join(dir))
+    })
+    .collect();
+
+  let absolute_vendor_dir = render_details.bazel_root.join(&vendor_dir);
+  if !absolute_vendor_dir.is_dir() {
+    return Ok(Vec::new());
+  }
+
+  let mut directories = Vec::new();
+  for entry in fs::read_dir(&absolute_vendor_dir)
+    .with_context(|| format!("Failed to read {}", absolute_vendor_dir.display()))?
+  {
+    let entry = entry?;
+    if !entry.file_type()?.is_dir() {
+      continue;
+    }
+    let dir = vendor_dir.join(entry.file_name());
+    if !planned_dirs.contains(&dir) {
+      directories.push(dir.to_string_lossy().into_owned());
+    }
+  }
+  directories.sort();
+
+  Ok(directories)
+}
+
+/// Replaces the section of the `.bazelignore` file `contents` managed by cargo-raze with one
+/// listing `directories`, appending the section if there is none yet. Any other entries are kept.
+pub fn update_bazelignore(contents: &str, directories: &[String]) -> String {
+  let section = iter::once(SECTION_BEGIN)
+    .chain(directories.iter().map(String::as_str))
+    .chain(iter::once(SECTION_END))
+    .join("\n");
+
+  let mut lines: Vec<&str> = contents.lines().collect();
+  let begin = lines.iter().position(|line| line.trim() == SECTION_BEGIN);
+  let end = lines.iter().position(|line| line.trim() == SECTION_END);
+  match (begin, end) {
+    (Some(begin), Some(end)) if begin < end => {
+      lines.splice(begin..=end, iter::once(section.as_str()));
+    },
+    _ => {
+      if matches!(lines.last(), Some(line) if !line.trim().is_empty()) {
+        lines.push("");
+      }
+      lines.push(&section);
+    },
+  }
+
+  lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::{
+    planning::{BuildPlanner, BuildPlannerImpl},
+    settings::{tests::dummy_raze_settings, GenMode},
+    testing::*,
+    util::PlatformDetails,
+  };
+
+  #[test]
+  fn test_unplanned_vendored_directories_are_listed() {
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Vendored;
+    let planned_build = BuildPlannerImpl::new(
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings.clone(),
+    )
+    .plan_build(Some(PlatformDetails::new(
+      "some_target_triple".to_owned(),
+      Vec::new(), /* attrs */
+    )))
+    .unwrap();
+
+    let bazel_root = TempDir::new().unwrap();
+    let vendor_dir = bazel_root.as_ref().join("cargo/vendor");
+    for dir in &["cfg-if-0.1.10", "log-0.4.13", "winapi-0.3.9", "wasi-0.10.2"] {
+      fs::create_dir_all(vendor_dir.join(dir)).unwrap();
+    }
+    fs::write(vendor_dir.join("README.md"), "").unwrap();
+
+    let render_details = RenderDetails::new(
+      &settings,
+      PathBuf::from("/some/cargo/root"),
+      bazel_root.as_ref().to_path_buf(),
+    );
+    assert_eq!(
+      unplanned_vendored_directories(&render_details, &planned_build).unwrap(),
+      vec!["cargo/vendor/wasi-0.10.2", "cargo/vendor/winapi-0.3.9"]
+    );
+  }
+
+  #[test]
+  fn test_bazelignore_section_is_replaced_in_place() {
+    let directories = vec!["cargo/vendor/winapi-0.3.9".to_owned()];
+    let appended = update_bazelignore("node_modules\n", &directories);
+    assert_eq!(
+      appended,
+      concat!(
+        "node_modules\n",
+        "\n",
+        "# BEGIN cargo-raze vendored directories\n",
+        "cargo/vendor/winapi-0.3.9\n",
+        "# END cargo-raze vendored directories\n",
+      )
+    );
+
+    let replaced = update_bazelignore(
+      &(appended + "bazel-out\n"),
+      &["cargo/vendor/wasi-0.10.2".to_owned()],
+    );
+    assert_eq!(
+      replaced,
+      concat!(
+        "node_modules\n",
+        "\n",
+        "# BEGIN cargo-raze vendored directories\n",
+        "cargo/vendor/wasi-0.10.2\n",
+        "# END cargo-raze vendored directories\n",
+        "bazel-out\n",
+      )
+    );
+    assert_eq!(
+      update_bazelignore("", &[]),
+      concat!(
+        "# BEGIN cargo-raze vendored directories\n",
+        "# END cargo-raze vendored directories\n",
+      )
+    );
+  }
+}
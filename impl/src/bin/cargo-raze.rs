@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::{
+  collections::BTreeMap,
   env,
   fs::{self, File},
   io::Write,
@@ -25,18 +26,24 @@ use cargo_metadata::Metadata;
 use docopt::Docopt;
 
 use cargo_raze::{
-  checks,
-  metadata::{MetadataFetcher, RazeMetadata, RazeMetadataFetcher},
-  planning::{BuildPlanner, BuildPlannerImpl, PlannedBuild},
-  rendering::FileOutputs,
-  rendering::{bazel::BazelRenderer, BuildRenderer, RenderDetails},
+  audit, bazelignore, checks,
+  cleaning::{self, OutputManifest},
+  drift,
+  graph::{self, GraphFormat},
+  incremental,
+  metadata::{default_cargo_home, RazeMetadata},
+  patching,
+  planning::PlannedBuild,
+  pruning,
+  rendering::RenderDetails,
+  report,
   settings::RazeSettings,
-  settings::{load_settings, GenMode, SettingsMetadataFetcher},
-  util::{find_bazel_workspace_root, find_lockfile, PlatformDetails},
+  settings::{format_settings, GenMode},
+  util::find_lockfile,
+  Raze, RazeBuilder,
 };
 
 use serde::Deserialize;
-use url::Url;
 
 #[derive(Debug, Deserialize)]
 struct Options {
@@ -50,6 +57,25 @@ struct Options {
   flag_output: Option<String>,
   flag_manifest_path: Option<String>,
   flag_generate_lockfile: Option<bool>,
+  flag_print_config: Option<bool>,
+  flag_no_cache: Option<bool>,
+  flag_no_clean: Option<bool>,
+  flag_cargo_target_dir: Option<String>,
+  flag_features: Option<String>,
+  flag_all_features: Option<bool>,
+  flag_minimal_versions: Option<bool>,
+  flag_report: Option<String>,
+  flag_compare_to: Option<String>,
+  flag_offline: Option<bool>,
+  flag_since: Option<String>,
+  flag_feature_profile: Option<String>,
+  flag_bazelignore: Option<String>,
+  flag_allow_unverified: Option<bool>,
+  flag_jobs: Option<usize>,
+  flag_check: Option<bool>,
+  flag_output_format: Option<String>,
+  flag_graph: Option<String>,
+  flag_audit: Option<bool>,
 }
 
 const USAGE: &str = r#"
@@ -59,7 +85,12 @@ Usage:
     cargo-raze (-h | --help)
     cargo-raze (-V | --version)
     cargo-raze [--verbose] [--quiet] [--color=<WHEN>] [--dryrun] [--cargo-bin-path=<PATH>] 
-               [--manifest-path=<PATH>] [--output=<PATH>] [--generate-lockfile]
+               [--manifest-path=<PATH>] [--output=<PATH>] [--generate-lockfile] [--print-config]
+               [--no-cache] [--no-clean] [--cargo-target-dir=<PATH>] [--features=<FEATURES>]
+               [--all-features] [--minimal-versions] [--report=<PATH>] [--compare-to=<PATH>]
+               [--offline] [--since=<REF>] [--feature-profile=<NAME>] [--bazelignore=<PATH>]
+               [--allow-unverified] [--jobs=<N>] [--check] [--output-format=<FORMAT>]
+               [--graph=<PATH>] [--audit]
 
 Options:
     -h, --help                          Print this message
@@ -72,6 +103,25 @@ Options:
     --manifest-path=<PATH>              Path to the Cargo.toml file to generate BUILD files for
     --output=<PATH>                     Path to output the generated into.
     --generate-lockfile                 Force a new `Cargo.raze.lock` file to be generated
+    --print-config                      Print the effective raze settings, including any `CARGO_RAZE_<SETTING>` environment variable overrides, and exit
+    --no-cache                          Always run `cargo metadata` and plan and render every crate instead of reusing cached results
+    --no-clean                          Keep the files and vendored crates generated by earlier runs which are no longer part of the plan, instead of removing them
+    --cargo-target-dir=<PATH>           Directory for the internal cargo invocations to use instead of the workspace's `target/`
+    --features=<FEATURES>               Space or comma separated features to resolve with. Use `member/feature` to enable a feature of a single workspace member
+    --all-features                      Resolve with every feature of every workspace member enabled
+    --minimal-versions                  Resolve every dependency to the lowest version its requirements allow instead of reusing the lockfile, as with cargo's `-Z minimal-versions`
+    --report=<PATH>                     Write a JSON summary of what was done with every resolved crate, and why, along with the dependencies whose features were unified across workspace members, to this path
+    --compare-to=<PATH>                 Print the crates added, removed, upgraded or rebuilt with other features or checksums compared to the plan of this `--report` file, without writing any files
+    --offline                           Fail instead of accessing the network, reading crates from the local index and `crate_cache_dir` or else cargo's registry cache
+    --since=<REF>                       Only rewrite the files of crates whose locked inputs changed since this git revision
+    --feature-profile=<NAME>            Resolve with the features of this entry of the `feature_profiles` raze setting, in addition to any `--features`
+    --bazelignore=<PATH>                Maintain a section of this `.bazelignore` file listing the vendored directories no BUILD file is generated for
+    --allow-unverified                  Skip checking downloaded crate archives against the checksums of the lockfile and crate index
+    -j, --jobs=<N>                      Download up to this many crates at a time, overriding the `max_concurrent_downloads` raze setting
+    --check                             Print a diff of the generated files which are out of date and fail if there are any, without writing any files
+    --output-format=<FORMAT>            Print the dependency graph of the plan as `json` or `dot` instead of generating any files. Defaults to `bazel`, which generates BUILD files
+    --graph=<PATH>                      Also write the dependency graph of the plan to this path, as DOT if it ends in `.dot` and as JSON otherwise
+    --audit                             Check the planned crates for RustSec advisories and yanked versions, as with the `audit` raze setting
 "#;
 
 fn main() -> Result<()> {
@@ -79,20 +129,150 @@ fn main() -> Result<()> {
   let options = parse_options();
 
   // Load settings
-  let (local_metadata, settings) = load_raze_settings(&options)?;
+  let raze = raze_builder(&options).build()?;
+  let (local_metadata, settings) = (raze.local_metadata(), raze.settings());
+  if options.flag_verbose.unwrap_or(false) {
+    println!("Loaded override settings: {:#?}", settings);
+  }
+
+  // Print the effective settings instead of generating any files
+  if options.flag_print_config.unwrap_or(false) {
+    println!("{}", format_settings(settings)?);
+    return Ok(());
+  }
+
+  // Warn about outputs which would be deleted by `cargo clean`
+  if let Err(err) = checks::check_output_dir_outside_target_dir(
+    settings,
+    raze.bazel_workspace_root(),
+    local_metadata,
+  ) {
+    eprintln!("WARNING: {}", err);
+  }
+
+  // Bail out on unknown formats before doing any of the work
+  let graph_format: Option<GraphFormat> = match options.flag_output_format.as_deref() {
+    None | Some("bazel") => None,
+    Some(format) => Some(format.parse()?),
+  };
 
   // Fetch metadata
-  let raze_metadata = fetch_raze_metadata(&options, &settings, &local_metadata)?;
+  let raze_metadata = raze.fetch_metadata()?;
 
   // Do Planning
-  let planned_build = do_planning(&settings, &raze_metadata)?;
+  let planned_build = raze.plan(&raze_metadata)?;
+
+  // Check the planned crates for security advisories and yanked versions
+  if settings.audit || options.flag_audit.unwrap_or(false) {
+    audit_crates(
+      settings,
+      &options,
+      &raze_metadata,
+      &planned_build,
+      local_metadata,
+    )?;
+  }
+
+  // Write the audit report of the planning decisions
+  if let Some(report_path) = &options.flag_report {
+    let report = report::produce_report(&raze_metadata, settings, &planned_build)?;
+    report::write_report(&report, Path::new(report_path))?;
+  }
+
+  // Export the dependency graph of the plan for other tools
+  if let Some(graph_path) = &options.flag_graph {
+    graph::write_graph(&planned_build, Path::new(graph_path))?;
+  }
+
+  // Print the dependency graph of the plan instead of generating any files
+  if let Some(format) = graph_format {
+    print!(
+      "{}",
+      graph::format_graph(&graph::produce_graph(&planned_build), format)?
+    );
+    return Ok(());
+  }
+
+  // Summarize how the plan differs from a previously exported one instead of generating any files
+  if let Some(old_report_path) = &options.flag_compare_to {
+    let old_report = report::read_report(Path::new(old_report_path))?;
+    let report = report::produce_report(&raze_metadata, settings, &planned_build)?;
+    print!(
+      "{}",
+      report::format_changes(&report::compare_reports(&old_report, &report))
+    );
+    return Ok(());
+  }
 
   // Render BUILD files
-  let (render_details, bazel_file_outputs) =
-    render_files(&settings, &raze_metadata, &planned_build, &local_metadata)?;
+  let (render_details, bazel_file_outputs) = raze.render(&raze_metadata, &planned_build)?;
+
+  // Skip rewriting the files of crates which did not change since the requested revision
+  let changed_file_outputs = match &options.flag_since {
+    Some(git_ref) => affected_file_outputs(
+      git_ref,
+      &bazel_file_outputs,
+      &render_details,
+      &planned_build,
+      &raze_metadata,
+      settings,
+      local_metadata,
+    )?,
+    None => bazel_file_outputs.clone(),
+  };
+
+  // Report the files which are out of date instead of writing them
+  if options.flag_check.unwrap_or(false) {
+    return check_files(
+      &bazel_file_outputs,
+      &changed_file_outputs,
+      &render_details,
+      &planned_build,
+      settings,
+      &options,
+    );
+  }
 
   // Write BUILD files
-  write_files(&bazel_file_outputs, &render_details, &settings, &options)?;
+  write_files(
+    &bazel_file_outputs,
+    &changed_file_outputs,
+    &render_details,
+    &planned_build,
+    settings,
+    &options,
+  )?;
+
+  // Strip vendored crates of the sources which are not needed to build them, and patch them as
+  // `http_archive` does for remote ones
+  if !options.flag_dryrun.unwrap_or(false) {
+    for (crate_dir, removed_files) in
+      pruning::prune_vendored_sources(settings, &render_details, &planned_build)?
+    {
+      if options.flag_verbose.unwrap_or(false) {
+        println!(
+          "Removed {} files from {}",
+          removed_files,
+          crate_dir.display()
+        );
+      }
+    }
+    for (patch, crate_dir) in patching::apply_vendored_patches(&render_details, &planned_build)? {
+      if options.flag_verbose.unwrap_or(false) {
+        println!("Applied {} to {}", patch.display(), crate_dir.display());
+      }
+    }
+  }
+
+  // Have Bazel skip the parts of the vendor tree which are not packages
+  if let Some(bazelignore_path) = &options.flag_bazelignore {
+    update_bazelignore(
+      Path::new(bazelignore_path),
+      &render_details,
+      &planned_build,
+      &options,
+    )?;
+  }
 
   Ok(())
 }
@@ -120,180 +300,280 @@ fn parse_options() -> Options {
   options
 }
 
-fn load_raze_settings(options: &Options) -> Result<(Metadata, RazeSettings)> {
-  let metadata = fetch_local_metadata(options)?;
-
-  // Parse settings with that metadata
-  let settings = match load_settings(&metadata) {
-    Ok(settings) => settings,
-    Err(err) => return Err(anyhow!(err.to_string())),
-  };
-
-  if options.flag_verbose.unwrap_or(false) {
-    println!("Loaded override settings: {:#?}", settings);
+/// Configures the library entry point with the flags of the command
+fn raze_builder(options: &Options) -> RazeBuilder {
+  let mut builder = Raze::builder()
+    .all_features(options.flag_all_features.unwrap_or(false))
+    .minimal_versions(options.flag_minimal_versions.unwrap_or(false))
+    .offline(options.flag_offline.unwrap_or(false))
+    .use_metadata_cache(!options.flag_no_cache.unwrap_or(false))
+    .generate_lockfile(options.flag_generate_lockfile.unwrap_or(false))
+    .allow_unverified(options.flag_allow_unverified.unwrap_or(false));
+  if let Some(manifest_path) = &options.flag_manifest_path {
+    builder = builder.manifest_path(manifest_path);
   }
-
-  Ok((metadata, settings))
-}
-
-fn fetch_local_metadata(options: &Options) -> Result<Metadata> {
-  // Gather basic, offline metadata to parse settings from
-  let fetcher = if let Some(cargo_bin_path) = &options.flag_cargo_bin_path {
-    SettingsMetadataFetcher {
-      cargo_bin_path: PathBuf::from(cargo_bin_path),
-    }
-  } else {
-    SettingsMetadataFetcher::default()
-  };
-
-  let working_directory = if let Some(manifest_path) = &options.flag_manifest_path {
-    let manifest_path = PathBuf::from(manifest_path).canonicalize()?;
-    if !manifest_path.is_file() {
-      return Err(anyhow!(
-        "manifest path `{}` is not a file.",
-        manifest_path.display()
-      ));
-    }
-    // UNWRAP: Unwrap safe due to check above.
-    PathBuf::from(manifest_path.parent().unwrap())
-  } else {
-    env::current_dir()?
-  };
-
-  fetcher
-    .fetch_metadata(&working_directory, false)
-    .with_context(|| {
-      format!(
-        "Failed to fetch metadata for {}",
-        working_directory.display()
-      )
-    })
+  if let Some(cargo_bin_path) = &options.flag_cargo_bin_path {
+    builder = builder.cargo_bin_path(cargo_bin_path);
+  }
+  if let Some(cargo_target_dir) = &options.flag_cargo_target_dir {
+    builder = builder.cargo_target_dir(cargo_target_dir);
+  }
+  if let Some(features) = &options.flag_features {
+    builder = builder.features(vec![features.clone()]);
+  }
+  if let Some(profile) = &options.flag_feature_profile {
+    builder = builder.feature_profile(profile);
+  }
+  if let Some(jobs) = options.flag_jobs {
+    builder = builder.max_concurrent_downloads(jobs);
+  }
+  builder
 }
 
-fn fetch_raze_metadata(
-  options: &Options,
+fn audit_crates(
   settings: &RazeSettings,
+  options: &Options,
+  raze_metadata: &RazeMetadata,
+  planned_build: &PlannedBuild,
   local_metadata: &Metadata,
-) -> Result<RazeMetadata> {
-  let metadata_fetcher: RazeMetadataFetcher = match options.flag_cargo_bin_path {
-    Some(ref cargo_bin_path) => RazeMetadataFetcher::new(
-      cargo_bin_path,
-      Url::parse(&settings.registry)?,
-      Url::parse(&settings.index_url)?,
-    ),
-    None => RazeMetadataFetcher::default(),
-  };
-
-  let cargo_raze_working_dir =
-    find_bazel_workspace_root(&local_metadata.workspace_root).unwrap_or(env::current_dir()?);
-
-  let binary_dep_info = if settings.genmode == GenMode::Remote {
-    Some(&settings.binary_deps)
-  } else {
-    None
-  };
-
-  let reused_lockfile = if !options.flag_generate_lockfile.unwrap_or(false) {
-    find_lockfile(
-      &local_metadata.workspace_root,
-      &cargo_raze_working_dir.join(settings.workspace_path.trim_start_matches('/')),
-    )
-  } else {
-    None
-  };
-
-  let raze_metadata = metadata_fetcher.fetch_metadata(
-    &local_metadata.workspace_root,
-    binary_dep_info,
-    reused_lockfile,
-  )?;
-
-  checks::check_metadata(&raze_metadata, &settings, &cargo_raze_working_dir)?;
-  Ok(raze_metadata)
-}
-
-fn do_planning(settings: &RazeSettings, metadata: &RazeMetadata) -> Result<PlannedBuild> {
-  let platform_details = match &settings.target {
-    Some(target) => Some(PlatformDetails::new_using_rustc(target)?),
-    None => None,
+) -> Result<()> {
+  let cargo_home = default_cargo_home();
+  let db_dir = match &settings.advisory_db {
+    Some(db_dir) => local_metadata.workspace_root.join(db_dir),
+    None => {
+      let db_dir = cargo_home.join("advisory-db");
+      audit::fetch_advisory_db(
+        &db_dir,
+        settings.offline || options.flag_offline.unwrap_or(false),
+      )?;
+      db_dir
+    },
   };
 
-  BuildPlannerImpl::new(metadata.clone(), settings.clone()).plan_build(platform_details)
+  let advisories = audit::load_advisory_db(&db_dir)?;
+  let findings = audit::audit_plan(planned_build, raze_metadata, &advisories, &cargo_home)?;
+  audit::check_findings(&findings, settings.audit_policy)
 }
 
-fn render_files(
-  settings: &RazeSettings,
-  metadata: &RazeMetadata,
+/// Narrows `bazel_file_outputs` down to the files of crates affected by changes to the lockfile
+/// since `git_ref`. Changes to the raze settings of the workspace or any of its members affect
+/// every crate.
+fn affected_file_outputs(
+  git_ref: &str,
+  bazel_file_outputs: &BTreeMap<PathBuf, String>,
+  render_details: &RenderDetails,
   planned_build: &PlannedBuild,
+  raze_metadata: &RazeMetadata,
+  settings: &RazeSettings,
   local_metadata: &Metadata,
-) -> Result<(RenderDetails, Vec<FileOutputs>)> {
-  let cargo_raze_working_dir =
-    find_bazel_workspace_root(&local_metadata.workspace_root).unwrap_or(env::current_dir()?);
-
-  let mut bazel_renderer = BazelRenderer::new();
-  let render_details = RenderDetails {
-    cargo_root: metadata.cargo_workspace_root.clone(),
-    path_prefix: PathBuf::from(&settings.workspace_path.trim_start_matches('/')),
-    package_aliases_dir: settings.package_aliases_dir.clone(),
-    vendored_buildfile_name: settings.output_buildfile_suffix.clone(),
-    bazel_root: cargo_raze_working_dir,
-    rust_rules_workspace_name: settings.rust_rules_workspace_name.clone(),
-    experimental_api: settings.experimental_api,
-    render_package_aliases: settings.render_package_aliases,
+) -> Result<BTreeMap<PathBuf, String>> {
+  let workspace_root = &local_metadata.workspace_root;
+  let mut settings_changed =
+    incremental::raze_settings_changed(&workspace_root.join("Cargo.toml"), git_ref)?;
+  // Workspace members may carry raze settings of their own
+  for member in local_metadata
+    .packages
+    .iter()
+    .filter(|package| local_metadata.workspace_members.contains(&package.id))
+  {
+    settings_changed |= incremental::raze_settings_changed(&member.manifest_path, git_ref)?;
+  }
+  if let Some(crate_overrides_file) = &settings.crate_overrides_file {
+    let overrides_path = workspace_root.join(crate_overrides_file);
+    settings_changed |= incremental::read_file_at_ref(&overrides_path, git_ref)?
+      != Some(fs::read_to_string(&overrides_path)?);
+  }
+  if settings_changed {
+    println!(
+      "Raze settings changed since {}, regenerating all crates",
+      git_ref
+    );
+    return Ok(bazel_file_outputs.clone());
+  }
+
+  let lockfile_path = find_lockfile(
+    workspace_root,
+    &render_details.bazel_root.join(&render_details.path_prefix),
+  );
+  let old_lockfile = match &lockfile_path {
+    Some(lockfile_path) => incremental::read_lockfile_at_ref(lockfile_path, git_ref)?,
+    None => None,
   };
-  let bazel_file_outputs = match &settings.genmode {
-    GenMode::Vendored => bazel_renderer.render_planned_build(&render_details, &planned_build)?,
-    GenMode::Remote => {
-      bazel_renderer.render_remote_planned_build(&render_details, &planned_build)?
-    }, /* exhaustive, we control the definition */
-    // There are no file outputs to produce if `genmode` is Unspecified
-    GenMode::Unspecified => Vec::new(),
+  let (old_lockfile, new_lockfile) = match (old_lockfile, &raze_metadata.lockfile) {
+    (Some(old_lockfile), Some(new_lockfile)) => (old_lockfile, new_lockfile),
+    _ => {
+      println!(
+        "No lockfile to compare against at {}, regenerating all crates",
+        git_ref
+      );
+      return Ok(bazel_file_outputs.clone());
+    },
   };
 
-  Ok((render_details, bazel_file_outputs))
+  let affected = incremental::affected_crates(&old_lockfile, new_lockfile);
+  Ok(incremental::retain_affected_outputs(
+    bazel_file_outputs,
+    render_details,
+    planned_build,
+    &affected,
+  ))
 }
 
 fn write_files(
-  bazel_file_outputs: &[FileOutputs],
+  bazel_file_outputs: &BTreeMap<PathBuf, String>,
+  changed_file_outputs: &BTreeMap<PathBuf, String>,
   render_details: &RenderDetails,
+  planned_build: &PlannedBuild,
   settings: &RazeSettings,
   options: &Options,
 ) -> Result<()> {
-  if settings.genmode == GenMode::Remote {
-    let remote_dir = render_details
-      .bazel_root
-      .join(&render_details.path_prefix)
-      .join("remote");
+  let (dryrun, verbose) = (
+    options.flag_dryrun.unwrap_or(false),
+    options.flag_verbose.unwrap_or(false),
+  );
+  let mut output_manifest = OutputManifest::new(bazel_file_outputs, render_details, planned_build);
+  let stale_outputs = stale_outputs(&output_manifest, render_details)?;
+  if options.flag_no_clean.unwrap_or(false) {
+    // Outputs which are kept stay tracked, so a later run can still remove them
+    output_manifest.extend(stale_outputs);
+  } else {
     // Clean out the "remote" directory so users can easily see what build files are relevant
-    if remote_dir.exists() {
-      let build_glob = format!("{}/BUILD*.bazel", remote_dir.display());
-      for entry in glob::glob(&build_glob)? {
-        if let Ok(path) = entry {
-          fs::remove_file(path)?;
+    for path in stale_remote_build_files(bazel_file_outputs, render_details, settings)? {
+      fs::remove_file(path)?;
+    }
+
+    // Remove the files and vendored crates of earlier runs which are no longer generated
+    if dryrun {
+      for path in stale_outputs
+        .existing_vendored_dirs(render_details)
+        .into_iter()
+        .chain(stale_outputs.existing_files(render_details))
+      {
+        println!("Would remove {}", path.display());
+      }
+    } else {
+      for path in cleaning::remove_stale_outputs(render_details, &stale_outputs)? {
+        if verbose {
+          println!("Removed {}", path.display());
         }
       }
     }
   }
 
-  for output in bazel_file_outputs.iter() {
+  for (path, contents) in changed_file_outputs.iter() {
     if options.flag_dryrun.unwrap_or(false) {
-      println!("{}:\n{}", output.path.display(), output.contents);
+      println!("{}:\n{}", path.display(), contents);
       continue;
     }
     // Ensure all parent directories exist
-    if let Some(parent) = &output.path.parent() {
+    if let Some(parent) = &path.parent() {
       fs::create_dir_all(parent)?
     }
-    write_to_file(
-      &output.path,
-      &output.contents,
-      options.flag_verbose.unwrap_or(false),
-    )?;
+    write_to_file(path, contents, options.flag_verbose.unwrap_or(false))?;
   }
 
+  if !dryrun {
+    cleaning::write_output_manifest(render_details, &output_manifest)?;
+  }
   Ok(())
 }
 
+/// The outputs listed by the manifest of the last run which are missing from `output_manifest`
+fn stale_outputs(
+  output_manifest: &OutputManifest,
+  render_details: &RenderDetails,
+) -> Result<OutputManifest> {
+  Ok(
+    cleaning::read_output_manifest(render_details)?
+      .map(|previous| previous.stale_outputs(output_manifest))
+      .unwrap_or_default(),
+  )
+}
+
+/// Lists the BUILD files of the "remote" directory which are no longer generated in Remote genmode.
+fn stale_remote_build_files(
+  bazel_file_outputs: &BTreeMap<PathBuf, String>,
+  render_details: &RenderDetails,
+  settings: &RazeSettings,
+) -> Result<Vec<PathBuf>> {
+  let remote_dir = render_details
+    .bazel_root
+    .join(&render_details.path_prefix)
+    .join("remote");
+  if settings.genmode != GenMode::Remote || !remote_dir.exists() {
+    return Ok(Vec::new());
+  }
+
+  let build_glob = format!("{}/BUILD*.bazel", remote_dir.display());
+  Ok(
+    glob::glob(&build_glob)?
+      .filter_map(|entry| entry.ok())
+      .filter(|path| !bazel_file_outputs.contains_key(path))
+      .collect(),
+  )
+}
+
+/// Prints a unified diff of each file of `changed_file_outputs` which differs from the one on disk,
+/// along with the stale files which would be removed, failing if there are any.
+fn check_files(
+  bazel_file_outputs: &BTreeMap<PathBuf, String>,
+  changed_file_outputs: &BTreeMap<PathBuf, String>,
+  render_details: &RenderDetails,
+  planned_build: &PlannedBuild,
+  settings: &RazeSettings,
+  options: &Options,
+) -> Result<()> {
+  let mut stale_files = Vec::new();
+  if !options.flag_no_clean.unwrap_or(false) {
+    let output_manifest = OutputManifest::new(bazel_file_outputs, render_details, planned_build);
+    stale_files.extend(stale_remote_build_files(
+      bazel_file_outputs,
+      render_details,
+      settings,
+    )?);
+    stale_files
+      .extend(stale_outputs(&output_manifest, render_details)?.existing_files(render_details));
+    stale_files.sort();
+    stale_files.dedup();
+  }
+  let diffs = drift::diff_outputs(changed_file_outputs, &stale_files)?;
+  if diffs.is_empty() {
+    return Ok(());
+  }
+
+  for diff in diffs.iter() {
+    print!("{}", diff);
+  }
+  Err(anyhow!(
+    "{} generated files are out of date, run cargo-raze to regenerate them",
+    diffs.len()
+  ))
+}
+
+/// Updates the section of the `.bazelignore` file at `path` managed by cargo-raze to list the
+/// vendored directories of `planned_build` which have no BUILD file.
+fn update_bazelignore(
+  path: &Path,
+  render_details: &RenderDetails,
+  planned_build: &PlannedBuild,
+  options: &Options,
+) -> Result<()> {
+  let directories = bazelignore::unplanned_vendored_directories(render_details, planned_build)?;
+  let contents = match path.exists() {
+    true => {
+      fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?
+    },
+    false => String::new(),
+  };
+  let contents = bazelignore::update_bazelignore(&contents, &directories);
+
+  if options.flag_dryrun.unwrap_or(false) {
+    println!("{}:\n{}", path.display(), contents);
+    return Ok(());
+  }
+  write_to_file(path, &contents, options.flag_verbose.unwrap_or(false))
+}
+
 /// Writes rendered files to filesystem.
 fn write_to_file(path: &Path, contents: &str, verbose: bool) -> Result<()> {
   File::create(&path).and_then(|mut f| f.write_all(contents.as_bytes()))?;
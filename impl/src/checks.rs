@@ -24,7 +24,7 @@ use anyhow::{anyhow, Result};
 use crate::{
   error::RazeError,
   metadata::RazeMetadata,
-  settings::{CrateSettingsPerVersion, GenMode, RazeSettings},
+  settings::{crate_genmode, CrateSettingsPerVersion, GenMode, RazeSettings},
   util::collect_up_to,
   util::package_ident,
 };
@@ -44,9 +44,7 @@ pub fn check_metadata(
   // Check for errors
   check_resolve_matches_packages(&raze_metadata.metadata)?;
 
-  if settings.genmode == GenMode::Vendored {
-    check_all_vendored(&raze_metadata.metadata, settings, bazel_workspace_root)?;
-  }
+  check_all_vendored(&raze_metadata.metadata, settings, bazel_workspace_root)?;
 
   // Check for incomplete lockfiles
   if let Err(err) =
@@ -61,6 +59,35 @@ pub fn check_metadata(
   Ok(())
 }
 
+/// Ensures generated files are not written within a Cargo `target/` directory, where they would be
+/// deleted by `cargo clean`.
+pub fn check_output_dir_outside_target_dir(
+  settings: &RazeSettings,
+  bazel_workspace_root: &Path,
+  local_metadata: &Metadata,
+) -> Result<()> {
+  let output_dir = bazel_workspace_root.join(settings.workspace_path.trim_start_matches('/'));
+  let target_dirs = [
+    local_metadata.target_directory.clone(),
+    local_metadata.workspace_root.join("target"),
+  ];
+
+  if let Some(target_dir) = target_dirs
+    .iter()
+    .find(|target_dir| output_dir.starts_with(target_dir))
+  {
+    return Err(anyhow!(
+      "The output directory `{}` for `workspace_path = \"{}\"` is within the Cargo target \
+       directory `{}`. Files generated there will be deleted by `cargo clean`.",
+      output_dir.display(),
+      settings.workspace_path,
+      target_dir.display()
+    ));
+  }
+
+  Ok(())
+}
+
 fn check_lockfile_for_missing_checksums(
   metadata: &Metadata,
   checksums: &HashMap<String, String>,
@@ -102,7 +129,8 @@ fn check_lockfile_for_missing_checksums(
   Ok(())
 }
 
-/// Verifies that all provided packages are vendored (in settings.vendor_dir relative to CWD)
+/// Verifies that all provided packages generated in Vendored mode are vendored (in
+/// settings.vendor_dir relative to CWD)
 fn check_all_vendored(
   metadata: &Metadata,
   settings: &RazeSettings,
@@ -112,6 +140,7 @@ fn check_all_vendored(
     .packages
     .iter()
     .filter(|pkg| !metadata.workspace_members.contains(&pkg.id))
+    .filter(|pkg| crate_genmode(settings, pkg) == GenMode::Vendored)
     .collect();
 
   let missing_package_ident_iter = non_workspace_packages
@@ -296,6 +325,26 @@ mod tests {
     assert!(result.is_err());
   }
 
+  #[test]
+  fn test_output_dir_within_target_dir_is_reported() {
+    let mut metadata = template_metadata(templates::DUMMY_WORKSPACE_MEMBERS_METADATA);
+    metadata.workspace_root = PathBuf::from("/some/workspace");
+    metadata.target_directory = PathBuf::from("/some/workspace/target");
+    let mut settings = dummy_raze_settings();
+
+    settings.workspace_path = "//third_party/cargo".to_owned();
+    check_output_dir_outside_target_dir(&settings, Path::new("/some/workspace"), &metadata)
+      .unwrap();
+
+    settings.workspace_path = "//target/cargo".to_owned();
+    let result =
+      check_output_dir_outside_target_dir(&settings, Path::new("/some/workspace"), &metadata);
+    assert!(result
+      .unwrap_err()
+      .to_string()
+      .contains("within the Cargo target directory `/some/workspace/target`"));
+  }
+
   #[test]
   fn test_missing_checksums() {
     let metadata = template_metadata(templates::DUMMY_WORKSPACE_MEMBERS_METADATA);
@@ -0,0 +1,287 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  fs,
+  path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{planning::PlannedBuild, rendering::RenderDetails};
+
+/// The name of the file listing the outputs of the last run, which is kept next to `crates.bzl`
+pub const OUTPUT_MANIFEST_FILE: &str = "raze_outputs.json";
+
+/// The files and vendored crate directories generated by a run of cargo-raze within the directory
+/// of the manifest, relative to it, so the ones a later run no longer generates can be removed.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OutputManifest {
+  pub files: BTreeSet<PathBuf>,
+  pub vendored_dirs: BTreeSet<PathBuf>,
+}
+
+impl OutputManifest {
+  /// Lists the files of `bazel_file_outputs` and the vendored crate directories of
+  /// `planned_build`. Files outside of the output directory, eg: those of workspace members, are
+  /// never removed, so they are not listed.
+  pub fn new(
+    bazel_file_outputs: &BTreeMap<PathBuf, String>,
+    render_details: &RenderDetails,
+    planned_build: &PlannedBuild,
+  ) -> OutputManifest {
+    let output_dir = output_dir(render_details);
+
+    OutputManifest {
+      files: bazel_file_outputs
+        .keys()
+        .filter_map(|path| path.strip_prefix(&output_dir).ok())
+        .map(Path::to_path_buf)
+        .collect(),
+      vendored_dirs: planned_build
+        .crate_contexts
+        .iter()
+        .filter(|ctx| ctx.expected_build_path.starts_with("vendor/"))
+        .filter_map(|ctx| {
+          Path::new(&ctx.expected_build_path)
+            .parent()
+            .map(Path::to_path_buf)
+        })
+        .collect(),
+    }
+  }
+
+  /// Fails if any output of this manifest is not a path below the output directory, eg: an
+  /// absolute path, one with `.` or `..` components, or the output directory itself, as removing
+  /// it could delete anything.
+  pub fn validate(&self) -> Result<()> {
+    for path in self.files.iter().chain(self.vendored_dirs.iter()) {
+      let below_output_dir = path.components().next().is_some()
+        && path
+          .components()
+          .all(|component| matches!(component, Component::Normal(_)));
+      if !below_output_dir {
+        bail!(
+          "The output `{}` of {} is not within its directory",
+          path.display(),
+          OUTPUT_MANIFEST_FILE
+        );
+      }
+    }
+    Ok(())
+  }
+
+  /// The outputs of this manifest which are missing from `current`
+  pub fn stale_outputs(&self, current: &OutputManifest) -> OutputManifest {
+    OutputManifest {
+      files: self.files.difference(&current.files).cloned().collect(),
+      vendored_dirs: self
+        .vendored_dirs
+        .difference(&current.vendored_dirs)
+        .cloned()
+        .collect(),
+    }
+  }
+
+  /// Adds the outputs of `other` to this manifest, eg: to keep tracking stale outputs which were
+  /// not removed.
+  pub fn extend(&mut self, other: OutputManifest) {
+    self.files.extend(other.files);
+    self.vendored_dirs.extend(other.vendored_dirs);
+  }
+
+  /// The absolute paths of the files of this manifest which exist
+  pub fn existing_files(&self, render_details: &RenderDetails) -> Vec<PathBuf> {
+    let output_dir = output_dir(render_details);
+    self
+      .files
+      .iter()
+      .map(|path| output_dir.join(path))
+      .filter(|path| path.is_file())
+      .collect()
+  }
+
+  /// The absolute paths of the vendored crate directories of this manifest which exist
+  pub fn existing_vendored_dirs(&self, render_details: &RenderDetails) -> Vec<PathBuf> {
+    let output_dir = output_dir(render_details);
+    self
+      .vendored_dirs
+      .iter()
+      .map(|path| output_dir.join(path))
+      .filter(|path| path.is_dir())
+      .collect()
+  }
+}
+
+/// The directory generated files are written to, which holds the manifest
+fn output_dir(render_details: &RenderDetails) -> PathBuf {
+  render_details.bazel_root.join(&render_details.path_prefix)
+}
+
+/// Where the manifest of the outputs of the last run is kept
+pub fn output_manifest_path(render_details: &RenderDetails) -> PathBuf {
+  output_dir(render_details).join(OUTPUT_MANIFEST_FILE)
+}
+
+/// Reads the manifest of the outputs of the last run, if there was one.
+pub fn read_output_manifest(render_details: &RenderDetails) -> Result<Option<OutputManifest>> {
+  let path = output_manifest_path(render_details);
+  if !path.exists() {
+    return Ok(None);
+  }
+  let content =
+    fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+  let manifest: OutputManifest = serde_json::from_str(&content)
+    .with_context(|| format!("Failed to parse {}", path.display()))?;
+  manifest.validate()?;
+  Ok(Some(manifest))
+}
+
+/// Writes `manifest` for the next run to compare its outputs against.
+pub fn write_output_manifest(
+  render_details: &RenderDetails,
+  manifest: &OutputManifest,
+) -> Result<()> {
+  let path = output_manifest_path(render_details);
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  fs::write(&path, serde_json::to_string_pretty(manifest)? + "\n")
+    .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Removes the `stale` outputs which still exist, yielding the absolute path of each removed file
+/// and vendored crate directory. Nothing is removed if any of them is outside of the output
+/// directory.
+pub fn remove_stale_outputs(
+  render_details: &RenderDetails,
+  stale: &OutputManifest,
+) -> Result<Vec<PathBuf>> {
+  stale.validate()?;
+  let mut removed = Vec::new();
+  // Directories are removed first, as stale files may be within them
+  for dir in stale.existing_vendored_dirs(render_details) {
+    fs::remove_dir_all(&dir).with_context(|| format!("Failed to remove {}", dir.display()))?;
+    removed.push(dir);
+  }
+  for file in stale.existing_files(render_details) {
+    fs::remove_file(&file).with_context(|| format!("Failed to remove {}", file.display()))?;
+    removed.push(file);
+  }
+  Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::{
+    planning::{BuildPlanner, BuildPlannerImpl},
+    rendering::render_files,
+    settings::{tests::dummy_raze_settings, GenMode},
+    testing::*,
+    util::PlatformDetails,
+  };
+
+  #[test]
+  fn test_outputs_missing_from_the_current_plan_are_removed() {
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Vendored;
+    let planned_build = BuildPlannerImpl::new(
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings.clone(),
+    )
+    .plan_build(Some(PlatformDetails::new(
+      "some_target_triple".to_owned(),
+      Vec::new(), /* attrs */
+    )))
+    .unwrap();
+    let bazel_root = TempDir::new().unwrap();
+    let render_details = RenderDetails::new(
+      &settings,
+      PathBuf::from("/some/cargo/root"),
+      bazel_root.as_ref().to_path_buf(),
+    );
+    let file_outputs = render_files(&settings, &render_details, &planned_build).unwrap();
+
+    let current = OutputManifest::new(&file_outputs, &render_details, &planned_build);
+    assert!(current.files.contains(Path::new("vendor/log-0.4.13/BUILD")));
+    assert!(current
+      .vendored_dirs
+      .contains(Path::new("vendor/log-0.4.13")));
+    assert!(current.validate().is_ok());
+
+    // An earlier run vendored an older version of `log`
+    let mut previous = current.clone();
+    previous
+      .files
+      .insert(PathBuf::from("vendor/log-0.4.12/BUILD"));
+    previous
+      .vendored_dirs
+      .insert(PathBuf::from("vendor/log-0.4.12"));
+    previous
+      .files
+      .insert(PathBuf::from("remote/BUILD.gone-1.0.0.bazel"));
+    write_output_manifest(&render_details, &previous).unwrap();
+    assert_eq!(
+      read_output_manifest(&render_details).unwrap(),
+      Some(previous.clone())
+    );
+
+    let output_dir = bazel_root.as_ref().join("cargo");
+    let stale_dir = output_dir.join("vendor/log-0.4.12");
+    fs::create_dir_all(stale_dir.join("src")).unwrap();
+    fs::write(stale_dir.join("BUILD"), "").unwrap();
+    fs::write(stale_dir.join("src/lib.rs"), "").unwrap();
+    let current_dir = output_dir.join("vendor/log-0.4.13");
+    fs::create_dir_all(&current_dir).unwrap();
+
+    let stale = previous.stale_outputs(&current);
+    assert_eq!(
+      stale.existing_files(&render_details),
+      vec![stale_dir.join("BUILD")]
+    );
+    assert_eq!(
+      remove_stale_outputs(&render_details, &stale).unwrap(),
+      vec![stale_dir.clone()]
+    );
+    assert!(!stale_dir.exists());
+    assert!(current_dir.exists());
+    assert!(remove_stale_outputs(&render_details, &stale)
+      .unwrap()
+      .is_empty());
+
+    // Entries which escape the output directory are rejected before anything is removed
+    fs::create_dir_all(&stale_dir).unwrap();
+    let outside_dir = TempDir::new().unwrap();
+    for escaping in &[
+      outside_dir.as_ref().to_path_buf(),
+      PathBuf::from("vendor/../..").join(outside_dir.as_ref().file_name().unwrap()),
+      PathBuf::from(""),
+      PathBuf::from("."),
+    ] {
+      let mut tampered = stale.clone();
+      tampered.vendored_dirs.insert(escaping.clone());
+      let err = remove_stale_outputs(&render_details, &tampered).unwrap_err();
+      assert!(err.to_string().contains(&escaping.display().to_string()));
+      write_output_manifest(&render_details, &tampered).unwrap();
+      assert!(read_output_manifest(&render_details).is_err());
+    }
+    assert!(stale_dir.exists());
+    assert!(outside_dir.as_ref().exists());
+  }
+}
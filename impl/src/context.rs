@@ -12,14 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
 use crate::settings::CrateSettings;
 use semver::Version;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 /// A struct containing information about a crate's dependency that's buildable in Bazel
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct BuildableDependency {
   // Note: Buildifier-compliant BUILD file generation depends on correct sorting of collections
   // of this struct by `buildable_target`. Do not add fields preceeding this field.
@@ -27,15 +27,17 @@ pub struct BuildableDependency {
   pub name: String,
   pub version: Version,
   pub is_proc_macro: bool,
+  // Whether the dependency is a path dependency or a workspace member rather than a fetched crate
+  pub is_first_party: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct DependencyAlias {
   pub target: String,
   pub alias: String,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct BuildableTarget {
   pub kind: String,
   pub name: String,
@@ -43,18 +45,25 @@ pub struct BuildableTarget {
   /// The path in Bazel's format (i.e. with forward slashes) to the target's entry point.
   pub path: String,
   pub edition: String,
+
+  /// Whether the target's tests are built, as per the `test` field of its Cargo.toml section.
+  pub test: bool,
+  /// Whether the target's documentation examples are tested, as per its `doctest` field.
+  pub doctest: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Metadep {
   pub name: String,
   pub min_version: Version,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct LicenseData {
   pub name: String,
   pub rating: String,
+  // The SPDX identifiers of the licenses in the license expression, sorted and deduplicated.
+  pub spdx_ids: Vec<String>,
 }
 
 impl Default for LicenseData {
@@ -62,11 +71,12 @@ impl Default for LicenseData {
     LicenseData {
       name: "no license".into(),
       rating: "restricted".into(),
+      spdx_ids: Vec::new(),
     }
   }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct GitRepo {
   pub remote: String,
   pub commit: String,
@@ -76,12 +86,14 @@ pub struct GitRepo {
   pub path_to_crate_root: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SourceDetails {
   pub git_data: Option<GitRepo>,
+  /// The directory of a crate which is a path dependency, eg. `{ path = "../foo" }`
+  pub local_path: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct CrateDependencyContext {
   pub dependencies: Vec<BuildableDependency>,
   pub proc_macro_dependencies: Vec<BuildableDependency>,
@@ -93,6 +105,7 @@ pub struct CrateDependencyContext {
   pub build_data_dependencies: Vec<BuildableDependency>,
   pub dev_dependencies: Vec<BuildableDependency>,
   pub aliased_dependencies: Vec<DependencyAlias>,
+  pub build_aliased_dependencies: Vec<DependencyAlias>,
 }
 
 impl CrateDependencyContext {
@@ -106,14 +119,16 @@ impl CrateDependencyContext {
   }
 }
 
-#[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct CrateTargetedDepContext {
   pub target: String,
   pub deps: CrateDependencyContext,
   pub conditions: Vec<String>,
+  // Features enabled in addition to the crate's common features when these conditions match.
+  pub features: Vec<String>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CrateContext {
   pub pkg_name: String,
   pub pkg_version: Version,
@@ -123,14 +138,46 @@ pub struct CrateContext {
   pub default_deps: CrateDependencyContext,
   pub targeted_deps: Vec<CrateTargetedDepContext>,
   pub license: LicenseData,
+  // The license texts shipped with the crate, which are only collected when a `NOTICE` file is
+  // rendered.
+  pub license_text: Option<String>,
   pub features: Vec<String>,
+  // The features of the lib as seen by integration tests, which additionally include any features
+  // enabled by the crate's dev-dependencies on itself.
+  pub dev_features: Vec<String>,
+  // Rustc flags translating the lint levels of the crate's `[lints]` table, eg: `-Dunsafe_code`.
+  pub lint_flags: Vec<String>,
+  // The `{from}={to}` mapping of the crate's `--remap-path-prefix` rustc flag, if any.
+  pub remap_path_prefix: Option<String>,
+  // Rustc flags added to the crate's rules on specific platforms, keyed by the condition matching
+  // those platforms.
+  pub targeted_rustc_flags: BTreeMap<String, Vec<String>>,
+  // The `compile_data` clauses, rustc environment variables and build script environment
+  // variables of the crate on specific platforms, keyed by the condition matching those platforms.
+  pub targeted_compile_data: BTreeMap<String, String>,
+  pub targeted_rustc_env: BTreeMap<String, BTreeMap<String, String>>,
+  pub targeted_build_script_env: BTreeMap<String, BTreeMap<String, String>>,
+  // Editions the crate's rules are compiled with on specific platforms, keyed by the condition
+  // matching those platforms.
+  pub targeted_editions: BTreeMap<String, String>,
+  // Groups of conditions which match when all of their conditions do, keyed by the name of the
+  // `config_setting_group` rendered for them, eg: a toggled feature on a specific platform.
+  pub config_setting_groups: BTreeMap<String, Vec<String>>,
+  pub categories: Vec<String>,
   pub workspace_path_to_crate: String,
   pub workspace_member_dependents: Vec<PathBuf>,
   pub workspace_member_dev_dependents: Vec<PathBuf>,
+  pub workspace_member_build_dependents: Vec<PathBuf>,
   pub is_workspace_member_dependency: bool,
   pub is_binary_dependency: bool,
+  // Whether this is a member of the cargo workspace which is rendered into its own package.
+  pub is_workspace_member: bool,
   pub targets: Vec<BuildableTarget>,
   pub build_script_target: Option<BuildableTarget>,
+  // The glob patterns, relative to the crate root, of sources used by the build script target.
+  pub build_script_srcs: Vec<String>,
+  // The glob patterns, relative to the crate root, of files excluded from the build script sources.
+  pub build_script_srcs_exclude: Vec<String>,
   pub links: Option<String>,
   pub source_details: SourceDetails,
   pub sha256: Option<String>,
@@ -146,12 +193,24 @@ pub struct CrateContext {
   // The name of the main lib target for this crate (if present).
   // Currently only one such lib can exist per crate.
   pub lib_target_name: Option<String>,
+  // The name of the rule the lib target is rendered as when the `library_target_name` setting
+  // overrides it, which the crate's other targets and its dependents refer to the lib by.
+  pub library_target_name: Option<String>,
   // This field tracks whether or not the lib target of `lib_target_name`
   // is a proc_macro library or not.
   pub is_proc_macro: bool,
+  // The crate types of the lib target, eg: `["rlib", "dylib"]`, each rendered as a separate rule.
+  // The first one is rendered under the lib's name, the others are suffixed with their type.
+  pub lib_crate_types: Vec<String>,
+  // The name under which the crate refers to its own lib target (eg: through a renamed
+  // dev-dependency on itself), when it differs from the lib target's name.
+  pub self_alias: Option<String>,
+  // Constraints limiting the crate's rules to the platforms it is reachable on, eg:
+  // `["@platforms//os:windows"]` for a crate only depended upon on Windows.
+  pub target_compatible_with: Vec<String>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct WorkspaceContext {
   // The bazel path prefix to the vendor directory
   pub workspace_path: String,
@@ -161,6 +220,11 @@ pub struct WorkspaceContext {
   // This has no effect unless the GenMode setting is Remote.
   pub gen_workspace_prefix: String,
 
+  // The name of the function defining the crate repositories in crates.bzl.
+  //
+  // This has no effect unless the GenMode setting is Remote.
+  pub repositories_function_name: String,
+
   // The file extension of generated BUILD files.
   //
   // Bare files will just be named after this setting. Named files, such as those passed to
@@ -169,4 +233,16 @@ pub struct WorkspaceContext {
 
   // A list of relative paths from a Cargo workspace root to a Cargo package.
   pub workspace_members: Vec<PathBuf>,
+
+  // Whether the `deps` of generated rules are split into first-party and third-party sections.
+  pub group_deps: bool,
+
+  // Tags added to the rules of proc-macro crates.
+  pub proc_macro_tags: Vec<String>,
+
+  // Labels of toolchains the rules of proc-macro crates require.
+  pub proc_macro_toolchains: Vec<String>,
+
+  // Whether `rules_license` rules are generated for the crates, see `RazeSettings::license_rules`.
+  pub license_rules: bool,
 }
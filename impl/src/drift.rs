@@ -0,0 +1,269 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+  collections::BTreeMap,
+  fmt::Write,
+  fs,
+  path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// The number of unchanged lines shown around each change of a diff
+const CONTEXT_LINES: usize = 3;
+
+/// A single line of a line-based diff
+#[derive(Debug, PartialEq)]
+enum Edit<'a> {
+  Keep(&'a str),
+  Remove(&'a str),
+  Add(&'a str),
+}
+
+/// Compares the rendered `file_outputs` against the files on disk, yielding a unified diff of each
+/// file which is out of date. `stale_files` are files on disk which would be removed instead.
+pub fn diff_outputs(
+  file_outputs: &BTreeMap<PathBuf, String>,
+  stale_files: &[PathBuf],
+) -> Result<Vec<String>> {
+  let read = |path: &Path| -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))
+  };
+
+  let mut diffs = Vec::new();
+  for (path, contents) in file_outputs.iter() {
+    let on_disk = match path.exists() {
+      true => Some(read(path)?),
+      false => None,
+    };
+    if on_disk.as_deref() != Some(contents.as_str()) {
+      diffs.push(unified_diff(path, on_disk.as_deref(), Some(contents)));
+    }
+  }
+  for path in stale_files.iter() {
+    diffs.push(unified_diff(path, Some(&read(path)?), None));
+  }
+
+  Ok(diffs)
+}
+
+/// Renders the changes from the `old` to the `new` contents of the file at `path` as a unified
+/// diff. A file which does not exist on either side is shown as `/dev/null`.
+pub fn unified_diff(path: &Path, old: Option<&str>, new: Option<&str>) -> String {
+  let label = |contents: Option<&str>| match contents {
+    Some(_) => path.display().to_string(),
+    None => "/dev/null".to_owned(),
+  };
+  let old_lines: Vec<&str> = old.unwrap_or_default().lines().collect();
+  let new_lines: Vec<&str> = new.unwrap_or_default().lines().collect();
+  let edits = diff_lines(&old_lines, &new_lines);
+
+  // Group each change with its surrounding context, merging groups which overlap
+  let mut hunks: Vec<(usize, usize)> = Vec::new();
+  for (idx, _) in edits
+    .iter()
+    .enumerate()
+    .filter(|(_, edit)| !matches!(edit, Edit::Keep(_)))
+  {
+    let start = idx.saturating_sub(CONTEXT_LINES);
+    let end = (idx + CONTEXT_LINES + 1).min(edits.len());
+    match hunks.last_mut() {
+      Some(last) if start <= last.1 => last.1 = end,
+      _ => hunks.push((start, end)),
+    }
+  }
+
+  let mut diff = format!("--- {}\n+++ {}\n", label(old), label(new));
+  let (mut old_line, mut new_line, mut idx) = (0, 0, 0);
+  for (start, end) in hunks {
+    // Count the lines on either side which come before the hunk
+    for edit in &edits[idx..start] {
+      match edit {
+        Edit::Keep(_) => {
+          old_line += 1;
+          new_line += 1;
+        },
+        Edit::Remove(_) => old_line += 1,
+        Edit::Add(_) => new_line += 1,
+      }
+    }
+    let hunk = &edits[start..end];
+    let old_len = hunk.iter().filter(|e| !matches!(e, Edit::Add(_))).count();
+    let new_len = hunk
+      .iter()
+      .filter(|e| !matches!(e, Edit::Remove(_)))
+      .count();
+    // Empty ranges start at the line before them, as with `diff -u`
+    let range_start = |line: usize, len: usize| if len == 0 { line } else { line + 1 };
+    // UNWRAP: Writing to a `String` never fails
+    writeln!(
+      diff,
+      "@@ -{},{} +{},{} @@",
+      range_start(old_line, old_len),
+      old_len,
+      range_start(new_line, new_len),
+      new_len
+    )
+    .unwrap();
+    for edit in hunk {
+      let (marker, line) = match edit {
+        Edit::Keep(line) => (' ', line),
+        Edit::Remove(line) => ('-', line),
+        Edit::Add(line) => ('+', line),
+      };
+      writeln!(diff, "{}{}", marker, line).unwrap();
+    }
+    old_line += old_len;
+    new_line += new_len;
+    idx = end;
+  }
+
+  diff
+}
+
+/// Computes the edits turning `old` into `new` from the longest common subsequence of their lines.
+/// Common leading and trailing lines are skipped first to keep the comparison small, as changes
+/// to generated files tend to be local.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Edit<'a>> {
+  let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+  let suffix = old[prefix..]
+    .iter()
+    .rev()
+    .zip(new[prefix..].iter().rev())
+    .take_while(|(a, b)| a == b)
+    .count();
+  let old_mid = &old[prefix..old.len() - suffix];
+  let new_mid = &new[prefix..new.len() - suffix];
+
+  // `lcs[i][j]` is the length of the longest common subsequence of `old_mid[i..]` and
+  // `new_mid[j..]`
+  let mut lcs = vec![vec![0usize; new_mid.len() + 1]; old_mid.len() + 1];
+  for i in (0..old_mid.len()).rev() {
+    for j in (0..new_mid.len()).rev() {
+      lcs[i][j] = match old_mid[i] == new_mid[j] {
+        true => lcs[i + 1][j + 1] + 1,
+        false => lcs[i + 1][j].max(lcs[i][j + 1]),
+      };
+    }
+  }
+
+  let mut edits: Vec<Edit> = old[..prefix].iter().map(|line| Edit::Keep(line)).collect();
+  let (mut i, mut j) = (0, 0);
+  while i < old_mid.len() || j < new_mid.len() {
+    if i < old_mid.len() && j < new_mid.len() && old_mid[i] == new_mid[j] {
+      edits.push(Edit::Keep(old_mid[i]));
+      i += 1;
+      j += 1;
+    } else if i < old_mid.len() && (j == new_mid.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+      edits.push(Edit::Remove(old_mid[i]));
+      i += 1;
+    } else {
+      edits.push(Edit::Add(new_mid[j]));
+      j += 1;
+    }
+  }
+  edits.extend(
+    old[old.len() - suffix..]
+      .iter()
+      .map(|line| Edit::Keep(line)),
+  );
+
+  edits
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_diff_lines_keeps_the_common_lines() {
+    assert_eq!(
+      diff_lines(&["a", "b", "c", "d"], &["a", "c", "e", "d"]),
+      vec![
+        Edit::Keep("a"),
+        Edit::Remove("b"),
+        Edit::Keep("c"),
+        Edit::Add("e"),
+        Edit::Keep("d"),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_unified_diffs_show_changes_with_context() {
+    let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n";
+    let new = "1\n2\n3\n4\nfive\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n16\n";
+    assert_eq!(
+      unified_diff(Path::new("BUILD.bazel"), Some(old), Some(new)),
+      indoc! { r#"
+        --- BUILD.bazel
+        +++ BUILD.bazel
+        @@ -2,7 +2,7 @@
+         2
+         3
+         4
+        -5
+        +five
+         6
+         7
+         8
+        @@ -13,3 +13,4 @@
+         13
+         14
+         15
+        +16
+      "# }
+    );
+
+    assert_eq!(
+      unified_diff(Path::new("BUILD.bazel"), None, Some("1\n2\n")),
+      "--- /dev/null\n+++ BUILD.bazel\n@@ -0,0 +1,2 @@\n+1\n+2\n"
+    );
+    assert_eq!(
+      unified_diff(Path::new("BUILD.bazel"), Some("1\n"), None),
+      "--- BUILD.bazel\n+++ /dev/null\n@@ -1,1 +0,0 @@\n-1\n"
+    );
+  }
+
+  #[test]
+  fn test_only_out_of_date_outputs_are_diffed() {
+    let dir = TempDir::new().unwrap();
+    let current = dir.as_ref().join("current.bazel");
+    let outdated = dir.as_ref().join("outdated.bazel");
+    let missing = dir.as_ref().join("missing.bazel");
+    let stale = dir.as_ref().join("stale.bazel");
+    fs::write(&current, "current\n").unwrap();
+    fs::write(&outdated, "old\n").unwrap();
+    fs::write(&stale, "stale\n").unwrap();
+
+    let mut file_outputs = BTreeMap::new();
+    file_outputs.insert(current, "current\n".to_owned());
+    file_outputs.insert(outdated.clone(), "new\n".to_owned());
+    file_outputs.insert(missing.clone(), "missing\n".to_owned());
+
+    let diffs = diff_outputs(&file_outputs, std::slice::from_ref(&stale)).unwrap();
+    assert_eq!(
+      diffs,
+      vec![
+        unified_diff(&missing, None, Some("missing\n")),
+        unified_diff(&outdated, Some("old\n"), Some("new\n")),
+        unified_diff(&stale, Some("stale\n"), None),
+      ]
+    );
+  }
+}
@@ -0,0 +1,314 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt::Write, fs, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+
+use crate::{
+  context::{BuildableDependency, CrateContext, CrateDependencyContext},
+  planning::PlannedBuild,
+};
+
+/// The formats the dependency graph of a planned build can be exported in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+  Json,
+  Dot,
+}
+
+impl FromStr for GraphFormat {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "json" => Ok(GraphFormat::Json),
+      "dot" => Ok(GraphFormat::Dot),
+      _ => Err(anyhow!(
+        "Unknown dependency graph format `{}`, expected `json` or `dot`",
+        s
+      )),
+    }
+  }
+}
+
+/// The crates of a planned build and the dependencies between them, eg: for `--output-format`.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DependencyGraph {
+  pub crates: Vec<CrateNode>,
+}
+
+/// A single crate of a planned build.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CrateNode {
+  pub name: String,
+  pub version: String,
+  pub features: Vec<String>,
+  /// The license of the crate, eg: `MIT OR Apache-2.0`, or `no license` if it declares none
+  pub license: String,
+  /// Where the sources of the crate come from, eg: its download url
+  pub source: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sha256: Option<String>,
+  pub is_workspace_member: bool,
+  pub has_build_script: bool,
+  /// The native library the crate links, from its `links` manifest key
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub links: Option<String>,
+  pub dependencies: Vec<DependencyEdge>,
+}
+
+/// A dependency of a crate on another crate of the planned build.
+#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DependencyEdge {
+  pub name: String,
+  pub version: String,
+  /// The kind of the dependency, eg: `normal`, `build` or `dev`
+  pub kind: &'static str,
+  /// The target platform the dependency is limited to, if any
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub target: Option<String>,
+}
+
+/// Collects the dependency graph of every crate of `planned_build`, including workspace members
+/// whose BUILD files are generated. Crates and their dependencies are sorted so the graph of a
+/// plan is always the same.
+pub fn produce_graph(planned_build: &PlannedBuild) -> DependencyGraph {
+  let mut crates: Vec<CrateNode> = planned_build
+    .crate_contexts
+    .iter()
+    .chain(planned_build.workspace_member_contexts.iter())
+    .map(crate_node)
+    .collect();
+  crates.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+
+  DependencyGraph { crates }
+}
+
+fn crate_node(package: &CrateContext) -> CrateNode {
+  let mut dependencies = dependency_edges(&package.default_deps, None);
+  for targeted_deps in package.targeted_deps.iter() {
+    dependencies.extend(dependency_edges(
+      &targeted_deps.deps,
+      Some(&targeted_deps.target),
+    ));
+  }
+  dependencies.sort();
+  dependencies.dedup();
+
+  let source = match (
+    &package.source_details.git_data,
+    &package.source_details.local_path,
+  ) {
+    (Some(git_data), _) => format!("git+{}#{}", git_data.remote, git_data.commit),
+    (None, Some(local_path)) => format!("path+{}", local_path.display()),
+    (None, None) => package.registry_url.clone(),
+  };
+
+  CrateNode {
+    name: package.pkg_name.clone(),
+    version: package.pkg_version.to_string(),
+    features: package.features.clone(),
+    license: package.license.name.clone(),
+    source,
+    sha256: package.sha256.clone(),
+    is_workspace_member: package.is_workspace_member,
+    has_build_script: package.build_script_target.is_some(),
+    links: package.links.clone(),
+    dependencies,
+  }
+}
+
+fn dependency_edges(deps: &CrateDependencyContext, target: Option<&str>) -> Vec<DependencyEdge> {
+  let kinds: [(&'static str, &Vec<BuildableDependency>); 7] = [
+    ("normal", &deps.dependencies),
+    ("proc_macro", &deps.proc_macro_dependencies),
+    ("data", &deps.data_dependencies),
+    ("build", &deps.build_dependencies),
+    ("build_proc_macro", &deps.build_proc_macro_dependencies),
+    ("build_data", &deps.build_data_dependencies),
+    ("dev", &deps.dev_dependencies),
+  ];
+
+  kinds
+    .iter()
+    .flat_map(|(kind, deps)| {
+      deps.iter().map(move |dep| DependencyEdge {
+        name: dep.name.clone(),
+        version: dep.version.to_string(),
+        kind,
+        target: target.map(str::to_owned),
+      })
+    })
+    .collect()
+}
+
+/// Serializes `graph` in `format`.
+pub fn format_graph(graph: &DependencyGraph, format: GraphFormat) -> Result<String> {
+  match format {
+    GraphFormat::Json => Ok(serde_json::to_string_pretty(graph)? + "\n"),
+    GraphFormat::Dot => Ok(format_dot(graph)),
+  }
+}
+
+/// Renders `graph` for graphviz. Dependencies other than normal ones are labelled with their kind,
+/// and those limited to a target platform are dashed.
+fn format_dot(graph: &DependencyGraph) -> String {
+  let node_id = |name: &str, version: &str| format!("\"{} {}\"", name, version);
+
+  let mut dot = String::from("digraph dependencies {\n");
+  // UNWRAP: Writing to a `String` never fails
+  for node in graph.crates.iter() {
+    writeln!(dot, "  {};", node_id(&node.name, &node.version)).unwrap();
+  }
+  for node in graph.crates.iter() {
+    for dep in node.dependencies.iter() {
+      let mut attrs = Vec::new();
+      if dep.kind != "normal" {
+        attrs.push(format!("label=\"{}\"", dep.kind));
+      }
+      if dep.target.is_some() {
+        attrs.push("style=dashed".to_owned());
+      }
+      let attrs = match attrs.is_empty() {
+        true => String::new(),
+        false => format!(" [{}]", attrs.join(", ")),
+      };
+      writeln!(
+        dot,
+        "  {} -> {}{};",
+        node_id(&node.name, &node.version),
+        node_id(&dep.name, &dep.version),
+        attrs
+      )
+      .unwrap();
+    }
+  }
+  dot.push_str("}\n");
+
+  dot
+}
+
+/// Writes the dependency graph of `planned_build` to `path`, as DOT if it has a `.dot` extension
+/// and as JSON otherwise.
+pub fn write_graph(planned_build: &PlannedBuild, path: &Path) -> Result<()> {
+  let format = match path.extension().and_then(|ext| ext.to_str()) {
+    Some("dot") => GraphFormat::Dot,
+    _ => GraphFormat::Json,
+  };
+  let contents = format_graph(&produce_graph(planned_build), format)?;
+  fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+
+  use super::*;
+  use crate::{
+    planning::{BuildPlanner, BuildPlannerImpl},
+    settings::tests::dummy_raze_settings,
+    testing::*,
+    util::PlatformDetails,
+  };
+
+  #[test]
+  fn test_graphs_list_the_dependencies_of_planned_crates() {
+    let planned_build = BuildPlannerImpl::new(
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_BUILD_PROC_MACRO_DEPENDENCIES),
+      dummy_raze_settings(),
+    )
+    .plan_build(Some(PlatformDetails::new(
+      "some_target_triple".to_owned(),
+      Vec::new(), /* attrs */
+    )))
+    .unwrap();
+
+    let graph = produce_graph(&planned_build);
+    assert_eq!(graph.crates.len(), planned_build.crate_contexts.len());
+    assert!(graph
+      .crates
+      .windows(2)
+      .all(|pair| (&pair[0].name, &pair[0].version) <= (&pair[1].name, &pair[1].version)));
+
+    let markup = graph
+      .crates
+      .iter()
+      .find(|node| node.name == "markup5ever")
+      .unwrap();
+    assert!(markup.has_build_script);
+    assert!(markup.source.ends_with("/markup5ever/0.10.0/download"));
+    let serde_derive = markup
+      .dependencies
+      .iter()
+      .find(|dep| dep.name == "serde_derive")
+      .unwrap();
+    assert_eq!(serde_derive.kind, "build_proc_macro");
+    assert_eq!(serde_derive.target, None);
+
+    let json: serde_json::Value =
+      serde_json::from_str(&format_graph(&graph, GraphFormat::Json).unwrap()).unwrap();
+    assert_eq!(json["crates"].as_array().unwrap().len(), graph.crates.len());
+  }
+
+  #[test]
+  fn test_graphs_are_rendered_for_graphviz() {
+    let node = |name: &str, dependencies| CrateNode {
+      name: name.to_owned(),
+      version: "1.0.0".to_owned(),
+      features: Vec::new(),
+      license: "MIT".to_owned(),
+      source: format!("https://crates.io/api/v1/crates/{}/1.0.0/download", name),
+      sha256: None,
+      is_workspace_member: false,
+      has_build_script: false,
+      links: None,
+      dependencies,
+    };
+    let edge = |name: &str, kind, target: Option<&str>| DependencyEdge {
+      name: name.to_owned(),
+      version: "1.0.0".to_owned(),
+      kind,
+      target: target.map(str::to_owned),
+    };
+    let graph = DependencyGraph {
+      crates: vec![
+        node(
+          "a",
+          vec![
+            edge("b", "normal", None),
+            edge("c", "build", Some("cfg(unix)")),
+          ],
+        ),
+        node("b", Vec::new()),
+        node("c", Vec::new()),
+      ],
+    };
+
+    assert_eq!(
+      format_graph(&graph, GraphFormat::Dot).unwrap(),
+      indoc! { r#"
+        digraph dependencies {
+          "a 1.0.0";
+          "b 1.0.0";
+          "c 1.0.0";
+          "a 1.0.0" -> "b 1.0.0";
+          "a 1.0.0" -> "c 1.0.0" [label="build", style=dashed];
+        }
+      "# }
+    );
+    assert!("svg".parse::<GraphFormat>().is_err());
+  }
+}
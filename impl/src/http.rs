@@ -0,0 +1,465 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+  collections::HashMap,
+  env, fs,
+  path::{Path, PathBuf},
+  process::Command,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::blocking::{Client, RequestBuilder};
+use url::Url;
+
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+/// How raze makes its HTTP requests, as configured by the `[http]` table of cargo's config and the
+/// environment variables cargo reads, so raze works wherever cargo itself does.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HttpConfig {
+  /// The proxy every request is sent through, from `http.proxy`. Without one, the proxy of the
+  /// `HTTPS_PROXY`, `https_proxy` or `http_proxy` environment variables is used, if any.
+  pub proxy: Option<String>,
+  /// A PEM bundle of the certificate authorities trusted in addition to the system's, from
+  /// `http.cainfo`, eg: those of a proxy intercepting TLS connections
+  pub cainfo: Option<PathBuf>,
+  /// How long each request may take, from `http.timeout`
+  pub timeout: Option<Duration>,
+  /// The netrc file the credentials of each host are read from, if it exists
+  pub netrc: Option<PathBuf>,
+  /// The command yielding a bearer token for each host, as the `credential_command` raze setting
+  pub credential_command: Vec<String>,
+}
+
+impl HttpConfig {
+  /// Reads the `[http]` config which applies to `dir`, from the `.cargo/config.toml` files of it
+  /// and its parents and from `cargo_home`, along with the environment variables overriding them.
+  pub fn load(dir: &Path, cargo_home: &Path) -> Result<HttpConfig> {
+    HttpConfig::load_with_env(dir, cargo_home, |key| env::var(key).ok())
+  }
+
+  fn load_with_env(
+    dir: &Path,
+    cargo_home: &Path,
+    var: impl Fn(&str) -> Option<String>,
+  ) -> Result<HttpConfig> {
+    let mut config = HttpConfig::default();
+    // The config files closest to `dir` take precedence, so they are read last
+    for config_path in cargo_config_paths(dir, cargo_home).iter().rev() {
+      let content = fs::read_to_string(config_path)
+        .with_context(|| format!("Failed to read {}", config_path.display()))?;
+      let value: toml::Value = toml::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", config_path.display()))?;
+      let http = match value.get("http") {
+        Some(http) => http,
+        None => continue,
+      };
+      if let Some(proxy) = http.get("proxy").and_then(toml::Value::as_str) {
+        config.proxy = Some(proxy.to_owned());
+      }
+      if let Some(cainfo) = http.get("cainfo").and_then(toml::Value::as_str) {
+        // Relative paths are relative to the directory holding the `.cargo` directory
+        // UNWRAP: Config files are always found within a directory
+        let config_root = config_path
+          .parent()
+          .unwrap()
+          .parent()
+          .unwrap_or_else(|| Path::new(""));
+        config.cainfo = Some(config_root.join(cainfo));
+      }
+      if let Some(timeout) = http.get("timeout").and_then(toml::Value::as_integer) {
+        config.timeout = Some(Duration::from_secs(timeout.max(0) as u64));
+      }
+    }
+
+    if let Some(proxy) = var("CARGO_HTTP_PROXY") {
+      config.proxy = Some(proxy);
+    }
+    if let Some(cainfo) = var("CARGO_HTTP_CAINFO") {
+      config.cainfo = Some(PathBuf::from(cainfo));
+    }
+    if let Some(timeout) = var("CARGO_HTTP_TIMEOUT").or_else(|| var("HTTP_TIMEOUT")) {
+      let secs = timeout
+        .parse()
+        .with_context(|| format!("Invalid HTTP timeout `{}`", timeout))?;
+      config.timeout = Some(Duration::from_secs(secs));
+    }
+    config.netrc = match (var("NETRC"), var("HOME")) {
+      (Some(netrc), _) => Some(PathBuf::from(netrc)),
+      (None, Some(home)) => Some(PathBuf::from(home).join(".netrc")),
+      (None, None) => None,
+    };
+
+    Ok(config)
+  }
+}
+
+/// The cargo config files which apply to `dir`, from the closest to the furthest, as with cargo's
+/// hierarchical config. A `config.toml` file is preferred over a legacy `config` file.
+fn cargo_config_paths(dir: &Path, cargo_home: &Path) -> Vec<PathBuf> {
+  let config_dirs = dir
+    .ancestors()
+    .map(|dir| dir.join(".cargo"))
+    .chain(std::iter::once(cargo_home.to_path_buf()));
+
+  let mut config_paths: Vec<PathBuf> = Vec::new();
+  for config_dir in config_dirs {
+    let config_path = ["config.toml", "config"]
+      .iter()
+      .map(|file_name| config_dir.join(file_name))
+      .find(|path| path.is_file());
+    if let Some(config_path) = config_path {
+      // `cargo_home` is often one of the ancestors
+      if !config_paths.contains(&config_path) {
+        config_paths.push(config_path);
+      }
+    }
+  }
+  config_paths
+}
+
+/// The login of a netrc `machine` entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NetrcLogin {
+  login: String,
+  password: Option<String>,
+}
+
+/// Parses the logins of the entries of a netrc file by host. The `default` entry is keyed by an
+/// empty host.
+fn parse_netrc(content: &str) -> HashMap<String, NetrcLogin> {
+  let mut logins = HashMap::new();
+  let mut tokens = content.split_whitespace();
+  let mut host: Option<String> = None;
+  let mut login: Option<NetrcLogin> = None;
+  let mut finish_entry = |host: &mut Option<String>, login: &mut Option<NetrcLogin>| {
+    if let (Some(host), Some(login)) = (host.take(), login.take()) {
+      logins.entry(host).or_insert(login);
+    }
+  };
+  while let Some(token) = tokens.next() {
+    match token {
+      "machine" => {
+        finish_entry(&mut host, &mut login);
+        host = tokens.next().map(str::to_owned);
+      },
+      "default" => {
+        finish_entry(&mut host, &mut login);
+        host = Some(String::new());
+      },
+      "login" => {
+        let password = login.take().and_then(|login| login.password);
+        login = tokens.next().map(|name| NetrcLogin {
+          login: name.to_owned(),
+          password,
+        });
+      },
+      "password" => {
+        let password = tokens.next().map(str::to_owned);
+        match &mut login {
+          Some(login) => login.password = password,
+          None => {
+            login = Some(NetrcLogin {
+              login: String::new(),
+              password,
+            })
+          },
+        }
+      },
+      // The values of other keys, eg: `account`, are unused
+      "account" | "macdef" => {
+        tokens.next();
+      },
+      _ => {},
+    }
+  }
+  finish_entry(&mut host, &mut login);
+  logins
+}
+
+/// The client every request to crate registries and indexes is made with. Requests are sent with
+/// the bearer token of the `credential_command` for their host, or else with the basic auth
+/// credentials of the netrc file.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+  client: Client,
+  netrc_logins: Arc<HashMap<String, NetrcLogin>>,
+  credential_command: Vec<String>,
+  /// The token the credential command printed for each host, so it runs once per host
+  tokens: Arc<Mutex<HashMap<String, Option<String>>>>,
+}
+
+impl HttpClient {
+  pub fn new(config: &HttpConfig) -> Result<HttpClient> {
+    // crates.io rejects requests without a User Agent
+    let mut builder =
+      Client::builder().user_agent(concat!("cargo-raze/", env!("CARGO_PKG_VERSION")));
+    if let Some(proxy) = &config.proxy {
+      builder = builder.proxy(
+        reqwest::Proxy::all(proxy.as_str())
+          .with_context(|| format!("Invalid HTTP proxy `{}`", proxy))?,
+      );
+    }
+    if let Some(cainfo) = &config.cainfo {
+      for certificate in read_pem_certificates(cainfo)? {
+        builder = builder.add_root_certificate(certificate);
+      }
+    }
+    if let Some(timeout) = config.timeout {
+      builder = builder.timeout(timeout).connect_timeout(timeout);
+    }
+
+    let netrc_logins = match config.netrc.as_ref().filter(|netrc| netrc.is_file()) {
+      Some(netrc) => parse_netrc(
+        &fs::read_to_string(netrc)
+          .with_context(|| format!("Failed to read {}", netrc.display()))?,
+      ),
+      None => HashMap::new(),
+    };
+
+    Ok(HttpClient {
+      client: builder.build()?,
+      netrc_logins: Arc::new(netrc_logins),
+      credential_command: config.credential_command.clone(),
+      tokens: Arc::new(Mutex::new(HashMap::new())),
+    })
+  }
+
+  /// Starts a GET request to `url`, authenticated for its host.
+  pub fn get(&self, url: &str) -> Result<RequestBuilder> {
+    let request = self.client.get(url);
+    let host = match Url::parse(url)
+      .ok()
+      .and_then(|url| url.host_str().map(str::to_owned))
+    {
+      Some(host) => host,
+      None => return Ok(request),
+    };
+
+    if let Some(token) = self.credential_token(&host)? {
+      return Ok(request.bearer_auth(token));
+    }
+    let login = self
+      .netrc_logins
+      .get(&host)
+      .or_else(|| self.netrc_logins.get(""));
+    Ok(match login {
+      Some(login) => request.basic_auth(&login.login, login.password.as_ref()),
+      None => request,
+    })
+  }
+
+  /// The token the credential command prints for `host`, if it prints one.
+  fn credential_token(&self, host: &str) -> Result<Option<String>> {
+    let (program, args) = match self.credential_command.split_first() {
+      Some(command) => command,
+      None => return Ok(None),
+    };
+    // UNWRAP: Requests never panic while holding the lock
+    let mut tokens = self.tokens.lock().unwrap();
+    if let Some(token) = tokens.get(host) {
+      return Ok(token.clone());
+    }
+
+    let output = Command::new(program)
+      .args(args)
+      .arg(host)
+      .output()
+      .with_context(|| format!("Failed to run the credential command `{}`", program))?;
+    if !output.status.success() {
+      return Err(anyhow!(
+        "The credential command `{}` failed for {} with {}: {}",
+        program,
+        host,
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+      ));
+    }
+    let token = String::from_utf8(output.stdout)
+      .with_context(|| format!("The credential command `{}` printed invalid UTF-8", program))?
+      .trim()
+      .to_owned();
+    let token = Some(token).filter(|token| !token.is_empty());
+    tokens.insert(host.to_owned(), token.clone());
+    Ok(token)
+  }
+}
+
+/// Reads each certificate of the PEM bundle at `path`.
+fn read_pem_certificates(path: &Path) -> Result<Vec<reqwest::Certificate>> {
+  let bundle = fs::read_to_string(path)
+    .with_context(|| format!("Failed to read the CA bundle {}", path.display()))?;
+  let certificates = bundle
+    .split_inclusive(PEM_CERTIFICATE_END)
+    .filter(|pem| pem.contains(PEM_CERTIFICATE_END))
+    .map(|pem| {
+      reqwest::Certificate::from_pem(pem.trim().as_bytes())
+        .with_context(|| format!("Invalid certificate in the CA bundle {}", path.display()))
+    })
+    .collect::<Result<Vec<_>>>()?;
+  if certificates.is_empty() {
+    return Err(anyhow!(
+      "No certificates found in the CA bundle {}",
+      path.display()
+    ));
+  }
+  Ok(certificates)
+}
+
+#[cfg(test)]
+mod tests {
+  use httpmock::{Method::GET, MockServer};
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_http_config_follows_cargo_config_precedence() {
+    let root = TempDir::new().unwrap();
+    let cargo_home = root.as_ref().join("cargo_home");
+    let workspace = root.as_ref().join("workspace");
+    let member = workspace.join("member");
+    for (dir, config) in &[
+      (
+        &cargo_home,
+        "[http]\nproxy = \"http://home-proxy:3128\"\ncainfo = \"/etc/ca.pem\"\ntimeout = 10\n",
+      ),
+      (
+        &workspace.join(".cargo"),
+        "[http]\nproxy = \"http://proxy:3128\"\ncainfo = \"ca.pem\"\n",
+      ),
+    ] {
+      fs::create_dir_all(dir).unwrap();
+      fs::write(dir.join("config.toml"), config).unwrap();
+    }
+    fs::create_dir_all(&member).unwrap();
+
+    let no_env = |_: &str| None;
+    assert_eq!(
+      HttpConfig::load_with_env(&member, &cargo_home, no_env).unwrap(),
+      HttpConfig {
+        proxy: Some("http://proxy:3128".to_owned()),
+        cainfo: Some(workspace.join("ca.pem")),
+        timeout: Some(Duration::from_secs(10)),
+        netrc: None,
+        credential_command: Vec::new(),
+      }
+    );
+
+    let env = |key: &str| match key {
+      "CARGO_HTTP_PROXY" => Some("http://env-proxy:3128".to_owned()),
+      "HTTP_TIMEOUT" => Some("60".to_owned()),
+      "HOME" => Some("/home/user".to_owned()),
+      _ => None,
+    };
+    let config = HttpConfig::load_with_env(&member, &cargo_home, env).unwrap();
+    assert_eq!(config.proxy, Some("http://env-proxy:3128".to_owned()));
+    assert_eq!(config.timeout, Some(Duration::from_secs(60)));
+    assert_eq!(config.netrc, Some(PathBuf::from("/home/user/.netrc")));
+  }
+
+  #[test]
+  fn test_netrc_logins_are_parsed_by_host() {
+    let logins = parse_netrc(
+      "machine registry.example.com login user password secret\n\
+       machine other.example.com\n  account ignored\n  password token\n\
+       default login anonymous\n",
+    );
+    assert_eq!(
+      logins.get("registry.example.com"),
+      Some(&NetrcLogin {
+        login: "user".to_owned(),
+        password: Some("secret".to_owned()),
+      })
+    );
+    assert_eq!(
+      logins.get("other.example.com"),
+      Some(&NetrcLogin {
+        login: String::new(),
+        password: Some("token".to_owned()),
+      })
+    );
+    assert_eq!(logins.get("").unwrap().login, "anonymous");
+  }
+
+  #[test]
+  fn test_requests_are_authenticated_for_their_host() {
+    let server = MockServer::start();
+    let netrc_mock = server.mock(|when, then| {
+      when
+        .method(GET)
+        .path("/netrc")
+        .header("Authorization", "Basic dXNlcjpzZWNyZXQ=");
+      then.status(200);
+    });
+    let token_mock = server.mock(|when, then| {
+      when
+        .method(GET)
+        .path("/token")
+        .header("Authorization", "Bearer token-for-127.0.0.1");
+      then.status(200);
+    });
+
+    let dir = TempDir::new().unwrap();
+    let netrc = dir.as_ref().join(".netrc");
+    fs::write(&netrc, "machine 127.0.0.1 login user password secret\n").unwrap();
+    let mut config = HttpConfig {
+      netrc: Some(netrc),
+      ..Default::default()
+    };
+    let send = |client: &HttpClient, path: &str| {
+      client
+        .get(&server.url(path))
+        .unwrap()
+        .send()
+        .unwrap()
+        .status()
+    };
+
+    assert!(send(&HttpClient::new(&config).unwrap(), "/netrc").is_success());
+    netrc_mock.assert();
+
+    // The host is passed as the last argument, which is `$0` of the script
+    config.credential_command = vec![
+      "sh".to_owned(),
+      "-c".to_owned(),
+      "printf 'token-for-%s\\n' \"$0\"".to_owned(),
+    ];
+    let client = HttpClient::new(&config).unwrap();
+    assert!(send(&client, "/token").is_success());
+    assert!(send(&client.clone(), "/token").is_success());
+    token_mock.assert_hits(2);
+
+    config.credential_command = vec!["false".to_owned()];
+    assert!(HttpClient::new(&config)
+      .unwrap()
+      .get(&server.url("/token"))
+      .is_err());
+  }
+
+  #[test]
+  fn test_ca_bundles_must_hold_certificates() {
+    let dir = TempDir::new().unwrap();
+    let cainfo = dir.as_ref().join("ca.pem");
+    fs::write(&cainfo, "not a certificate").unwrap();
+    assert!(HttpClient::new(&HttpConfig {
+      cainfo: Some(cainfo),
+      ..Default::default()
+    })
+    .is_err());
+  }
+}
@@ -0,0 +1,573 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+  collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+  fs,
+  hash::Hasher,
+  path::{Path, PathBuf},
+  process::Command,
+  str::FromStr,
+  sync::OnceLock,
+};
+
+use anyhow::{anyhow, Context, Result};
+use cargo_lock::Lockfile;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{planning::PlannedBuild, rendering::RenderDetails};
+
+/// A crate as identified by its name and version, eg: `("log", "0.4.13")`
+pub type CrateKey = (String, String);
+
+/// Reads the contents `path` had at the git revision `git_ref`, or `None` if it did not
+/// exist there.
+pub fn read_file_at_ref(path: &Path, git_ref: &str) -> Result<Option<String>> {
+  let (dir, file_name) = match (path.parent(), path.file_name()) {
+    (Some(dir), Some(file_name)) => (dir, file_name.to_string_lossy()),
+    _ => return Err(anyhow!("{} is not a file path", path.display())),
+  };
+
+  let verify_output = Command::new("git")
+    .arg("-C")
+    .arg(dir)
+    .args(["rev-parse", "--verify", "--quiet"])
+    .arg(format!("{}^{{commit}}", git_ref))
+    .output()
+    .context("Failed to run git")?;
+  if !verify_output.status.success() {
+    return Err(anyhow!(
+      "`{}` is not a git revision of the repository containing {}",
+      git_ref,
+      dir.display()
+    ));
+  }
+
+  let show_output = Command::new("git")
+    .arg("-C")
+    .arg(dir)
+    .arg("show")
+    .arg(format!("{}:./{}", git_ref, file_name))
+    .output()
+    .context("Failed to run git")?;
+  if !show_output.status.success() {
+    return Ok(None);
+  }
+
+  Ok(Some(String::from_utf8(show_output.stdout).with_context(
+    || format!("{} at {} is not valid UTF-8", path.display(), git_ref),
+  )?))
+}
+
+/// Reads the lockfile at `lockfile_path` as it was at the git revision `git_ref`, or `None` if
+/// it did not exist there.
+pub fn read_lockfile_at_ref(lockfile_path: &Path, git_ref: &str) -> Result<Option<Lockfile>> {
+  match read_file_at_ref(lockfile_path, git_ref)? {
+    Some(contents) => Ok(Some(Lockfile::from_str(&contents).with_context(|| {
+      format!("Failed to parse {} at {}", lockfile_path.display(), git_ref)
+    })?)),
+    None => Ok(None),
+  }
+}
+
+/// Whether the raze settings of the manifest at `manifest_path` differ from those it had at the
+/// git revision `git_ref`. A manifest which did not exist at `git_ref` counts as changed.
+pub fn raze_settings_changed(manifest_path: &Path, git_ref: &str) -> Result<bool> {
+  let old_contents = match read_file_at_ref(manifest_path, git_ref)? {
+    Some(contents) => contents,
+    None => return Ok(true),
+  };
+  let new_contents = std::fs::read_to_string(manifest_path)
+    .with_context(|| format!("Failed to read {}", manifest_path.display()))?;
+
+  let raze_tables = |contents: &str| -> Result<Vec<Option<toml::Value>>> {
+    let manifest: toml::Value = toml::from_str(contents)?;
+    Ok(
+      ["package", "workspace"]
+        .iter()
+        .map(|section| {
+          manifest
+            .get(section)
+            .and_then(|table| table.get("metadata"))
+            .and_then(|metadata| metadata.get("raze"))
+            .cloned()
+        })
+        .collect(),
+    )
+  };
+
+  Ok(raze_tables(&old_contents)? != raze_tables(&new_contents)?)
+}
+
+/// Finds the crates of `new_lockfile` whose generated files may differ from those generated for
+/// `old_lockfile`: the crates which are new or whose source, checksum or dependencies changed, and
+/// the crates depending on any crate which was added, removed or changed.
+pub fn affected_crates(old_lockfile: &Lockfile, new_lockfile: &Lockfile) -> HashSet<CrateKey> {
+  let old_packages: HashMap<CrateKey, _> = old_lockfile
+    .packages
+    .iter()
+    .map(|package| (crate_key(&package.name, &package.version), package))
+    .collect();
+  let new_packages: HashMap<CrateKey, _> = new_lockfile
+    .packages
+    .iter()
+    .map(|package| (crate_key(&package.name, &package.version), package))
+    .collect();
+
+  let mut changed: HashSet<CrateKey> = new_packages
+    .iter()
+    .filter(|(key, package)| old_packages.get(*key) != Some(*package))
+    .map(|(key, _)| key.clone())
+    .collect();
+  changed.extend(
+    old_packages
+      .keys()
+      .filter(|key| !new_packages.contains_key(*key))
+      .cloned(),
+  );
+
+  // Dependents render the labels of their dependencies, so they are affected as well
+  let dependents = new_lockfile
+    .packages
+    .iter()
+    .filter(|package| {
+      package
+        .dependencies
+        .iter()
+        .any(|dep| changed.contains(&crate_key(&dep.name, &dep.version)))
+    })
+    .map(|package| crate_key(&package.name, &package.version))
+    .collect::<Vec<_>>();
+
+  changed
+    .into_iter()
+    .chain(dependents)
+    .filter(|key| new_packages.contains_key(key))
+    .collect()
+}
+
+/// Filters `file_outputs` down to the files which need rewriting when only the `affected` crates
+/// of `planned_build` changed. Files which are not specific to a single crate, such as
+/// `crates.bzl`, are always kept.
+pub fn retain_affected_outputs(
+  file_outputs: &BTreeMap<PathBuf, String>,
+  render_details: &RenderDetails,
+  planned_build: &PlannedBuild,
+  affected: &HashSet<CrateKey>,
+) -> BTreeMap<PathBuf, String> {
+  let path_prefix = render_details.bazel_root.join(&render_details.path_prefix);
+  let unaffected_paths: HashSet<PathBuf> = planned_build
+    .crate_contexts
+    .iter()
+    .filter(|ctx| !affected.contains(&crate_key(&ctx.pkg_name, &ctx.pkg_version)))
+    .map(|ctx| path_prefix.join(&ctx.expected_build_path))
+    .collect();
+
+  file_outputs
+    .iter()
+    .filter(|(path, _)| !unaffected_paths.contains(*path))
+    .map(|(path, contents)| (path.clone(), contents.clone()))
+    .collect()
+}
+
+fn crate_key(name: impl ToString, version: impl ToString) -> CrateKey {
+  (name.to_string(), version.to_string())
+}
+
+/// An on-disk cache of the contexts planned, and the BUILD files rendered, for each crate.
+///
+/// Entries are keyed by a `CacheKey` of everything the cached value was produced from, so crates
+/// whose version, resolved features and dependencies, and settings are unchanged reuse the results
+/// of previous runs, while any change to them or to cargo-raze itself produces a new entry.
+#[derive(Debug, Clone)]
+pub struct CrateCache {
+  cache_dir: PathBuf,
+}
+
+impl CrateCache {
+  pub fn new<P: Into<PathBuf>>(cache_dir: P) -> Self {
+    Self {
+      cache_dir: cache_dir.into(),
+    }
+  }
+
+  fn entry_path(&self, kind: &str, key: &CacheKey) -> PathBuf {
+    self
+      .cache_dir
+      .join(format!("{}-{:016x}.json", kind, key.finish()))
+  }
+
+  /// The value of `kind`, eg: `context`, cached under `key`, if any.
+  pub fn load<T: DeserializeOwned>(&self, kind: &str, key: &CacheKey) -> Option<T> {
+    let entry_path = self.entry_path(kind, key);
+    let contents = fs::read_to_string(&entry_path).ok()?;
+    match serde_json::from_str(&contents) {
+      Ok(value) => Some(value),
+      Err(err) => {
+        log::debug!(
+          "Ignoring invalid crate cache entry {}: {}",
+          entry_path.display(),
+          err
+        );
+        None
+      },
+    }
+  }
+
+  /// Caches `value` as the value of `kind` under `key`. Failing to do so only costs producing the
+  /// value again on the next run.
+  pub fn store<T: Serialize>(&self, kind: &str, key: &CacheKey, value: &T) {
+    let entry_path = self.entry_path(kind, key);
+    let stored = serde_json::to_string(value)
+      .map_err(anyhow::Error::from)
+      .and_then(|contents| {
+        fs::create_dir_all(&self.cache_dir)?;
+        Ok(fs::write(&entry_path, contents)?)
+      });
+    if let Err(err) = stored {
+      log::debug!(
+        "Failed to write crate cache entry {}: {}",
+        entry_path.display(),
+        err
+      );
+    }
+  }
+}
+
+/// The sources which plan crate contexts and render BUILD files, so a build of cargo-raze with
+/// changes to them never reuses the entries of another build of the same version.
+const PLANNER_SOURCES: &[&str] = &[
+  include_str!("context.rs"),
+  include_str!("planning.rs"),
+  include_str!("planning/crate_catalog.rs"),
+  include_str!("planning/license.rs"),
+  include_str!("planning/subplanners.rs"),
+  include_str!("rendering.rs"),
+  include_str!("rendering/bazel.rs"),
+  include_str!("settings.rs"),
+  include_str!("util.rs"),
+];
+
+/// The hash of `PLANNER_SOURCES`, which is only computed once
+fn planner_fingerprint() -> u64 {
+  static FINGERPRINT: OnceLock<u64> = OnceLock::new();
+  *FINGERPRINT.get_or_init(|| {
+    let mut hasher = DefaultHasher::new();
+    for source in PLANNER_SOURCES {
+      hasher.write(source.as_bytes());
+    }
+    hasher.finish()
+  })
+}
+
+/// The key of a `CrateCache` entry, hashing the values it is produced from along with the version
+/// and planner sources of cargo-raze.
+pub struct CacheKey(DefaultHasher);
+
+impl CacheKey {
+  pub fn new() -> Self {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.write_u64(planner_fingerprint());
+    Self(hasher)
+  }
+
+  /// Adds `value` to the key. Values are hashed by their JSON representation, in which the entries
+  /// of maps are sorted, so equal values always produce the same key.
+  pub fn add<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<&mut Self> {
+    let json = serde_json::to_value(value)?;
+    self.0.write(json.to_string().as_bytes());
+    // Separate values, so `("ab", "c")` and `("a", "bc")` produce different keys
+    self.0.write_u8(0);
+    Ok(self)
+  }
+
+  /// The hash of the values added to the key
+  pub fn finish(&self) -> u64 {
+    self.0.finish()
+  }
+}
+
+impl Default for CacheKey {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::HashMap, fs};
+
+  use semver::VersionReq;
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::{
+    metadata::RazeMetadata,
+    planning::{BuildPlanner, BuildPlannerImpl},
+    rendering::render_files,
+    settings::{tests::dummy_raze_settings, CrateSettings, RazeSettings},
+    testing::*,
+    util::PlatformDetails,
+  };
+
+  const OLD_LOCKFILE: &str = r#"
+[[package]]
+name = "cargo-raze-alias-test"
+version = "0.1.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+dependencies = [
+ "log 0.3.9",
+ "log 0.4.13",
+]
+
+[[package]]
+name = "log"
+version = "0.3.9"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+dependencies = [
+ "log 0.4.13",
+]
+
+[[package]]
+name = "log"
+version = "0.4.13"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+
+  #[test]
+  fn test_only_affected_crate_files_are_rewritten() {
+    // `log 0.3.9` gained a dependency on the newly added `cfg-if`, listed by its bare name
+    let log_dependencies = concat!(
+      "version = \"0.3.9\"\n",
+      "source = \"registry+https://github.com/rust-lang/crates.io-index\"\n",
+      "dependencies = [\n",
+    );
+    let new_lockfile = OLD_LOCKFILE.replace(
+      log_dependencies,
+      &format!("{} \"cfg-if\",\n", log_dependencies),
+    ) + r#"
+[[package]]
+name = "cfg-if"
+version = "0.1.10"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+    let affected = affected_crates(
+      &Lockfile::from_str(OLD_LOCKFILE).unwrap(),
+      &Lockfile::from_str(&new_lockfile).unwrap(),
+    );
+    // The crate depending on `log 0.3.9` is affected as well
+    assert_eq!(
+      affected,
+      vec![
+        crate_key("cargo-raze-alias-test", "0.1.0"),
+        crate_key("cfg-if", "0.1.10"),
+        crate_key("log", "0.3.9"),
+      ]
+      .into_iter()
+      .collect()
+    );
+
+    // Only the version of `cfg-if` changes, while `log 0.3.9` still lists it by its bare name
+    let bumped_lockfile = new_lockfile.replace("version = \"0.1.10\"", "version = \"1.0.0\"");
+    assert_eq!(
+      affected_crates(
+        &Lockfile::from_str(&new_lockfile).unwrap(),
+        &Lockfile::from_str(&bumped_lockfile).unwrap(),
+      ),
+      vec![
+        crate_key("cargo-raze-alias-test", "0.1.0"),
+        crate_key("cfg-if", "1.0.0"),
+        crate_key("log", "0.3.9"),
+      ]
+      .into_iter()
+      .collect()
+    );
+
+    let raze_metadata = template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    let settings = dummy_raze_settings();
+    let planned_build = BuildPlannerImpl::new(raze_metadata.clone(), settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let render_details = RenderDetails::new(
+      &settings,
+      PathBuf::from("/some/cargo/root"),
+      PathBuf::from("/some/bazel/root"),
+    );
+    let file_outputs = render_files(&settings, &render_details, &planned_build).unwrap();
+
+    let output_dir = PathBuf::from("/some/bazel/root/cargo");
+    let remote_dir = output_dir.join("remote");
+    let rewritten =
+      retain_affected_outputs(&file_outputs, &render_details, &planned_build, &affected);
+    let rewritten_paths: Vec<_> = rewritten.keys().collect();
+    assert_eq!(
+      rewritten_paths,
+      vec![
+        &output_dir.join("BUILD.bazel"),
+        &output_dir.join("Cargo.raze.lock"),
+        &output_dir.join("crates.bzl"),
+        &remote_dir.join("BUILD"),
+        &remote_dir.join("BUILD.cargo-raze-alias-test-0.1.0.bazel"),
+        &remote_dir.join("BUILD.cfg-if-0.1.10.bazel"),
+        &remote_dir.join("BUILD.log-0.3.9.bazel"),
+        &PathBuf::from("/some/cargo/root/cargo/BUILD.bazel"),
+      ]
+    );
+    // Everything else was still rendered, but is left untouched
+    assert!(file_outputs.contains_key(&remote_dir.join("BUILD.log-0.4.13.bazel")));
+  }
+
+  #[test]
+  fn test_cached_crates_are_reused_until_their_inputs_change() {
+    let cache_dir = TempDir::new().unwrap();
+    let cache = CrateCache::new(cache_dir.as_ref().to_path_buf());
+    let cache_entries = || {
+      let mut entries: Vec<_> = fs::read_dir(cache_dir.as_ref())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+      entries.sort();
+      entries
+    };
+    // The same workspace is planned each time, as its root is part of each crate's key
+    let raze_metadata = template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    let plan_and_render_metadata = |raze_metadata: &RazeMetadata, settings: &RazeSettings| {
+      let mut planner = BuildPlannerImpl::new(raze_metadata.clone(), settings.clone());
+      planner.set_crate_cache(cache.clone());
+      let planned_build = planner
+        .plan_build(Some(PlatformDetails::new(
+          "some_target_triple".to_owned(),
+          Vec::new(), /* attrs */
+        )))
+        .unwrap();
+      let mut render_details = RenderDetails::new(
+        settings,
+        PathBuf::from("/some/cargo/root"),
+        PathBuf::from("/some/bazel/root"),
+      );
+      render_details.crate_cache = Some(cache.clone());
+      (
+        serde_json::to_value(&planned_build.crate_contexts).unwrap(),
+        render_files(settings, &render_details, &planned_build).unwrap(),
+      )
+    };
+    let plan_and_render =
+      |settings: &RazeSettings| plan_and_render_metadata(&raze_metadata, settings);
+
+    let mut settings = dummy_raze_settings();
+    let (crate_contexts, file_outputs) = plan_and_render(&settings);
+    let entries = cache_entries();
+    // A context and a BUILD file for each of the crates
+    assert_eq!(entries.len(), 8);
+
+    // Nothing changed, so everything is read back from the cache
+    assert_eq!(plan_and_render(&settings), (crate_contexts, file_outputs));
+    assert_eq!(cache_entries(), entries);
+
+    let mut versions = HashMap::new();
+    versions.insert(
+      VersionReq::parse("0.3.9").unwrap(),
+      CrateSettings {
+        gen_buildrs: Some(false),
+        ..Default::default()
+      },
+    );
+    settings.crates.insert("log".to_owned(), versions);
+    plan_and_render(&settings);
+    // Only `log 0.3.9` and the crate depending on it were planned again, and only the BUILD file
+    // of `log 0.3.9` changed
+    assert_eq!(cache_entries().len(), entries.len() + 3);
+
+    // `log 0.3.9` requests another feature of `log 0.4.13`, which is not a workspace member
+    let entries = cache_entries();
+    let mut changed_metadata = raze_metadata.clone();
+    let old_log = changed_metadata
+      .metadata
+      .packages
+      .iter_mut()
+      .find(|package| package.name == "log" && package.version.to_string() == "0.3.9")
+      .unwrap();
+    old_log
+      .dependencies
+      .iter_mut()
+      .find(|dep| dep.name == "log")
+      .unwrap()
+      .features
+      .push("std".to_owned());
+    plan_and_render_metadata(&changed_metadata, &settings);
+    // `log 0.3.9` itself, the crate depending on it and `log 0.4.13`, whose feature requests
+    // changed, were planned again
+    assert_eq!(cache_entries().len(), entries.len() + 3);
+  }
+
+  #[test]
+  fn test_reading_files_at_git_revisions() {
+    let repo = TempDir::new().unwrap();
+    let git = |args: &[&str]| {
+      let output = Command::new("git")
+        .arg("-C")
+        .arg(repo.as_ref())
+        .args(args)
+        .output()
+        .unwrap();
+      assert!(output.status.success(), "{:?}", output);
+    };
+    let manifest_path = repo.as_ref().join("Cargo.toml");
+
+    git(&["init", "--quiet"]);
+    fs::write(
+      &manifest_path,
+      "[package]\nname = \"foo\"\n\n[package.metadata.raze]\ngenmode = \"Remote\"\n",
+    )
+    .unwrap();
+    git(&["add", "Cargo.toml"]);
+    git(&[
+      "-c",
+      "user.name=test",
+      "-c",
+      "user.email=test@example.com",
+      "commit",
+      "--quiet",
+      "--message=init",
+    ]);
+
+    // Changes outside of the raze settings don't matter
+    fs::write(
+      &manifest_path,
+      concat!(
+        "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n\n",
+        "[package.metadata.raze]\ngenmode = \"Remote\"\n",
+      ),
+    )
+    .unwrap();
+    assert!(!raze_settings_changed(&manifest_path, "HEAD").unwrap());
+
+    fs::write(
+      &manifest_path,
+      "[package]\nname = \"foo\"\n\n[package.metadata.raze]\ngenmode = \"Vendored\"\n",
+    )
+    .unwrap();
+    assert!(raze_settings_changed(&manifest_path, "HEAD").unwrap());
+
+    assert_eq!(
+      read_file_at_ref(&repo.as_ref().join("Cargo.lock"), "HEAD").unwrap(),
+      None
+    );
+    assert!(read_file_at_ref(&manifest_path, "not-a-ref").is_err());
+  }
+}
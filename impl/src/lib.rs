@@ -12,14 +12,32 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+//! Generates Bazel BUILD files for the dependencies of a cargo workspace.
+//!
+//! [Raze] runs the same steps as the `cargo raze` command, returning the generated files in
+//! memory instead of writing them. The modules it is built from are public for finer control.
+
+pub mod api;
+pub mod audit;
+pub mod bazelignore;
 pub mod checks;
+pub mod cleaning;
 pub mod context;
+pub mod drift;
 pub mod error;
+pub mod graph;
+pub mod http;
+pub mod incremental;
 pub mod metadata;
+pub mod patching;
 pub mod planning;
+pub mod pruning;
 pub mod rendering;
+pub mod report;
 pub mod settings;
 pub mod util;
 
+pub use api::{Raze, RazeBuilder, RazeOutput};
+
 #[cfg(test)]
 mod testing;
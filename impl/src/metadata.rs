@@ -13,41 +13,162 @@
 // limitations under the License.
 
 use std::{
-  collections::HashMap,
-  fs,
+  collections::{HashMap, HashSet},
+  env, fs,
+  io::Read,
+  panic::{self, AssertUnwindSafe},
   path::{Path, PathBuf},
+  process::{Child, Stdio},
   string::String,
+  sync::{mpsc, Arc, Mutex},
+  thread,
+  time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context, Result};
 use cargo_lock::Lockfile;
-use cargo_metadata::{Metadata, MetadataCommand};
+use cargo_metadata::{CargoOpt, Metadata, MetadataCommand};
+use flate2::read::GzDecoder;
 use glob::glob;
+use itertools::Itertools;
 use pathdiff::diff_paths;
 use regex::Regex;
 use rustc_serialize::hex::ToHex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 use url::Url;
 
-use crate::util::{cargo_bin_path, package_ident};
+use crate::{
+  context::GitRepo,
+  http::{HttpClient, HttpConfig},
+  incremental::CacheKey,
+  util::{cargo_bin_path, package_ident},
+};
 
 pub(crate) const DEFAULT_CRATE_REGISTRY_URL: &str = "https://crates.io";
 pub(crate) const DEFAULT_CRATE_INDEX_URL: &str = "https://github.com/rust-lang/crates.io-index";
+/// The index of crates.io served over cargo's sparse HTTP protocol, the default since cargo 1.70
+pub(crate) const DEFAULT_CRATE_SPARSE_INDEX_URL: &str = "sparse+https://index.crates.io/";
+
+/// The prefix of lockfile `[metadata]` keys pinning the features of a crate, eg:
+/// `"features log 0.4.13" = "std,serde"`
+pub(crate) const LOCKED_FEATURES_KEY_PREFIX: &str = "features ";
+
+/// The number of binary dependency downloads run at a time unless configured otherwise
+pub(crate) const DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 8;
+
+/// How often a message is printed while waiting on `cargo metadata`, so a slow resolution of a
+/// large workspace can be told apart from a hang
+const METADATA_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
 
 /// An entity that can generate Cargo metadata within a Cargo workspace
 pub trait MetadataFetcher {
   fn fetch_metadata(&self, working_dir: &Path, include_deps: bool) -> Result<Metadata>;
 }
 
+/// Runs `command`, directing any of cargo's build outputs to `cargo_target_dir` when one is given.
+///
+/// The command is killed if it runs for longer than `timeout`.
+pub(crate) fn exec_metadata_command(
+  command: &MetadataCommand,
+  cargo_target_dir: Option<&Path>,
+  timeout: Option<Duration>,
+) -> Result<Metadata> {
+  let mut cargo_command = command.cargo_command();
+  if let Some(cargo_target_dir) = cargo_target_dir {
+    cargo_command.env("CARGO_TARGET_DIR", cargo_target_dir);
+  }
+
+  let mut child = cargo_command
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()?;
+  // The outputs are drained while waiting so that cargo never blocks on a full pipe
+  let stdout = read_in_background(child.stdout.take());
+  let stderr = read_in_background(child.stderr.take());
+
+  let started = Instant::now();
+  let mut next_heartbeat = METADATA_HEARTBEAT_INTERVAL;
+  let status = loop {
+    if let Some(status) = child.try_wait()? {
+      break status;
+    }
+    let elapsed = started.elapsed();
+    if let Some(timeout) = timeout.filter(|timeout| elapsed >= *timeout) {
+      kill(&mut child);
+      return Err(anyhow!(
+        "`cargo metadata` did not finish within {:?}. Consider raising `metadata_timeout_secs`.",
+        timeout
+      ));
+    }
+    if elapsed >= next_heartbeat {
+      eprintln!(
+        "Still waiting on `cargo metadata` ({}s elapsed)...",
+        elapsed.as_secs()
+      );
+      next_heartbeat += METADATA_HEARTBEAT_INTERVAL;
+    }
+    thread::sleep(Duration::from_millis(50));
+  };
+
+  let stdout = stdout.join().unwrap_or_default();
+  let stderr = stderr.join().unwrap_or_default();
+  if !status.success() {
+    return Err(anyhow!(
+      "`cargo metadata` failed: {}",
+      String::from_utf8_lossy(&stderr)
+    ));
+  }
+  let stdout = String::from_utf8(stdout)?;
+  let json = stdout
+    .lines()
+    .find(|line| line.starts_with('{'))
+    .ok_or_else(|| anyhow!("`cargo metadata` produced no JSON output"))?;
+  Ok(MetadataCommand::parse(json)?)
+}
+
+/// Reads all of `pipe` on a separate thread.
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+  thread::spawn(move || {
+    let mut contents = Vec::new();
+    if let Some(mut pipe) = pipe {
+      let _ = pipe.read_to_end(&mut contents);
+    }
+    contents
+  })
+}
+
+/// Kills `child`, reaping it so it does not linger as a zombie process.
+fn kill(child: &mut Child) {
+  let _ = child.kill();
+  let _ = child.wait();
+}
+
 /// A lockfile generator which simply wraps the `cargo_metadata::MetadataCommand` command
 struct CargoMetadataFetcher {
   pub cargo_bin_path: PathBuf,
+  pub cargo_target_dir: Option<PathBuf>,
+  // Features to resolve the workspace with. Entries in the form `member/feature` only apply to
+  // the named workspace member.
+  pub features: Vec<String>,
+  // Whether to resolve the workspace with every feature of every workspace member enabled
+  pub all_features: bool,
+  // Whether `cargo` must resolve the workspace without accessing the network
+  pub offline: bool,
+  // How long `cargo metadata` may run before it is aborted
+  pub metadata_timeout: Option<Duration>,
 }
 
 impl Default for CargoMetadataFetcher {
   fn default() -> CargoMetadataFetcher {
     CargoMetadataFetcher {
       cargo_bin_path: cargo_bin_path(),
+      cargo_target_dir: None,
+      features: Vec::new(),
+      all_features: false,
+      offline: false,
+      metadata_timeout: None,
     }
   }
 }
@@ -60,17 +181,198 @@ impl MetadataFetcher for CargoMetadataFetcher {
       command.no_deps();
     }
 
+    if !self.features.is_empty() {
+      command.features(CargoOpt::SomeFeatures(self.features.clone()));
+    }
+
+    if self.all_features {
+      command.features(CargoOpt::AllFeatures);
+    }
+
+    if self.offline {
+      command.other_options(vec!["--offline".to_owned()]);
+    }
+
     command
       .cargo_path(&self.cargo_bin_path)
-      .current_dir(working_dir)
-      .exec()
-      .with_context(|| {
-        format!(
-          "Failed to fetch Metadata with `{}` from `{}`",
-          &self.cargo_bin_path.display(),
-          working_dir.display()
-        )
-      })
+      .current_dir(working_dir);
+    exec_metadata_command(
+      &command,
+      self.cargo_target_dir.as_deref(),
+      self.metadata_timeout,
+    )
+    .with_context(|| {
+      format!(
+        "Failed to fetch Metadata with `{}` from `{}`",
+        &self.cargo_bin_path.display(),
+        working_dir.display()
+      )
+    })
+  }
+}
+
+/// A metadata fetcher which caches the dependency-including results of another fetcher on disk.
+///
+/// Entries are keyed by the contents of every `Cargo.toml` file of the workspace and its path
+/// dependencies, the source files their targets are discovered from and the `Cargo.lock` file,
+/// along with the options the workspace is resolved with, so a cached result is reused until any
+/// of them changes.
+struct CachingMetadataFetcher<'fetcher> {
+  cache_dir: PathBuf,
+  fetcher: &'fetcher dyn MetadataFetcher,
+  // The features `fetcher` resolves with, which are part of the cache key
+  features: Vec<String>,
+  // Whether `fetcher` resolves with all features, which is part of the cache key
+  all_features: bool,
+  // Whether the workspace is resolved offline, which is part of the cache key
+  offline: bool,
+  // Whether the lockfile holds the minimal versions, which is part of the cache key
+  minimal_versions: bool,
+}
+
+/// Stands in for the temporary workspace metadata is fetched from in cache entries, as each fetch
+/// happens in a new one.
+const CACHED_WORKSPACE_PLACEHOLDER: &str = "{RAZE_CACHED_WORKSPACE}";
+
+impl CachingMetadataFetcher<'_> {
+  /// Determines the path of the cache entry for the workspace in `working_dir`
+  fn cache_entry_path(&self, working_dir: &Path) -> Result<PathBuf> {
+    let mut key = CacheKey::new();
+    let canonical_working_dir = fs::canonicalize(working_dir)?;
+    let mut external_dirs = Vec::new();
+    // The manifests of workspace members and injected binary dependencies are all within the
+    // temporary workspace, along with the sources targets are discovered from
+    let pattern = format!("{}/**/Cargo.toml", working_dir.display());
+    for manifest in glob(&pattern)? {
+      let manifest = manifest?;
+      let contents = fs::read_to_string(&manifest)?;
+      // UNWRAP: Manifests are files within `working_dir`
+      let package_dir = manifest.parent().unwrap();
+      key
+        .add(&manifest.strip_prefix(working_dir)?)?
+        .add(&contents)?
+        .add(&target_discovery_files(package_dir)?)?;
+      external_dirs.extend(
+        path_dependency_dirs(&contents, package_dir)?
+          .into_iter()
+          .filter(|dir| !dir.starts_with(&canonical_working_dir)),
+      );
+    }
+    // Path dependencies outside of the workspace root are referred to by absolute paths, so they
+    // are read from where they are, as are their own path dependencies
+    let mut keyed_dirs = HashSet::new();
+    while let Some(package_dir) = external_dirs.pop() {
+      if !keyed_dirs.insert(package_dir.clone()) {
+        continue;
+      }
+      let contents = match fs::read_to_string(package_dir.join("Cargo.toml")) {
+        Ok(contents) => contents,
+        Err(_) => continue,
+      };
+      key
+        .add(&package_dir)?
+        .add(&contents)?
+        .add(&target_discovery_files(&package_dir)?)?;
+      external_dirs.extend(path_dependency_dirs(&contents, &package_dir)?);
+    }
+    let lockfile = working_dir.join("Cargo.lock");
+    let lockfile_contents = if lockfile.exists() {
+      Some(fs::read_to_string(lockfile)?)
+    } else {
+      None
+    };
+    key
+      .add(&lockfile_contents)?
+      .add(&self.features)?
+      .add(&self.all_features)?
+      .add(&self.offline)?
+      .add(&self.minimal_versions)?;
+
+    Ok(
+      self
+        .cache_dir
+        .join(format!("metadata-{:016x}.json", key.finish())),
+    )
+  }
+}
+
+/// The forms `working_dir` may take in serialized metadata, as JSON string contents
+fn serialized_workspace_paths(working_dir: &Path) -> Result<Vec<String>> {
+  let mut paths = vec![working_dir.to_path_buf()];
+  // Cargo reports canonical paths, eg: `/private/var` for `/var` on macOS
+  if let Ok(canonical) = working_dir.canonicalize() {
+    if canonical != working_dir {
+      paths.insert(0, canonical);
+    }
+  }
+  paths
+    .iter()
+    .map(|path| {
+      let json = serde_json::to_string(path)?;
+      Ok(json.trim_matches('"').to_owned())
+    })
+    .collect()
+}
+
+impl MetadataFetcher for CachingMetadataFetcher<'_> {
+  fn fetch_metadata(&self, working_dir: &Path, include_deps: bool) -> Result<Metadata> {
+    // Metadata without dependencies is cheap to produce and is used to discover workspace members
+    if !include_deps {
+      return self.fetcher.fetch_metadata(working_dir, include_deps);
+    }
+
+    let cache_entry = self.cache_entry_path(working_dir)?;
+    let workspace_paths = serialized_workspace_paths(working_dir)?;
+    if let Ok(contents) = fs::read_to_string(&cache_entry) {
+      // The canonical path of `working_dir` comes first, as cargo reports it
+      let contents = contents.replace(CACHED_WORKSPACE_PLACEHOLDER, &workspace_paths[0]);
+      match serde_json::from_str::<Metadata>(&contents) {
+        Ok(metadata) => return Ok(metadata),
+        Err(err) => log::debug!(
+          "Ignoring invalid metadata cache entry {}: {}",
+          cache_entry.display(),
+          err
+        ),
+      }
+    }
+
+    let metadata = self.fetcher.fetch_metadata(working_dir, include_deps)?;
+
+    // Paths into the temporary workspace are stored relative to it, so cached metadata refers to
+    // the workspace of the fetch reusing it
+    let contents = workspace_paths
+      .iter()
+      .fold(serde_json::to_string(&metadata)?, |contents, path| {
+        contents.replace(path.as_str(), CACHED_WORKSPACE_PLACEHOLDER)
+      });
+    fs::create_dir_all(&self.cache_dir)?;
+    fs::write(&cache_entry, contents).with_context(|| {
+      format!(
+        "Failed to write metadata cache entry {}",
+        cache_entry.display()
+      )
+    })?;
+
+    Ok(metadata)
+  }
+}
+
+/// The default directory in which to cache `cargo metadata` results
+pub fn default_metadata_cache_dir() -> PathBuf {
+  let cache_root = match (env::var_os("XDG_CACHE_HOME"), env::var_os("HOME")) {
+    (Some(cache_home), _) => PathBuf::from(cache_home),
+    (None, Some(home)) => PathBuf::from(home).join(".cache"),
+    (None, None) => env::temp_dir(),
+  };
+  cache_root.join("cargo-raze")
+}
+
+/// The directory cargo keeps its registry caches in, eg: `~/.cargo`
+pub fn default_cargo_home() -> PathBuf {
+  match (env::var_os("CARGO_HOME"), env::var_os("HOME")) {
+    (Some(cargo_home), _) => PathBuf::from(cargo_home),
+    (None, Some(home)) => PathBuf::from(home).join(".cargo"),
+    (None, None) => PathBuf::from(".cargo"),
   }
 }
 
@@ -82,6 +384,20 @@ pub trait LockfileGenerator {
 /// A lockfile generator which simply wraps the `cargo generate-lockfile` command
 struct CargoLockfileGenerator {
   cargo_bin_path: PathBuf,
+  cargo_target_dir: Option<PathBuf>,
+  offline: bool,
+  minimal_versions: bool,
+}
+
+impl Default for CargoLockfileGenerator {
+  fn default() -> CargoLockfileGenerator {
+    CargoLockfileGenerator {
+      cargo_bin_path: cargo_bin_path(),
+      cargo_target_dir: None,
+      offline: false,
+      minimal_versions: false,
+    }
+  }
 }
 
 impl LockfileGenerator for CargoLockfileGenerator {
@@ -90,10 +406,21 @@ impl LockfileGenerator for CargoLockfileGenerator {
     let lockfile_path = crate_root_dir.join("Cargo.lock");
 
     // Generate lockfile
-    std::process::Command::new(&self.cargo_bin_path)
-      .arg("generate-lockfile")
-      .current_dir(&crate_root_dir)
-      .output()?;
+    let mut command = std::process::Command::new(&self.cargo_bin_path);
+    command.arg("generate-lockfile").current_dir(crate_root_dir);
+    if self.offline {
+      command.arg("--offline");
+    }
+    if self.minimal_versions {
+      // `-Z` flags are unstable, which stable releases of cargo only accept when bootstrapping
+      command
+        .arg("-Zminimal-versions")
+        .env("RUSTC_BOOTSTRAP", "1");
+    }
+    if let Some(cargo_target_dir) = &self.cargo_target_dir {
+      command.env("CARGO_TARGET_DIR", cargo_target_dir);
+    }
+    command.output()?;
 
     // Load lockfile contents
     Lockfile::load(&lockfile_path)
@@ -117,6 +444,18 @@ pub struct RazeMetadata {
 
   // A map of all known crates with checksums. Use `checksums_for` to access data from this map.
   pub checksums: HashMap<String, String>,
+
+  // A map of crates to the exact features pinned for them by the lockfile. Use
+  // `locked_features_for` to access data from this map.
+  pub locked_features: HashMap<String, Vec<String>>,
+
+  // A map of crates from registries other than the default one to their download urls. Use
+  // `download_url_for` to access data from this map.
+  pub download_urls: HashMap<String, String>,
+
+  // A map of the binary dependencies which were cloned from their repository, as they could not be
+  // downloaded, to that repository. Use `git_fallback_for` to access data from this map.
+  pub git_fallbacks: HashMap<String, GitRepo>,
 }
 
 impl RazeMetadata {
@@ -124,6 +463,94 @@ impl RazeMetadata {
   pub fn checksum_for(&self, name: &str, version: &str) -> Option<&String> {
     self.checksums.get(&package_ident(name, version))
   }
+
+  /// Get the features pinned for a crate by the lockfile, if any.
+  pub fn locked_features_for(&self, name: &str, version: &str) -> Option<&Vec<String>> {
+    self.locked_features.get(&package_ident(name, version))
+  }
+
+  /// Get the download url of a crate from a registry other than the default one.
+  pub fn download_url_for(&self, name: &str, version: &str) -> Option<&String> {
+    self.download_urls.get(&package_ident(name, version))
+  }
+
+  /// Get the repository a crate was cloned from as its download failed, if it was.
+  pub fn git_fallback_for(&self, name: &str, version: &str) -> Option<&GitRepo> {
+    self.git_fallbacks.get(&package_ident(name, version))
+  }
+}
+
+/// Expands the `dl` endpoint of a registry index's `config.json` into the download url of a crate.
+///
+/// See https://doc.rust-lang.org/cargo/reference/registries.html#index-format
+fn registry_download_url(dl: &str, name: &str, version: &str, checksum: Option<&String>) -> String {
+  let markers = [
+    "{crate}",
+    "{version}",
+    "{prefix}",
+    "{lowerprefix}",
+    "{sha256-checksum}",
+  ];
+  if !markers.iter().any(|marker| dl.contains(marker)) {
+    return format!("{}/{}/{}/download", dl.trim_end_matches('/'), name, version);
+  }
+
+  let prefix = match name.len() {
+    1 => "1".to_owned(),
+    2 => "2".to_owned(),
+    3 => format!("3/{}", &name[..1]),
+    _ => format!("{}/{}", &name[..2], &name[2..4]),
+  };
+  dl.replace("{crate}", name)
+    .replace("{version}", version)
+    .replace("{prefix}", &prefix)
+    .replace("{lowerprefix}", &prefix.to_lowercase())
+    .replace(
+      "{sha256-checksum}",
+      checksum.map(String::as_str).unwrap_or_default(),
+    )
+}
+
+/// Collects the checksums a lockfile lists for its packages, keyed by `package_ident`. Packages
+/// which are replaced by another, eg: by `[replace]`, are skipped as their checksum is not the one
+/// of the sources that are built.
+fn collect_checksums(lockfile: &Lockfile) -> HashMap<String, String> {
+  lockfile
+    .packages
+    .iter()
+    .filter(|package| package.replace.is_none())
+    .filter_map(|package| {
+      package.checksum.as_ref().map(|checksum| {
+        (
+          package_ident(package.name.as_ref(), &package.version.to_string()),
+          checksum.to_string(),
+        )
+      })
+    })
+    .collect()
+}
+
+/// Collects the crate features pinned by the `[metadata]` table of a lockfile
+fn collect_locked_features(lockfile: &Lockfile) -> HashMap<String, Vec<String>> {
+  lockfile
+    .metadata
+    .iter()
+    .filter_map(|(key, value)| {
+      let mut ident = key
+        .as_ref()
+        .strip_prefix(LOCKED_FEATURES_KEY_PREFIX)?
+        .split_whitespace();
+      let (name, version) = (ident.next()?, ident.next()?);
+      let features = value
+        .as_ref()
+        .split(',')
+        .map(str::trim)
+        .filter(|feature| !feature.is_empty())
+        .map(str::to_owned)
+        .collect();
+      Some((package_ident(name, version), features))
+    })
+    .collect()
 }
 
 /// Create a symlink file on unix systems
@@ -140,548 +567,3402 @@ fn make_symlink(src: &Path, dest: &Path) -> Result<()> {
     .with_context(|| "Failed to create symlink for generating metadata")
 }
 
-/// A workspace metadata fetcher that uses the Cargo commands to gather information about a Cargo
-/// project and it's transitive dependencies for planning and rendering of Bazel BUILD files.
-pub struct RazeMetadataFetcher {
-  registry_url: Url,
-  index_url: Url,
-  metadata_fetcher: Box<dyn MetadataFetcher>,
-  lockfile_generator: Box<dyn LockfileGenerator>,
+/// The tables of a manifest which declare dependencies, and so may hold `path` dependencies
+const DEPENDENCY_TABLES: [&str; 3] = ["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// The values of `value` if it is a table
+fn table_values_mut(value: &mut toml::Value) -> impl Iterator<Item = &mut toml::Value> {
+  value
+    .as_table_mut()
+    .into_iter()
+    .flat_map(|table| table.iter_mut().map(|(_, value)| value))
 }
 
-impl RazeMetadataFetcher {
-  pub fn new<P: Into<PathBuf>>(
-    cargo_bin_path: P,
-    registry_url: Url,
-    index_url: Url,
-  ) -> RazeMetadataFetcher {
-    let cargo_bin_pathbuf: PathBuf = cargo_bin_path.into();
-    RazeMetadataFetcher {
-      registry_url,
-      index_url,
-      metadata_fetcher: Box::new(CargoMetadataFetcher {
-        cargo_bin_path: cargo_bin_pathbuf.clone(),
-      }),
-      lockfile_generator: Box::new(CargoLockfileGenerator {
-        cargo_bin_path: cargo_bin_pathbuf,
-      }),
+/// The dependencies declared anywhere in the parsed `manifest`, including those of specific
+/// platforms, patches and replacements
+fn dependencies_mut(manifest: &mut toml::Value) -> impl Iterator<Item = &mut toml::Value> {
+  let mut dependency_tables = Vec::new();
+  if let Some(manifest) = manifest.as_table_mut() {
+    for (key, value) in manifest.iter_mut() {
+      match key.as_str() {
+        key if DEPENDENCY_TABLES.contains(&key) => dependency_tables.push(value),
+        // Eg: `[target.'cfg(unix)'.dependencies]`
+        "target" => {
+          for platform in table_values_mut(value) {
+            for (key, value) in platform.as_table_mut().into_iter().flatten() {
+              if DEPENDENCY_TABLES.contains(&key.as_str()) {
+                dependency_tables.push(value);
+              }
+            }
+          }
+        },
+        // Eg: `[patch.crates-io]`
+        "patch" => dependency_tables.extend(table_values_mut(value)),
+        // Eg: `[replace]` with `"foo:0.1.0" = { path = "../foo" }`
+        "replace" => dependency_tables.push(value),
+        _ => {},
+      }
     }
   }
+  dependency_tables.into_iter().flat_map(table_values_mut)
+}
 
-  /// Reassign the [`crate::metadata::MetadataFetcher`] associated with the Raze Metadata Fetcher
-  pub fn set_metadata_fetcher(&mut self, fetcher: Box<dyn MetadataFetcher>) {
-    self.metadata_fetcher = fetcher;
-  }
+/// The canonical directories of the `path` dependencies of `manifest`, the contents of the
+/// manifest in `manifest_dir`. Dependencies whose directory does not exist are left to cargo to
+/// report.
+fn path_dependency_dirs(manifest: &str, manifest_dir: &Path) -> Result<Vec<PathBuf>> {
+  let mut manifest: toml::Value = toml::from_str(manifest)
+    .with_context(|| format!("Failed to parse the manifest in {}", manifest_dir.display()))?;
+  Ok(
+    dependencies_mut(&mut manifest)
+      .filter_map(|dependency| dependency.get("path")?.as_str().map(PathBuf::from))
+      .filter_map(|path| fs::canonicalize(manifest_dir.join(path)).ok())
+      .collect(),
+  )
+}
 
-  /// Reassign the [`crate::metadata::LockfileGenerator`] associated with the current Fetcher
-  pub fn set_lockfile_generator(&mut self, generator: Box<dyn LockfileGenerator>) {
-    self.lockfile_generator = generator;
+/// The files of the package in `package_dir` whose presence makes cargo discover a target, as
+/// paths relative to `package_dir`
+fn target_discovery_files(package_dir: &Path) -> Result<Vec<PathBuf>> {
+  let mut files = Vec::new();
+  for pattern in &[
+    "src/lib.rs",
+    "src/main.rs",
+    "src/bin/*.rs",
+    "src/bin/*/main.rs",
+  ] {
+    for file in glob(&format!("{}/{}", package_dir.display(), pattern))? {
+      files.push(file?.strip_prefix(package_dir)?.to_path_buf());
+    }
   }
+  files.sort();
+  Ok(files)
+}
 
-  /// Symlinks the source code of all workspace members into the temp workspace
-  fn link_src_to_workspace(&self, no_deps_metadata: &Metadata, temp_dir: &Path) -> Result<()> {
-    let crate_member_id_re = Regex::new(r".+\(path\+file://(.+)\)")?;
-    for member in no_deps_metadata.workspace_members.iter() {
-      // Get a path to the workspace member directory
-      let workspace_member_directory = {
-        let crate_member_id_match = crate_member_id_re
-          .captures(&member.repr)
-          .and_then(|cap| cap.get(1));
+/// Rewrites the `path` of every dependency of the manifest at `manifest_path`, a copy of the one in
+/// `manifest_dir`, which points outside of `workspace_root` to an absolute path. Such dependencies
+/// are not part of the copy of the workspace metadata is fetched from, so their relative paths
+/// would not resolve from there.
+fn absolutize_external_path_dependencies(
+  manifest_path: &Path,
+  manifest_dir: &Path,
+  workspace_root: &Path,
+) -> Result<()> {
+  let content = fs::read_to_string(manifest_path)?;
+  let mut manifest: toml::Value = toml::from_str(&content)
+    .with_context(|| format!("Failed to parse {}", manifest_path.display()))?;
+  let workspace_root = fs::canonicalize(workspace_root)?;
 
-        if crate_member_id_match.is_none() {
-          continue;
-        }
+  let mut rewritten = false;
+  for dependency in dependencies_mut(&mut manifest) {
+    let path = match dependency.get_mut("path") {
+      Some(path) => path,
+      None => continue,
+    };
+    let dependency_dir = match path
+      .as_str()
+      .map(|path| fs::canonicalize(manifest_dir.join(path)))
+    {
+      Some(Ok(dependency_dir)) => dependency_dir,
+      _ => continue,
+    };
+    if !dependency_dir.starts_with(&workspace_root) {
+      *path = toml::Value::String(dependency_dir.to_string_lossy().into_owned());
+      rewritten = true;
+    }
+  }
 
-        // UNWRAP: guarded above
-        PathBuf::from(crate_member_id_match.unwrap().as_str())
-      };
+  if rewritten {
+    fs::write(manifest_path, toml::to_string(&manifest)?)
+      .with_context(|| format!("Failed to write {}", manifest_path.display()))?;
+  }
 
-      // Sanity check: The assumption is that any crate with an `id` that matches
-      // the regex pattern above should contain a Cargo.toml file with which we
-      // can use to infer the existence of libraries from relative paths such as
-      // `src/lib.rs` and `src/main.rs`.
-      let toml_path = workspace_member_directory.join("Cargo.toml");
-      if !toml_path.exists() {
-        return Err(anyhow!(format!(
-          "The regex pattern `{}` found a path that did not contain a Cargo.toml file: `{}`",
-          crate_member_id_re.as_str(),
-          workspace_member_directory.display()
-        )));
-      }
+  Ok(())
+}
 
-      // Copy the Cargo.toml files into the temp directory to match the directory structure on disk
-      let diff = diff_paths(
-        &workspace_member_directory,
-        &no_deps_metadata.workspace_root,
-      )
-      .ok_or_else(|| {
-        anyhow!("All workspace memebers are expected to be under the workspace root")
-      })?;
-      let new_path = temp_dir.join(diff);
-      fs::create_dir_all(&new_path)?;
-      fs::copy(
-        workspace_member_directory.join("Cargo.toml"),
-        new_path.join("Cargo.toml"),
-      )?;
+/// The url of a registry api, which is only the host URL with ports, along with any base path the
+/// registry api is served under, eg: `https://example.com/private/api/v1/...`
+fn registry_api_url(registry_url: &Url) -> String {
+  let mut url = registry_url.clone();
+  let base_path = match url.path().find("/api/v1/") {
+    Some(idx) => url.path()[..idx].to_owned(),
+    None => String::new(),
+  };
+  url.set_path(&base_path);
+  url.to_string().trim_end_matches('/').to_owned()
+}
 
-      // Additionally, symlink everything in some common source directories to ensure specified
-      // library targets can be relied on and won't prevent fetching metadata
-      for dir in vec!["bin", "src"].iter() {
-        let glob_pattern = format!("{}/**/*.rs", workspace_member_directory.join(dir).display());
-        for entry in glob(glob_pattern.as_str()).expect("Failed to read glob pattern") {
-          let path = entry?;
+/// Downloads the archive of a crate from the registry api at `registry_url`, yielding it along
+/// with the url it was downloaded from.
+fn download_crate_archive(
+  client: &HttpClient,
+  registry_url: &str,
+  name: &str,
+  version: &str,
+) -> Result<(Vec<u8>, String)> {
+  log::debug!(
+    "Downloading binary dependency: {} from {}",
+    &name,
+    registry_url
+  );
+  let pkg_info_url = format!("{}/api/v1/crates/{}", registry_url, name);
+  let pkg_info: serde_json::Value = client
+    .get(&pkg_info_url)?
+    .send()
+    .and_then(|response| response.error_for_status())
+    .and_then(|response| response.json())
+    .with_context(|| format!("Failed to fetch package info from {}", pkg_info_url))?;
+  let dl_path = pkg_info["versions"]
+    .as_array()
+    .and_then(|versions| versions.iter().find(|info| info["num"] == version))
+    .and_then(|info| info["dl_path"].as_str())
+    .ok_or_else(|| anyhow!("Failed to find version {} for crate {}", version, name))?;
 
-          // Determine the difference between the workspace root and the current file
-          let diff = diff_paths(&path, &no_deps_metadata.workspace_root).ok_or_else(|| {
-            anyhow!("All workspace memebers are expected to be under the workspace root")
-          })?;
+  let dl_url = format!("{}{}", registry_url, dl_path);
+  let mut archive = Vec::new();
+  client
+    .get(&dl_url)?
+    .send()
+    .and_then(|response| response.error_for_status())
+    .and_then(|mut response| response.copy_to(&mut archive))
+    .with_context(|| format!("Failed to download {}", dl_url))?;
 
-          // Create a matching directory tree for the current file within the temp workspace
-          let new_path = temp_dir.join(diff);
-          if let Some(parent) = new_path.parent() {
-            fs::create_dir_all(parent)?;
-          }
+  Ok((archive, dl_url))
+}
 
-          make_symlink(&path, &new_path)?;
-        }
-      }
+/// Looks up the `repository` of the crate `name` in the first of the registry apis at
+/// `registry_urls` listing one.
+fn fetch_crate_repository(
+  client: &HttpClient,
+  registry_urls: &[String],
+  name: &str,
+) -> Result<String> {
+  let mut failures = Vec::new();
+  for registry_url in registry_urls {
+    let pkg_info_url = format!("{}/api/v1/crates/{}", registry_url, name);
+    let pkg_info = client
+      .get(&pkg_info_url)?
+      .send()
+      .and_then(|response| response.error_for_status())
+      .and_then(|response| response.json::<serde_json::Value>());
+    match pkg_info {
+      Ok(pkg_info) => match pkg_info["crate"]["repository"].as_str() {
+        Some(repository) => return Ok(repository.to_owned()),
+        None => failures.push(format!("{}: no repository is listed", pkg_info_url)),
+      },
+      Err(err) => failures.push(format!("{}: {}", pkg_info_url, err)),
     }
-
-    Ok(())
   }
 
-  /// Creates a copy workspace in a temporary directory for fetching the metadata of the current workspace
-  fn make_temp_workspace(&self, cargo_workspace_root: &Path) -> Result<(TempDir, PathBuf)> {
-    let temp_dir = TempDir::new()?;
-
-    // First gather metadata without downloading any dependencies so we can identify any path dependencies.
-    let no_deps_metadata = self
-      .metadata_fetcher
-      .fetch_metadata(cargo_workspace_root, /*include_deps=*/ false)?;
+  Err(anyhow!(
+    "Failed to find the repository of {}:\n  {}",
+    name,
+    failures.join("\n  ")
+  ))
+}
 
-    // There should be a `Cargo.toml` file in the workspace root
-    fs::copy(
-      no_deps_metadata.workspace_root.join("Cargo.toml"),
-      temp_dir.as_ref().join("Cargo.toml"),
-    )?;
+/// Clones `repository` into `dir` at the tag of `version` of the crate `name`, trying the tag
+/// forms commonly used for releases in order.
+///
+/// Yields the directory of the crate within the clone, along with the commit it was cloned at.
+fn clone_crate_src(
+  repository: &str,
+  dir: &Path,
+  name: &str,
+  version: &str,
+) -> Result<(PathBuf, GitRepo)> {
+  let clone_dir = dir.join(package_ident(name, version));
+  let tags = [
+    format!("v{}", version),
+    version.to_owned(),
+    format!("{}-v{}", name, version),
+    format!("{}-{}", name, version),
+  ];
+  let mut failures = Vec::new();
+  for tag in tags.iter() {
+    let clone_output = std::process::Command::new("git")
+      .args(["clone", "--quiet", "--depth=1", "--branch", tag, repository])
+      .arg(&clone_dir)
+      .output()
+      .context("Failed to run git")?;
+    if !clone_output.status.success() {
+      failures.push(format!(
+        "{}: {}",
+        tag,
+        String::from_utf8_lossy(&clone_output.stderr).trim()
+      ));
+      continue;
+    }
 
-    // Optionally copy over the lock file
-    if no_deps_metadata.workspace_root.join("Cargo.lock").exists() {
-      fs::copy(
-        no_deps_metadata.workspace_root.join("Cargo.lock"),
-        temp_dir.as_ref().join("Cargo.lock"),
-      )?;
+    let rev_parse_output = std::process::Command::new("git")
+      .arg("-C")
+      .arg(&clone_dir)
+      .args(["rev-parse", "HEAD"])
+      .output()
+      .context("Failed to run git")?;
+    if !rev_parse_output.status.success() {
+      return Err(anyhow!(
+        "Failed to read the commit {} was cloned at: {}",
+        repository,
+        String::from_utf8_lossy(&rev_parse_output.stderr)
+      ));
     }
+    let commit = String::from_utf8(rev_parse_output.stdout)?
+      .trim()
+      .to_owned();
 
-    // Copy over the Cargo.toml files of each workspace member
-    self.link_src_to_workspace(&no_deps_metadata, temp_dir.as_ref())?;
+    let crate_dir = find_package_dir(&clone_dir, name)?;
+    let path_to_crate_root = crate_dir
+      .strip_prefix(&clone_dir)?
+      .to_str()
+      .filter(|path| !path.is_empty())
+      .map(str::to_owned);
+    return Ok((
+      crate_dir,
+      GitRepo {
+        remote: repository.to_owned(),
+        commit,
+        path_to_crate_root,
+      },
+    ));
+  }
 
-    Ok((temp_dir, no_deps_metadata.workspace_root))
+  Err(anyhow!(
+    "Failed to clone {} {} from {} at any of the tags {}:\n  {}",
+    name,
+    version,
+    repository,
+    tags.join(", "),
+    failures.join("\n  ")
+  ))
+}
+
+/// Finds the directory of the package `name` in the repository cloned into `clone_dir`, which may
+/// hold a workspace of several packages. The package closest to the root of the repository wins.
+fn find_package_dir(clone_dir: &Path, name: &str) -> Result<PathBuf> {
+  let pattern = format!("{}/**/Cargo.toml", clone_dir.display());
+  let mut package_dirs = Vec::new();
+  for manifest in glob(&pattern)? {
+    let manifest = manifest?;
+    let package_name = fs::read_to_string(&manifest)
+      .ok()
+      .and_then(|contents| toml::from_str::<toml::Value>(&contents).ok())
+      .and_then(|manifest| {
+        manifest
+          .get("package")
+          .and_then(|package| package.get("name"))
+          .and_then(toml::Value::as_str)
+          .map(str::to_owned)
+      });
+    if package_name.as_deref() == Some(name) {
+      // UNWRAP: Manifests are files within `clone_dir`
+      package_dirs.push(manifest.parent().unwrap().to_path_buf());
+    }
   }
 
-  /// Download a crate's source code from the current registry url
-  fn fetch_crate_src(&self, dir: &Path, name: &str, version: &str) -> Result<PathBuf> {
-    // The registry url should only be the host URL with ports. No path
-    let registry_url = {
-      let mut r_url = self.registry_url.clone();
-      r_url.set_path("");
-      r_url.to_string()
-    };
+  package_dirs
+    .into_iter()
+    .min_by_key(|package_dir| package_dir.components().count())
+    .ok_or_else(|| {
+      anyhow!(
+        "The repository cloned into {} has no package {}",
+        clone_dir.display(),
+        name
+      )
+    })
+}
 
-    // Generate a URL with no path. This allows the path to keep any port information
-    // associated with it.
-    let mut url = url::Url::parse(&registry_url)?;
-    url.set_path("");
-
-    log::debug!("Cloning binary dependency: {}", &name);
-    let mut cloner = cargo_clone::Cloner::new();
-    cloner
-      .set_registry_url(url.to_string().trim_end_matches('/'))
-      .set_out_dir(dir);
-
-    cloner.clone(
-      cargo_clone::CloneMethodKind::Crate,
-      name,
-      Some(version),
-      &Vec::new(),
-    )?;
+/// Read a crate's source code from the `{name}-{version}.crate` archive in the first of
+/// `cache_dirs` holding one, verifying it against `checksum` like downloaded archives.
+fn read_cached_crate_src(
+  cache_dirs: &[PathBuf],
+  dir: &Path,
+  name: &str,
+  version: &str,
+  checksum: Option<&str>,
+) -> Result<PathBuf> {
+  let archive_path = cache_dirs
+    .iter()
+    .map(|cache_dir| cache_dir.join(format!("{}.crate", package_ident(name, version))))
+    .find(|archive_path| archive_path.exists())
+    .ok_or_else(|| {
+      anyhow!(
+        "Crate {}@{} not found in local cache while offline, searched: {}",
+        name,
+        version,
+        cache_dirs.iter().map(|dir| dir.display()).join(", ")
+      )
+    })?;
+  let archive = fs::read(&archive_path)?;
 
-    let crate_dir = dir.join(package_ident(name, version));
-    if !crate_dir.exists() {
-      return Err(anyhow!("Directory does not exist"));
+  unpack_crate_src(
+    &archive,
+    &format!("read from {}", archive_path.display()),
+    dir,
+    name,
+    version,
+    checksum,
+  )
+}
+
+/// Unpack a crate's source code from its `.crate` archive into `dir`. When `checksum` is given,
+/// the archive must have that sha256 digest or no source is extracted. `origin` describes where
+/// the archive came from for error messages.
+fn unpack_crate_src(
+  archive: &[u8],
+  origin: &str,
+  dir: &Path,
+  name: &str,
+  version: &str,
+  checksum: Option<&str>,
+) -> Result<PathBuf> {
+  if let Some(expected) = checksum {
+    let actual = Sha256::digest(archive).to_hex();
+    if actual != expected {
+      return Err(anyhow!(
+        "Checksum mismatch for {} {} {}: expected sha256 {}, found {}",
+        name,
+        version,
+        origin,
+        expected,
+        actual
+      ));
     }
+  }
 
-    Ok(crate_dir)
+  let crate_dir = dir.join(package_ident(name, version));
+  let mut tar = tar::Archive::new(GzDecoder::new(archive));
+  for entry in tar.entries()? {
+    let mut entry = entry?;
+    let entry_path = entry.path()?.into_owned();
+    if !entry_path.starts_with(package_ident(name, version)) {
+      return Err(anyhow!(
+        "Archive of {} {} has an entry outside of its crate directory: {}",
+        name,
+        version,
+        entry_path.display()
+      ));
+    }
+    entry
+      .unpack_in(dir)
+      .with_context(|| format!("Failed to unpack {}", entry_path.display()))?;
   }
 
-  /// Add binary dependencies as workspace members to the given workspace root Cargo.toml file
-  fn inject_binaries_into_workspace(
-    &self,
-    binary_deps: Vec<String>,
-    root_toml: &Path,
-  ) -> Result<()> {
-    // Read the current manifest
-    let mut manifest = {
-      let content = fs::read_to_string(root_toml)?;
-      cargo_toml::Manifest::from_str(content.as_str())?
-    };
+  if !crate_dir.exists() {
+    return Err(anyhow!("Directory does not exist"));
+  }
 
-    // Parse the current `workspace` section of the manifest if one exists
-    let mut workspace = match manifest.workspace {
-      Some(workspace) => workspace,
-      None => cargo_toml::Workspace::default(),
-    };
+  Ok(crate_dir)
+}
 
-    // Add the binary dependencies as workspace members to the `workspace` metadata
-    for dep in binary_deps.iter() {
-      workspace.members.push(dep.to_string());
-    }
+/// A single version of a crate, as listed on a line of the crate's file in a registry index
+#[derive(Debug, Deserialize)]
+pub(crate) struct IndexEntry {
+  pub name: String,
+  pub vers: String,
+  pub cksum: String,
+  #[serde(default)]
+  pub yanked: bool,
+}
 
-    // Replace the workspace metadata with the modified metadata
-    manifest.workspace = Some(workspace);
-
-    // Write the metadata back to disk.
-    // cargo_toml::Manifest cannot be serialized direcly.
-    // see: https://gitlab.com/crates.rs/cargo_toml/-/issues/3
-    let value = toml::Value::try_from(&manifest)?;
-    std::fs::write(root_toml, toml::to_string(&value)?).with_context(|| {
-      format!(
-        "Failed to inject workspace metadata to {}",
-        root_toml.display()
-      )
+/// Parses the line-delimited JSON entries of a crate's file in a registry index. Both git and
+/// sparse indexes share this format.
+pub(crate) fn parse_index_entries(content: &str) -> Result<Vec<IndexEntry>> {
+  content
+    .lines()
+    .filter(|line| !line.trim().is_empty())
+    .map(|line| {
+      serde_json::from_str(line)
+        .with_context(|| format!("Failed to parse registry index entry: {}", line))
     })
+    .collect()
+}
+
+/// Parses a crate's file in cargo's local cache of a registry index, eg:
+/// `~/.cargo/registry/index/index.crates.io-*/.cache/3/l/log`. These start with the version of
+/// the cache format, followed by the version of the index format since cache version 2 and the
+/// revision of the index the file was cached at. Each version of the crate follows as its version
+/// and its index entry. Strings are each terminated by a nul byte.
+pub(crate) fn parse_index_cache_entries(content: &[u8]) -> Result<Vec<IndexEntry>> {
+  let (cache_version, rest) = content
+    .split_first()
+    .ok_or_else(|| anyhow!("The index cache file is empty"))?;
+  let rest = match cache_version {
+    1 => rest,
+    2 | 3 => rest
+      .get(4..)
+      .ok_or_else(|| anyhow!("The index cache file is truncated"))?,
+    _ => return Err(anyhow!("Unsupported index cache version {}", cache_version)),
+  };
+
+  // The first string is the revision of the index
+  let mut strings = rest.split(|byte| *byte == 0).skip(1);
+  let mut entries = Vec::new();
+  while let (Some(_version), Some(entry)) = (strings.next(), strings.next()) {
+    entries.extend(parse_index_entries(std::str::from_utf8(entry)?)?);
   }
+  Ok(entries)
+}
 
-  /// Look up a crate in a specified crate index to determine it's checksum
-  fn fetch_crate_checksum(&self, name: &str, version: &str) -> Result<String> {
-    let index_url_is_file = self.index_url.scheme().to_lowercase() == "file";
-    let crate_index_path = if !index_url_is_file {
-      crates_index::BareIndex::from_url(&self.index_url.to_string())?
-        .open_or_clone()?
-        .crate_(name)
-        .ok_or_else(|| anyhow!("Failed to find crate '{}' in index", name))?
-    } else {
-      crates_index::Index::new(&self.index_url.path())
-        .crate_(name)
-        .ok_or_else(|| anyhow!("Failed to find crate '{}' in index", name))?
-    };
+/// The directories below `parent` which cargo keeps the caches of `index_url` in, eg:
+/// `index.crates.io-1949cf8c6b5b557f`. Cargo names these after the host of the index, and both
+/// the git and sparse index of crates.io are considered the same registry.
+fn cargo_registry_cache_dirs(parent: &Path, index_url: &Url) -> Result<Vec<PathBuf>> {
+  let host = Url::parse(index_url.as_str().trim_start_matches("sparse+"))?
+    .host_str()
+    .unwrap_or_default()
+    .to_owned();
+  let hosts = match host.as_str() {
+    "github.com" | "index.crates.io" => vec!["index.crates.io".to_owned(), "github.com".to_owned()],
+    _ => vec![host],
+  };
+  if !parent.is_dir() {
+    return Ok(Vec::new());
+  }
 
-    let (_index, crate_version) = crate_index_path
-      .versions()
+  let mut dirs = Vec::new();
+  for entry in fs::read_dir(parent)? {
+    let path = entry?.path();
+    let dirname = path
+      .file_name()
+      .and_then(|dirname| dirname.to_str())
+      .unwrap_or_default();
+    if let Some(rank) = hosts
       .iter()
-      .enumerate()
-      .find(|(_, ver)| ver.version() == version)
-      .ok_or_else(|| anyhow!("Failed to find version {} for crate {}", version, name))?;
+      .position(|host| dirname.starts_with(&format!("{}-", host)))
+    {
+      dirs.push((rank, path));
+    }
+  }
+  dirs.sort();
+  Ok(dirs.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Reads the entries of a crate from cargo's cache of `index_url` within `cargo_home`, or `None` if
+/// cargo has not cached the crate's file of the index.
+pub(crate) fn read_cargo_index_cache(
+  cargo_home: &Path,
+  index_url: &Url,
+  name: &str,
+) -> Result<Option<Vec<IndexEntry>>> {
+  let index_dirs =
+    cargo_registry_cache_dirs(&cargo_home.join("registry").join("index"), index_url)?;
+  let cache_path = match index_dirs
+    .iter()
+    .map(|index_dir| index_dir.join(".cache").join(index_entry_path(name)))
+    .find(|cache_path| cache_path.exists())
+  {
+    Some(cache_path) => cache_path,
+    None => return Ok(None),
+  };
 
-    Ok(crate_version.checksum()[..].to_hex())
+  parse_index_cache_entries(&fs::read(&cache_path)?)
+    .map(Some)
+    .with_context(|| format!("Failed to read {}", cache_path.display()))
+}
+
+/// The path of a crate's file relative to the root of a registry index, eg: `3/l/log` or
+/// `se/rd/serde`.
+pub(crate) fn index_entry_path(name: &str) -> String {
+  let name = name.to_lowercase();
+  match name.len() {
+    1 => format!("1/{}", name),
+    2 => format!("2/{}", name),
+    3 => format!("3/{}/{}", &name[..1], name),
+    _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
   }
+}
 
-  /// Ensures a lockfile is generated for a crate on disk
-  ///
-  /// Args:
-  ///   - reused_lockfile: An optional lockfile to use for fetching metadata to
-  ///       ensure subsequent metadata fetches return consistent results.
-  ///   - cargo_dir: The directory of the cargo workspace to gather metadata for.
-  /// Returns:
-  ///   If a new lockfile was generated via the `lockfile_generator`, that
-  ///   Lockfile object is returned. New lockfiles are generated when
-  ///   `reused_lockfile` is not provided.
-  fn cargo_generate_lockfile(
-    &self,
-    reused_lockfile: &Option<PathBuf>,
-    cargo_dir: &Path,
-  ) -> Result<Option<Lockfile>> {
-    let lockfile_path = cargo_dir.join("Cargo.lock");
+/// Whether the given index is read over cargo's sparse HTTP protocol, eg:
+/// `sparse+https://index.crates.io/`
+fn is_sparse_index(index_url: &Url) -> bool {
+  index_url.scheme().starts_with("sparse+")
+}
 
-    // Use the reusable lockfile if one is provided
-    if let Some(reused_lockfile) = reused_lockfile {
-      fs::copy(&reused_lockfile, &lockfile_path)?;
-      return Ok(None);
-    }
+/// Fetches the file at `path` within a sparse index. Files are kept in `cache_dir`, if any, along
+/// with the `ETag` they were served with, so files which have not changed since are revalidated
+/// rather than downloaded again. The cached file is used if the index can't be reached.
+fn fetch_sparse_index_file(
+  client: &HttpClient,
+  index_url: &Url,
+  path: &str,
+  cache_dir: Option<&Path>,
+) -> Result<String> {
+  let url = format!(
+    "{}/{}",
+    index_url
+      .as_str()
+      .trim_start_matches("sparse+")
+      .trim_end_matches('/'),
+    path
+  );
+  let cache_path = cache_dir.map(|cache_dir| sparse_index_cache_path(cache_dir, index_url, path));
+  let cached = cache_path
+    .as_ref()
+    .and_then(|cache_path| fs::read_to_string(cache_path).ok());
+  let etag = cache_path
+    .as_ref()
+    .and_then(|cache_path| fs::read_to_string(etag_path(cache_path)).ok());
 
-    let lockfile = self.lockfile_generator.generate_lockfile(&cargo_dir)?;
+  let mut request = client.get(&url)?;
+  if let (Some(_), Some(etag)) = (&cached, &etag) {
+    request = request.header(reqwest::header::IF_NONE_MATCH, etag.as_str());
+  }
+  let response = match (request.send(), cached) {
+    (Ok(response), Some(cached)) if response.status() == reqwest::StatusCode::NOT_MODIFIED => {
+      return Ok(cached)
+    },
+    (Ok(response), _) => response,
+    (Err(err), Some(cached)) => {
+      eprintln!(
+        "WARNING: Failed to fetch {} from the sparse index, using the cached copy: {}",
+        url, err
+      );
+      return Ok(cached);
+    },
+    (Err(err), None) => {
+      return Err(err).with_context(|| format!("Failed to fetch {} from the sparse index", url))
+    },
+  };
 
-    // Returning the lockfile here signifies that a new lockfile has been created.
-    Ok(Some(lockfile))
+  let response = response
+    .error_for_status()
+    .with_context(|| format!("Failed to fetch {} from the sparse index", url))?;
+  let etag = response
+    .headers()
+    .get(reqwest::header::ETAG)
+    .and_then(|etag| etag.to_str().ok())
+    .map(str::to_owned);
+  let content = response
+    .text()
+    .with_context(|| format!("Failed to fetch {} from the sparse index", url))?;
+
+  if let Some(cache_path) = &cache_path {
+    // Failing to cache a file only costs downloading it again
+    let cached = fs::create_dir_all(cache_path.parent().unwrap_or(cache_path))
+      .and_then(|_| fs::write(cache_path, &content))
+      .and_then(|_| match &etag {
+        Some(etag) => fs::write(etag_path(cache_path), etag),
+        None => fs::remove_file(etag_path(cache_path)).or(Ok(())),
+      });
+    if let Err(err) = cached {
+      log::debug!(
+        "Failed to cache {} in {}: {}",
+        url,
+        cache_path.display(),
+        err
+      );
+    }
   }
+  Ok(content)
+}
 
-  /// Gather all information about a Cargo project to use for planning and rendering steps
-  pub fn fetch_metadata(
-    &self,
-    cargo_workspace_root: &Path,
-    binary_dep_info: Option<&HashMap<String, cargo_toml::Dependency>>,
-    reused_lockfile: Option<PathBuf>,
-  ) -> Result<RazeMetadata> {
-    let (cargo_dir, cargo_workspace_root) = self.make_temp_workspace(cargo_workspace_root)?;
-    let cargo_root_toml = cargo_dir.as_ref().join("Cargo.toml");
+/// The path `fetch_sparse_index_file` caches the file at `path` within a sparse index at, eg:
+/// `{cache_dir}/index-crates-io/3/l/log` for `sparse+https://index.crates.io/`.
+fn sparse_index_cache_path(cache_dir: &Path, index_url: &Url, path: &str) -> PathBuf {
+  let index_dir = slug::slugify(
+    index_url
+      .as_str()
+      .trim_start_matches("sparse+")
+      .split_once("://")
+      .map_or(index_url.as_str(), |(_, rest)| rest),
+  );
+  cache_dir.join(index_dir).join(path)
+}
 
-    // Gather new lockfile data if any binary dependencies were provided
-    let mut checksums: HashMap<String, String> = HashMap::new();
-    if let Some(binary_dep_info) = binary_dep_info {
-      if !binary_dep_info.is_empty() {
-        let mut src_dirnames: Vec<String> = Vec::new();
+fn etag_path(cache_path: &Path) -> PathBuf {
+  let mut etag_path = cache_path.as_os_str().to_owned();
+  etag_path.push(".etag");
+  PathBuf::from(etag_path)
+}
 
-        for (name, info) in binary_dep_info.iter() {
-          let version = info.req();
-          let src_dir = self.fetch_crate_src(cargo_dir.as_ref(), &name, version)?;
-          checksums.insert(
-            package_ident(name, version),
-            self.fetch_crate_checksum(name, version)?,
-          );
-          if let Some(dirname) = src_dir.file_name() {
-            if let Some(dirname_str) = dirname.to_str() {
-              src_dirnames.push(dirname_str.to_string());
-            }
-          }
-        }
+/// The url of the index of a registry package source, for both git (`registry+{url}`) and sparse
+/// (`sparse+{url}`) indexes
+pub(crate) fn source_index_url(source: &str) -> Option<&str> {
+  if source.starts_with("sparse+") {
+    Some(source)
+  } else {
+    source.strip_prefix("registry+")
+  }
+}
 
-        self.inject_binaries_into_workspace(src_dirnames, &cargo_root_toml)?;
+/// The settings of a `RazeMetadataFetcher` which reading crate indexes needs, so that crates can
+/// be looked up on several threads at once.
+#[derive(Clone)]
+struct IndexReader {
+  offline: bool,
+  index_cache_dir: Option<PathBuf>,
+  cargo_home: PathBuf,
+  http_config: HttpConfig,
+  upstream_index_url: Url,
+}
+
+impl IndexReader {
+  /// Look up a crate in a specified crate index to determine it's checksum. Sparse indexes are
+  /// read over HTTP, `file://` indexes from disk and any other index from a clone of its
+  /// repository.
+  fn fetch_crate_checksum(&self, index_url: &Url, name: &str, version: &str) -> Result<String> {
+    let is_local_index = index_url.scheme().to_lowercase() == "file";
+    let cached_sparse_file = || {
+      self.index_cache_dir.as_ref().and_then(|cache_dir| {
+        fs::read_to_string(sparse_index_cache_path(
+          cache_dir,
+          index_url,
+          &index_entry_path(name),
+        ))
+        .ok()
+      })
+    };
+    let entries = if self.offline && !is_local_index {
+      match cached_sparse_file().filter(|_| is_sparse_index(index_url)) {
+        Some(content) => parse_index_entries(&content)?,
+        None => self.read_cargo_cached_index_entries(index_url, name, version)?,
+      }
+    } else if is_sparse_index(index_url) {
+      parse_index_entries(&fetch_sparse_index_file(
+        &HttpClient::new(&self.http_config)?,
+        index_url,
+        &index_entry_path(name),
+        self.index_cache_dir.as_deref(),
+      )?)?
+    } else if is_local_index {
+      let entry_path = Path::new(index_url.path()).join(index_entry_path(name));
+      if self.offline && !entry_path.exists() {
+        return Err(anyhow!(
+          "Crate {}@{} not found in local index {} while offline",
+          name,
+          version,
+          index_url
+        ));
       }
+      parse_index_entries(
+        &fs::read_to_string(entry_path)
+          .with_context(|| format!("Failed to find crate '{}' in index", name))?,
+      )?
+    } else {
+      let crate_index_path = crates_index::BareIndex::from_url(index_url.as_str())?
+        .open_or_clone()?
+        .crate_(name)
+        .ok_or_else(|| anyhow!("Failed to find crate '{}' in index", name))?;
+      let crate_version = crate_index_path
+        .versions()
+        .iter()
+        .find(|ver| ver.version() == version)
+        .ok_or_else(|| anyhow!("Failed to find version {} for crate {}", version, name))?;
+      return Ok(crate_version.checksum()[..].to_hex());
+    };
+
+    let entry = entries
+      .into_iter()
+      .find(|entry| entry.name.eq_ignore_ascii_case(name) && entry.vers == version)
+      .ok_or_else(|| anyhow!("Failed to find version {} for crate {}", version, name))?;
+    if entry.yanked {
+      eprintln!(
+        "WARNING: Version {} of crate {} has been yanked",
+        version, name
+      );
     }
+    Ok(entry.cksum)
+  }
 
-    let output_lockfile = self.cargo_generate_lockfile(&reused_lockfile, cargo_dir.as_ref())?;
+  /// Reads the entries of a crate from cargo's own cache of `index_url`, which is how indexes
+  /// other than local `file://` ones are read while offline.
+  fn read_cargo_cached_index_entries(
+    &self,
+    index_url: &Url,
+    name: &str,
+    version: &str,
+  ) -> Result<Vec<IndexEntry>> {
+    read_cargo_index_cache(&self.cargo_home, index_url, name)?.ok_or_else(|| {
+      anyhow!(
+        "Crate {}@{} not found in cargo's cache of the index {} below {} while offline",
+        name,
+        version,
+        index_url,
+        self.cargo_home.join("registry").join("index").display()
+      )
+    })
+  }
 
-    // Load checksums from the lockfile
-    let workspace_toml_lock = cargo_dir.as_ref().join("Cargo.lock");
-    if workspace_toml_lock.exists() {
-      let lockfile = Lockfile::load(workspace_toml_lock)?;
-      for package in &lockfile.packages {
-        if let Some(checksum) = &package.checksum {
-          checksums.insert(
-            package_ident(&package.name.to_string(), &package.version.to_string()),
-            checksum.to_string(),
-          );
-        }
-      }
+  /// Ensure the `checksum` which `origin` lists for a crate is the one crates.io's own index lists.
+  fn verify_upstream_checksum(
+    &self,
+    name: &str,
+    version: &str,
+    checksum: &str,
+    origin: &str,
+  ) -> Result<()> {
+    let upstream_checksum = self.fetch_crate_checksum(&self.upstream_index_url, name, version)?;
+    if upstream_checksum != checksum {
+      return Err(anyhow!(
+        "Checksum mismatch for {} {}: {} lists sha256 {}, but the upstream index {} lists {}",
+        name,
+        version,
+        origin,
+        checksum,
+        self.upstream_index_url,
+        upstream_checksum
+      ));
     }
+    Ok(())
+  }
 
-    let metadata = self
-      .metadata_fetcher
-      .fetch_metadata(cargo_dir.as_ref(), /*include_deps=*/ true)?;
+  /// Clone `index_url` ahead of looking crates up in it, if it is read from a clone of its
+  /// repository, so that concurrent lookups only ever open the clone.
+  fn clone_git_index(&self, index_url: &Url) -> Result<()> {
+    let is_git_index = index_url.scheme().to_lowercase() != "file" && !is_sparse_index(index_url);
+    if !self.offline && is_git_index {
+      crates_index::BareIndex::from_url(index_url.as_str())?.open_or_clone()?;
+    }
+    Ok(())
+  }
+}
 
-    Ok(RazeMetadata {
-      metadata,
-      checksums,
-      cargo_workspace_root,
-      lockfile: output_lockfile,
+/// The crate directory a crate was downloaded into, along with a message for each mirror its
+/// download failed from
+type DownloadedCrate = (PathBuf, Vec<String>);
+
+/// Runs `work` on each of `items` on a pool of up to `max_workers` threads, returning its result
+/// for each item in the order of `items`, or `None` for the items no worker got to.
+fn run_on_worker_pool<T, R, F>(max_workers: usize, items: Vec<T>, work: F) -> Result<Vec<Option<R>>>
+where
+  T: Send + Sync + 'static,
+  R: Send + 'static,
+  F: Fn(&T) -> R + Send + Sync + 'static,
+{
+  let item_count = items.len();
+  let items = Arc::new(items);
+  let work = Arc::new(work);
+  let pending = Arc::new(Mutex::new(0..item_count));
+  let (sender, receiver) = mpsc::channel();
+
+  let workers: Vec<_> = (0..max_workers.min(item_count))
+    .map(|_| {
+      let (items, work, pending, sender) =
+        (items.clone(), work.clone(), pending.clone(), sender.clone());
+      thread::spawn(move || loop {
+        // UNWRAP: Workers never panic while holding the lock
+        let idx = match pending.lock().unwrap().next() {
+          Some(idx) => idx,
+          None => break,
+        };
+        if sender.send((idx, work(&items[idx]))).is_err() {
+          break;
+        }
+      })
     })
+    .collect();
+  drop(sender);
+
+  // Results arrive in order of completion, so they are slotted back into the order of `items`
+  let mut results: Vec<Option<R>> = (0..item_count).map(|_| None).collect();
+  for (idx, result) in receiver {
+    results[idx] = Some(result);
   }
+  for worker in workers {
+    worker
+      .join()
+      .map_err(|_| anyhow!("A worker thread panicked"))?;
+  }
+
+  Ok(results)
 }
 
-impl Default for RazeMetadataFetcher {
-  fn default() -> RazeMetadataFetcher {
-    RazeMetadataFetcher::new(
-      cargo_bin_path(),
-      // UNWRAP: The default is covered by testing and should never return err
-      Url::parse(DEFAULT_CRATE_REGISTRY_URL).unwrap(),
-      Url::parse(DEFAULT_CRATE_INDEX_URL).unwrap(),
-    )
-  }
+/// Runs the `job` of a download worker on the crate `name` `version`, turning a panic into an
+/// error so the crate still gets an outcome and the worker lives on to take the next job.
+fn catch_job_panic<R>(name: &str, version: &str, job: impl FnOnce() -> Result<R>) -> Result<R> {
+  panic::catch_unwind(AssertUnwindSafe(job))
+    .unwrap_or_else(|_| Err(anyhow!("Panicked while fetching {} {}", name, version)))
 }
 
-/// A struct containing information about a binary dependency
-pub struct BinaryDependencyInfo {
-  pub name: String,
-  pub info: cargo_toml::Dependency,
-  pub lockfile: Option<PathBuf>,
+/// A workspace metadata fetcher that uses the Cargo commands to gather information about a Cargo
+/// project and it's transitive dependencies for planning and rendering of Bazel BUILD files.
+pub struct RazeMetadataFetcher {
+  cargo_bin_path: PathBuf,
+  cargo_target_dir: Option<PathBuf>,
+  features: Vec<String>,
+  all_features: bool,
+  metadata_timeout: Option<Duration>,
+  registry_url: Url,
+  download_mirrors: Vec<Url>,
+  git_fallback: bool,
+  index_url: Url,
+  verify_download_checksums: bool,
+  verify_against_upstream_index: bool,
+  upstream_index_url: Url,
+  max_concurrent_downloads: usize,
+  offline: bool,
+  minimal_versions: bool,
+  crate_cache_dir: Option<PathBuf>,
+  index_cache_dir: Option<PathBuf>,
+  cargo_home: PathBuf,
+  http_config: HttpConfig,
+  metadata_cache_dir: Option<PathBuf>,
+  metadata_fetcher: Box<dyn MetadataFetcher>,
+  // Whether `metadata_fetcher` was set with `set_metadata_fetcher` rather than built from the
+  // options of this fetcher
+  custom_metadata_fetcher: bool,
+  lockfile_generator: Box<dyn LockfileGenerator>,
+  // Whether `lockfile_generator` was set with `set_lockfile_generator`
+  custom_lockfile_generator: bool,
 }
 
-#[cfg(test)]
-pub mod tests {
-  use anyhow::Context;
-  use httpmock::MockServer;
-  use tera::Tera;
+impl RazeMetadataFetcher {
+  pub fn new<P: Into<PathBuf>>(
+    cargo_bin_path: P,
+    registry_url: Url,
+    index_url: Url,
+  ) -> RazeMetadataFetcher {
+    let mut fetcher = RazeMetadataFetcher {
+      cargo_bin_path: cargo_bin_path.into(),
+      cargo_target_dir: None,
+      features: Vec::new(),
+      all_features: false,
+      metadata_timeout: None,
+      registry_url,
+      download_mirrors: Vec::new(),
+      git_fallback: false,
+      index_url,
+      verify_download_checksums: true,
+      verify_against_upstream_index: false,
+      // UNWRAP: The default is covered by testing and should never return err
+      upstream_index_url: Url::parse(DEFAULT_CRATE_SPARSE_INDEX_URL).unwrap(),
+      max_concurrent_downloads: DEFAULT_MAX_CONCURRENT_DOWNLOADS,
+      offline: false,
+      minimal_versions: false,
+      crate_cache_dir: None,
+      index_cache_dir: None,
+      cargo_home: default_cargo_home(),
+      http_config: HttpConfig::default(),
+      metadata_cache_dir: None,
+      metadata_fetcher: Box::new(CargoMetadataFetcher::default()),
+      custom_metadata_fetcher: false,
+      lockfile_generator: Box::new(CargoLockfileGenerator::default()),
+      custom_lockfile_generator: false,
+    };
+    fetcher.rebuild_cargo_fetcher();
+    fetcher
+  }
 
-  use super::*;
-  use crate::testing::*;
+  /// Rebuilds the [`crate::metadata::MetadataFetcher`] and [`crate::metadata::LockfileGenerator`]
+  /// which run `cargo` from the current options, keeping any set in their place.
+  fn rebuild_cargo_fetcher(&mut self) {
+    if !self.custom_metadata_fetcher {
+      self.metadata_fetcher = Box::new(CargoMetadataFetcher {
+        cargo_bin_path: self.cargo_bin_path.clone(),
+        cargo_target_dir: self.cargo_target_dir.clone(),
+        features: self.features.clone(),
+        all_features: self.all_features,
+        offline: self.offline,
+        metadata_timeout: self.metadata_timeout,
+      });
+    }
+    if !self.custom_lockfile_generator {
+      self.lockfile_generator = Box::new(CargoLockfileGenerator {
+        cargo_bin_path: self.cargo_bin_path.clone(),
+        cargo_target_dir: self.cargo_target_dir.clone(),
+        offline: self.offline,
+        minimal_versions: self.minimal_versions,
+      });
+    }
+  }
+
+  /// Direct the `cargo` invocations of the Raze Metadata Fetcher to `target_dir` so they never
+  /// touch the workspace's own `target/` directory.
+  pub fn set_cargo_target_dir<P: Into<PathBuf>>(&mut self, target_dir: P) {
+    self.cargo_target_dir = Some(target_dir.into());
+    self.rebuild_cargo_fetcher();
+  }
+
+  /// Resolve the workspace with `features` enabled. Entries in the form `member/feature` are
+  /// only enabled on the named workspace member.
+  pub fn set_features(&mut self, features: Vec<String>) {
+    self.features = features;
+    self.rebuild_cargo_fetcher();
+  }
+
+  /// Resolve the workspace with every feature of every workspace member enabled, as with
+  /// `cargo build --all-features`.
+  pub fn set_all_features(&mut self, all_features: bool) {
+    self.all_features = all_features;
+    self.rebuild_cargo_fetcher();
+  }
+
+  /// Abort `cargo metadata` if it runs for longer than `timeout`.
+  pub fn set_metadata_timeout(&mut self, timeout: Duration) {
+    self.metadata_timeout = Some(timeout);
+    self.rebuild_cargo_fetcher();
+  }
+
+  /// Never access the network: crate indexes are read from local `file://` indexes or else
+  /// cargo's own cache of them, binary dependencies are read from the `crate_cache_dir` or else the
+  /// registry cache of cargo, and `cargo` runs with `--offline`.
+  pub fn set_offline(&mut self, offline: bool) {
+    self.offline = offline;
+    self.rebuild_cargo_fetcher();
+  }
+
+  /// Resolve every dependency to the lowest version its requirements allow, as with cargo's
+  /// `-Z minimal-versions`, to catch requirements which are lower than what a crate needs.
+  pub fn set_minimal_versions(&mut self, minimal_versions: bool) {
+    self.minimal_versions = minimal_versions;
+    self.rebuild_cargo_fetcher();
+  }
+
+  /// Read the archives of binary dependencies from `cache_dir` while offline. Archives are named
+  /// `{name}-{version}.crate`, as in cargo's own `registry/cache` directories.
+  pub fn set_crate_cache_dir<P: Into<PathBuf>>(&mut self, cache_dir: P) {
+    self.crate_cache_dir = Some(cache_dir.into());
+  }
+
+  /// Keep the files fetched from sparse indexes in `cache_dir`, so unchanged files are not
+  /// downloaded again and can be read while offline.
+  pub fn set_index_cache_dir<P: Into<PathBuf>>(&mut self, cache_dir: P) {
+    self.index_cache_dir = Some(cache_dir.into());
+  }
+
+  /// Read cargo's caches of crate archives and registry indexes from below `cargo_home` while
+  /// offline. This defaults to `$CARGO_HOME`, or else `~/.cargo`.
+  pub fn set_cargo_home<P: Into<PathBuf>>(&mut self, cargo_home: P) {
+    self.cargo_home = cargo_home.into();
+  }
+
+  /// Make every request to registries and indexes with a client configured by `http_config`,
+  /// eg: to go through a proxy.
+  pub fn set_http_config(&mut self, http_config: HttpConfig) {
+    self.http_config = http_config;
+  }
+
+  /// Check downloaded binary dependency archives against the checksums of the crate index. This
+  /// is enabled by default.
+  pub fn set_verify_download_checksums(&mut self, verify: bool) {
+    self.verify_download_checksums = verify;
+  }
+
+  /// Cross-check the checksums of crates.io crates listed by the lockfile and the crate index
+  /// against crates.io's own index, so a tampered mirror is caught even if the lockfile was also
+  /// altered. This is disabled by default.
+  pub fn set_verify_against_upstream_index(&mut self, verify: bool) {
+    self.verify_against_upstream_index = verify;
+  }
+
+  /// Run up to `max_concurrent_downloads` binary dependency downloads, and crate index lookups, at
+  /// a time.
+  pub fn set_max_concurrent_downloads(&mut self, max_concurrent_downloads: usize) {
+    self.max_concurrent_downloads = max_concurrent_downloads.max(1);
+  }
+
+  /// Fall back to downloading binary dependencies from each of `mirrors`, in order, whenever the
+  /// download from the registry fails or does not match its checksum.
+  pub fn set_download_mirrors(&mut self, mirrors: Vec<Url>) {
+    self.download_mirrors = mirrors;
+  }
+
+  /// Clone binary dependencies which can't be downloaded from the registry or any of the download
+  /// mirrors from the repository the registry lists for them, at the tag of their version.
+  pub fn set_git_fallback(&mut self, git_fallback: bool) {
+    self.git_fallback = git_fallback;
+  }
+
+  /// Reassign the [`crate::metadata::MetadataFetcher`] associated with the Raze Metadata Fetcher.
+  /// Options set afterwards no longer replace it with one which runs `cargo`.
+  pub fn set_metadata_fetcher(&mut self, fetcher: Box<dyn MetadataFetcher>) {
+    self.metadata_fetcher = fetcher;
+    self.custom_metadata_fetcher = true;
+  }
+
+  /// Cache the results of the current [`crate::metadata::MetadataFetcher`] in `cache_dir`
+  pub fn set_metadata_cache_dir<P: Into<PathBuf>>(&mut self, cache_dir: P) {
+    self.metadata_cache_dir = Some(cache_dir.into());
+  }
+
+  /// Reassign the [`crate::metadata::LockfileGenerator`] associated with the current Fetcher.
+  /// Options set afterwards no longer replace it with one which runs `cargo`.
+  pub fn set_lockfile_generator(&mut self, generator: Box<dyn LockfileGenerator>) {
+    self.lockfile_generator = generator;
+    self.custom_lockfile_generator = true;
+  }
+
+  /// Fetches the metadata of the workspace in `working_dir` with the current
+  /// [`crate::metadata::MetadataFetcher`], through the metadata cache when one is set.
+  fn fetch_workspace_metadata(&self, working_dir: &Path, include_deps: bool) -> Result<Metadata> {
+    match &self.metadata_cache_dir {
+      Some(cache_dir) => CachingMetadataFetcher {
+        cache_dir: cache_dir.clone(),
+        fetcher: self.metadata_fetcher.as_ref(),
+        features: self.features.clone(),
+        all_features: self.all_features,
+        offline: self.offline,
+        minimal_versions: self.minimal_versions,
+      }
+      .fetch_metadata(working_dir, include_deps),
+      None => self
+        .metadata_fetcher
+        .fetch_metadata(working_dir, include_deps),
+    }
+  }
+
+  /// Symlinks the source code of all workspace members into the temp workspace
+  fn link_src_to_workspace(&self, no_deps_metadata: &Metadata, temp_dir: &Path) -> Result<()> {
+    // Package ids are either of the form `name version (path+file://path)` or, since Cargo 1.77,
+    // `path+file://path#name@version`
+    let crate_member_id_re =
+      Regex::new(r"^(?:.+\(path\+file://(.+)\)|path\+file://([^#]+)(?:#.*)?)$")?;
+    for member in no_deps_metadata.workspace_members.iter() {
+      // Get a path to the workspace member directory
+      let workspace_member_directory = {
+        let crate_member_id_match = crate_member_id_re
+          .captures(&member.repr)
+          .and_then(|cap| cap.get(1).or_else(|| cap.get(2)));
+
+        if crate_member_id_match.is_none() {
+          continue;
+        }
+
+        // UNWRAP: guarded above
+        PathBuf::from(crate_member_id_match.unwrap().as_str())
+      };
+
+      // Sanity check: The assumption is that any crate with an `id` that matches
+      // the regex pattern above should contain a Cargo.toml file with which we
+      // can use to infer the existence of libraries from relative paths such as
+      // `src/lib.rs` and `src/main.rs`.
+      let toml_path = workspace_member_directory.join("Cargo.toml");
+      if !toml_path.exists() {
+        return Err(anyhow!(format!(
+          "The regex pattern `{}` found a path that did not contain a Cargo.toml file: `{}`",
+          crate_member_id_re.as_str(),
+          workspace_member_directory.display()
+        )));
+      }
+
+      // Copy the Cargo.toml files into the temp directory to match the directory structure on disk
+      let diff = diff_paths(
+        &workspace_member_directory,
+        &no_deps_metadata.workspace_root,
+      )
+      .ok_or_else(|| {
+        anyhow!("All workspace memebers are expected to be under the workspace root")
+      })?;
+      let new_path = temp_dir.join(diff);
+      fs::create_dir_all(&new_path)?;
+      fs::copy(
+        workspace_member_directory.join("Cargo.toml"),
+        new_path.join("Cargo.toml"),
+      )?;
+      absolutize_external_path_dependencies(
+        &new_path.join("Cargo.toml"),
+        &workspace_member_directory,
+        &no_deps_metadata.workspace_root,
+      )?;
+
+      // Additionally, symlink everything in some common source directories to ensure specified
+      // library targets can be relied on and won't prevent fetching metadata
+      for dir in vec!["bin", "src"].iter() {
+        let glob_pattern = format!("{}/**/*.rs", workspace_member_directory.join(dir).display());
+        for entry in glob(glob_pattern.as_str()).expect("Failed to read glob pattern") {
+          let path = entry?;
+
+          // Determine the difference between the workspace root and the current file
+          let diff = diff_paths(&path, &no_deps_metadata.workspace_root).ok_or_else(|| {
+            anyhow!("All workspace memebers are expected to be under the workspace root")
+          })?;
+
+          // Create a matching directory tree for the current file within the temp workspace
+          let new_path = temp_dir.join(diff);
+          if let Some(parent) = new_path.parent() {
+            fs::create_dir_all(parent)?;
+          }
+
+          make_symlink(&path, &new_path)?;
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Creates a copy workspace in a temporary directory for fetching the metadata of the current workspace
+  fn make_temp_workspace(&self, cargo_workspace_root: &Path) -> Result<(TempDir, PathBuf)> {
+    let temp_dir = TempDir::new()?;
+
+    // First gather metadata without downloading any dependencies so we can identify any path dependencies.
+    let no_deps_metadata =
+      self.fetch_workspace_metadata(cargo_workspace_root, /*include_deps=*/ false)?;
+
+    // There should be a `Cargo.toml` file in the workspace root
+    fs::copy(
+      no_deps_metadata.workspace_root.join("Cargo.toml"),
+      temp_dir.as_ref().join("Cargo.toml"),
+    )?;
+    absolutize_external_path_dependencies(
+      &temp_dir.as_ref().join("Cargo.toml"),
+      &no_deps_metadata.workspace_root,
+      &no_deps_metadata.workspace_root,
+    )?;
+
+    // Optionally copy over the lock file
+    if no_deps_metadata.workspace_root.join("Cargo.lock").exists() {
+      fs::copy(
+        no_deps_metadata.workspace_root.join("Cargo.lock"),
+        temp_dir.as_ref().join("Cargo.lock"),
+      )?;
+    }
+
+    // Copy over the Cargo.toml files of each workspace member
+    self.link_src_to_workspace(&no_deps_metadata, temp_dir.as_ref())?;
+
+    Ok((temp_dir, no_deps_metadata.workspace_root))
+  }
+
+  /// The urls of the current registry api followed by those of the download mirrors, in the order
+  /// downloads are attempted from.
+  fn registry_api_urls(&self) -> Vec<String> {
+    std::iter::once(&self.registry_url)
+      .chain(self.download_mirrors.iter())
+      .map(registry_api_url)
+      .collect()
+  }
+
+  /// Download the source code of each `(name, version, checksum)` of `crates` from the current
+  /// registry url, or else from the download mirrors, or read it from the `crate_cache_dir` while
+  /// offline. With `git_fallback`, crates which fail to download are cloned from their repository
+  /// instead. The crate directories are returned in the order of `crates`, along with the
+  /// repositories crates were cloned from, and every failed download is reported.
+  fn fetch_crate_srcs(
+    &self,
+    dir: &Path,
+    crates: Vec<(String, String, Option<String>)>,
+  ) -> Result<(Vec<PathBuf>, HashMap<String, GitRepo>)> {
+    let results = if self.offline {
+      let mut cache_dirs: Vec<PathBuf> = self.crate_cache_dir.iter().cloned().collect();
+      cache_dirs.extend(cargo_registry_cache_dirs(
+        &self.cargo_home.join("registry").join("cache"),
+        &self.index_url,
+      )?);
+      crates
+        .iter()
+        .map(|(name, version, checksum)| {
+          Some(
+            read_cached_crate_src(&cache_dirs, dir, name, version, checksum.as_deref())
+              .map(|crate_dir| (crate_dir, Vec::new())),
+          )
+        })
+        .collect()
+    } else {
+      self.download_crate_srcs(dir, crates.clone())?
+    };
+
+    let mut crate_dirs = Vec::new();
+    let mut git_fallbacks = HashMap::new();
+    let mut errors = Vec::new();
+    for (idx, result) in results.into_iter().enumerate() {
+      let (name, version, _) = &crates[idx];
+      match result {
+        Some(Err(err)) if self.git_fallback && !self.offline => {
+          match self.clone_crate_src(dir, name, version) {
+            Ok((crate_dir, git_repo)) => {
+              eprintln!(
+                "WARNING: Failed to download {} {}, so it was cloned from {} at commit {} \
+                 instead, without checking its checksum: {:#}",
+                name, version, git_repo.remote, git_repo.commit, err
+              );
+              git_fallbacks.insert(package_ident(name, version), git_repo);
+              crate_dirs.push(crate_dir);
+            },
+            Err(clone_err) => errors.push((
+              package_ident(name, version),
+              anyhow!("{:#}\nFalling back to git failed too: {:#}", err, clone_err),
+            )),
+          }
+        },
+        Some(Ok((crate_dir, failures))) => {
+          for failure in failures {
+            eprintln!(
+              "WARNING: Failed to download {} {} from {}, a later mirror was used instead",
+              name, version, failure
+            );
+          }
+          crate_dirs.push(crate_dir)
+        },
+        Some(Err(err)) => errors.push((package_ident(name, version), err)),
+        None => errors.push((
+          package_ident(name, version),
+          anyhow!("Download never finished"),
+        )),
+      }
+    }
+
+    match errors.len() {
+      0 => Ok((crate_dirs, git_fallbacks)),
+      1 => Err(errors.remove(0).1),
+      _ => Err(anyhow!(
+        "Failed to download {} crates:\n{}",
+        errors.len(),
+        errors
+          .iter()
+          .map(|(ident, err)| format!("  {}: {:#}", ident, err))
+          .join("\n")
+      )),
+    }
+  }
+
+  /// Download each of `crates` on a pool of `max_concurrent_downloads` workers, which hand the
+  /// archives over to a pool of one hashing worker per cpu to verify and extract them. A crate
+  /// whose download or checksum fails is downloaded again from the next mirror, if any.
+  ///
+  /// Yields the crate directory of each of `crates`, in their order, along with a message for each
+  /// mirror its download failed from.
+  fn download_crate_srcs(
+    &self,
+    dir: &Path,
+    crates: Vec<(String, String, Option<String>)>,
+  ) -> Result<Vec<Option<Result<DownloadedCrate>>>> {
+    let crate_count = crates.len();
+    if crate_count == 0 {
+      return Ok(Vec::new());
+    }
+    let client = HttpClient::new(&self.http_config)?;
+    let registry_urls = Arc::new(self.registry_api_urls());
+    let crates = Arc::new(crates);
+
+    // Downloads are queued by the index of their crate and that of the mirror to try. Only this
+    // thread queues them, so both pools stop once it is done with the queue.
+    let (download_sender, download_receiver) = mpsc::channel::<(usize, usize)>();
+    let download_receiver = Arc::new(Mutex::new(download_receiver));
+    let (archive_sender, archive_receiver) = mpsc::channel::<(usize, usize, Vec<u8>, String)>();
+    let archive_receiver = Arc::new(Mutex::new(archive_receiver));
+    let (outcome_sender, outcome_receiver) = mpsc::channel::<(usize, usize, Result<PathBuf>)>();
+
+    let mut workers = Vec::new();
+    for _ in 0..self.max_concurrent_downloads.min(crate_count) {
+      let (client, registry_urls, crates) = (client.clone(), registry_urls.clone(), crates.clone());
+      let (download_receiver, archive_sender, outcome_sender) = (
+        download_receiver.clone(),
+        archive_sender.clone(),
+        outcome_sender.clone(),
+      );
+      workers.push(thread::spawn(move || loop {
+        // UNWRAP: Workers never panic while holding the lock
+        let (idx, mirror) = match download_receiver.lock().unwrap().recv() {
+          Ok(download) => download,
+          Err(_) => break,
+        };
+        let (name, version, _) = &crates[idx];
+        let downloaded = catch_job_panic(name, version, || {
+          download_crate_archive(&client, &registry_urls[mirror], name, version)
+        });
+        let sent = match downloaded {
+          Ok((archive, dl_url)) => archive_sender.send((idx, mirror, archive, dl_url)).is_ok(),
+          Err(err) => outcome_sender.send((idx, mirror, Err(err))).is_ok(),
+        };
+        if !sent {
+          break;
+        }
+      }));
+    }
+    drop(archive_sender);
+
+    let cpu_count = thread::available_parallelism().map_or(1, |count| count.get());
+    for _ in 0..cpu_count.min(crate_count) {
+      let (crates, dir) = (crates.clone(), dir.to_owned());
+      let (archive_receiver, outcome_sender) = (archive_receiver.clone(), outcome_sender.clone());
+      workers.push(thread::spawn(move || loop {
+        // UNWRAP: Workers never panic while holding the lock
+        let (idx, mirror, archive, dl_url) = match archive_receiver.lock().unwrap().recv() {
+          Ok(downloaded) => downloaded,
+          Err(_) => break,
+        };
+        let (name, version, checksum) = &crates[idx];
+        let result = catch_job_panic(name, version, || {
+          unpack_crate_src(
+            &archive,
+            &format!("downloaded from {}", dl_url),
+            &dir,
+            name,
+            version,
+            checksum.as_deref(),
+          )
+        });
+        if outcome_sender.send((idx, mirror, result)).is_err() {
+          break;
+        }
+      }));
+    }
+    drop(outcome_sender);
+
+    for idx in 0..crate_count {
+      download_sender
+        .send((idx, 0))
+        .map_err(|_| anyhow!("Every crate download worker stopped"))?;
+    }
+    let mut failures: Vec<Vec<String>> = vec![Vec::new(); crate_count];
+    let mut results: Vec<Option<Result<DownloadedCrate>>> =
+      (0..crate_count).map(|_| None).collect();
+    let mut remaining = crate_count;
+    while remaining > 0 {
+      // Every worker stopping early leaves the remaining crates without a result
+      let (idx, mirror, result) = match outcome_receiver.recv() {
+        Ok(outcome) => outcome,
+        Err(_) => break,
+      };
+      let (name, version, _) = &crates[idx];
+      let settled = match result {
+        Ok(crate_dir) => Ok((crate_dir, std::mem::take(&mut failures[idx]))),
+        // Without mirrors to fall back to, the error is reported as is
+        Err(err) if registry_urls.len() == 1 => Err(err),
+        Err(err) => {
+          failures[idx].push(format!("{}: {:#}", registry_urls[mirror], err));
+          if mirror + 1 < registry_urls.len() {
+            download_sender
+              .send((idx, mirror + 1))
+              .map_err(|_| anyhow!("Every crate download worker stopped"))?;
+            continue;
+          }
+          Err(anyhow!(
+            "Failed to download {} {} from any of {} mirrors:\n  {}",
+            name,
+            version,
+            failures[idx].len(),
+            failures[idx].join("\n  ")
+          ))
+        },
+      };
+      results[idx] = Some(settled);
+      remaining -= 1;
+    }
+    drop(download_sender);
+
+    for worker in workers {
+      worker
+        .join()
+        .map_err(|_| anyhow!("A crate download worker panicked"))?;
+    }
+
+    Ok(results)
+  }
+
+  /// Clones the crate `name` `version` into `dir` from the repository the registry lists for it
+  fn clone_crate_src(&self, dir: &Path, name: &str, version: &str) -> Result<(PathBuf, GitRepo)> {
+    let client = HttpClient::new(&self.http_config)?;
+    let repository = fetch_crate_repository(&client, &self.registry_api_urls(), name)?;
+    clone_crate_src(&repository, dir, name, version)
+  }
+
+  /// What reading crate indexes needs of the settings of this fetcher
+  fn index_reader(&self) -> IndexReader {
+    IndexReader {
+      offline: self.offline,
+      index_cache_dir: self.index_cache_dir.clone(),
+      cargo_home: self.cargo_home.clone(),
+      http_config: self.http_config.clone(),
+      upstream_index_url: self.upstream_index_url.clone(),
+    }
+  }
+
+  /// Add binary dependencies as workspace members to the given workspace root Cargo.toml file
+  fn inject_binaries_into_workspace(
+    &self,
+    binary_deps: Vec<String>,
+    root_toml: &Path,
+  ) -> Result<()> {
+    // Read the current manifest
+    let mut manifest = {
+      let content = fs::read_to_string(root_toml)?;
+      cargo_toml::Manifest::from_str(content.as_str())?
+    };
+
+    // Parse the current `workspace` section of the manifest if one exists
+    let mut workspace = match manifest.workspace {
+      Some(workspace) => workspace,
+      None => cargo_toml::Workspace::default(),
+    };
+
+    // Add the binary dependencies as workspace members to the `workspace` metadata
+    for dep in binary_deps.iter() {
+      workspace.members.push(dep.to_string());
+    }
+
+    // Replace the workspace metadata with the modified metadata
+    manifest.workspace = Some(workspace);
+
+    // Write the metadata back to disk.
+    // cargo_toml::Manifest cannot be serialized direcly.
+    // see: https://gitlab.com/crates.rs/cargo_toml/-/issues/3
+    let value = toml::Value::try_from(&manifest)?;
+    std::fs::write(root_toml, toml::to_string(&value)?).with_context(|| {
+      format!(
+        "Failed to inject workspace metadata to {}",
+        root_toml.display()
+      )
+    })
+  }
+
+  /// Ensure reading `index_url` requires no network access while offline.
+  fn ensure_local_index(&self, index_url: &Url) -> Result<()> {
+    if self.offline && index_url.scheme().to_lowercase() != "file" {
+      return Err(anyhow!(
+        "The crate index {} can't be read while offline, only local `file://` indexes can",
+        index_url
+      ));
+    }
+    Ok(())
+  }
+
+  /// Reads the `dl` endpoint from the `config.json` of a registry index, either over HTTP for
+  /// sparse indexes, from disk for `file://` indexes or from a shallow clone of the index
+  /// repository otherwise.
+  fn fetch_registry_dl(&self, index_url: &Url) -> Result<String> {
+    let cached_config = self
+      .index_cache_dir
+      .as_ref()
+      .filter(|_| self.offline && is_sparse_index(index_url))
+      .and_then(|cache_dir| {
+        fs::read_to_string(sparse_index_cache_path(cache_dir, index_url, "config.json")).ok()
+      });
+    if cached_config.is_none() {
+      self.ensure_local_index(index_url)?;
+    }
+    let config = if let Some(cached_config) = cached_config {
+      cached_config
+    } else if is_sparse_index(index_url) {
+      fetch_sparse_index_file(
+        &HttpClient::new(&self.http_config)?,
+        index_url,
+        "config.json",
+        self.index_cache_dir.as_deref(),
+      )?
+    } else if index_url.scheme().to_lowercase() == "file" {
+      fs::read_to_string(Path::new(index_url.path()).join("config.json"))?
+    } else {
+      let clone_dir = TempDir::new()?;
+      let clone_output = std::process::Command::new("git")
+        .args([
+          "clone",
+          "--quiet",
+          "--bare",
+          "--depth=1",
+          index_url.as_str(),
+        ])
+        .arg(clone_dir.as_ref())
+        .output()?;
+      if !clone_output.status.success() {
+        return Err(anyhow!(
+          "Failed to clone registry index {}: {}",
+          index_url,
+          String::from_utf8_lossy(&clone_output.stderr)
+        ));
+      }
+      let show_output = std::process::Command::new("git")
+        .arg("--git-dir")
+        .arg(clone_dir.as_ref())
+        .args(["show", "HEAD:config.json"])
+        .output()?;
+      if !show_output.status.success() {
+        return Err(anyhow!(
+          "Failed to read the config.json of registry index {}: {}",
+          index_url,
+          String::from_utf8_lossy(&show_output.stderr)
+        ));
+      }
+      String::from_utf8(show_output.stdout)?
+    };
+
+    serde_json::from_str::<serde_json::Value>(&config)?
+      .get("dl")
+      .and_then(|dl| dl.as_str())
+      .map(str::to_owned)
+      .ok_or_else(|| {
+        anyhow!(
+          "The config.json of registry index {} has no `dl`",
+          index_url
+        )
+      })
+  }
+
+  /// Determines the download urls of crates from registries other than the default one, based on
+  /// the `dl` endpoint of each of those registries.
+  fn collect_download_urls(
+    &self,
+    metadata: &Metadata,
+    checksums: &HashMap<String, String>,
+  ) -> Result<HashMap<String, String>> {
+    let default_index_urls = [
+      DEFAULT_CRATE_INDEX_URL,
+      DEFAULT_CRATE_SPARSE_INDEX_URL,
+      self.index_url.as_str(),
+    ];
+
+    let mut dl_endpoints: HashMap<&str, String> = HashMap::new();
+    let mut download_urls = HashMap::new();
+    for package in metadata.packages.iter() {
+      let index_url = match package
+        .source
+        .as_ref()
+        .and_then(|source| source_index_url(&source.repr))
+      {
+        Some(index_url) => index_url,
+        None => continue,
+      };
+      if default_index_urls
+        .iter()
+        .any(|default| default.trim_end_matches('/') == index_url.trim_end_matches('/'))
+      {
+        continue;
+      }
+
+      if !dl_endpoints.contains_key(index_url) {
+        let dl = self
+          .fetch_registry_dl(&Url::parse(index_url)?)
+          .with_context(|| format!("Failed to find the download url of {}", package.id))?;
+        dl_endpoints.insert(index_url, dl);
+      }
+
+      let ident = package_ident(&package.name, &package.version.to_string());
+      let download_url = registry_download_url(
+        &dl_endpoints[index_url],
+        &package.name,
+        &package.version.to_string(),
+        checksums.get(&ident),
+      );
+      download_urls.insert(ident, download_url);
+    }
+
+    Ok(download_urls)
+  }
+
+  /// Ensures a lockfile is generated for a crate on disk
+  ///
+  /// Args:
+  ///   - reused_lockfile: An optional lockfile to use for fetching metadata to
+  ///       ensure subsequent metadata fetches return consistent results.
+  ///   - cargo_dir: The directory of the cargo workspace to gather metadata for.
+  /// Returns:
+  ///   If a new lockfile was generated via the `lockfile_generator`, that
+  ///   Lockfile object is returned. New lockfiles are generated when
+  ///   `reused_lockfile` is not provided.
+  fn cargo_generate_lockfile(
+    &self,
+    reused_lockfile: &Option<PathBuf>,
+    cargo_dir: &Path,
+  ) -> Result<Option<Lockfile>> {
+    let lockfile_path = cargo_dir.join("Cargo.lock");
+
+    // Use the reusable lockfile if one is provided
+    if let Some(reused_lockfile) = reused_lockfile {
+      fs::copy(&reused_lockfile, &lockfile_path)?;
+      return Ok(None);
+    }
+
+    let lockfile = self.lockfile_generator.generate_lockfile(&cargo_dir)?;
+
+    // Returning the lockfile here signifies that a new lockfile has been created.
+    Ok(Some(lockfile))
+  }
+
+  /// Ensure the checksums `lockfile` lists for crates.io crates are those of crates.io's own index,
+  /// looking up to `max_concurrent_downloads` crates up at a time.
+  fn verify_locked_checksums_upstream(&self, lockfile: &Lockfile) -> Result<()> {
+    let locked_checksums: Vec<(String, String, String)> = lockfile
+      .packages
+      .iter()
+      .filter(|package| package.replace.is_none())
+      .filter(|package| matches!(&package.source, Some(source) if source.is_default_registry()))
+      .filter_map(|package| {
+        package.checksum.as_ref().map(|checksum| {
+          (
+            package.name.to_string(),
+            package.version.to_string(),
+            checksum.to_string(),
+          )
+        })
+      })
+      .collect();
+
+    let index_reader = self.index_reader();
+    index_reader.clone_git_index(&self.upstream_index_url)?;
+    let verified = run_on_worker_pool(
+      self.max_concurrent_downloads,
+      locked_checksums,
+      move |(name, version, checksum)| {
+        index_reader.verify_upstream_checksum(name, version, checksum, "the lockfile")
+      },
+    )?;
+    for result in verified {
+      result.unwrap_or_else(|| Err(anyhow!("Verifying a locked checksum never finished")))?;
+    }
+    Ok(())
+  }
+
+  /// Gather all information about a Cargo project to use for planning and rendering steps
+  pub fn fetch_metadata(
+    &self,
+    cargo_workspace_root: &Path,
+    binary_dep_info: Option<&HashMap<String, cargo_toml::Dependency>>,
+    reused_lockfile: Option<PathBuf>,
+  ) -> Result<RazeMetadata> {
+    let (cargo_dir, cargo_workspace_root) = self.make_temp_workspace(cargo_workspace_root)?;
+    let cargo_root_toml = cargo_dir.as_ref().join("Cargo.toml");
+
+    // Gather new lockfile data if any binary dependencies were provided
+    let mut checksums: HashMap<String, String> = HashMap::new();
+    let mut git_fallbacks = HashMap::new();
+    if let Some(binary_dep_info) = binary_dep_info {
+      if !binary_dep_info.is_empty() {
+        // The archives must match the checksums the reused lockfile pins for them, if any
+        let locked_checksums = match &reused_lockfile {
+          Some(lockfile_path) if self.verify_download_checksums => {
+            collect_checksums(&Lockfile::load(lockfile_path)?)
+          },
+          _ => HashMap::new(),
+        };
+
+        // Sort the dependencies so they are always injected into the workspace in the same order
+        let binary_deps: Vec<(String, String)> = binary_dep_info
+          .iter()
+          .sorted_by_key(|(name, _)| *name)
+          .map(|(name, info)| (name.clone(), info.req().to_owned()))
+          .collect();
+        let index_reader = self.index_reader();
+        index_reader.clone_git_index(&self.index_url)?;
+        if self.verify_against_upstream_index {
+          index_reader.clone_git_index(&self.upstream_index_url)?;
+        }
+        let (index_url, verify_against_upstream_index) =
+          (self.index_url.clone(), self.verify_against_upstream_index);
+        let looked_up_checksums = run_on_worker_pool(
+          self.max_concurrent_downloads,
+          binary_deps.clone(),
+          move |(name, version)| {
+            let checksum = index_reader.fetch_crate_checksum(&index_url, name, version)?;
+            if verify_against_upstream_index {
+              let origin = format!("the index {}", index_url);
+              index_reader.verify_upstream_checksum(name, version, &checksum, &origin)?;
+            }
+            Ok(checksum)
+          },
+        )?;
+
+        let mut crates = Vec::new();
+        for ((name, version), checksum) in binary_deps.iter().zip(looked_up_checksums) {
+          let version = version.as_str();
+          let checksum: String = checksum
+            .unwrap_or_else(|| Err(anyhow!("Looking up {} {} never finished", name, version)))?;
+          if let Some(locked_checksum) = locked_checksums.get(&package_ident(name, version)) {
+            if locked_checksum != &checksum {
+              return Err(anyhow!(
+                "Checksum mismatch for {} {}: the lockfile lists sha256 {}, but the index {} \
+                 lists {}",
+                name,
+                version,
+                locked_checksum,
+                self.index_url,
+                checksum
+              ));
+            }
+          }
+          crates.push((
+            name.clone(),
+            version.to_owned(),
+            Some(checksum.clone())
+              .filter(|_| self.verify_download_checksums || self.verify_against_upstream_index),
+          ));
+          checksums.insert(package_ident(name, version), checksum);
+        }
+
+        let (src_dirs, cloned_crates) = self.fetch_crate_srcs(cargo_dir.as_ref(), crates)?;
+        git_fallbacks = cloned_crates;
+        // Crates cloned from a repository may be nested within it
+        let src_dirnames: Vec<String> = src_dirs
+          .iter()
+          .filter_map(|src_dir| src_dir.strip_prefix(cargo_dir.as_ref()).ok())
+          .filter_map(|dirname| dirname.to_str())
+          .map(str::to_owned)
+          .collect();
+
+        self.inject_binaries_into_workspace(src_dirnames, &cargo_root_toml)?;
+      }
+    }
+
+    let output_lockfile = self.cargo_generate_lockfile(&reused_lockfile, cargo_dir.as_ref())?;
+
+    // Load checksums and pinned features from the lockfile
+    let mut locked_features = HashMap::new();
+    let workspace_toml_lock = cargo_dir.as_ref().join("Cargo.lock");
+    if workspace_toml_lock.exists() {
+      let lockfile = Lockfile::load(workspace_toml_lock)?;
+      locked_features = collect_locked_features(&lockfile);
+      if self.verify_against_upstream_index {
+        self.verify_locked_checksums_upstream(&lockfile)?;
+      }
+      // The replacements of `[replace]` entries are listed, and planned, on their own
+      for package in lockfile
+        .packages
+        .iter()
+        .filter(|package| package.replace.is_none())
+      {
+        if let Some(checksum) = &package.checksum {
+          checksums.insert(
+            package_ident(&package.name.to_string(), &package.version.to_string()),
+            checksum.to_string(),
+          );
+        }
+      }
+    }
+
+    let metadata =
+      self.fetch_workspace_metadata(cargo_dir.as_ref(), /*include_deps=*/ true)?;
+    let download_urls = self.collect_download_urls(&metadata, &checksums)?;
+
+    Ok(RazeMetadata {
+      metadata,
+      checksums,
+      locked_features,
+      download_urls,
+      git_fallbacks,
+      cargo_workspace_root,
+      lockfile: output_lockfile,
+    })
+  }
+}
+
+impl Default for RazeMetadataFetcher {
+  fn default() -> RazeMetadataFetcher {
+    RazeMetadataFetcher::new(
+      cargo_bin_path(),
+      // UNWRAP: The default is covered by testing and should never return err
+      Url::parse(DEFAULT_CRATE_REGISTRY_URL).unwrap(),
+      Url::parse(DEFAULT_CRATE_INDEX_URL).unwrap(),
+    )
+  }
+}
+
+/// A struct containing information about a binary dependency
+pub struct BinaryDependencyInfo {
+  pub name: String,
+  pub info: cargo_toml::Dependency,
+  pub lockfile: Option<PathBuf>,
+}
+
+#[cfg(test)]
+pub mod tests {
+  use anyhow::Context;
+  use httpmock::{Method::GET, MockServer};
+  use tera::Tera;
+
+  use super::*;
+  use crate::testing::*;
+
+  use std::{cell::Cell, fs::File, io::Write, rc::Rc, str::FromStr};
+
+  pub struct DummyCargoMetadataFetcher {
+    pub metadata_template: Option<String>,
+  }
+
+  impl DummyCargoMetadataFetcher {
+    fn render_metadata(&self, mock_workspace_path: &Path) -> Option<Metadata> {
+      if self.metadata_template.is_none() {
+        return None;
+      }
+
+      let dir = TempDir::new().unwrap();
+      let mut renderer = Tera::new(&format!("{}/*", dir.as_ref().display())).unwrap();
+
+      let templates_dir = PathBuf::from(std::file!())
+        .parent()
+        .unwrap()
+        .join("testing/metadata_templates")
+        .canonicalize()
+        .unwrap();
+
+      renderer
+        .add_raw_templates(vec![(
+          self.metadata_template.as_ref().unwrap(),
+          fs::read_to_string(templates_dir.join(self.metadata_template.as_ref().unwrap())).unwrap(),
+        )])
+        .unwrap();
+
+      let mut context = tera::Context::new();
+      context.insert("mock_workspace", &mock_workspace_path);
+      context.insert("crate_index_root", "/some/fake/home/path/.cargo");
+      let content = renderer
+        .render(self.metadata_template.as_ref().unwrap(), &context)
+        .unwrap();
+
+      Some(serde_json::from_str::<Metadata>(&content).unwrap())
+    }
+  }
+
+  impl MetadataFetcher for DummyCargoMetadataFetcher {
+    fn fetch_metadata(&self, working_dir: &Path, include_deps: bool) -> Result<Metadata> {
+      // Only use the template if the command is looking to reach out to the internet.
+      if include_deps {
+        if let Some(metadata) = self.render_metadata(working_dir) {
+          return Ok(metadata);
+        }
+      }
+
+      // Ensure no the command is ran in `offline` mode and no dependencies are checked.
+      MetadataCommand::new()
+        .cargo_path(cargo_bin_path())
+        .no_deps()
+        .current_dir(working_dir)
+        .other_options(vec!["--offline".to_string()])
+        .exec()
+        .with_context(|| {
+          format!(
+            "Failed to run `{} metadata` with contents:\n{}",
+            cargo_bin_path().display(),
+            fs::read_to_string(working_dir.join("Cargo.toml")).unwrap()
+          )
+        })
+    }
+  }
+
+  /// A metadata fetcher which counts the fetches of metadata including dependencies
+  struct CountingMetadataFetcher {
+    fetcher: DummyCargoMetadataFetcher,
+    count: Rc<Cell<usize>>,
+  }
+
+  impl MetadataFetcher for CountingMetadataFetcher {
+    fn fetch_metadata(&self, working_dir: &Path, include_deps: bool) -> Result<Metadata> {
+      if include_deps {
+        self.count.set(self.count.get() + 1);
+      }
+      self.fetcher.fetch_metadata(working_dir, include_deps)
+    }
+  }
+
+  /// A metadata fetcher which moves all crates of the given names to another registry
+  struct AlternativeRegistryMetadataFetcher {
+    fetcher: DummyCargoMetadataFetcher,
+    crate_names: Vec<String>,
+    index_url: Url,
+  }
+
+  impl MetadataFetcher for AlternativeRegistryMetadataFetcher {
+    fn fetch_metadata(&self, working_dir: &Path, include_deps: bool) -> Result<Metadata> {
+      let mut metadata = self.fetcher.fetch_metadata(working_dir, include_deps)?;
+      for package in metadata.packages.iter_mut() {
+        if self.crate_names.contains(&package.name) {
+          package.source = Some(cargo_metadata::Source {
+            repr: if is_sparse_index(&self.index_url) {
+              self.index_url.to_string()
+            } else {
+              format!("registry+{}", self.index_url)
+            },
+          });
+        }
+      }
+      Ok(metadata)
+    }
+  }
+
+  pub struct DummyLockfileGenerator {
+    // Optional lockfile to use for generation
+    pub lockfile_contents: Option<String>,
+  }
+
+  impl LockfileGenerator for DummyLockfileGenerator {
+    fn generate_lockfile(&self, _crate_root_dir: &Path) -> Result<Lockfile> {
+      match &self.lockfile_contents {
+        Some(contents) => Lockfile::from_str(contents)
+          .with_context(|| format!("Failed to load provided lockfile:\n{}", contents)),
+        None => Lockfile::from_str(basic_lock_contents())
+          .with_context(|| format!("Failed to load dummy lockfile:\n{}", basic_lock_contents())),
+      }
+    }
+  }
+
+  pub fn dummy_raze_metadata_fetcher() -> (RazeMetadataFetcher, MockServer, TempDir) {
+    let tempdir = TempDir::new().unwrap();
+    let mock_server = MockServer::start();
+    let mut fetcher = RazeMetadataFetcher::new(
+      cargo_bin_path(),
+      Url::parse(&mock_server.base_url()).unwrap(),
+      Url::parse(&format!("file://{}", tempdir.as_ref().display())).unwrap(),
+    );
+    fetcher.set_metadata_fetcher(Box::new(DummyCargoMetadataFetcher {
+      metadata_template: None,
+    }));
+    fetcher.set_lockfile_generator(Box::new(DummyLockfileGenerator {
+      lockfile_contents: None,
+    }));
+    // Keep the registry caches of the cargo running the tests out of them
+    fetcher.set_cargo_home(tempdir.as_ref().join("cargo_home"));
+
+    (fetcher, mock_server, tempdir)
+  }
+
+  pub fn dummy_raze_metadata() -> RazeMetadata {
+    let dir = make_basic_workspace();
+    let (mut fetcher, _server, _index_dir) = dummy_raze_metadata_fetcher();
+
+    // Always render basic metadata
+    fetcher.set_metadata_fetcher(Box::new(DummyCargoMetadataFetcher {
+      metadata_template: Some(templates::BASIC_METADATA.to_string()),
+    }));
+
+    fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap()
+  }
+
+  #[test]
+  fn test_cargo_subcommand_metadata_fetcher_works_without_lock() {
+    let dir = TempDir::new().unwrap();
+    let toml_path = dir.path().join("Cargo.toml");
+    let mut toml = File::create(&toml_path).unwrap();
+    toml.write_all(basic_toml_contents().as_bytes()).unwrap();
+
+    let mut fetcher = RazeMetadataFetcher::default();
+    fetcher.set_lockfile_generator(Box::new(DummyLockfileGenerator {
+      lockfile_contents: None,
+    }));
+    fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap();
+  }
+
+  #[test]
+  fn test_cargo_subcommand_metadata_fetcher_works_with_lock() {
+    let dir = TempDir::new().unwrap();
+    // Create Cargo.toml
+    {
+      let path = dir.path().join("Cargo.toml");
+      let mut toml = File::create(&path).unwrap();
+      toml.write_all(basic_toml_contents().as_bytes()).unwrap();
+    }
+
+    // Create Cargo.lock
+    {
+      let path = dir.path().join("Cargo.lock");
+      let mut lock = File::create(&path).unwrap();
+      lock.write_all(basic_lock_contents().as_bytes()).unwrap();
+    }
+
+    let mut fetcher = RazeMetadataFetcher::default();
+    fetcher.set_lockfile_generator(Box::new(DummyLockfileGenerator {
+      lockfile_contents: None,
+    }));
+    fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap();
+  }
+
+  #[test]
+  fn test_metadata_cache_reuses_unchanged_workspaces() {
+    let dir = make_basic_workspace();
+    let cache_dir = TempDir::new().unwrap();
+    let count = Rc::new(Cell::new(0));
+
+    let (mut fetcher, _server, _index_dir) = dummy_raze_metadata_fetcher();
+    fetcher.set_metadata_fetcher(Box::new(CountingMetadataFetcher {
+      fetcher: DummyCargoMetadataFetcher {
+        metadata_template: Some(templates::BASIC_METADATA.to_string()),
+      },
+      count: count.clone(),
+    }));
+    fetcher.set_metadata_cache_dir(cache_dir.as_ref());
+
+    let first = fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap();
+    let second = fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap();
+    assert_eq!(count.get(), 1);
+    assert_eq!(
+      first.metadata.workspace_members.len(),
+      second.metadata.workspace_members.len()
+    );
+
+    // Cached paths refer to the temporary workspace of the fetch reusing them, not the one they
+    // were fetched from
+    assert_ne!(
+      first.metadata.workspace_root,
+      second.metadata.workspace_root
+    );
+    let entries: Vec<PathBuf> = fs::read_dir(cache_dir.as_ref())
+      .unwrap()
+      .map(|entry| entry.unwrap().path())
+      .collect();
+    assert_eq!(entries.len(), 1);
+    let cached = fs::read_to_string(&entries[0]).unwrap();
+    assert!(cached.contains(CACHED_WORKSPACE_PLACEHOLDER));
+    assert!(!cached.contains(first.metadata.workspace_root.to_str().unwrap()));
+
+    // Changes to the lockfile invalidate the cache
+    fs::write(
+      dir.as_ref().join("Cargo.lock"),
+      format!("{}\n# changed\n", basic_lock_contents()),
+    )
+    .unwrap();
+    fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap();
+    assert_eq!(count.get(), 2);
+
+    // So do sources cargo discovers new targets from
+    fs::create_dir_all(dir.as_ref().join("src/bin")).unwrap();
+    fs::write(dir.as_ref().join("src/bin/tool.rs"), "fn main() {}").unwrap();
+    fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap();
+    assert_eq!(count.get(), 3);
+    fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap();
+    assert_eq!(count.get(), 3);
+  }
+
+  #[test]
+  fn test_setting_options_keeps_custom_fetchers_and_the_cache() {
+    let dir = make_basic_workspace();
+    let cache_dir = TempDir::new().unwrap();
+    let target_dir = TempDir::new().unwrap();
+    let count = Rc::new(Cell::new(0));
+
+    let (mut fetcher, _server, _index_dir) = dummy_raze_metadata_fetcher();
+    fetcher.set_metadata_fetcher(Box::new(CountingMetadataFetcher {
+      fetcher: DummyCargoMetadataFetcher {
+        metadata_template: Some(templates::BASIC_METADATA.to_string()),
+      },
+      count: count.clone(),
+    }));
+    fetcher.set_metadata_cache_dir(cache_dir.as_ref());
+    fetcher.set_cargo_target_dir(target_dir.as_ref());
+    fetcher.set_features(vec!["feature".to_owned()]);
+    fetcher.set_all_features(true);
+    fetcher.set_metadata_timeout(Duration::from_secs(60));
+    fetcher.set_minimal_versions(true);
+
+    fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap();
+    fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap();
+    assert_eq!(count.get(), 1);
+    assert_eq!(fs::read_dir(cache_dir.as_ref()).unwrap().count(), 1);
+  }
+
+  #[test]
+  fn test_metadata_cache_keys_cover_every_manifest_and_resolve_option() {
+    let dir = make_workspace(
+      indoc::indoc! { r#"
+        [workspace]
+        members = ["member"]
+      "# },
+      None,
+    );
+    fs::create_dir_all(dir.as_ref().join("member")).unwrap();
+    fs::write(
+      dir.as_ref().join("member/Cargo.toml"),
+      named_toml_contents("member", "0.1.0"),
+    )
+    .unwrap();
+
+    let key = |features: &[&str], offline: bool, minimal_versions: bool| {
+      CachingMetadataFetcher {
+        cache_dir: PathBuf::from("/cache"),
+        fetcher: &DummyCargoMetadataFetcher {
+          metadata_template: None,
+        },
+        features: features.iter().map(|feature| feature.to_string()).collect(),
+        all_features: false,
+        offline,
+        minimal_versions,
+      }
+      .cache_entry_path(dir.as_ref())
+      .unwrap()
+    };
+
+    let base = key(&[], false, false);
+    assert_eq!(key(&[], false, false), base);
+    assert_ne!(key(&[], true, false), base);
+    assert_ne!(key(&[], false, true), base);
+    // Features are not concatenated into the key
+    assert_ne!(
+      key(&["ab", "c"], false, false),
+      key(&["a", "bc"], false, false)
+    );
+
+    // Changes to the manifests of workspace members invalidate the cache
+    fs::write(
+      dir.as_ref().join("member/Cargo.toml"),
+      format!("{}\n# changed\n", named_toml_contents("member", "0.1.0")),
+    )
+    .unwrap();
+    assert_ne!(key(&[], false, false), base);
+
+    // As do those of path dependencies outside of the workspace, which are referred to by
+    // absolute paths
+    let external_dir = TempDir::new().unwrap();
+    fs::write(
+      external_dir.as_ref().join("Cargo.toml"),
+      named_toml_contents("external", "0.1.0"),
+    )
+    .unwrap();
+    fs::write(
+      dir.as_ref().join("member/Cargo.toml"),
+      format!(
+        "{}\n[dependencies]\nexternal = {{ path = {:?} }}\n",
+        named_toml_contents("member", "0.1.0"),
+        external_dir.as_ref().display().to_string()
+      ),
+    )
+    .unwrap();
+    let with_external = key(&[], false, false);
+    assert_eq!(key(&[], false, false), with_external);
+    fs::write(
+      external_dir.as_ref().join("Cargo.toml"),
+      format!("{}\n# changed\n", named_toml_contents("external", "0.1.0")),
+    )
+    .unwrap();
+    assert_ne!(key(&[], false, false), with_external);
+
+    // And adding a binary target to any of them
+    let with_changed_external = key(&[], false, false);
+    fs::create_dir_all(external_dir.as_ref().join("src")).unwrap();
+    fs::write(external_dir.as_ref().join("src/main.rs"), "fn main() {}").unwrap();
+    assert_ne!(key(&[], false, false), with_changed_external);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_cargo_target_dir_isolates_cargo_invocations() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A `cargo` which records the target directory of each of its invocations
+    let dir = make_basic_workspace();
+    let target_dir = TempDir::new().unwrap();
+    let cargo_dir = TempDir::new().unwrap();
+    let invocations = cargo_dir.as_ref().join("invocations");
+    let recording_cargo = cargo_dir.as_ref().join("recording_cargo");
+    fs::write(
+      &recording_cargo,
+      format!(
+        "#!/bin/sh\necho \"$1 $CARGO_TARGET_DIR\" >> {}\nexec {} \"$@\"\n",
+        invocations.display(),
+        cargo_bin_path().display()
+      ),
+    )
+    .unwrap();
+    fs::set_permissions(&recording_cargo, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut fetcher = RazeMetadataFetcher::new(
+      &recording_cargo,
+      Url::parse(DEFAULT_CRATE_REGISTRY_URL).unwrap(),
+      Url::parse(DEFAULT_CRATE_INDEX_URL).unwrap(),
+    );
+    fetcher.set_cargo_target_dir(target_dir.as_ref());
+    let raze_metadata = fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap();
+
+    assert_eq!(
+      raze_metadata.metadata.target_directory,
+      target_dir.as_ref().to_path_buf()
+    );
+    let invocations = fs::read_to_string(invocations).unwrap();
+    let invocations: Vec<&str> = invocations.lines().collect();
+    let expected = |subcommand: &str| format!("{} {}", subcommand, target_dir.as_ref().display());
+    assert!(invocations.contains(&expected("generate-lockfile").as_str()));
+    assert!(invocations.contains(&expected("metadata").as_str()));
+    assert!(invocations
+      .iter()
+      .all(|invocation| invocation.ends_with(&target_dir.as_ref().display().to_string())));
+  }
+
+  #[test]
+  fn test_package_namespaced_features_only_apply_to_their_member() {
+    let dir = make_workspace(
+      indoc::indoc! { r#"
+        [workspace]
+        members = ["mycrate", "othercrate"]
+      "# },
+      None,
+    );
+    for member in &["mycrate", "othercrate"] {
+      fs::create_dir_all(dir.as_ref().join(member).join("src")).unwrap();
+      fs::write(
+        dir.as_ref().join(member).join("Cargo.toml"),
+        indoc::formatdoc! { r#"
+          [package]
+          name = "{}"
+          version = "0.1.0"
+
+          [features]
+          extra = []
+        "#, member },
+      )
+      .unwrap();
+      File::create(dir.as_ref().join(member).join("src/lib.rs")).unwrap();
+    }
+
+    let fetcher = CargoMetadataFetcher {
+      features: vec!["mycrate/extra".to_owned()],
+      ..CargoMetadataFetcher::default()
+    };
+    let metadata = fetcher.fetch_metadata(dir.as_ref(), true).unwrap();
+
+    let resolved_features = |name: &str| {
+      let package = metadata.packages.iter().find(|p| p.name == name).unwrap();
+      let resolve = metadata.resolve.as_ref().unwrap();
+      let node = resolve.nodes.iter().find(|n| n.id == package.id).unwrap();
+      node.features.clone()
+    };
+    assert_eq!(resolved_features("mycrate"), vec!["extra".to_owned()]);
+    assert!(resolved_features("othercrate").is_empty());
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_metadata_timeout_aborts_slow_cargo() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A `cargo` which never finishes resolving the workspace in time
+    let dir = make_basic_workspace();
+    let slow_cargo = dir.as_ref().join("slow_cargo");
+    fs::write(&slow_cargo, "#!/bin/sh\nsleep 10\n").unwrap();
+    fs::set_permissions(&slow_cargo, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let fetcher = CargoMetadataFetcher {
+      cargo_bin_path: slow_cargo,
+      metadata_timeout: Some(Duration::from_millis(200)),
+      ..CargoMetadataFetcher::default()
+    };
+    let started = Instant::now();
+    let err = fetcher.fetch_metadata(dir.as_ref(), true).unwrap_err();
+
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(format!("{:#}", err).contains("`cargo metadata` did not finish within 200ms"));
+  }
+
+  #[test]
+  fn test_all_features_enable_every_member_feature_and_its_deps() {
+    let dir = make_workspace(
+      indoc::indoc! { r#"
+        [workspace]
+        members = ["mycrate", "small_dep", "full_dep"]
+      "# },
+      None,
+    );
+    for member in &["small_dep", "full_dep"] {
+      fs::create_dir_all(dir.as_ref().join(member).join("src")).unwrap();
+      fs::write(
+        dir.as_ref().join(member).join("Cargo.toml"),
+        indoc::formatdoc! { r#"
+          [package]
+          name = "{}"
+          version = "0.1.0"
+
+          [features]
+          unused = []
+        "#, member },
+      )
+      .unwrap();
+      File::create(dir.as_ref().join(member).join("src/lib.rs")).unwrap();
+    }
+    fs::create_dir_all(dir.as_ref().join("mycrate/src")).unwrap();
+    fs::write(
+      dir.as_ref().join("mycrate/Cargo.toml"),
+      indoc::indoc! { r#"
+        [package]
+        name = "mycrate"
+        version = "0.1.0"
+
+        [dependencies]
+        small_dep = { path = "../small_dep", optional = true }
+        full_dep = { path = "../full_dep", optional = true }
+
+        [features]
+        default = ["small"]
+        small = ["small_dep"]
+        full = ["full_dep"]
+        extra = []
+      "# },
+    )
+    .unwrap();
+    File::create(dir.as_ref().join("mycrate/src/lib.rs")).unwrap();
+
+    let fetcher = CargoMetadataFetcher {
+      all_features: true,
+      ..CargoMetadataFetcher::default()
+    };
+    let metadata = fetcher.fetch_metadata(dir.as_ref(), true).unwrap();
+    let resolved = |name: &str| {
+      let package = metadata.packages.iter().find(|p| p.name == name).unwrap();
+      let resolve = metadata.resolve.as_ref().unwrap();
+      let node = resolve.nodes.iter().find(|n| n.id == package.id).unwrap();
+      let deps: Vec<String> = node
+        .deps
+        .iter()
+        .map(|dep| dep.name.clone())
+        .sorted()
+        .collect();
+      (node.features.clone(), deps)
+    };
+
+    assert_eq!(
+      resolved("mycrate"),
+      (
+        vec![
+          "default".to_owned(),
+          "extra".to_owned(),
+          "full".to_owned(),
+          "full_dep".to_owned(),
+          "small".to_owned(),
+          "small_dep".to_owned()
+        ],
+        vec!["full_dep".to_owned(), "small_dep".to_owned()]
+      )
+    );
+    // Dependencies get every feature too, as they are also workspace members
+    assert_eq!(resolved("small_dep").0, vec!["unused".to_owned()]);
+    assert_eq!(resolved("full_dep").0, vec!["unused".to_owned()]);
+  }
+
+  #[test]
+  fn test_feature_profiles_drive_resolution() {
+    let dir = make_workspace(
+      indoc::indoc! { r#"
+        [workspace]
+        members = ["mycrate", "small_dep", "full_dep"]
+
+        [workspace.metadata.raze]
+        workspace_path = "//cargo"
+
+        [workspace.metadata.raze.feature_profiles]
+        minimal = ["mycrate/small"]
+        full = ["mycrate/small", "mycrate/full"]
+      "# },
+      None,
+    );
+    for member in &["small_dep", "full_dep"] {
+      fs::create_dir_all(dir.as_ref().join(member).join("src")).unwrap();
+      fs::write(
+        dir.as_ref().join(member).join("Cargo.toml"),
+        indoc::formatdoc! { r#"
+          [package]
+          name = "{}"
+          version = "0.1.0"
+        "#, member },
+      )
+      .unwrap();
+      File::create(dir.as_ref().join(member).join("src/lib.rs")).unwrap();
+    }
+    fs::create_dir_all(dir.as_ref().join("mycrate/src")).unwrap();
+    fs::write(
+      dir.as_ref().join("mycrate/Cargo.toml"),
+      indoc::indoc! { r#"
+        [package]
+        name = "mycrate"
+        version = "0.1.0"
+
+        [dependencies]
+        small_dep = { path = "../small_dep", optional = true }
+        full_dep = { path = "../full_dep", optional = true }
+
+        [features]
+        small = ["small_dep"]
+        full = ["full_dep"]
+      "# },
+    )
+    .unwrap();
+    File::create(dir.as_ref().join("mycrate/src/lib.rs")).unwrap();
+
+    let settings =
+      crate::settings::load_settings_from_manifest(dir.as_ref().join("Cargo.toml"), None).unwrap();
+    let resolve_profile = |profile: &str| {
+      let fetcher = CargoMetadataFetcher {
+        features: crate::settings::feature_profile(&settings, profile)
+          .unwrap()
+          .clone(),
+        ..CargoMetadataFetcher::default()
+      };
+      let metadata = fetcher.fetch_metadata(dir.as_ref(), true).unwrap();
+      let package = metadata
+        .packages
+        .iter()
+        .find(|p| p.name == "mycrate")
+        .unwrap();
+      let resolve = metadata.resolve.as_ref().unwrap();
+      let node = resolve.nodes.iter().find(|n| n.id == package.id).unwrap();
+      let deps: Vec<String> = node
+        .deps
+        .iter()
+        .map(|dep| dep.name.clone())
+        .sorted()
+        .collect();
+      (node.features.clone(), deps)
+    };
+
+    assert_eq!(
+      resolve_profile("minimal"),
+      (
+        vec!["small".to_owned(), "small_dep".to_owned()],
+        vec!["small_dep".to_owned()]
+      )
+    );
+    assert_eq!(
+      resolve_profile("full"),
+      (
+        vec![
+          "full".to_owned(),
+          "full_dep".to_owned(),
+          "small".to_owned(),
+          "small_dep".to_owned()
+        ],
+        vec!["full_dep".to_owned(), "small_dep".to_owned()]
+      )
+    );
+    let err = crate::settings::feature_profile(&settings, "embedded").unwrap_err();
+    assert!(err
+      .to_string()
+      .contains("Unknown feature profile `embedded`, the available profiles are: [full, minimal]"));
+  }
+
+  #[test]
+  fn test_external_patch_and_replace_paths_are_absolutized() {
+    let root = TempDir::new().unwrap();
+    let workspace_root = root.as_ref().join("workspace");
+    for dir in &["workspace/member", "patched", "replaced"] {
+      fs::create_dir_all(root.as_ref().join(dir)).unwrap();
+    }
+    let manifest_path = root.as_ref().join("Cargo.toml.copy");
+    fs::write(
+      &manifest_path,
+      indoc::indoc! { r#"
+        [workspace]
+        members = ["member"]
+
+        [patch.crates-io]
+        patched = { path = "../patched" }
+        member = { path = "member" }
+
+        [replace]
+        "replaced:0.1.0" = { path = "../replaced" }
+        "git-replaced:0.1.0" = { git = "https://github.com/example/git-replaced.git" }
+      "# },
+    )
+    .unwrap();
+
+    absolutize_external_path_dependencies(&manifest_path, &workspace_root, &workspace_root)
+      .unwrap();
+
+    let manifest: toml::Value =
+      toml::from_str(&fs::read_to_string(&manifest_path).unwrap()).unwrap();
+    let canonical_root = fs::canonicalize(root.as_ref()).unwrap();
+    assert_eq!(
+      manifest["patch"]["crates-io"]["patched"]["path"].as_str(),
+      Some(canonical_root.join("patched").to_str().unwrap())
+    );
+    // Paths within the workspace are part of its copy and so are kept as they are
+    assert_eq!(
+      manifest["patch"]["crates-io"]["member"]["path"].as_str(),
+      Some("member")
+    );
+    assert_eq!(
+      manifest["replace"]["replaced:0.1.0"]["path"].as_str(),
+      Some(canonical_root.join("replaced").to_str().unwrap())
+    );
+    assert_eq!(
+      manifest["replace"]["git-replaced:0.1.0"]["git"].as_str(),
+      Some("https://github.com/example/git-replaced.git")
+    );
+  }
+
+  #[test]
+  fn test_checksums_of_replaced_packages_are_skipped() {
+    let lockfile = Lockfile::from_str(indoc::indoc! { r#"
+      [[package]]
+      name = "foo"
+      version = "1.0.0"
+
+      [[package]]
+      name = "foo"
+      version = "1.0.0"
+      source = "registry+https://github.com/rust-lang/crates.io-index"
+      checksum = "8a648e87a02fa31d9d9a3b7c76dbfee469402fbb4af3ae98b36592d8f960c0f4"
+      replace = "foo 1.0.0"
+
+      [[package]]
+      name = "bar"
+      version = "2.0.0"
+      source = "registry+https://github.com/rust-lang/crates.io-index"
+      checksum = "f7fe0bb3479651439c9112f72b6c505038574c9fbb575ed1bf3b797fa39dd564"
+    "# })
+    .unwrap();
+
+    let checksums = collect_checksums(&lockfile);
+    assert_eq!(checksums.len(), 1);
+    assert_eq!(
+      checksums.get("bar-2.0.0").map(String::as_str),
+      Some("f7fe0bb3479651439c9112f72b6c505038574c9fbb575ed1bf3b797fa39dd564")
+    );
+  }
+
+  #[test]
+  fn test_cargo_subcommand_metadata_fetcher_handles_bad_files() {
+    let dir = TempDir::new().unwrap();
+    // Create Cargo.toml
+    {
+      let path = dir.path().join("Cargo.toml");
+      let mut toml = File::create(&path).unwrap();
+      toml.write_all(b"hello").unwrap();
+    }
+
+    let fetcher = RazeMetadataFetcher::default();
+    assert!(fetcher.fetch_metadata(dir.as_ref(), None, None).is_err());
+  }
+
+  #[test]
+  fn test_fetching_src() {
+    let (fetcher, mock_server, _index_url) = dummy_raze_metadata_fetcher();
+    let mock = mock_remote_crate("fake-crate", "3.3.3", &mock_server);
+
+    let (path, _) = download_crate(
+      &fetcher,
+      mock.data_dir.as_ref(),
+      "fake-crate",
+      "3.3.3",
+      Some(&mock.checksum),
+    )
+    .unwrap();
+
+    for mock in mock.endpoints.iter() {
+      mock.assert();
+    }
+
+    assert!(path.exists());
+
+    // Ensure the name follows a consistent pattern: `{name}-{version}`
+    assert_eq!(
+      mock.data_dir.into_path().join("fake-crate-3.3.3").as_path(),
+      path.as_path()
+    );
+    assert!(path.join("Cargo.toml").exists());
+    assert!(path.join("Cargo.lock").exists());
+    assert!(path.join("test").exists());
+  }
+
+  #[test]
+  fn test_fetching_src_from_a_registry_base_path() {
+    let (mut fetcher, mock_server, _index_url) = dummy_raze_metadata_fetcher();
+    fetcher.registry_url = Url::parse(&format!(
+      "{}/private/api/v1/crates/{{crate}}/{{version}}/download",
+      mock_server.base_url()
+    ))
+    .unwrap();
+    let mock = mock_remote_crate_at("/private", "fake-crate", "3.3.3", &mock_server);
+
+    let (path, _) = download_crate(
+      &fetcher,
+      mock.data_dir.as_ref(),
+      "fake-crate",
+      "3.3.3",
+      None,
+    )
+    .unwrap();
+
+    for mock in mock.endpoints.iter() {
+      mock.assert();
+    }
+    assert!(path.join("Cargo.toml").exists());
+  }
+
+  /// Downloads a single crate through the download pipeline of `fetcher`
+  fn download_crate(
+    fetcher: &RazeMetadataFetcher,
+    dir: &Path,
+    name: &str,
+    version: &str,
+    checksum: Option<&str>,
+  ) -> Result<DownloadedCrate> {
+    fetcher
+      .download_crate_srcs(
+        dir,
+        vec![(
+          name.to_owned(),
+          version.to_owned(),
+          checksum.map(str::to_owned),
+        )],
+      )
+      .unwrap()
+      .remove(0)
+      .unwrap()
+  }
+
+  #[test]
+  fn test_fetching_many_srcs_concurrently() {
+    let (mut fetcher, mock_server, _index_url) = dummy_raze_metadata_fetcher();
+    fetcher.set_max_concurrent_downloads(4);
+    let names: Vec<String> = (0..20).map(|idx| format!("fake-crate-{}", idx)).collect();
+    let mocks: Vec<MockRemoteCrateInfo> = names
+      .iter()
+      .map(|name| mock_remote_crate(name, "3.3.3", &mock_server))
+      .collect();
+
+    let dir = TempDir::new().unwrap();
+    let (paths, git_fallbacks) = fetcher
+      .fetch_crate_srcs(
+        dir.as_ref(),
+        names
+          .iter()
+          .zip(mocks.iter())
+          .map(|(name, mock)| {
+            (
+              name.clone(),
+              "3.3.3".to_owned(),
+              Some(mock.checksum.clone()),
+            )
+          })
+          .collect(),
+      )
+      .unwrap();
+
+    for mock in mocks.iter() {
+      for endpoint in mock.endpoints.iter() {
+        endpoint.assert();
+      }
+    }
+
+    // Crate directories are returned in the order the crates were requested in
+    let expected_paths: Vec<PathBuf> = names
+      .iter()
+      .map(|name| dir.as_ref().join(format!("{}-3.3.3", name)))
+      .collect();
+    assert_eq!(paths, expected_paths);
+    assert!(paths.iter().all(|path| path.join("Cargo.toml").exists()));
+    assert!(git_fallbacks.is_empty());
+  }
+
+  #[test]
+  fn test_download_pipeline_settles_every_crate_despite_failures() {
+    // The pipeline runs on a thread of its own so a deadlock fails the test rather than hang it
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+      let (mut fetcher, mock_server, _index_url) = dummy_raze_metadata_fetcher();
+      fetcher.set_max_concurrent_downloads(2);
+      let names = [
+        "fake-crate-a",
+        "truncated-crate",
+        "mismatched-crate",
+        "fake-crate-b",
+      ];
+      let mut mocks: Vec<MockRemoteCrateInfo> = names
+        .iter()
+        .map(|name| mock_remote_crate(name, "3.3.3", &mock_server))
+        .collect();
+
+      // The connection is closed before the announced length of the archive was sent
+      mocks[1].endpoints[1].delete();
+      let _truncated_download = mock_server.mock(|when, then| {
+        when
+          .method(GET)
+          .path("/api/v1/crates/truncated-crate/3.3.3/download");
+        then
+          .status(200)
+          .header("content-length", "4096")
+          .body("partial archive");
+      });
+
+      let dir = TempDir::new().unwrap();
+      let crates = names
+        .iter()
+        .zip(mocks.iter())
+        .map(|(name, mock)| {
+          let checksum = match *name {
+            "mismatched-crate" => "0".repeat(64),
+            _ => mock.checksum.clone(),
+          };
+          (name.to_string(), "3.3.3".to_owned(), Some(checksum))
+        })
+        .collect();
+      let results: Vec<Result<PathBuf>> = fetcher
+        .download_crate_srcs(dir.as_ref(), crates)
+        .unwrap()
+        .into_iter()
+        .map(|result| result.unwrap().map(|(crate_dir, _)| crate_dir))
+        .collect();
+
+      // The checksums of the archives which downloaded are verified
+      for idx in &[0, 3] {
+        let crate_dir = results[*idx].as_ref().unwrap();
+        assert_eq!(
+          crate_dir,
+          &dir.as_ref().join(format!("{}-3.3.3", names[*idx]))
+        );
+        assert!(crate_dir.join("Cargo.toml").exists());
+      }
+      let truncated_err = format!("{:#}", results[1].as_ref().unwrap_err());
+      assert!(truncated_err.starts_with(&format!(
+        "Failed to download {}/api/v1/crates/truncated-crate/3.3.3/download",
+        mock_server.base_url()
+      )));
+      assert!(results[2]
+        .as_ref()
+        .unwrap_err()
+        .to_string()
+        .starts_with("Checksum mismatch for mismatched-crate 3.3.3 downloaded from "));
+      assert!(!dir.as_ref().join("mismatched-crate-3.3.3").exists());
+
+      sender.send(()).unwrap();
+    });
+
+    assert!(receiver.recv_timeout(Duration::from_secs(60)).is_ok());
+  }
+
+  #[test]
+  fn test_download_jobs_which_panic_yield_errors() {
+    let result: Result<()> = catch_job_panic("fake-crate", "3.3.3", || panic!("worker bug"));
+    assert_eq!(
+      result.unwrap_err().to_string(),
+      "Panicked while fetching fake-crate 3.3.3"
+    );
+    assert_eq!(catch_job_panic("fake-crate", "3.3.3", || Ok(3)).unwrap(), 3);
+  }
+
+  #[test]
+  fn test_fetching_srcs_reports_every_failed_download() {
+    let (fetcher, mock_server, _index_url) = dummy_raze_metadata_fetcher();
+    let mock = mock_remote_crate("fake-crate", "3.3.3", &mock_server);
+
+    let crates = vec!["missing-crate-a", "fake-crate", "missing-crate-b"]
+      .into_iter()
+      .map(|name| (name.to_owned(), "3.3.3".to_owned(), None))
+      .collect();
+    let err = fetcher
+      .fetch_crate_srcs(mock.data_dir.as_ref(), crates)
+      .unwrap_err()
+      .to_string();
+
+    assert!(err.starts_with("Failed to download 2 crates:\n  missing-crate-a-3.3.3: "));
+    assert!(err.contains("\n  missing-crate-b-3.3.3: "));
+    assert!(!err.contains("fake-crate-3.3.3"));
+    // The other downloads still ran
+    assert!(mock.data_dir.as_ref().join("fake-crate-3.3.3").exists());
+  }
+
+  fn git(dir: &Path, args: &[&str]) {
+    let output = std::process::Command::new("git")
+      .arg("-C")
+      .arg(dir)
+      .args(["-c", "user.name=raze", "-c", "user.email=raze@example.com"])
+      .args(args)
+      .output()
+      .unwrap();
+    assert!(
+      output.status.success(),
+      "{}",
+      String::from_utf8_lossy(&output.stderr)
+    );
+  }
+
+  #[test]
+  fn test_fetching_srcs_falls_back_to_git_when_downloads_fail() {
+    let (mut fetcher, mock_server, _index_url) = dummy_raze_metadata_fetcher();
+
+    // A repository holding the crate in a subdirectory, tagged at its version
+    let repo_dir = TempDir::new().unwrap();
+    let crate_dir = repo_dir.as_ref().join("fake-crate");
+    fs::create_dir_all(&crate_dir).unwrap();
+    fs::write(
+      crate_dir.join("Cargo.toml"),
+      named_toml_contents("fake-crate", "3.3.3"),
+    )
+    .unwrap();
+    git(repo_dir.as_ref(), &["init", "--quiet"]);
+    git(repo_dir.as_ref(), &["add", "-A"]);
+    git(repo_dir.as_ref(), &["commit", "--quiet", "-m", "Release"]);
+    git(repo_dir.as_ref(), &["tag", "v3.3.3"]);
+    let repository = format!("file://{}", repo_dir.as_ref().display());
+
+    let info_mock = mock_server.mock(|when, then| {
+      when.method(GET).path("/api/v1/crates/fake-crate");
+      then.status(200).json_body(serde_json::json!({
+          "crate": {
+              "id": "fake-crate",
+              "name": "fake-crate",
+              "repository": repository,
+              "versions": [
+                  123456
+              ],
+          },
+          "versions": [
+              {
+                  "id": 123456,
+                  "crate": "fake-crate",
+                  "num": "3.3.3",
+                  "dl_path": "/api/v1/crates/fake-crate/3.3.3/download",
+              }
+          ],
+      }));
+    });
+    let download_mock = mock_server.mock(|when, then| {
+      when
+        .method(GET)
+        .path("/api/v1/crates/fake-crate/3.3.3/download");
+      then.status(404);
+    });
+
+    let crates = || vec![("fake-crate".to_owned(), "3.3.3".to_owned(), None)];
+
+    // Without the fallback, the failed download is reported
+    let dir = TempDir::new().unwrap();
+    let err = fetcher
+      .fetch_crate_srcs(dir.as_ref(), crates())
+      .unwrap_err()
+      .to_string();
+    assert!(err.starts_with(&format!(
+      "Failed to download {}/api/v1/crates/fake-crate/3.3.3/download",
+      mock_server.base_url()
+    )));
+
+    fetcher.set_git_fallback(true);
+    let dir = TempDir::new().unwrap();
+    let (paths, git_fallbacks) = fetcher.fetch_crate_srcs(dir.as_ref(), crates()).unwrap();
+
+    assert!(download_mock.hits() >= 2);
+    assert!(info_mock.hits() >= 2);
+    assert_eq!(
+      paths,
+      vec![dir.as_ref().join("fake-crate-3.3.3/fake-crate")]
+    );
+    assert!(paths[0].join("Cargo.toml").exists());
+    let git_repo = &git_fallbacks["fake-crate-3.3.3"];
+    assert_eq!(git_repo.remote, repository);
+    assert_eq!(git_repo.commit.len(), 40);
+    assert_eq!(git_repo.path_to_crate_root.as_deref(), Some("fake-crate"));
+  }
+
+  #[test]
+  fn test_fetching_src_rejects_archives_with_mismatched_checksums() {
+    let (fetcher, mock_server, _index_url) = dummy_raze_metadata_fetcher();
+    let mock = mock_remote_crate("fake-crate", "3.3.3", &mock_server);
+    let expected = "0".repeat(64);
+
+    let err = download_crate(
+      &fetcher,
+      mock.data_dir.as_ref(),
+      "fake-crate",
+      "3.3.3",
+      Some(&expected),
+    )
+    .unwrap_err();
+
+    assert_eq!(
+      err.to_string(),
+      format!(
+        "Checksum mismatch for fake-crate 3.3.3 downloaded from \
+         {}/api/v1/crates/fake-crate/3.3.3/download: expected sha256 {}, found {}",
+        mock_server.base_url(),
+        expected,
+        mock.checksum
+      )
+    );
+    // Nothing of the tampered archive is extracted
+    assert!(!mock.data_dir.as_ref().join("fake-crate-3.3.3").exists());
+  }
+
+  #[test]
+  fn test_fetching_src_falls_back_to_mirrors_on_checksum_mismatches() {
+    let (mut fetcher, corrupt_mirror, _index_url) = dummy_raze_metadata_fetcher();
+    let mirror = MockServer::start();
+    fetcher.set_download_mirrors(vec![Url::parse(&mirror.base_url()).unwrap()]);
+    let mock = mock_remote_crate("fake-crate", "3.3.3", &mirror);
+    let mut corrupt_mock = mock_remote_crate("fake-crate", "3.3.3", &corrupt_mirror);
+    corrupt_mock.endpoints[1].delete();
+    let corrupt_download = corrupt_mirror.mock(|when, then| {
+      when
+        .method(GET)
+        .path("/api/v1/crates/fake-crate/3.3.3/download");
+      then.status(200).body("corrupt bytes");
+    });
+
+    let (path, failures) = download_crate(
+      &fetcher,
+      mock.data_dir.as_ref(),
+      "fake-crate",
+      "3.3.3",
+      Some(&mock.checksum),
+    )
+    .unwrap();
+
+    corrupt_download.assert();
+    for endpoint in mock.endpoints.iter() {
+      endpoint.assert();
+    }
+    assert!(path.join("Cargo.toml").exists());
+    assert_eq!(failures.len(), 1);
+    assert!(failures[0].starts_with(&format!(
+      "{}: Checksum mismatch for fake-crate 3.3.3 downloaded from {}/api/v1/crates",
+      corrupt_mirror.base_url(),
+      corrupt_mirror.base_url()
+    )));
+
+    // Downloads only fail once every mirror mismatches, naming each of them
+    let err = download_crate(
+      &fetcher,
+      mock.data_dir.as_ref(),
+      "fake-crate",
+      "3.3.3",
+      Some(&"0".repeat(64)),
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(err.starts_with("Failed to download fake-crate 3.3.3 from any of 2 mirrors:\n  "));
+    assert!(err.contains(&format!(
+      "\n  {}: Checksum mismatch",
+      corrupt_mirror.base_url()
+    )));
+    assert!(err.contains(&format!("\n  {}: Checksum mismatch", mirror.base_url())));
+  }
+
+  #[test]
+  fn test_binary_dependency_checksums_are_verified_unless_disabled() {
+    let (mut fetcher, mock_server, index_dir) = dummy_raze_metadata_fetcher();
+    fetcher.set_metadata_fetcher(Box::new(DummyCargoMetadataFetcher {
+      metadata_template: Some(templates::BASIC_METADATA.to_string()),
+    }));
+    let _mock = mock_remote_crate("some-binary-crate", "3.3.3", &mock_server);
+
+    // The index lists a checksum other than the one of the archive served by the registry
+    mock_crate_index(
+      &to_index_crates_map(vec![("some-binary-crate", "3.3.3")]),
+      Some(index_dir.as_ref()),
+    );
+
+    let mut binary_deps = HashMap::new();
+    binary_deps.insert(
+      "some-binary-crate".to_string(),
+      cargo_toml::Dependency::Simple("3.3.3".to_string()),
+    );
 
-  use std::{fs::File, io::Write, str::FromStr};
+    let dir = make_basic_workspace();
+    let err = fetcher
+      .fetch_metadata(dir.as_ref(), Some(&binary_deps), None)
+      .unwrap_err();
+    assert!(err
+      .to_string()
+      .starts_with("Checksum mismatch for some-binary-crate 3.3.3"));
 
-  pub struct DummyCargoMetadataFetcher {
-    pub metadata_template: Option<String>,
+    fetcher.set_verify_download_checksums(false);
+    assert!(fetcher
+      .fetch_metadata(dir.as_ref(), Some(&binary_deps), None)
+      .is_ok());
   }
 
-  impl DummyCargoMetadataFetcher {
-    fn render_metadata(&self, mock_workspace_path: &Path) -> Option<Metadata> {
-      if self.metadata_template.is_none() {
-        return None;
-      }
-
-      let dir = TempDir::new().unwrap();
-      let mut renderer = Tera::new(&format!("{}/*", dir.as_ref().display())).unwrap();
+  #[test]
+  fn test_binary_dependencies_are_verified_against_the_reused_lockfile() {
+    let (mut fetcher, mock_server, index_dir) = dummy_raze_metadata_fetcher();
+    fetcher.set_metadata_fetcher(Box::new(DummyCargoMetadataFetcher {
+      metadata_template: Some(templates::BASIC_METADATA.to_string()),
+    }));
+    let mock = mock_remote_crate("some-binary-crate", "3.3.3", &mock_server);
+    mock_crate_index_entry(
+      index_dir.as_ref(),
+      "some-binary-crate",
+      "3.3.3",
+      &mock.checksum,
+    );
 
-      let templates_dir = PathBuf::from(std::file!())
-        .parent()
-        .unwrap()
-        .join("testing/metadata_templates")
-        .canonicalize()
-        .unwrap();
+    // The lockfile pins a checksum other than the one of the archive listed by the index
+    let locked_checksum = "8a648e87a02fa31d9d9a3b7c76dbfee469402fbb4af3ae98b36592d8f960c0f4";
+    let dir = make_basic_workspace();
+    let lockfile_path = dir.as_ref().join("Cargo.raze.lock");
+    fs::write(
+      &lockfile_path,
+      indoc::formatdoc! { r#"
+        [[package]]
+        name = "some-binary-crate"
+        version = "3.3.3"
+        source = "registry+https://github.com/rust-lang/crates.io-index"
+        checksum = "{}"
+      "#, locked_checksum },
+    )
+    .unwrap();
 
-      renderer
-        .add_raw_templates(vec![(
-          self.metadata_template.as_ref().unwrap(),
-          fs::read_to_string(templates_dir.join(self.metadata_template.as_ref().unwrap())).unwrap(),
-        )])
-        .unwrap();
+    let mut binary_deps = HashMap::new();
+    binary_deps.insert(
+      "some-binary-crate".to_string(),
+      cargo_toml::Dependency::Simple("3.3.3".to_string()),
+    );
 
-      let mut context = tera::Context::new();
-      context.insert("mock_workspace", &mock_workspace_path);
-      context.insert("crate_index_root", "/some/fake/home/path/.cargo");
-      let content = renderer
-        .render(self.metadata_template.as_ref().unwrap(), &context)
-        .unwrap();
+    let err = fetcher
+      .fetch_metadata(
+        dir.as_ref(),
+        Some(&binary_deps),
+        Some(lockfile_path.clone()),
+      )
+      .unwrap_err()
+      .to_string();
+    assert!(err.starts_with(&format!(
+      "Checksum mismatch for some-binary-crate 3.3.3: the lockfile lists sha256 {}",
+      locked_checksum
+    )));
+    assert!(err.contains(&mock.checksum));
 
-      Some(serde_json::from_str::<Metadata>(&content).unwrap())
-    }
+    fetcher.set_verify_download_checksums(false);
+    assert!(fetcher
+      .fetch_metadata(dir.as_ref(), Some(&binary_deps), Some(lockfile_path))
+      .is_ok());
   }
 
-  impl MetadataFetcher for DummyCargoMetadataFetcher {
-    fn fetch_metadata(&self, working_dir: &Path, include_deps: bool) -> Result<Metadata> {
-      // Only use the template if the command is looking to reach out to the internet.
-      if include_deps {
-        if let Some(metadata) = self.render_metadata(working_dir) {
-          return Ok(metadata);
-        }
-      }
+  #[test]
+  fn test_mirrored_binary_dependencies_are_verified_against_the_upstream_index() {
+    let (mut fetcher, mirror, index_dir) = dummy_raze_metadata_fetcher();
+    fetcher.set_metadata_fetcher(Box::new(DummyCargoMetadataFetcher {
+      metadata_template: Some(templates::BASIC_METADATA.to_string()),
+    }));
+    let upstream = MockServer::start();
+    fetcher.upstream_index_url = mock_sparse_index_url(&upstream);
 
-      // Ensure no the command is ran in `offline` mode and no dependencies are checked.
-      MetadataCommand::new()
-        .cargo_path(cargo_bin_path())
-        .no_deps()
-        .current_dir(working_dir)
-        .other_options(vec!["--offline".to_string()])
-        .exec()
-        .with_context(|| {
-          format!(
-            "Failed to run `{} metadata` with contents:\n{}",
-            cargo_bin_path().display(),
-            fs::read_to_string(working_dir.join("Cargo.toml")).unwrap()
-          )
-        })
-    }
-  }
+    // The mirror's index lists the checksum of the tampered archive it serves
+    let mock = mock_remote_crate("some-binary-crate", "3.3.3", &mirror);
+    mock_crate_index_entry(
+      index_dir.as_ref(),
+      "some-binary-crate",
+      "3.3.3",
+      &mock.checksum,
+    );
+    let upstream_checksum = "8a648e87a02fa31d9d9a3b7c76dbfee469402fbb4af3ae98b36592d8f960c0f4";
+    let _upstream_entry =
+      mock_sparse_crate_index_entry("some-binary-crate", "3.3.3", upstream_checksum, &upstream);
 
-  pub struct DummyLockfileGenerator {
-    // Optional lockfile to use for generation
-    pub lockfile_contents: Option<String>,
-  }
+    let mut binary_deps = HashMap::new();
+    binary_deps.insert(
+      "some-binary-crate".to_string(),
+      cargo_toml::Dependency::Simple("3.3.3".to_string()),
+    );
 
-  impl LockfileGenerator for DummyLockfileGenerator {
-    fn generate_lockfile(&self, _crate_root_dir: &Path) -> Result<Lockfile> {
-      match &self.lockfile_contents {
-        Some(contents) => Lockfile::from_str(contents)
-          .with_context(|| format!("Failed to load provided lockfile:\n{}", contents)),
-        None => Lockfile::from_str(basic_lock_contents())
-          .with_context(|| format!("Failed to load dummy lockfile:\n{}", basic_lock_contents())),
-      }
-    }
+    let dir = make_basic_workspace();
+    assert!(fetcher
+      .fetch_metadata(dir.as_ref(), Some(&binary_deps), None)
+      .is_ok());
+
+    fetcher.set_verify_against_upstream_index(true);
+    let err = fetcher
+      .fetch_metadata(dir.as_ref(), Some(&binary_deps), None)
+      .unwrap_err()
+      .to_string();
+    assert!(err.starts_with("Checksum mismatch for some-binary-crate 3.3.3"));
+    assert!(err.contains(&mock.checksum));
+    assert!(err.contains(upstream_checksum));
   }
 
-  pub fn dummy_raze_metadata_fetcher() -> (RazeMetadataFetcher, MockServer, TempDir) {
-    let tempdir = TempDir::new().unwrap();
-    let mock_server = MockServer::start();
-    let mut fetcher = RazeMetadataFetcher::new(
-      cargo_bin_path(),
-      Url::parse(&mock_server.base_url()).unwrap(),
-      Url::parse(&format!("file://{}", tempdir.as_ref().display())).unwrap(),
+  #[test]
+  fn test_lockfile_checksums_are_verified_against_the_upstream_index() {
+    let (mut fetcher, _server, _index_dir) = dummy_raze_metadata_fetcher();
+    fetcher.set_metadata_fetcher(Box::new(DummyCargoMetadataFetcher {
+      metadata_template: Some(templates::BASIC_METADATA.to_string()),
+    }));
+    let upstream = MockServer::start();
+    fetcher.upstream_index_url = mock_sparse_index_url(&upstream);
+    fetcher.set_verify_against_upstream_index(true);
+
+    // `proc-macro2` was tampered with in the lockfile while `unicode-xid` was not
+    let _proc_macro2 = mock_sparse_crate_index_entry(
+      "proc-macro2",
+      "1.0.24",
+      "c8b5dd3e60dbb9c0a10e9f284ce12ba1ba0b1e6bb525c1a0e1408f7ab2e1d273",
+      &upstream,
     );
+    let _unicode_xid = mock_sparse_crate_index_entry(
+      "unicode-xid",
+      "0.2.1",
+      "f7fe0bb3479651439c9112f72b6c505038574c9fbb575ed1bf3b797fa39dd564",
+      &upstream,
+    );
+
+    let dir = make_workspace_with_dependency();
+    let err = fetcher
+      .fetch_metadata(dir.as_ref(), None, Some(dir.as_ref().join("Cargo.lock")))
+      .unwrap_err()
+      .to_string();
+    assert!(err.starts_with("Checksum mismatch for proc-macro2 1.0.24: the lockfile lists"));
+  }
+
+  #[test]
+  fn test_offline_fetches_read_binary_dependencies_from_local_data() {
+    let (mut fetcher, mock_server, index_dir) = dummy_raze_metadata_fetcher();
+    fetcher.set_offline(true);
     fetcher.set_metadata_fetcher(Box::new(DummyCargoMetadataFetcher {
-      metadata_template: None,
+      metadata_template: Some(templates::DUMMY_BINARY_DEPENDENCY_REMOTE.to_string()),
     }));
     fetcher.set_lockfile_generator(Box::new(DummyLockfileGenerator {
       lockfile_contents: None,
     }));
 
-    (fetcher, mock_server, tempdir)
-  }
+    // Pre-populate the local index and crate cache with the archive the registry would serve
+    let mock = mock_remote_crate("some-binary-crate", "3.3.3", &mock_server);
+    mock_crate_index_entry(
+      index_dir.as_ref(),
+      "some-binary-crate",
+      "3.3.3",
+      &mock.checksum,
+    );
+    let cache_dir = TempDir::new().unwrap();
+    let cached_archive = cache_dir.as_ref().join("some-binary-crate-3.3.3.crate");
+    fs::copy(
+      mock.data_dir.as_ref().join("some-binary-crate.tar.gz"),
+      &cached_archive,
+    )
+    .unwrap();
+    fetcher.set_crate_cache_dir(cache_dir.as_ref());
+
+    let mut binary_deps = HashMap::new();
+    binary_deps.insert(
+      "some-binary-crate".to_string(),
+      cargo_toml::Dependency::Simple("3.3.3".to_string()),
+    );
 
-  pub fn dummy_raze_metadata() -> RazeMetadata {
     let dir = make_basic_workspace();
-    let (mut fetcher, _server, _index_dir) = dummy_raze_metadata_fetcher();
+    let raze_metadata = fetcher
+      .fetch_metadata(dir.as_ref(), Some(&binary_deps), None)
+      .unwrap();
+    for endpoint in mock.endpoints.iter() {
+      assert_eq!(endpoint.hits(), 0);
+    }
 
-    // Always render basic metadata
-    fetcher.set_metadata_fetcher(Box::new(DummyCargoMetadataFetcher {
-      metadata_template: Some(templates::BASIC_METADATA.to_string()),
-    }));
+    let mut settings = crate::settings::tests::dummy_raze_settings();
+    settings.genmode = crate::settings::GenMode::Remote;
+    settings.binary_deps = binary_deps.clone();
+    settings.target = None;
+    let planned_build = crate::planning::BuildPlannerImpl::new(raze_metadata, settings)
+      .plan_for_target()
+      .unwrap();
+    assert_eq!(
+      planned_build.crate_contexts[0].pkg_name,
+      "some-binary-crate"
+    );
 
-    fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap()
+    fs::remove_file(&cached_archive).unwrap();
+    let err = fetcher
+      .fetch_metadata(dir.as_ref(), Some(&binary_deps), None)
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      format!(
+        "Crate some-binary-crate@3.3.3 not found in local cache while offline, searched: {}",
+        cache_dir.as_ref().display()
+      )
+    );
+
+    fetcher.index_url = mock_sparse_index_url(&mock_server);
+    let err = fetcher
+      .fetch_metadata(dir.as_ref(), Some(&binary_deps), None)
+      .unwrap_err();
+    assert!(err
+      .to_string()
+      .starts_with("Crate some-binary-crate@3.3.3 not found in cargo's cache of the index"));
   }
 
   #[test]
-  fn test_cargo_subcommand_metadata_fetcher_works_without_lock() {
-    let dir = TempDir::new().unwrap();
-    let toml_path = dir.path().join("Cargo.toml");
-    let mut toml = File::create(&toml_path).unwrap();
-    toml.write_all(basic_toml_contents().as_bytes()).unwrap();
-
-    let mut fetcher = RazeMetadataFetcher::default();
+  fn test_offline_fetches_read_binary_dependencies_from_the_cargo_registry_cache() {
+    let (mut fetcher, mock_server, _index_dir) = dummy_raze_metadata_fetcher();
+    fetcher.set_offline(true);
+    fetcher.set_metadata_fetcher(Box::new(DummyCargoMetadataFetcher {
+      metadata_template: Some(templates::DUMMY_BINARY_DEPENDENCY_REMOTE.to_string()),
+    }));
     fetcher.set_lockfile_generator(Box::new(DummyLockfileGenerator {
       lockfile_contents: None,
     }));
-    fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap();
+    fetcher.index_url = mock_sparse_index_url(&mock_server);
+    let cargo_home = TempDir::new().unwrap();
+    fetcher.set_cargo_home(cargo_home.as_ref());
+
+    // Populate cargo's caches of the index and the archives as `cargo fetch` would
+    let mock = mock_remote_crate("some-binary-crate", "3.3.3", &mock_server);
+    let registry_dirname = format!("{}-1949cf8c6b5b557f", fetcher.index_url.host_str().unwrap());
+    let index_cache = cargo_home
+      .as_ref()
+      .join("registry/index")
+      .join(&registry_dirname)
+      .join(".cache")
+      .join(index_entry_path("some-binary-crate"));
+    fs::create_dir_all(index_cache.parent().unwrap()).unwrap();
+    let mut index_cache_content = vec![3, 2, 0, 0, 0];
+    index_cache_content.extend(b"etag: \"some-revision\"\0");
+    for version in ["3.3.2", "3.3.3"].iter() {
+      let checksum = match *version {
+        "3.3.3" => mock.checksum.clone(),
+        _ => "0".repeat(64),
+      };
+      index_cache_content.extend(format!("{}\0", version).as_bytes());
+      index_cache_content.extend(
+        format!(
+          "{}\0",
+          mock_crate_index_line("some-binary-crate", version, &checksum)
+        )
+        .as_bytes(),
+      );
+    }
+    fs::write(&index_cache, index_cache_content).unwrap();
+    let archive_cache = cargo_home
+      .as_ref()
+      .join("registry/cache")
+      .join(&registry_dirname);
+    fs::create_dir_all(&archive_cache).unwrap();
+    fs::copy(
+      mock.data_dir.as_ref().join("some-binary-crate.tar.gz"),
+      archive_cache.join("some-binary-crate-3.3.3.crate"),
+    )
+    .unwrap();
+
+    let mut binary_deps = HashMap::new();
+    binary_deps.insert(
+      "some-binary-crate".to_string(),
+      cargo_toml::Dependency::Simple("3.3.3".to_string()),
+    );
+
+    let dir = make_basic_workspace();
+    let raze_metadata = fetcher
+      .fetch_metadata(dir.as_ref(), Some(&binary_deps), None)
+      .unwrap();
+    assert_eq!(
+      raze_metadata.checksum_for("some-binary-crate", "3.3.3"),
+      Some(&mock.checksum)
+    );
   }
 
   #[test]
-  fn test_cargo_subcommand_metadata_fetcher_works_with_lock() {
-    let dir = TempDir::new().unwrap();
-    // Create Cargo.toml
-    {
-      let path = dir.path().join("Cargo.toml");
-      let mut toml = File::create(&path).unwrap();
-      toml.write_all(basic_toml_contents().as_bytes()).unwrap();
-    }
+  fn test_fetching_download_urls_of_alternative_registries() {
+    let registry_index = TempDir::new().unwrap();
+    fs::write(
+      registry_index.as_ref().join("config.json"),
+      r#"{"dl": "https://crates.example.com/private/{crate}/{version}.crate", "api": null}"#,
+    )
+    .unwrap();
 
-    // Create Cargo.lock
-    {
-      let path = dir.path().join("Cargo.lock");
-      let mut lock = File::create(&path).unwrap();
-      lock.write_all(basic_lock_contents().as_bytes()).unwrap();
-    }
+    let (mut fetcher, _server, _index_dir) = dummy_raze_metadata_fetcher();
+    fetcher.set_metadata_fetcher(Box::new(AlternativeRegistryMetadataFetcher {
+      fetcher: DummyCargoMetadataFetcher {
+        metadata_template: Some(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES.to_string()),
+      },
+      crate_names: vec!["cfg-if".to_owned()],
+      index_url: Url::parse(&format!("file://{}", registry_index.as_ref().display())).unwrap(),
+    }));
+    let raze_metadata = fetcher
+      .fetch_metadata(make_basic_workspace().as_ref(), None, None)
+      .unwrap();
 
-    let mut fetcher = RazeMetadataFetcher::default();
-    fetcher.set_lockfile_generator(Box::new(DummyLockfileGenerator {
-      lockfile_contents: None,
+    assert_eq!(
+      raze_metadata.download_url_for("cfg-if", "0.1.10"),
+      Some(&"https://crates.example.com/private/cfg-if/0.1.10.crate".to_owned())
+    );
+    // Crates from the default registry are downloaded through the `registry` setting
+    assert_eq!(raze_metadata.download_url_for("log", "0.4.13"), None);
+  }
+
+  #[test]
+  fn test_binary_dependency_checksums_are_read_from_sparse_indexes() {
+    let (mut fetcher, mock_server, _index_dir) = dummy_raze_metadata_fetcher();
+    fetcher.set_metadata_fetcher(Box::new(DummyCargoMetadataFetcher {
+      metadata_template: Some(templates::BASIC_METADATA.to_string()),
     }));
-    fetcher.fetch_metadata(dir.as_ref(), None, None).unwrap();
+    fetcher.index_url = mock_sparse_index_url(&mock_server);
+    let mock = mock_remote_crate("some-binary-crate", "3.3.3", &mock_server);
+    let index_entry =
+      mock_sparse_crate_index_entry("some-binary-crate", "3.3.3", &mock.checksum, &mock_server);
+
+    let mut binary_deps = HashMap::new();
+    binary_deps.insert(
+      "some-binary-crate".to_string(),
+      cargo_toml::Dependency::Simple("3.3.3".to_string()),
+    );
+
+    let raze_metadata = fetcher
+      .fetch_metadata(make_basic_workspace().as_ref(), Some(&binary_deps), None)
+      .unwrap();
+
+    index_entry.assert();
+    assert_eq!(
+      raze_metadata.checksum_for("some-binary-crate", "3.3.3"),
+      Some(&mock.checksum)
+    );
   }
 
   #[test]
-  fn test_cargo_subcommand_metadata_fetcher_handles_bad_files() {
-    let dir = TempDir::new().unwrap();
-    // Create Cargo.toml
-    {
-      let path = dir.path().join("Cargo.toml");
-      let mut toml = File::create(&path).unwrap();
-      toml.write_all(b"hello").unwrap();
-    }
+  fn test_fetching_download_urls_of_alternative_sparse_registries() {
+    let (mut fetcher, mock_server, _index_dir) = dummy_raze_metadata_fetcher();
+    let config = mock_sparse_index_config(
+      "https://crates.example.com/private/{crate}/{version}.crate",
+      &mock_server,
+    );
+    fetcher.set_metadata_fetcher(Box::new(AlternativeRegistryMetadataFetcher {
+      fetcher: DummyCargoMetadataFetcher {
+        metadata_template: Some(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES.to_string()),
+      },
+      crate_names: vec!["cfg-if".to_owned()],
+      index_url: mock_sparse_index_url(&mock_server),
+    }));
+    let raze_metadata = fetcher
+      .fetch_metadata(make_basic_workspace().as_ref(), None, None)
+      .unwrap();
 
-    let fetcher = RazeMetadataFetcher::default();
-    assert!(fetcher.fetch_metadata(dir.as_ref(), None, None).is_err());
+    config.assert();
+    assert_eq!(
+      raze_metadata.download_url_for("cfg-if", "0.1.10"),
+      Some(&"https://crates.example.com/private/cfg-if/0.1.10.crate".to_owned())
+    );
   }
 
   #[test]
-  fn test_fetching_src() {
-    let (fetcher, mock_server, _index_url) = dummy_raze_metadata_fetcher();
-    let mock = mock_remote_crate("fake-crate", "3.3.3", &mock_server);
+  fn test_cached_sparse_index_files_are_revalidated() {
+    let mock_server = MockServer::start();
+    let index_url = mock_sparse_index_url(&mock_server);
+    let cache_dir = TempDir::new().unwrap();
+    let client = HttpClient::new(&HttpConfig::default()).unwrap();
+    let line = mock_crate_index_line("log", "0.4.13", "abc123");
 
-    let path = fetcher
-      .fetch_crate_src(mock.data_dir.as_ref(), "fake-crate", "3.3.3")
-      .unwrap();
+    let mut index_entry = mock_server.mock(|when, then| {
+      when.method(GET).path("/index/3/l/log");
+      then
+        .status(200)
+        .header("ETag", "\"v1\"")
+        .body(format!("{}\n", line));
+    });
+    let content =
+      fetch_sparse_index_file(&client, &index_url, "3/l/log", Some(cache_dir.as_ref())).unwrap();
+    index_entry.assert();
+    let cache_path = sparse_index_cache_path(cache_dir.as_ref(), &index_url, "3/l/log");
+    assert_eq!(fs::read_to_string(&cache_path).unwrap(), content);
+    assert_eq!(
+      fs::read_to_string(etag_path(&cache_path)).unwrap(),
+      "\"v1\""
+    );
 
-    for mock in mock.endpoints.iter() {
-      mock.assert();
-    }
+    // Files which were not modified since are read from the cache
+    index_entry.delete();
+    let not_modified = mock_server.mock(|when, then| {
+      when
+        .method(GET)
+        .path("/index/3/l/log")
+        .header("If-None-Match", "\"v1\"");
+      then.status(304);
+    });
+    assert_eq!(
+      fetch_sparse_index_file(&client, &index_url, "3/l/log", Some(cache_dir.as_ref())).unwrap(),
+      content
+    );
+    not_modified.assert();
+  }
 
-    assert!(path.exists());
+  #[test]
+  fn test_index_entry_paths_follow_the_index_layout() {
+    assert_eq!(index_entry_path("a"), "1/a");
+    assert_eq!(index_entry_path("ab"), "2/ab");
+    assert_eq!(index_entry_path("abc"), "3/a/abc");
+    assert_eq!(index_entry_path("Serde"), "se/rd/serde");
+  }
 
-    // Ensure the name follows a consistent pattern: `{name}-{version}`
+  #[test]
+  fn test_registry_download_urls_expand_the_dl_markers() {
+    let checksum = "abc123".to_owned();
     assert_eq!(
-      mock.data_dir.into_path().join("fake-crate-3.3.3").as_path(),
-      path.as_path()
+      registry_download_url("https://example.com/dl", "serde", "1.0.0", None),
+      "https://example.com/dl/serde/1.0.0/download"
+    );
+    assert_eq!(
+      registry_download_url(
+        "https://example.com/{prefix}/{lowerprefix}/{crate}-{version}-{sha256-checksum}",
+        "Log",
+        "0.4.13",
+        Some(&checksum)
+      ),
+      "https://example.com/3/L/3/l/Log-0.4.13-abc123"
+    );
+    assert_eq!(
+      registry_download_url(
+        "https://example.com/{prefix}/{crate}",
+        "cfg-if",
+        "1.0.0",
+        None
+      ),
+      "https://example.com/cf/g-/cfg-if"
     );
-    assert!(path.join("Cargo.toml").exists());
-    assert!(path.join("Cargo.lock").exists());
-    assert!(path.join("test").exists());
   }
 
   #[test]
@@ -767,6 +4048,72 @@ pub mod tests {
     );
   }
 
+  #[test]
+  fn test_minimal_versions_resolve_the_lowest_compatible_version() {
+    let crate_dir = make_workspace(
+      indoc::indoc! { r#"
+        [package]
+        name = "mycrate"
+        version = "0.1.0"
+
+        [dependencies]
+        dep = "^1.2"
+      "# },
+      None,
+    );
+    fs::create_dir_all(crate_dir.as_ref().join("src")).unwrap();
+    File::create(crate_dir.as_ref().join("src/lib.rs")).unwrap();
+
+    // Serve crates.io from a directory holding two compatible releases of `dep`
+    fs::create_dir_all(crate_dir.as_ref().join(".cargo")).unwrap();
+    fs::write(
+      crate_dir.as_ref().join(".cargo/config.toml"),
+      indoc::indoc! { r#"
+        [source.crates-io]
+        replace-with = "vendored"
+
+        [source.vendored]
+        directory = "vendor"
+      "# },
+    )
+    .unwrap();
+    for version in &["1.2.0", "1.5.0"] {
+      let dep_dir = crate_dir.as_ref().join(format!("vendor/dep-{}", version));
+      fs::create_dir_all(dep_dir.join("src")).unwrap();
+      File::create(dep_dir.join("src/lib.rs")).unwrap();
+      fs::write(
+        dep_dir.join("Cargo.toml"),
+        format!("[package]\nname = \"dep\"\nversion = \"{}\"\n", version),
+      )
+      .unwrap();
+      fs::write(
+        dep_dir.join(".cargo-checksum.json"),
+        format!("{{\"files\":{{}},\"package\":\"{}\"}}", "0".repeat(64)),
+      )
+      .unwrap();
+    }
+
+    let resolved_version = |minimal_versions: bool| {
+      let mut fetcher = RazeMetadataFetcher::default();
+      fetcher.set_offline(true);
+      fetcher.set_minimal_versions(minimal_versions);
+      let lockfile = fetcher
+        .cargo_generate_lockfile(&None, crate_dir.as_ref())
+        .unwrap()
+        .unwrap();
+      lockfile
+        .packages
+        .iter()
+        .find(|package| package.name.as_str() == "dep")
+        .unwrap()
+        .version
+        .to_string()
+    };
+
+    assert_eq!(resolved_version(true), "1.2.0");
+    assert_eq!(resolved_version(false), "1.5.0");
+  }
+
   #[test]
   fn test_cargo_generate_lockfile_no_file() {
     let (mut fetcher, _mock_server, _index_url) = dummy_raze_metadata_fetcher();
@@ -0,0 +1,235 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+  path::{Path, PathBuf},
+  process::{Command, Output},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{planning::PlannedBuild, rendering::RenderDetails, settings::GenMode};
+
+/// The patch tool used when a crate does not configure a `patch_tool`
+const DEFAULT_PATCH_TOOL: &str = "patch";
+
+/// Applies the `patches` of the vendored crates of `planned_build` to their sources in the vendor
+/// directory, as `http_archive` applies them to the sources of remote crates. Patches which are
+/// applied already, eg: by an earlier run, are skipped so the sources are only patched again once
+/// they are re-vendored.
+///
+/// Returns the path of each patch that was applied along with the directory it was applied to.
+pub fn apply_vendored_patches(
+  render_details: &RenderDetails,
+  planned_build: &PlannedBuild,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+  let output_dir = render_details.bazel_root.join(&render_details.path_prefix);
+  let mut applied_patches = Vec::new();
+  for package in planned_build.crate_contexts.iter().filter(|package| {
+    package.raze_settings.gen_mode == Some(GenMode::Vendored)
+      && !package.raze_settings.patches.is_empty()
+  }) {
+    // UNWRAP: The build file of a vendored crate is always inside of its directory
+    let crate_dir = output_dir
+      .join(&package.expected_build_path)
+      .parent()
+      .unwrap()
+      .to_path_buf();
+    let patch_tool = package
+      .raze_settings
+      .patch_tool
+      .as_deref()
+      .unwrap_or(DEFAULT_PATCH_TOOL);
+    // Bazel defaults to `-p0` as well
+    let patch_args = match package.raze_settings.patch_args.is_empty() {
+      true => vec!["-p0".to_owned()],
+      false => package.raze_settings.patch_args.clone(),
+    };
+
+    for label in package.raze_settings.patches.iter() {
+      let patch = render_details.bazel_root.join(patch_path(label)?);
+      let run_patch_tool = |extra_args: &[&str]| -> Result<Output> {
+        Command::new(patch_tool)
+          .args(&patch_args)
+          .args(extra_args)
+          .arg("-i")
+          .arg(&patch)
+          .current_dir(&crate_dir)
+          .output()
+          .with_context(|| format!("Failed to run `{}`", patch_tool))
+      };
+
+      // A patch that can be reversed has been applied already
+      if run_patch_tool(&["-R", "--dry-run", "-f", "-s"])?
+        .status
+        .success()
+      {
+        continue;
+      }
+      let output = run_patch_tool(&["-f", "-s"])?;
+      if !output.status.success() {
+        return Err(anyhow!(
+          "Failed to apply {} to {}:\n{}{}",
+          patch.display(),
+          crate_dir.display(),
+          String::from_utf8_lossy(&output.stdout),
+          String::from_utf8_lossy(&output.stderr)
+        ));
+      }
+      applied_patches.push((patch, crate_dir.clone()));
+    }
+  }
+
+  Ok(applied_patches)
+}
+
+/// Maps the label of a patch file in the main repository to its path relative to the Bazel
+/// workspace root, eg: `//patches:fix.patch` to `patches/fix.patch`.
+fn patch_path(label: &str) -> Result<PathBuf> {
+  let relative_label = label
+    .strip_prefix('@')
+    .unwrap_or(label)
+    .strip_prefix("//")
+    .ok_or_else(|| {
+      anyhow!(
+        "The patches of vendored crates must be labels of files in the main repository, eg: \
+         `//patches:fix.patch`. Found `{}`",
+        label
+      )
+    })?;
+
+  Ok(match relative_label.split_once(':') {
+    Some((package, name)) => Path::new(package).join(name),
+    // `//patches/fix` is short for `//patches/fix:fix`
+    None => {
+      let name = relative_label.rsplit('/').next().unwrap_or(relative_label);
+      Path::new(relative_label).join(name)
+    },
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::HashMap, fs};
+
+  use semver::VersionReq;
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::{
+    planning::{BuildPlanner, BuildPlannerImpl},
+    settings::{tests::dummy_raze_settings, CrateSettings},
+    testing::*,
+    util::PlatformDetails,
+  };
+
+  #[test]
+  fn test_patch_paths_are_resolved_from_labels() {
+    assert_eq!(
+      patch_path("//patches:fix.patch").unwrap(),
+      PathBuf::from("patches/fix.patch")
+    );
+    assert_eq!(
+      patch_path("@//third_party/patches:fix.patch").unwrap(),
+      PathBuf::from("third_party/patches/fix.patch")
+    );
+    assert_eq!(
+      patch_path("//patches/fix.patch").unwrap(),
+      PathBuf::from("patches/fix.patch/fix.patch")
+    );
+    assert!(patch_path("@other_repo//patches:fix.patch").is_err());
+    assert!(patch_path("fix.patch").is_err());
+  }
+
+  #[test]
+  fn test_patches_are_applied_to_vendored_crates_once() {
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Vendored;
+    let mut versions = HashMap::new();
+    versions.insert(
+      VersionReq::parse("0.4.13").unwrap(),
+      CrateSettings {
+        patches: vec!["//patches:log.patch".to_owned()],
+        patch_args: vec!["-p1".to_owned()],
+        ..Default::default()
+      },
+    );
+    settings.crates.insert("log".to_owned(), versions);
+    let planned_build = BuildPlannerImpl::new(
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings.clone(),
+    )
+    .plan_build(Some(PlatformDetails::new(
+      "some_target_triple".to_owned(),
+      Vec::new(), /* attrs */
+    )))
+    .unwrap();
+
+    let bazel_root = TempDir::new().unwrap();
+    let crate_dir = bazel_root.as_ref().join("cargo/vendor/log-0.4.13");
+    fs::create_dir_all(&crate_dir).unwrap();
+    fs::write(
+      crate_dir.join("build.rs"),
+      "fn main() {\n    broken();\n}\n",
+    )
+    .unwrap();
+    fs::create_dir_all(bazel_root.as_ref().join("patches")).unwrap();
+    fs::write(
+      bazel_root.as_ref().join("patches/log.patch"),
+      indoc::indoc! { r#"
+        --- a/build.rs
+        +++ b/build.rs
+        @@ -1,3 +1,3 @@
+         fn main() {
+        -    broken();
+        +    fixed();
+         }
+      "# },
+    )
+    .unwrap();
+
+    let render_details = RenderDetails::new(
+      &settings,
+      PathBuf::from("/some/cargo/root"),
+      bazel_root.as_ref().to_path_buf(),
+    );
+    assert_eq!(
+      apply_vendored_patches(&render_details, &planned_build).unwrap(),
+      vec![(
+        bazel_root.as_ref().join("patches/log.patch"),
+        crate_dir.clone()
+      )]
+    );
+    assert_eq!(
+      fs::read_to_string(crate_dir.join("build.rs")).unwrap(),
+      "fn main() {\n    fixed();\n}\n"
+    );
+
+    // The patch is recognized as applied when planning again
+    assert!(apply_vendored_patches(&render_details, &planned_build)
+      .unwrap()
+      .is_empty());
+    assert_eq!(
+      fs::read_to_string(crate_dir.join("build.rs")).unwrap(),
+      "fn main() {\n    fixed();\n}\n"
+    );
+
+    // Patches which don't apply are reported
+    fs::write(crate_dir.join("build.rs"), "fn main() {}\n").unwrap();
+    let message = apply_vendored_patches(&render_details, &planned_build)
+      .unwrap_err()
+      .to_string();
+    assert!(message.contains("Failed to apply"), "{}", message);
+  }
+}
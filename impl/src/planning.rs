@@ -21,6 +21,7 @@ use cargo_lock::Lockfile;
 
 use crate::{
   context::{CrateContext, WorkspaceContext},
+  incremental::CrateCache,
   metadata::RazeMetadata,
   settings::RazeSettings,
   util::PlatformDetails,
@@ -34,6 +35,8 @@ use subplanners::WorkspaceSubplanner;
 pub struct PlannedBuild {
   pub workspace_context: WorkspaceContext,
   pub crate_contexts: Vec<CrateContext>,
+  // The members of the cargo workspace, when they are rendered into their own packages
+  pub workspace_member_contexts: Vec<CrateContext>,
   pub lockfile: Option<Lockfile>,
 }
 
@@ -48,6 +51,7 @@ pub trait BuildPlanner {
 pub struct BuildPlannerImpl {
   metadata: RazeMetadata,
   settings: RazeSettings,
+  crate_cache: Option<CrateCache>,
 }
 
 impl BuildPlanner for BuildPlannerImpl {
@@ -62,6 +66,7 @@ impl BuildPlanner for BuildPlannerImpl {
       settings: &self.settings,
       platform_details: &platform_details,
       metadata: &self.metadata,
+      crate_cache: self.crate_cache.as_ref(),
     };
 
     workspace_subplanner.produce_planned_build()
@@ -73,26 +78,51 @@ impl BuildPlannerImpl {
     Self {
       metadata,
       settings,
+      crate_cache: None,
     }
   }
+
+  /// Reuse the contexts planned for unchanged crates by previous runs from `crate_cache`.
+  pub fn set_crate_cache(&mut self, crate_cache: CrateCache) {
+    self.crate_cache = Some(crate_cache);
+  }
+
+  /// Plans the build for the `target` of the settings, if any, whose platform details are queried
+  /// from rustc.
+  pub fn plan_for_target(&self) -> Result<PlannedBuild> {
+    let platform_details = match &self.settings.target {
+      Some(target) => Some(PlatformDetails::new_using_rustc(target)?),
+      None => None,
+    };
+
+    self.plan_build(platform_details)
+  }
 }
 
 #[cfg(test)]
 mod tests {
-  use std::{collections::HashMap, path::PathBuf};
+  use std::{collections::HashMap, fs, path::PathBuf};
 
   use crate::{
-    metadata::tests::{
-      dummy_raze_metadata, dummy_raze_metadata_fetcher, DummyCargoMetadataFetcher,
+    context::{BuildableDependency, DependencyAlias},
+    metadata::{
+      tests::{
+        dummy_raze_metadata, dummy_raze_metadata_fetcher, DummyCargoMetadataFetcher,
+        DummyLockfileGenerator,
+      },
+      RazeMetadataFetcher,
     },
-    settings::{tests::*, GenMode},
+    rendering::{bazel::BazelRenderer, render_files, BuildRenderer, FileOutputs, RenderDetails},
+    settings::{tests::*, CrateSettings, CustomTarget, GenMode},
     testing::*,
+    util,
   };
 
   use super::*;
-  use cargo_metadata::PackageId;
+  use cargo_metadata::{DependencyKind, PackageId};
   use indoc::indoc;
   use semver::{Version, VersionReq};
+  use tempfile::TempDir;
 
   fn dummy_resolve_dropping_metadata() -> RazeMetadata {
     let raze_metadata = dummy_raze_metadata();
@@ -104,6 +134,9 @@ mod tests {
       cargo_workspace_root: PathBuf::from("/some/crate"),
       lockfile: None,
       checksums: HashMap::new(),
+      locked_features: HashMap::new(),
+      download_urls: HashMap::new(),
+      git_fallbacks: HashMap::new(),
     }
   }
 
@@ -156,6 +189,18 @@ mod tests {
     );
   }
 
+  /// Renders `planned_build` as remote crates
+  fn render_remote_planned_build(planned_build: &PlannedBuild) -> Vec<FileOutputs> {
+    let render_details = RenderDetails::new(
+      &dummy_raze_settings(),
+      PathBuf::from("/some/cargo/root"),
+      PathBuf::from("/some/bazel/root"),
+    );
+    BazelRenderer::new()
+      .render_remote_planned_build(&render_details, planned_build)
+      .unwrap()
+  }
+
   fn dummy_workspace_crate_metadata(metadata_template: &str) -> RazeMetadata {
     let dir = make_basic_workspace();
     let (mut fetcher, _server, _index_dir) = dummy_raze_metadata_fetcher();
@@ -191,6 +236,9 @@ mod tests {
       cargo_workspace_root: PathBuf::from("/some/crate"),
       lockfile: None,
       checksums: HashMap::new(),
+      locked_features: HashMap::new(),
+      download_urls: HashMap::new(),
+      git_fallbacks: HashMap::new(),
     }
   }
 
@@ -264,14 +312,2982 @@ mod tests {
       "old_log_"
     );
   }
+  #[test]
+  fn test_plan_build_scopes_renames_to_the_renaming_crate() {
+    // `cargo-raze-alias-test` renames `log 0.4.13`, which `log 0.3.9` depends on by its real name
+    let mut raze_metadata =
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "cargo-raze-alias-test" {
+        package.dependencies[0].rename = Some("new-log".to_owned());
+      }
+    }
+    let resolve = raze_metadata.metadata.resolve.as_mut().unwrap();
+    for node in resolve.nodes.iter_mut() {
+      if node.id.repr.starts_with("cargo-raze-alias-test ") {
+        for node_dep in node.deps.iter_mut() {
+          if node_dep.pkg.repr.starts_with("log 0.4.13 ") {
+            node_dep.name = "new_log".to_owned();
+          }
+        }
+      }
+    }
+
+    let settings = dummy_raze_settings();
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let context = |name: &str, version: Version| {
+      planned_build
+        .crate_contexts
+        .iter()
+        .find(|ctx| ctx.pkg_name == name && ctx.pkg_version == version)
+        .unwrap()
+    };
+
+    let alias_test = context("cargo-raze-alias-test", Version::new(0, 1, 0));
+    assert!(alias_test
+      .default_deps
+      .aliased_dependencies
+      .contains(&DependencyAlias {
+        target: "@raze_test__log__0_4_13//:log".to_owned(),
+        alias: "new_log".to_owned(),
+      }));
+    let old_log = context("log", Version::new(0, 3, 9));
+    assert!(old_log
+      .default_deps
+      .dependencies
+      .iter()
+      .any(|dep| dep.buildable_target == "@raze_test__log__0_4_13//:log"));
+    assert!(old_log.default_deps.aliased_dependencies.is_empty());
+    assert!(old_log
+      .targeted_deps
+      .iter()
+      .all(|targeted| targeted.deps.aliased_dependencies.is_empty()));
+
+    let root = PathBuf::from("/some/cargo/root");
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let file_outputs = render_files(&settings, &render_details, &planned_build).unwrap();
+    for (path, contents) in file_outputs.iter() {
+      let declares_rename = path.ends_with("remote/BUILD.cargo-raze-alias-test-0.1.0.bazel");
+      assert_eq!(
+        contents.contains("\"new_log\""),
+        declares_rename,
+        "{}:\n{}",
+        path.display(),
+        contents
+      );
+    }
+  }
+
+  #[test]
+  fn test_plan_build_resolves_renames_colliding_with_real_crate_names_to_the_renamed_crate() {
+    // `cargo-raze-alias-test` renames `log 0.3.9` to `cfg-if`, while the real `cfg-if` is in the
+    // graph as a dependency of `log 0.4.13`
+    let mut raze_metadata =
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "cargo-raze-alias-test" {
+        for dep in package.dependencies.iter_mut() {
+          if dep.rename.is_some() {
+            dep.rename = Some("cfg-if".to_owned());
+          }
+        }
+      }
+    }
+    let resolve = raze_metadata.metadata.resolve.as_mut().unwrap();
+    for node in resolve.nodes.iter_mut() {
+      if node.id.repr.starts_with("cargo-raze-alias-test ") {
+        for node_dep in node.deps.iter_mut() {
+          if node_dep.pkg.repr.starts_with("log 0.3.9 ") {
+            node_dep.name = "cfg_if".to_owned();
+          }
+        }
+      }
+    }
+
+    let settings = dummy_raze_settings();
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let alias_test = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "cargo-raze-alias-test")
+      .unwrap();
+
+    assert_eq!(
+      alias_test.default_deps.aliased_dependencies,
+      vec![DependencyAlias {
+        target: "@raze_test__log__0_3_9//:log".to_owned(),
+        alias: "cfg_if".to_owned(),
+      }]
+    );
+    assert!(!alias_test
+      .default_deps
+      .dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+
+    let root = PathBuf::from("/some/cargo/root");
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let files = render_files(&settings, &render_details, &planned_build).unwrap();
+    let (_, build_file) = files
+      .iter()
+      .find(|(path, _)| path.ends_with("remote/BUILD.cargo-raze-alias-test-0.1.0.bazel"))
+      .unwrap();
+    assert!(build_file.contains(concat!(
+      "    aliases = {\n",
+      "        \"@raze_test__log__0_3_9//:log\": \"cfg_if\",\n",
+      "    },\n",
+    )));
+    assert!(!build_file.contains("@raze_test__cfg_if__0_1_10//:cfg_if"));
+  }
+
+  #[test]
+  fn test_plan_build_scopes_build_dependency_renames_to_the_build_script() {
+    // `log 0.3.9` renames its build-dependency on `log 0.4.13`, which also has a build script
+    let mut raze_metadata =
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    let build_script = raze_metadata
+      .metadata
+      .packages
+      .iter()
+      .find(|package| package.name == "log" && package.version == Version::new(0, 4, 13))
+      .and_then(|package| {
+        package
+          .targets
+          .iter()
+          .find(|target| target.kind.iter().any(|kind| kind == "custom-build"))
+      })
+      .cloned()
+      .unwrap();
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 3, 9) {
+        package.dependencies[0].kind = DependencyKind::Build;
+        package.dependencies[0].rename = Some("build-log".to_owned());
+        package.targets.push(build_script.clone());
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    settings.crates.insert(
+      "log".to_owned(),
+      vec![(
+        VersionReq::parse("*").unwrap(),
+        CrateSettings {
+          gen_buildrs: Some(true),
+          ..Default::default()
+        },
+      )]
+      .into_iter()
+      .collect(),
+    );
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    for ctx in planned_build.crate_contexts.iter() {
+      let expected_aliases = if ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 3, 9) {
+        vec![DependencyAlias {
+          target: "@raze_test__log__0_4_13//:log".to_owned(),
+          alias: "build_log".to_owned(),
+        }]
+      } else {
+        Vec::new()
+      };
+      assert_eq!(
+        ctx.default_deps.build_aliased_dependencies, expected_aliases,
+        "{}",
+        ctx.pkg_name
+      );
+      assert!(ctx
+        .default_deps
+        .aliased_dependencies
+        .iter()
+        .all(|alias| alias.alias != "build_log"));
+    }
+
+    let root = PathBuf::from("/some/cargo/root");
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let file_outputs = render_files(&settings, &render_details, &planned_build).unwrap();
+    for (path, contents) in file_outputs.iter() {
+      let build_script_rule = contents
+        .split("cargo_build_script(")
+        .nth(1)
+        .and_then(|rule| rule.split("\n)\n").next())
+        .unwrap_or_default();
+      let declares_rename = path.ends_with("remote/BUILD.log-0.3.9.bazel");
+      assert_eq!(
+        build_script_rule.contains("\"@raze_test__log__0_4_13//:log\": \"build_log\""),
+        declares_rename,
+        "{}:\n{}",
+        path.display(),
+        contents
+      );
+      assert_eq!(
+        contents.matches("build_log").count(),
+        declares_rename as usize,
+        "{}:\n{}",
+        path.display(),
+        contents
+      );
+    }
+  }
+
+  #[test]
+  fn test_plan_build_resolves_renames_matching_several_versions() {
+    let mut raze_metadata =
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    // `old_log_` is renamed with a requirement that also matches the version renamed `new-log`
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "cargo-raze-alias-test" {
+        package.dependencies[0].rename = Some("new-log".to_owned());
+        package.dependencies[1].req = VersionReq::parse(">=0.3").unwrap();
+      }
+    }
+    let resolve = raze_metadata.metadata.resolve.as_mut().unwrap();
+    for node in resolve.nodes.iter_mut() {
+      if node.id.repr.starts_with("cargo-raze-alias-test ") {
+        for node_dep in node.deps.iter_mut() {
+          node_dep.name = if node_dep.pkg.repr.starts_with("log 0.3.9 ") {
+            "old_log_".to_owned()
+          } else {
+            "new_log".to_owned()
+          };
+        }
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    let planned_build = BuildPlannerImpl::new(raze_metadata.clone(), settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    let alias_test = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "cargo-raze-alias-test")
+      .unwrap();
+    assert_eq!(
+      alias_test.default_deps.aliased_dependencies,
+      vec![
+        DependencyAlias {
+          target: "@raze_test__log__0_3_9//:log".to_owned(),
+          alias: "old_log_".to_owned(),
+        },
+        DependencyAlias {
+          target: "@raze_test__log__0_4_13//:log".to_owned(),
+          alias: "new_log".to_owned(),
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn test_plan_build_aliases_each_renamed_version_of_a_dependency() {
+    let mut raze_metadata =
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    // Rename both versions of `log`, along with a dev-dependency on one of them
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "cargo-raze-alias-test" {
+        let mut dev_dep = package.dependencies[0].clone();
+        dev_dep.kind = DependencyKind::Development;
+        dev_dep.rename = Some("dev_log".to_owned());
+        package.dependencies[0].rename = Some("new-log".to_owned());
+        package.dependencies.push(dev_dep);
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    let planned_build = BuildPlannerImpl::new(raze_metadata.clone(), settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    let alias_test = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "cargo-raze-alias-test")
+      .unwrap();
+    assert_eq!(
+      alias_test.default_deps.aliased_dependencies,
+      vec![
+        DependencyAlias {
+          target: "@raze_test__log__0_3_9//:log".to_owned(),
+          alias: "old_log_".to_owned(),
+        },
+        DependencyAlias {
+          target: "@raze_test__log__0_4_13//:log".to_owned(),
+          alias: "new_log".to_owned(),
+        },
+      ]
+    );
+
+    let render_details = RenderDetails::new(
+      &settings,
+      raze_metadata.cargo_workspace_root.clone(),
+      raze_metadata.cargo_workspace_root.clone(),
+    );
+    let files = render_files(&settings, &render_details, &planned_build).unwrap();
+    let (_, build_file) = files
+      .iter()
+      .find(|(path, _)| path.ends_with("remote/BUILD.cargo-raze-alias-test-0.1.0.bazel"))
+      .unwrap();
+    assert!(build_file.contains(concat!(
+      "    aliases = {\n",
+      "        \"@raze_test__log__0_3_9//:log\": \"old_log_\",\n",
+      "        \"@raze_test__log__0_4_13//:log\": \"new_log\",\n",
+      "    },\n",
+    )));
+  }
+
   #[test]
   fn test_plan_build_produces_proc_macro_dependencies() {
     let mut settings = dummy_raze_settings();
     settings.genmode = GenMode::Remote;
 
     let planner = BuildPlannerImpl::new(
-      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_PROC_MACRO_DEPENDENCIES),
-      settings,
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_PROC_MACRO_DEPENDENCIES),
+      settings,
+    );
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    let serde = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "serde")
+      .unwrap();
+
+    let serde_derive_proc_macro_deps: Vec<_> = serde
+      .default_deps
+      .proc_macro_dependencies
+      .iter()
+      .filter(|dep| dep.name == "serde_derive")
+      .collect();
+    assert_eq!(serde_derive_proc_macro_deps.len(), 1);
+
+    let serde_derive_normal_deps: Vec<_> = serde
+      .default_deps
+      .dependencies
+      .iter()
+      .filter(|dep| dep.name == "serde_derive")
+      .collect();
+    assert_eq!(serde_derive_normal_deps.len(), 0);
+  }
+
+  #[test]
+  fn test_plan_build_produces_build_proc_macro_dependencies() {
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+
+    let planner = BuildPlannerImpl::new(
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_BUILD_PROC_MACRO_DEPENDENCIES),
+      settings,
+    );
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    let markup = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "markup5ever")
+      .unwrap();
+
+    let markup_proc_macro_deps: Vec<_> = markup
+      .default_deps
+      .proc_macro_dependencies
+      .iter()
+      .filter(|dep| dep.name == "serde_derive")
+      .collect();
+    assert_eq!(markup_proc_macro_deps.len(), 0);
+
+    let markup_build_proc_macro_deps: Vec<_> = markup
+      .default_deps
+      .build_proc_macro_dependencies
+      .iter()
+      .filter(|dep| dep.name == "serde_derive")
+      .collect();
+    assert_eq!(markup_build_proc_macro_deps.len(), 1);
+  }
+
+  #[test]
+  fn test_subplan_produces_crate_root_with_forward_slash() {
+    let planner = BuildPlannerImpl::new(
+      dummy_workspace_crate_metadata(templates::SUBPLAN_PRODUCES_CRATE_ROOT_WITH_FORWARD_SLASH),
+      dummy_raze_settings(),
+    );
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    assert_eq!(
+      planned_build.crate_contexts[0].targets[0].path,
+      "src/lib.rs"
+    );
+  }
+
+  #[test]
+  fn test_plan_build_scopes_build_script_srcs() {
+    let planner = BuildPlannerImpl::new(
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      dummy_raze_settings(),
+    );
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    assert!(log.build_script_target.is_some());
+    assert_eq!(log.build_script_srcs, vec!["build.rs".to_owned()]);
+  }
+
+  #[test]
+  fn test_plan_build_adds_manifest_includes_to_build_script_srcs() {
+    let crate_dir = tempfile::TempDir::new().unwrap();
+    std::fs::write(
+      crate_dir.as_ref().join("build.rs"),
+      "fn main() {\n    println!(\"cargo:rustc-cfg=has_header\");\n}\n",
+    )
+    .unwrap();
+    std::fs::write(
+      crate_dir.as_ref().join("Cargo.toml"),
+      indoc! { r#"
+        [package]
+        name = "log"
+        version = "0.4.13"
+        include = ["build.rs", "/include/log.h", "templates/"]
+      "# },
+    )
+    .unwrap();
+
+    // Point `log 0.4.13` at a manifest listing the files its build script reads
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    relocate_package_root(&mut raze_metadata, "log", "0.4.13", crate_dir.as_ref());
+
+    let planned_build = BuildPlannerImpl::new(raze_metadata, dummy_raze_settings())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    // The srcs stay narrow: the build script and the included files, but not the whole crate
+    assert!(log.build_script_target.is_some());
+    assert_eq!(
+      log.build_script_srcs,
+      vec![
+        "build.rs".to_owned(),
+        "**/build.rs".to_owned(),
+        "**/build.rs/**".to_owned(),
+        "include/log.h".to_owned(),
+        "include/log.h/**".to_owned(),
+        "**/templates/**".to_owned(),
+      ]
+    );
+    assert!(log.build_script_srcs_exclude.is_empty());
+  }
+
+  fn build_script_srcs_with_includes(includes: &[&str]) -> (Vec<String>, Vec<String>) {
+    let includes: Vec<String> = includes.iter().map(|include| include.to_string()).collect();
+    subplanners::produce_build_script_srcs("build.rs", &includes)
+  }
+
+  #[test]
+  fn test_build_script_srcs_match_unanchored_includes_at_any_depth() {
+    let (srcs, excludes) = build_script_srcs_with_includes(&["*.h", "gen.rs"]);
+    assert_eq!(
+      srcs,
+      vec![
+        "build.rs",
+        "**/*.h",
+        "**/*.h/**",
+        "**/gen.rs",
+        "**/gen.rs/**"
+      ]
+    );
+    assert!(excludes.is_empty());
+  }
+
+  #[test]
+  fn test_build_script_srcs_anchor_includes_containing_a_slash() {
+    let (srcs, _) = build_script_srcs_with_includes(&["/build.rs", "build/*.rs", "/src/**"]);
+    assert_eq!(
+      srcs,
+      vec![
+        "build.rs",
+        "build.rs/**",
+        "build/*.rs",
+        "build/*.rs/**",
+        "src/**"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_build_script_srcs_include_the_files_within_included_directories() {
+    let (srcs, _) = build_script_srcs_with_includes(&["/src", "include/", "/vendor/"]);
+    assert_eq!(
+      srcs,
+      vec!["build.rs", "src", "src/**", "**/include/**", "vendor/**"]
+    );
+  }
+
+  #[test]
+  fn test_build_script_srcs_exclude_negated_includes() {
+    let (srcs, excludes) =
+      build_script_srcs_with_includes(&["/src/", "!/src/tests/", "!*.orig", "\\!important"]);
+    assert_eq!(
+      srcs,
+      vec!["build.rs", "src/**", "**/!important", "**/!important/**"]
+    );
+    assert_eq!(excludes, vec!["src/tests/**", "**/*.orig", "**/*.orig/**"]);
+  }
+
+  #[test]
+  fn test_build_script_srcs_skip_comments_and_empty_includes() {
+    let (srcs, excludes) = build_script_srcs_with_includes(&["# generated", "", "/"]);
+    assert_eq!(srcs, vec!["build.rs"]);
+    assert!(excludes.is_empty());
+  }
+
+  #[test]
+  fn test_build_script_srcs_widen_to_the_crate_for_untranslatable_includes() {
+    let (srcs, excludes) = build_script_srcs_with_includes(&["src/", "data/file?.txt", "!*.orig"]);
+    assert_eq!(srcs, vec!["build.rs", "**"]);
+    assert!(excludes.is_empty());
+  }
+
+  #[test]
+  fn test_plan_build_skips_build_scripts_only_emitting_rerun_if_directives() {
+    let crate_dir = tempfile::TempDir::new().unwrap();
+    std::fs::write(
+      crate_dir.as_ref().join("build.rs"),
+      indoc! { r#"
+        fn main() {
+            println!("cargo:rerun-if-changed=build.rs");
+            println!("cargo:rerun-if-env-changed=LOG_LEVEL");
+        }
+      "# },
+    )
+    .unwrap();
+
+    // Point `log 0.4.13` at a no-op build script
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    relocate_package_root(&mut raze_metadata, "log", "0.4.13", crate_dir.as_ref());
+
+    let planned_build = BuildPlannerImpl::new(raze_metadata, dummy_raze_settings())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    assert!(log.build_script_target.is_none());
+    assert!(log
+      .targets
+      .iter()
+      .all(|target| target.kind != "custom-build"));
+
+    let file_outputs = render_remote_planned_build(&planned_build);
+    let log_build = file_outputs
+      .iter()
+      .find(|output| output.path.ends_with("remote/BUILD.log-0.4.13.bazel"))
+      .unwrap();
+
+    assert!(!log_build.contents.contains("cargo_build_script("));
+    assert!(!log_build.contents.contains("_build_script\""));
+  }
+
+  #[test]
+  fn test_plan_build_aggregates_license_texts_into_a_notice_file() {
+    // `log 0.4.13` ships two license files, while `log 0.3.9` points at its own `license-file`
+    let new_log_dir = tempfile::TempDir::new().unwrap();
+    fs::write(
+      new_log_dir.as_ref().join("LICENSE-APACHE"),
+      "Apache License text\n",
+    )
+    .unwrap();
+    fs::write(
+      new_log_dir.as_ref().join("LICENSE-MIT"),
+      "MIT License text\n",
+    )
+    .unwrap();
+    fs::write(new_log_dir.as_ref().join("README.md"), "Not a license\n").unwrap();
+    let old_log_dir = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(old_log_dir.as_ref().join("docs")).unwrap();
+    fs::write(
+      old_log_dir.as_ref().join("docs/TERMS"),
+      "Custom license text\n",
+    )
+    .unwrap();
+    fs::write(
+      old_log_dir.as_ref().join("LICENSE"),
+      "Unused license text\n",
+    )
+    .unwrap();
+
+    let mut raze_metadata =
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    relocate_package_root(&mut raze_metadata, "log", "0.4.13", new_log_dir.as_ref());
+    relocate_package_root(&mut raze_metadata, "log", "0.3.9", old_log_dir.as_ref());
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 3, 9) {
+        package.license_file = Some("docs/TERMS".into());
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    settings.notice_file = true;
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    let root = PathBuf::from("/some/cargo/root");
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let file_outputs = render_files(&settings, &render_details, &planned_build).unwrap();
+    let notice = file_outputs
+      .iter()
+      .find(|(path, _)| path.ends_with("NOTICE"))
+      .map(|(_, contents)| contents)
+      .unwrap();
+
+    let rule = "=".repeat(80);
+    assert!(
+      notice.contains(&format!(
+        "{rule}\nlog 0.3.9\n{rule}\n\nCustom license text\n",
+        rule = rule
+      )),
+      "{}",
+      notice
+    );
+    assert!(
+      notice.contains(&format!(
+        "{rule}\nlog 0.4.13\n{rule}\n\nApache License text\n\nMIT License text\n",
+        rule = rule
+      )),
+      "{}",
+      notice
+    );
+    assert!(!notice.contains("Unused license text"));
+    assert!(!notice.contains("Not a license"));
+    // Crates without license texts available locally have no entry
+    assert_eq!(notice.matches(&rule).count(), 4, "{}", notice);
+  }
+
+  #[test]
+  fn test_plan_build_translates_lints_tables_into_rustc_flags() {
+    let crate_dir = tempfile::TempDir::new().unwrap();
+    std::fs::write(
+      crate_dir.as_ref().join("Cargo.toml"),
+      indoc! { r#"
+        [package]
+        name = "log"
+        version = "0.4.13"
+
+        [lints.rust]
+        unsafe_code = "forbid"
+        unused = { level = "allow", priority = -1 }
+        missing_docs = "warn"
+
+        [lints.clippy]
+        enum_glob_use = { level = "deny" }
+      "# },
+    )
+    .unwrap();
+
+    // Point `log 0.4.13` at a manifest with a `[lints]` table
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    relocate_package_root(&mut raze_metadata, "log", "0.4.13", crate_dir.as_ref());
+
+    let settings = dummy_raze_settings();
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    // The lower priority `unused` group comes first so the other lints override it
+    assert_eq!(
+      log.lint_flags,
+      vec![
+        "-Aunused",
+        "-Dclippy::enum_glob_use",
+        "-Wmissing_docs",
+        "-Funsafe_code",
+      ]
+    );
+
+    let root = PathBuf::from("/some/cargo/root");
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let file_outputs = render_files(&settings, &render_details, &planned_build).unwrap();
+    let log_build = file_outputs
+      .iter()
+      .find(|(path, _)| path.ends_with("remote/BUILD.log-0.4.13.bazel"))
+      .map(|(_, contents)| contents)
+      .unwrap();
+    assert!(log_build.contains(concat!(
+      "    rustc_flags = [\n",
+      "        \"--cap-lints=allow\",\n",
+      "        \"-Aunused\",\n",
+      "        \"-Dclippy::enum_glob_use\",\n",
+      "        \"-Wmissing_docs\",\n",
+      "        \"-Funsafe_code\",\n",
+    )));
+  }
+
+  #[test]
+  fn test_plan_build_remaps_crate_source_paths() {
+    let build_file_of_log = |genmode: GenMode| {
+      let mut settings = dummy_raze_settings();
+      settings.genmode = genmode;
+      settings.remap_path_prefix = Some("/cargo-raze/{name}-{version}".to_owned());
+      let planned_build = BuildPlannerImpl::new(
+        dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+        settings.clone(),
+      )
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+      let root = PathBuf::from("/some/cargo/root");
+      let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+      render_files(&settings, &render_details, &planned_build)
+        .unwrap()
+        .into_iter()
+        .find(|(path, _)| {
+          path.ends_with("remote/BUILD.log-0.4.13.bazel")
+            || path.ends_with("vendor/log-0.4.13/BUILD")
+        })
+        .map(|(_, contents)| contents)
+        .unwrap()
+    };
+
+    assert!(build_file_of_log(GenMode::Remote).contains(concat!(
+      "        \"--cap-lints=allow\",\n",
+      "        \"--remap-path-prefix=external/raze_test__log__0_4_13=/cargo-raze/log-0.4.13\",\n",
+    )));
+    assert!(build_file_of_log(GenMode::Vendored)
+      .contains("\"--remap-path-prefix=cargo/vendor/log-0.4.13=/cargo-raze/log-0.4.13\","));
+  }
+
+  #[test]
+  fn test_plan_build_wires_manifest_dir_relative_data() {
+    let plan_log = |genmode: GenMode| {
+      let mut settings = dummy_raze_settings();
+      settings.genmode = genmode;
+      let log_settings = CrateSettings {
+        manifest_dir_data: vec!["assets/**".to_owned()],
+        ..Default::default()
+      };
+      settings.crates.insert(
+        "log".to_owned(),
+        vec![(VersionReq::parse("0.4.13").unwrap(), log_settings)]
+          .into_iter()
+          .collect(),
+      );
+      let planned_build = BuildPlannerImpl::new(
+        dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+        settings.clone(),
+      )
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+      let root = PathBuf::from("/some/cargo/root");
+      let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+      let build_file = render_files(&settings, &render_details, &planned_build)
+        .unwrap()
+        .into_iter()
+        .find(|(path, _)| {
+          path.ends_with("remote/BUILD.log-0.4.13.bazel")
+            || path.ends_with("vendor/log-0.4.13/BUILD")
+        })
+        .map(|(_, contents)| contents)
+        .unwrap();
+      // Crates without manifest relative data keep the variable rules_rust sets
+      for ctx in planned_build
+        .crate_contexts
+        .iter()
+        .filter(|ctx| ctx.pkg_name != "log")
+      {
+        assert!(!ctx
+          .raze_settings
+          .additional_env
+          .contains_key("CARGO_MANIFEST_DIR"));
+      }
+      let log = planned_build
+        .crate_contexts
+        .into_iter()
+        .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+        .unwrap();
+      (log, build_file)
+    };
+
+    let (log, build_file) = plan_log(GenMode::Remote);
+    assert_eq!(
+      log.raze_settings.additional_env.get("CARGO_MANIFEST_DIR"),
+      Some(&"${pwd}/external/raze_test__log__0_4_13".to_owned())
+    );
+    assert!(build_file.contains(concat!(
+      "    data = [] + glob(\n",
+      "        [\n",
+      "            \"assets/**\",\n",
+      "        ],\n",
+      "        allow_empty = True,\n",
+      "    ),\n",
+      "    compile_data = glob(\n",
+      "        [\n",
+      "            \"assets/**\",\n",
+      "        ],\n",
+      "        allow_empty = True,\n",
+      "    ),\n",
+    )));
+    assert!(
+      build_file.contains("\"CARGO_MANIFEST_DIR\": \"${pwd}/external/raze_test__log__0_4_13\",")
+    );
+
+    let (log, _) = plan_log(GenMode::Vendored);
+    assert_eq!(
+      log.raze_settings.additional_env.get("CARGO_MANIFEST_DIR"),
+      Some(&"${pwd}/cargo/vendor/log-0.4.13".to_owned())
+    );
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_plan_build_resolves_symlinks_into_sibling_crates() {
+    let vendor_dir = tempfile::TempDir::new().unwrap();
+    let crate_dir = vendor_dir.as_ref().join("log-0.4.13");
+    let sibling_data_dir = vendor_dir.as_ref().join("cfg-if-0.1.10/data");
+    std::fs::create_dir_all(&crate_dir).unwrap();
+    std::fs::create_dir_all(&sibling_data_dir).unwrap();
+    std::fs::write(crate_dir.join("Cargo.toml"), "").unwrap();
+    std::fs::write(crate_dir.join("own.txt"), "").unwrap();
+    std::fs::write(sibling_data_dir.join("real.txt"), "").unwrap();
+    std::os::unix::fs::symlink("../cfg-if-0.1.10/data/real.txt", crate_dir.join("data.txt"))
+      .unwrap();
+    std::os::unix::fs::symlink("own.txt", crate_dir.join("alias.txt")).unwrap();
+
+    // Point `log 0.4.13` at a crate symlinking a file of `cfg-if 0.1.10`
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    relocate_package_root(&mut raze_metadata, "log", "0.4.13", &crate_dir);
+
+    let settings = dummy_raze_settings();
+    let plan = |raze_metadata: RazeMetadata| {
+      BuildPlannerImpl::new(raze_metadata, settings.clone()).plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+    };
+    let planned_build = plan(raze_metadata.clone()).unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+    assert_eq!(
+      log.raze_settings.data_dependencies,
+      vec!["../cfg-if-0.1.10/data/real.txt"]
+    );
+
+    let root = PathBuf::from("/some/cargo/root");
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let file_outputs = render_files(&settings, &render_details, &planned_build).unwrap();
+    let log_build = file_outputs
+      .iter()
+      .find(|(path, _)| path.ends_with("remote/BUILD.log-0.4.13.bazel"))
+      .map(|(_, contents)| contents)
+      .unwrap();
+    assert!(log_build.contains("\"@raze_test__cfg_if__0_1_10//:data/real.txt\""));
+
+    std::os::unix::fs::symlink("missing.txt", crate_dir.join("dangling.txt")).unwrap();
+    let error = plan(raze_metadata).unwrap_err();
+    assert!(error
+      .to_string()
+      .contains("is a dangling symlink to missing.txt"));
+  }
+
+  #[test]
+  fn test_plan_build_fetches_git_and_path_dependencies_from_their_sources() {
+    let mut raze_metadata =
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_GIT_AND_PATH_DEPENDENCIES);
+
+    // Git dependencies are located by the root of their checkout
+    let git_checkout = TempDir::new().unwrap();
+    fs::create_dir(git_checkout.as_ref().join(".git")).unwrap();
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "git-lib" {
+        package.manifest_path = git_checkout.as_ref().join("Cargo.toml");
+        package.targets[0].src_path = git_checkout.as_ref().join("src/lib.rs");
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    let planned_build = BuildPlannerImpl::new(raze_metadata.clone(), settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    let crate_context = |name: &str| {
+      planned_build
+        .crate_contexts
+        .iter()
+        .find(|ctx| ctx.pkg_name == name)
+        .unwrap()
+    };
+    let git_lib = crate_context("git-lib");
+    let git_data = git_lib.source_details.git_data.as_ref().unwrap();
+    assert_eq!(git_data.remote, "https://github.com/example/git-lib.git");
+    assert_eq!(git_data.commit, "0123456789abcdef0123456789abcdef01234567");
+    assert!(git_lib.source_details.local_path.is_none());
+    assert!(git_lib.sha256.is_none());
+
+    let shared_lib = crate_context("shared-lib");
+    assert!(shared_lib.source_details.git_data.is_none());
+    assert_eq!(
+      shared_lib.source_details.local_path,
+      Some(raze_metadata.cargo_workspace_root.join("local/shared-lib"))
+    );
+    assert!(shared_lib.sha256.is_none());
+
+    let render_details = RenderDetails::new(
+      &settings,
+      raze_metadata.cargo_workspace_root.clone(),
+      raze_metadata.cargo_workspace_root.clone(),
+    );
+    let files = render_files(&settings, &render_details, &planned_build).unwrap();
+    let (_, crates_bzl) = files
+      .iter()
+      .find(|(path, _)| path.ends_with("crates.bzl"))
+      .unwrap();
+
+    assert!(crates_bzl.contains(concat!(
+      "    maybe(\n",
+      "        native.new_local_repository,\n",
+      "        name = \"raze_test__shared_lib__0_2_0\",\n",
+      "        path = \"local/shared-lib\",\n",
+    )));
+    assert!(crates_bzl.contains("commit = \"0123456789abcdef0123456789abcdef01234567\","));
+    assert!(!crates_bzl.contains("        http_archive,\n"));
+  }
+
+  #[test]
+  fn test_plan_build_fetches_path_dependencies_outside_of_the_workspace_locally() {
+    let dir = TempDir::new().unwrap();
+    let app_dir = dir.as_ref().join("app");
+    let sibling_dir = dir.as_ref().join("sibling");
+    for crate_dir in &[&app_dir, &sibling_dir] {
+      fs::create_dir_all(crate_dir.join("src")).unwrap();
+      fs::write(crate_dir.join("src/lib.rs"), "").unwrap();
+    }
+    fs::write(
+      app_dir.join("Cargo.toml"),
+      indoc! { r#"
+        [package]
+        name = "app"
+        version = "0.1.0"
+
+        [dependencies]
+        sibling = { path = "../sibling" }
+      "# },
+    )
+    .unwrap();
+    fs::write(
+      sibling_dir.join("Cargo.toml"),
+      indoc! { r#"
+        [package]
+        name = "sibling"
+        version = "0.2.0"
+      "# },
+    )
+    .unwrap();
+
+    let mut fetcher = RazeMetadataFetcher::default();
+    fetcher.set_lockfile_generator(Box::new(DummyLockfileGenerator {
+      lockfile_contents: None,
+    }));
+    let raze_metadata = fetcher.fetch_metadata(&app_dir, None, None).unwrap();
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let sibling = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "sibling")
+      .unwrap();
+    assert_eq!(
+      sibling.source_details.local_path,
+      Some(fs::canonicalize(&sibling_dir).unwrap())
+    );
+
+    let render_details =
+      RenderDetails::new(&settings, app_dir.clone(), fs::canonicalize(&dir).unwrap());
+    let files = render_files(&settings, &render_details, &planned_build).unwrap();
+    let (_, crates_bzl) = files
+      .iter()
+      .find(|(path, _)| path.ends_with("crates.bzl"))
+      .unwrap();
+    assert!(crates_bzl.contains(concat!(
+      "    maybe(\n",
+      "        native.new_local_repository,\n",
+      "        name = \"raze_test__sibling__0_2_0\",\n",
+      "        path = \"sibling\",\n",
+    )));
+    assert!(!crates_bzl.contains("http_archive,"));
+  }
+
+  #[test]
+  fn test_plan_build_gives_dev_dependencies_only_to_test_targets() {
+    let raze_metadata = template_raze_metadata(templates::PLAN_BUILD_PRODUCES_TEST_DEPENDENCIES);
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    for name in &["tested-lib", "normal-dep"] {
+      let crate_settings = CrateSettings {
+        gen_tests: true,
+        ..Default::default()
+      };
+      settings.crates.insert(
+        name.to_string(),
+        vec![(VersionReq::parse("*").unwrap(), crate_settings)]
+          .into_iter()
+          .collect(),
+      );
+    }
+    let planned_build = BuildPlannerImpl::new(raze_metadata.clone(), settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    let tested_lib = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "tested-lib")
+      .unwrap();
+    let dep_names = |deps: &[BuildableDependency]| -> Vec<String> {
+      deps.iter().map(|dep| dep.name.clone()).collect()
+    };
+    assert_eq!(
+      dep_names(&tested_lib.default_deps.dependencies),
+      vec!["normal-dep"]
+    );
+    assert_eq!(
+      dep_names(&tested_lib.default_deps.dev_dependencies),
+      vec!["dev-dep"]
+    );
+
+    let render_details = RenderDetails::new(
+      &settings,
+      raze_metadata.cargo_workspace_root.clone(),
+      raze_metadata.cargo_workspace_root.clone(),
+    );
+    let files = render_files(&settings, &render_details, &planned_build).unwrap();
+    let build_file = |name: &str| {
+      files
+        .iter()
+        .find(|(path, _)| path.ends_with(format!("remote/BUILD.{}.bazel", name)))
+        .map(|(_, contents)| contents.clone())
+        .unwrap()
+    };
+
+    let tested_lib_build = build_file("tested-lib-1.0.0");
+    let rules: Vec<&str> = tested_lib_build.split("\n\n").collect();
+    let rule = |kind: &str, name: &str| {
+      let name_attr = format!("    name = \"{}\",\n", name);
+      rules
+        .iter()
+        .find(|rule| rule.starts_with(kind) && rule.contains(&name_attr))
+        .unwrap_or_else(|| panic!("no {} `{}` in:\n{}", kind, name, tested_lib_build))
+    };
+    let library = rule("rust_library(", "tested_lib");
+    assert!(library.contains("\"@raze_test__normal_dep__1_0_0//:normal_dep\","));
+    assert!(!library.contains("dev_dep"));
+    for test_name in &["cargo_unit_test_tested_lib", "cargo_test_integration"] {
+      let test = rule("rust_test(", test_name);
+      assert!(test.contains("\"@raze_test__normal_dep__1_0_0//:normal_dep\","));
+      assert!(test.contains("\"@raze_test__dev_dep__1_0_0//:dev_dep\","));
+      assert!(!test.contains("shard_count"));
+    }
+    let doc_test = rule("rust_doc_test(", "cargo_doc_test_tested_lib");
+    assert!(doc_test.contains("    dep = \":tested_lib\",\n"));
+
+    // Crates without tests get no test targets
+    let normal_dep_build = build_file("normal-dep-1.0.0");
+    assert!(!normal_dep_build.contains("rust_test("));
+    assert!(!normal_dep_build.contains("rust_doc_test("));
+  }
+
+  #[test]
+  fn test_plan_build_renders_libraries_under_the_configured_target_name() {
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    settings.library_target_name = Some("lib".to_owned());
+    let planned_build = BuildPlannerImpl::new(
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings.clone(),
+    )
+    .plan_build(Some(PlatformDetails::new(
+      "some_target_triple".to_owned(),
+      Vec::new(), /* attrs */
+    )))
+    .unwrap();
+
+    let root = PathBuf::from("/some/cargo/root");
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let file_outputs = render_files(&settings, &render_details, &planned_build).unwrap();
+    let build_file = |name: &str| {
+      file_outputs
+        .iter()
+        .find(|(path, _)| path.ends_with(name))
+        .map(|(_, contents)| contents)
+        .unwrap()
+    };
+
+    let new_log_build = build_file("remote/BUILD.log-0.4.13.bazel");
+    assert!(new_log_build.contains(concat!(
+      "alias(\n",
+      "    name = \"log\",\n",
+      "    actual = \":lib\",\n",
+    )));
+    assert!(new_log_build.contains("rust_library(\n    name = \"lib\",\n"));
+    assert!(new_log_build.contains("    crate_name = \"log\",\n"));
+    assert!(!new_log_build.contains("name = \"log\",\n    srcs"));
+
+    let old_log_build = build_file("remote/BUILD.log-0.3.9.bazel");
+    assert!(old_log_build.contains("rust_library(\n    name = \"lib\",\n"));
+    assert!(old_log_build.contains("        \"@raze_test__log__0_4_13//:lib\",\n"));
+    assert!(!old_log_build.contains("//:log\""));
+
+    // Dependency labels are resolved to the crates they refer to under the configured name
+    assert!(subplanners::find_dangling_dependency_labels(&planned_build.crate_contexts).is_empty());
+  }
+
+  #[test]
+  fn test_plan_build_renders_a_rule_per_lib_crate_type() {
+    let mut raze_metadata =
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version.to_string() == "0.4.13" {
+        let lib = package
+          .targets
+          .iter_mut()
+          .find(|target| target.kind == vec!["lib".to_owned()])
+          .unwrap();
+        lib.kind = vec!["rlib".to_owned(), "dylib".to_owned()];
+        lib.crate_types = lib.kind.clone();
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    let planned_build = BuildPlannerImpl::new(raze_metadata.clone(), settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version.to_string() == "0.4.13")
+      .unwrap();
+    assert_eq!(log.lib_crate_types, vec!["rlib", "dylib"]);
+    assert_eq!(log.lib_target_name.as_deref(), Some("log"));
+    assert!(!log.is_proc_macro);
+
+    let render_details = RenderDetails::new(
+      &settings,
+      raze_metadata.cargo_workspace_root.clone(),
+      raze_metadata.cargo_workspace_root.clone(),
+    );
+    let files = render_files(&settings, &render_details, &planned_build).unwrap();
+    let (_, build_file) = files
+      .iter()
+      .find(|(path, _)| path.ends_with("remote/BUILD.log-0.4.13.bazel"))
+      .unwrap();
+    let rules: Vec<&str> = build_file
+      .split("\n\n")
+      .filter(|rule| rule.starts_with("rust_library("))
+      .collect();
+    assert_eq!(rules.len(), 2, "{}", build_file);
+    let rule = |name: &str| {
+      let name_attr = format!("    name = \"{}\",\n", name);
+      *rules.iter().find(|rule| rule.contains(&name_attr)).unwrap()
+    };
+    // Dependents link against the rlib, which keeps the lib's name
+    assert!(rule("log").contains("    crate_type = \"rlib\",\n"));
+    assert!(rule("log_dylib").contains("    crate_type = \"dylib\",\n"));
+    assert!(!build_file.contains("alias("));
+  }
+
+  #[test]
+  fn test_planned_builds_render_without_writing_files() {
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    settings.target = None;
+
+    let planned_build = BuildPlannerImpl::new(
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings.clone(),
+    )
+    .plan_for_target()
+    .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+    assert_eq!(log.default_deps.dependencies[0].name, "cfg-if");
+
+    let bazel_root = TempDir::new().unwrap();
+    let render_details = RenderDetails::new(
+      &settings,
+      PathBuf::from("/some/cargo/root"),
+      bazel_root.as_ref().to_path_buf(),
+    );
+    let files = render_files(&settings, &render_details, &planned_build).unwrap();
+
+    let (_, log_build) = files
+      .iter()
+      .find(|(path, _)| path.ends_with("remote/BUILD.log-0.4.13.bazel"))
+      .unwrap();
+    assert!(log_build.contains("name = \"log\""));
+    assert!(fs::read_dir(bazel_root.as_ref()).unwrap().next().is_none());
+  }
+
+  #[test]
+  fn test_plan_build_wires_generated_sources_into_the_library() {
+    let crate_dir = tempfile::TempDir::new().unwrap();
+    std::fs::create_dir_all(crate_dir.as_ref().join("src")).unwrap();
+    std::fs::write(
+      crate_dir.as_ref().join("build.rs"),
+      indoc! { r#"
+        fn main() {
+            let out_dir = std::env::var("OUT_DIR").unwrap();
+            std::fs::write(format!("{}/generated.rs", out_dir), "pub const LEVELS: usize = 5;")
+                .unwrap();
+        }
+      "# },
+    )
+    .unwrap();
+    std::fs::write(
+      crate_dir.as_ref().join("src/lib.rs"),
+      "include!(concat!(env!(\"OUT_DIR\"), \"/generated.rs\"));\n",
+    )
+    .unwrap();
+
+    // Point `log 0.4.13` at a library including the output of its build script
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    relocate_package_root(&mut raze_metadata, "log", "0.4.13", crate_dir.as_ref());
+
+    // Build scripts are not generated by default, but the library can't compile without this one
+    let mut settings = dummy_raze_settings();
+    settings.default_gen_buildrs = false;
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings)
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+    assert!(log.build_script_target.is_some());
+
+    let file_outputs = render_remote_planned_build(&planned_build);
+    let log_build = file_outputs
+      .iter()
+      .find(|output| output.path.ends_with("remote/BUILD.log-0.4.13.bazel"))
+      .unwrap();
+    let library_rule = log_build
+      .contents
+      .split("\n)\n")
+      .find(|rule| rule.contains("rust_library(\n    name = \"log\","))
+      .unwrap();
+
+    assert!(log_build.contents.contains("cargo_build_script("));
+    assert!(library_rule.contains("        \":log_build_script\",\n"));
+  }
+
+  #[test]
+  fn test_plan_build_renders_target_sysroot_flags_in_their_platform_branch() {
+    let mut settings = dummy_raze_settings();
+    settings.target_sysroot.insert(
+      "aarch64-unknown-linux-gnu".to_owned(),
+      crate::settings::TargetSysroot {
+        sysroot: "/opt/sysroots/aarch64".to_owned(),
+        link_args: vec!["-L/opt/sysroots/aarch64/lib".to_owned()],
+      },
+    );
+    let planned_build = BuildPlannerImpl::new(
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings,
+    )
+    .plan_build(Some(PlatformDetails::new(
+      "some_target_triple".to_owned(),
+      Vec::new(), /* attrs */
+    )))
+    .unwrap();
+
+    let file_outputs = render_remote_planned_build(&planned_build);
+    let cfg_if_build = file_outputs
+      .iter()
+      .find(|output| output.path.ends_with("remote/BUILD.cfg-if-0.1.10.bazel"))
+      .unwrap();
+
+    assert!(cfg_if_build.contents.contains(concat!(
+      "    rustc_flags = [\n",
+      "        \"--cap-lints=allow\",\n",
+      "    ] + select({\n",
+      "        \"@rules_rust//rust/platform:aarch64-unknown-linux-gnu\": [\n",
+      "            \"--sysroot=/opt/sysroots/aarch64\",\n",
+      "            \"-Clink-arg=-L/opt/sysroots/aarch64/lib\",\n",
+      "        ],\n",
+      "        \"//conditions:default\": [],\n",
+      "    }),\n",
+    )));
+  }
+
+  #[test]
+  fn test_plan_build_wires_build_scripts_into_binary_targets() {
+    // Give `log 0.4.13`, whose build script sets `cfg`s and env vars, a binary target
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        let mut bin_target = package.targets[0].clone();
+        bin_target.name = "log-tool".to_owned();
+        bin_target.kind = vec!["bin".to_owned()];
+        bin_target.crate_types = vec!["bin".to_owned()];
+        bin_target.src_path = package.manifest_path.parent().unwrap().join("src/main.rs");
+        package.targets.push(bin_target);
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.crates.insert("log".to_owned(), {
+      let mut versions = HashMap::new();
+      versions.insert(VersionReq::parse("=0.4.13").unwrap(), {
+        let mut crate_settings = crate::settings::CrateSettings {
+          gen_buildrs: Some(true),
+          ..Default::default()
+        };
+        crate_settings
+          .buildrs_additional_environment_variables
+          .insert("LOG_TOOL_VERSION".to_owned(), "1.0".to_owned());
+        crate_settings
+      });
+      versions
+    });
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings)
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+    assert!(log.build_script_target.is_some());
+    assert!(log.targets.iter().any(|target| target.kind == "bin"));
+
+    let file_outputs = render_remote_planned_build(&planned_build);
+    let log_build = &file_outputs
+      .iter()
+      .find(|output| output.path.ends_with("remote/BUILD.log-0.4.13.bazel"))
+      .unwrap()
+      .contents;
+
+    // The build script's env vars and flags reach the binary through its dependency on the
+    // `cargo_build_script` rule.
+    assert!(log_build.contains("\"LOG_TOOL_VERSION\": \"1.0\","));
+    let binary_rule = log_build
+      .split("\n)\n")
+      .find(|rule| rule.contains("name = \"cargo_bin_log_tool\","))
+      .unwrap();
+    assert!(binary_rule.contains("\":log_build_script\","));
+    assert!(binary_rule.contains("\":log\","));
+  }
+
+  #[test]
+  fn test_plan_build_applies_global_rustc_env() {
+    let mut settings = dummy_raze_settings();
+    settings
+      .global_rustc_env
+      .insert("BUILD_FLAVOR".to_owned(), "release".to_owned());
+    settings.crates.insert("cfg-if".to_owned(), {
+      let mut versions = HashMap::new();
+      versions.insert(VersionReq::parse("*").unwrap(), {
+        let mut crate_settings = crate::settings::CrateSettings::default();
+        crate_settings
+          .additional_env
+          .insert("BUILD_FLAVOR".to_owned(), "debug".to_owned());
+        crate_settings
+      });
+      versions
+    });
+
+    let planner = BuildPlannerImpl::new(
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings.clone(),
+    );
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    for ctx in planned_build.crate_contexts.iter() {
+      let expected = if ctx.pkg_name == "cfg-if" {
+        "debug"
+      } else {
+        "release"
+      };
+      assert_eq!(
+        ctx.raze_settings.additional_env.get("BUILD_FLAVOR"),
+        Some(&expected.to_owned())
+      );
+    }
+
+    let root = PathBuf::from("/some/cargo/root");
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let file_outputs = render_files(&settings, &render_details, &planned_build).unwrap();
+    for ctx in planned_build.crate_contexts.iter() {
+      let expected = if ctx.pkg_name == "cfg-if" {
+        "debug"
+      } else {
+        "release"
+      };
+      let (_, build_file) = file_outputs
+        .iter()
+        .find(|(path, _)| path.ends_with(&ctx.expected_build_path))
+        .unwrap();
+      assert!(
+        build_file.contains(&format!("\"BUILD_FLAVOR\": \"{}\",", expected)),
+        "{}",
+        build_file
+      );
+    }
+  }
+
+  #[test]
+  fn test_plan_build_keys_per_target_settings_by_condition() {
+    let triple = "x86_64-unknown-linux-gnu".to_owned();
+    let mut settings = dummy_raze_settings();
+    settings.crates.insert("cfg-if".to_owned(), {
+      let mut versions = HashMap::new();
+      versions.insert(VersionReq::parse("*").unwrap(), {
+        let mut crate_settings = crate::settings::CrateSettings::default();
+        crate_settings
+          .rustc_flags
+          .insert(triple.clone(), vec!["--cfg=linux_only".to_owned()]);
+        crate_settings.rustc_env.insert(triple.clone(), {
+          let mut env = HashMap::new();
+          env.insert("LINKAGE".to_owned(), "static".to_owned());
+          env
+        });
+        crate_settings
+          .compile_data_attrs
+          .insert(triple, "[\"linux.h\"]".to_owned());
+        crate_settings
+      });
+      versions
+    });
+
+    let planned_build = BuildPlannerImpl::new(
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings,
+    )
+    .plan_build(Some(PlatformDetails::new(
+      "some_target_triple".to_owned(),
+      Vec::new(), /* attrs */
+    )))
+    .unwrap();
+
+    let condition = "@rules_rust//rust/platform:x86_64-unknown-linux-gnu".to_owned();
+    let cfg_if = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "cfg-if")
+      .unwrap();
+    assert_eq!(
+      cfg_if.targeted_rustc_flags.get(&condition),
+      Some(&vec!["--cfg=linux_only".to_owned()])
+    );
+    assert_eq!(
+      cfg_if.targeted_rustc_env[&condition].get("LINKAGE"),
+      Some(&"static".to_owned())
+    );
+    assert_eq!(
+      cfg_if.targeted_compile_data.get(&condition),
+      Some(&"[\"linux.h\"]".to_owned())
+    );
+    assert!(cfg_if.targeted_build_script_env.is_empty());
+  }
+
+  #[test]
+  fn test_plan_build_reports_build_script_cycles() {
+    // `log 0.3.9` depends on `log 0.4.13`, whose build script is made to depend on `log 0.3.9`
+    let mut settings = dummy_raze_settings();
+    settings.crates.insert("log".to_owned(), {
+      let mut versions = HashMap::new();
+      versions.insert(
+        VersionReq::parse("0.4.13").unwrap(),
+        crate::settings::CrateSettings {
+          buildrs_additional_deps: vec!["@raze_test__log__0_3_9//:log".to_owned()],
+          ..Default::default()
+        },
+      );
+      versions
+    });
+
+    let planner = BuildPlannerImpl::new(
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings,
+    );
+    let planned_build_res = planner.plan_build(Some(PlatformDetails::new(
+      "some_target_triple".to_owned(),
+      Vec::new(), /* attrs */
+    )));
+
+    let error = planned_build_res.unwrap_err().to_string();
+    assert!(error.contains("log-0.3.9 -> log-0.4.13 (build script) -> log-0.3.9"));
+  }
+
+  #[test]
+  fn test_plan_build_drops_features_only_enabled_on_excluded_platforms() {
+    // Make `log 0.4.13` only depend on `cfg-if` on windows, enabling an extra feature there
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.target = Some("cfg(windows)".parse().unwrap());
+            dep.features = vec!["windows_only".to_owned()];
+          }
+        }
+      }
+      if package.name == "cfg-if" {
+        package
+          .features
+          .insert("windows_only".to_owned(), Vec::new());
+      }
+    }
+    for node in raze_metadata
+      .metadata
+      .resolve
+      .as_mut()
+      .unwrap()
+      .nodes
+      .iter_mut()
+    {
+      if node.id.repr.starts_with("cfg-if ") {
+        node.features.push("windows_only".to_owned());
+      }
+    }
+
+    let cfg_if_context = |targets: Vec<&str>| {
+      let mut settings = dummy_raze_settings();
+      settings.targets = Some(targets.iter().map(|target| target.to_string()).collect());
+      let planner = BuildPlannerImpl::new(raze_metadata.clone(), settings);
+      let planned_build = planner
+        .plan_build(Some(PlatformDetails::new(
+          "some_target_triple".to_owned(),
+          Vec::new(), /* attrs */
+        )))
+        .unwrap();
+      planned_build
+        .crate_contexts
+        .into_iter()
+        .find(|ctx| ctx.pkg_name == "cfg-if")
+        .unwrap()
+    };
+
+    let windows_only = "windows_only".to_owned();
+    let linux_context = cfg_if_context(vec!["x86_64-unknown-linux-gnu"]);
+    assert!(!linux_context.features.contains(&windows_only));
+    assert!(linux_context.targeted_deps.is_empty());
+
+    // With windows configured, the feature is only enabled for it
+    let mixed_context = cfg_if_context(vec!["x86_64-unknown-linux-gnu", "x86_64-pc-windows-msvc"]);
+    assert!(!mixed_context.features.contains(&windows_only));
+    assert_eq!(mixed_context.targeted_deps.len(), 1);
+    assert_eq!(
+      mixed_context.targeted_deps[0].conditions,
+      vec!["@rules_rust//rust/platform:x86_64-pc-windows-msvc"]
+    );
+    assert_eq!(mixed_context.targeted_deps[0].features, vec![windows_only]);
+  }
+
+  #[test]
+  fn test_plan_build_includes_features_requested_through_dependent_features() {
+    // Make `log 0.4.13` only depend on `cfg-if` on windows, with a feature of its own enabling
+    // features of `cfg-if`
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.target = Some("cfg(windows)".parse().unwrap());
+          }
+        }
+        package.features.insert(
+          "windows_extras".to_owned(),
+          vec![
+            "cfg-if/windows_only".to_owned(),
+            "cfg-if?/weak_windows_only".to_owned(),
+          ],
+        );
+      }
+      if package.name == "cfg-if" {
+        for feature in &["windows_only", "weak_windows_only"] {
+          package.features.insert(feature.to_string(), Vec::new());
+        }
+      }
+    }
+    for node in raze_metadata
+      .metadata
+      .resolve
+      .as_mut()
+      .unwrap()
+      .nodes
+      .iter_mut()
+    {
+      if node.id.repr.starts_with("log 0.4.13 ") {
+        node.features.push("windows_extras".to_owned());
+      }
+      if node.id.repr.starts_with("cfg-if ") {
+        node.features.push("weak_windows_only".to_owned());
+        node.features.push("windows_only".to_owned());
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.targets = Some(vec![
+      "x86_64-unknown-linux-gnu".to_owned(),
+      "x86_64-pc-windows-msvc".to_owned(),
+    ]);
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings)
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let cfg_if_context = planned_build
+      .crate_contexts
+      .into_iter()
+      .find(|ctx| ctx.pkg_name == "cfg-if")
+      .unwrap();
+
+    // Both features are requested through the windows-only dependency, so only windows gets them
+    assert!(!cfg_if_context.features.contains(&"windows_only".to_owned()));
+    assert_eq!(cfg_if_context.targeted_deps.len(), 1);
+    assert_eq!(
+      cfg_if_context.targeted_deps[0].conditions,
+      vec!["@rules_rust//rust/platform:x86_64-pc-windows-msvc"]
+    );
+    assert_eq!(
+      cfg_if_context.targeted_deps[0].features,
+      vec!["weak_windows_only".to_owned(), "windows_only".to_owned()]
+    );
+  }
+
+  #[test]
+  fn test_plan_build_moves_toggled_features_into_config_setting_branches() {
+    // Make `cfg-if` an optional dependency of `log 0.4.13`, only pulled in by its `fancy` feature
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.optional = true;
+          }
+        }
+        package
+          .features
+          .insert("fancy".to_owned(), vec!["cfg-if".to_owned()]);
+      }
+    }
+    for node in raze_metadata
+      .metadata
+      .resolve
+      .as_mut()
+      .unwrap()
+      .nodes
+      .iter_mut()
+    {
+      if node.id.repr.starts_with("log 0.4.13 ") {
+        node.features.push("fancy".to_owned());
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.crates.insert("log".to_owned(), {
+      let mut versions = HashMap::new();
+      versions.insert(VersionReq::parse("0.4.13").unwrap(), {
+        let mut crate_settings = crate::settings::CrateSettings::default();
+        crate_settings
+          .feature_config_settings
+          .insert("fancy".to_owned(), "//features:fancy_enabled".to_owned());
+        crate_settings
+      });
+      versions
+    });
+    let planner = BuildPlannerImpl::new(raze_metadata, settings);
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    assert!(!log.features.contains(&"fancy".to_owned()));
+    assert!(!log.default_deps.contains("cfg-if", Version::new(0, 1, 10)));
+
+    let fancy = log
+      .targeted_deps
+      .iter()
+      .find(|branch| branch.conditions == vec!["//features:fancy_enabled".to_owned()])
+      .unwrap();
+    assert_eq!(fancy.target, "feature = \"fancy\"");
+    assert!(fancy.features.contains(&"fancy".to_owned()));
+    assert!(fancy.deps.contains("cfg-if", Version::new(0, 1, 10)));
+  }
+
+  #[test]
+  fn test_plan_build_toggles_optional_platform_deps_on_their_platform_only() {
+    // Make `cfg-if` an optional dependency of `log 0.4.13` on windows, only pulled in by `fancy`
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.optional = true;
+            dep.target = Some("cfg(windows)".parse().unwrap());
+          }
+        }
+        package
+          .features
+          .insert("fancy".to_owned(), vec!["cfg-if".to_owned()]);
+      }
+    }
+    for node in raze_metadata
+      .metadata
+      .resolve
+      .as_mut()
+      .unwrap()
+      .nodes
+      .iter_mut()
+    {
+      if node.id.repr.starts_with("log 0.4.13 ") {
+        node.features.push("fancy".to_owned());
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.target = None;
+    settings.targets = Some(vec![
+      "x86_64-unknown-linux-gnu".to_owned(),
+      "x86_64-pc-windows-msvc".to_owned(),
+    ]);
+    settings.crates.insert("log".to_owned(), {
+      let mut versions = HashMap::new();
+      versions.insert(VersionReq::parse("0.4.13").unwrap(), {
+        let mut crate_settings = crate::settings::CrateSettings::default();
+        crate_settings
+          .feature_config_settings
+          .insert("fancy".to_owned(), "//features:fancy_enabled".to_owned());
+        crate_settings
+      });
+      versions
+    });
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings)
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    // The dependency is neither linked on windows alone nor whenever `fancy` is toggled on
+    assert!(!log.default_deps.contains("cfg-if", Version::new(0, 1, 10)));
+    assert!(log
+      .targeted_deps
+      .iter()
+      .filter(|branch| branch.target != "all(cfg(windows), feature = \"fancy\")")
+      .all(|branch| !branch.deps.contains("cfg-if", Version::new(0, 1, 10))));
+
+    let fancy_on_windows = log
+      .targeted_deps
+      .iter()
+      .find(|branch| branch.target == "all(cfg(windows), feature = \"fancy\")")
+      .unwrap();
+    assert!(fancy_on_windows
+      .deps
+      .contains("cfg-if", Version::new(0, 1, 10)));
+    assert_eq!(
+      fancy_on_windows.conditions,
+      vec![":feature_fancy_x86_64-pc-windows-msvc".to_owned()]
+    );
+    assert_eq!(
+      log.config_setting_groups,
+      vec![(
+        "feature_fancy_x86_64-pc-windows-msvc".to_owned(),
+        vec![
+          "//features:fancy_enabled".to_owned(),
+          "@rules_rust//rust/platform:x86_64-pc-windows-msvc".to_owned(),
+        ],
+      )]
+      .into_iter()
+      .collect()
+    );
+  }
+
+  #[test]
+  fn test_plan_build_gates_aliases_of_feature_gated_renamed_deps() {
+    // Make `cfg-if` an optional, renamed dependency of `log 0.4.13`, only pulled in by `fancy`
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.optional = true;
+            dep.rename = Some("fancy_cfg".to_owned());
+          }
+        }
+        package
+          .features
+          .insert("fancy".to_owned(), vec!["fancy_cfg".to_owned()]);
+      }
+    }
+    for node in raze_metadata
+      .metadata
+      .resolve
+      .as_mut()
+      .unwrap()
+      .nodes
+      .iter_mut()
+    {
+      if node.id.repr.starts_with("log 0.4.13 ") {
+        node.features.push("fancy".to_owned());
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.crates.insert("log".to_owned(), {
+      let mut versions = HashMap::new();
+      versions.insert(VersionReq::parse("0.4.13").unwrap(), {
+        let mut crate_settings = crate::settings::CrateSettings::default();
+        crate_settings
+          .feature_config_settings
+          .insert("fancy".to_owned(), "//features:fancy_enabled".to_owned());
+        crate_settings
+      });
+      versions
+    });
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings)
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    let fancy_alias = DependencyAlias {
+      target: "@raze_test__cfg_if__0_1_10//:cfg_if".to_owned(),
+      alias: "fancy_cfg".to_owned(),
+    };
+    assert!(log.default_deps.aliased_dependencies.is_empty());
+    let fancy = log
+      .targeted_deps
+      .iter()
+      .find(|branch| branch.conditions == vec!["//features:fancy_enabled".to_owned()])
+      .unwrap();
+    assert_eq!(fancy.deps.aliased_dependencies, vec![fancy_alias]);
+
+    let file_outputs = render_remote_planned_build(&planned_build);
+    let log_build = file_outputs
+      .iter()
+      .find(|output| output.path.ends_with("remote/BUILD.log-0.4.13.bazel"))
+      .unwrap();
+
+    assert!(log_build.contents.contains(concat!(
+      "    aliases = {\n",
+      "    } | selects.with_or({\n",
+      "        # feature = \"fancy\"\n",
+      "        _CONDITIONS_1: {\n",
+      "            \"@raze_test__cfg_if__0_1_10//:cfg_if\": \"fancy_cfg\",\n",
+      "        },\n",
+      "        \"//conditions:default\": {},\n",
+      "    }),\n",
+    )));
+    assert_eq!(log_build.contents.matches("\"fancy_cfg\"").count(), 1);
+  }
+
+  #[test]
+  fn test_plan_build_gates_platform_specific_proc_macro_deps() {
+    // Make `cfg-if` a proc-macro which `log 0.4.13` only depends on for windows
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.target = Some("cfg(windows)".parse().unwrap());
+          }
+        }
+      }
+      if package.name == "cfg-if" {
+        for target in package.targets.iter_mut() {
+          target.kind = vec!["proc-macro".to_owned()];
+          target.crate_types = vec!["proc-macro".to_owned()];
+        }
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.target = None;
+    settings.targets = Some(vec![
+      "x86_64-pc-windows-msvc".to_owned(),
+      "x86_64-unknown-linux-gnu".to_owned(),
+    ]);
+    let planner = BuildPlannerImpl::new(raze_metadata, settings);
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    assert!(!log
+      .default_deps
+      .proc_macro_dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+    assert_eq!(log.targeted_deps.len(), 1);
+    let windows = &log.targeted_deps[0];
+    assert_eq!(
+      windows.conditions,
+      vec!["@rules_rust//rust/platform:x86_64-pc-windows-msvc".to_owned()]
+    );
+    assert!(windows
+      .deps
+      .proc_macro_dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+    assert!(!windows
+      .deps
+      .dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+  }
+
+  #[test]
+  fn test_plan_build_gates_platform_specific_dev_deps_of_test_rules() {
+    // Make `cfg-if` a dev-dependency of `log 0.4.13` on windows only
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.kind = DependencyKind::Development;
+            dep.target = Some("cfg(windows)".parse().unwrap());
+          }
+        }
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    settings.target = None;
+    settings.targets = Some(vec![
+      "x86_64-pc-windows-msvc".to_owned(),
+      "x86_64-unknown-linux-gnu".to_owned(),
+    ]);
+    settings.crates.insert(
+      "log".to_owned(),
+      vec![(
+        VersionReq::parse("0.4.13").unwrap(),
+        CrateSettings {
+          gen_tests: true,
+          ..Default::default()
+        },
+      )]
+      .into_iter()
+      .collect(),
+    );
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    assert!(!log
+      .default_deps
+      .dev_dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+    assert_eq!(log.targeted_deps.len(), 1);
+    let windows = &log.targeted_deps[0];
+    assert_eq!(
+      windows.conditions,
+      vec!["@rules_rust//rust/platform:x86_64-pc-windows-msvc".to_owned()]
+    );
+    assert!(windows
+      .deps
+      .dev_dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+
+    let root = PathBuf::from("/some/cargo/root");
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let file_outputs = render_files(&settings, &render_details, &planned_build).unwrap();
+    let log_build = file_outputs
+      .iter()
+      .find(|(path, _)| path.ends_with("remote/BUILD.log-0.4.13.bazel"))
+      .map(|(_, contents)| contents)
+      .unwrap();
+
+    let test_rules: Vec<&str> = log_build
+      .split("rust_test(")
+      .skip(1)
+      .map(|rule| rule.split("\n)\n").next().unwrap())
+      .collect();
+    assert!(!test_rules.is_empty());
+    for test_rule in test_rules {
+      let (_, deps) = test_rule.split_once("deps = [").unwrap();
+      let (default_deps, targeted_deps) = deps.split_once(']').unwrap();
+      assert!(!default_deps.contains("cfg_if"), "{}", test_rule);
+      assert!(
+        targeted_deps.contains(concat!(
+          " + selects.with_or({\n",
+          "        # cfg(windows)\n",
+          "        (\n",
+          "            \"@rules_rust//rust/platform:x86_64-pc-windows-msvc\",\n",
+          "        ): [\n",
+          "            \"@raze_test__cfg_if__0_1_10//:cfg_if\",\n",
+          "        ],\n",
+          "        \"//conditions:default\": [],\n",
+          "    })",
+        )),
+        "{}",
+        test_rule
+      );
+    }
+    let library_rule = log_build.split("rust_library(").nth(1).unwrap();
+    let library_rule = library_rule.split("\n)\n").next().unwrap();
+    assert!(!library_rule.contains("cfg_if"), "{}", library_rule);
+  }
+
+  #[test]
+  fn test_plan_build_gates_build_script_deps_on_cross_compilation_targets() {
+    // Make `log 0.4.13` only build-depend on `cfg-if` when compiled to wasm
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.kind = DependencyKind::Build;
+            dep.target = Some("cfg(target_arch = \"wasm32\")".parse().unwrap());
+          }
+        }
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    settings.target = None;
+    settings.targets = Some(vec![
+      "aarch64-unknown-linux-gnu".to_owned(),
+      "wasm32-unknown-unknown".to_owned(),
+    ]);
+    settings.crates.insert(
+      "log".to_owned(),
+      vec![(
+        VersionReq::parse("0.4.13").unwrap(),
+        CrateSettings {
+          gen_buildrs: Some(true),
+          ..Default::default()
+        },
+      )]
+      .into_iter()
+      .collect(),
+    );
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    assert!(!log
+      .default_deps
+      .build_dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+    assert_eq!(log.targeted_deps.len(), 1);
+    let wasm = &log.targeted_deps[0];
+    assert_eq!(
+      wasm.conditions,
+      vec!["@rules_rust//rust/platform:wasm32-unknown-unknown".to_owned()]
+    );
+    assert!(wasm
+      .deps
+      .build_dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+    assert!(wasm.deps.dependencies.is_empty());
+
+    let root = PathBuf::from("/some/cargo/root");
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let file_outputs = render_files(&settings, &render_details, &planned_build).unwrap();
+    let log_build = file_outputs
+      .iter()
+      .find(|(path, _)| path.ends_with("remote/BUILD.log-0.4.13.bazel"))
+      .map(|(_, contents)| contents)
+      .unwrap();
+    let build_script_rule = log_build.split("cargo_build_script(").nth(1).unwrap();
+    let build_script_rule = build_script_rule.split("\n)\n").next().unwrap();
+    assert!(
+      build_script_rule.contains(concat!(
+        " + selects.with_or({\n",
+        "        # cfg(target_arch = \"wasm32\")\n",
+        "        (\n",
+        "            \"@rules_rust//rust/platform:wasm32-unknown-unknown\",\n",
+        "        ): [\n",
+        "            \"@raze_test__cfg_if__0_1_10//:cfg_if\",\n",
+        "        ],\n",
+        "        \"//conditions:default\": [],\n",
+        "    })",
+      )),
+      "{}",
+      build_script_rule
+    );
+    let library_rule = log_build.split("rust_library(").nth(1).unwrap();
+    let library_rule = library_rule.split("\n)\n").next().unwrap();
+    assert!(!library_rule.contains("cfg_if"), "{}", library_rule);
+  }
+
+  #[test]
+  fn test_plan_build_gates_deps_on_target_has_atomic() {
+    // Make `log 0.4.13` only depend on `cfg-if` where 64 bit atomics are available
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.target = Some("cfg(target_has_atomic = \"64\")".parse().unwrap());
+          }
+        }
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.target = None;
+    settings.targets = Some(vec![
+      "powerpc-unknown-linux-gnu".to_owned(),
+      "x86_64-unknown-linux-gnu".to_owned(),
+    ]);
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings)
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    // 32 bit powerpc has no 64 bit atomics
+    assert!(!log
+      .default_deps
+      .dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+    assert_eq!(log.targeted_deps.len(), 1);
+    assert_eq!(
+      log.targeted_deps[0].conditions,
+      vec!["@rules_rust//rust/platform:x86_64-unknown-linux-gnu".to_owned()]
+    );
+    assert!(log.targeted_deps[0]
+      .deps
+      .dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+  }
+
+  #[test]
+  fn test_plan_build_gates_deps_on_the_properties_of_custom_targets() {
+    // Make `log 0.4.13` only depend on `cfg-if` on 32 bit platforms without an OS
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.target = Some(
+              "cfg(all(target_os = \"none\", target_pointer_width = \"32\"))"
+                .parse()
+                .unwrap(),
+            );
+          }
+        }
+      }
+    }
+
+    let spec_dir = TempDir::new().unwrap();
+    let spec_path = spec_dir.path().join("thumb-custom.json");
+    fs::write(
+      &spec_path,
+      indoc! { r#"
+        {
+          "arch": "arm",
+          "os": "none",
+          "llvm-target": "thumbv7em-none-eabi",
+          "target-endian": "little",
+          "target-pointer-width": "32",
+          "max-atomic-width": 32
+        }
+      "# },
+    )
+    .unwrap();
+
+    let mut settings = dummy_raze_settings();
+    settings.target = None;
+    settings.targets = Some(vec![
+      "thumb-custom".to_owned(),
+      "x86_64-unknown-linux-gnu".to_owned(),
+    ]);
+    settings.custom_targets.insert(
+      "thumb-custom".to_owned(),
+      CustomTarget {
+        spec: "thumb-custom.json".to_owned(),
+        condition: "//platforms:thumb_custom".to_owned(),
+        properties: util::read_target_spec(&spec_path).unwrap(),
+      },
+    );
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings)
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    assert!(!log
+      .default_deps
+      .dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+    assert_eq!(log.targeted_deps.len(), 1);
+    assert_eq!(
+      log.targeted_deps[0].conditions,
+      vec!["//platforms:thumb_custom".to_owned()]
+    );
+    assert!(log.targeted_deps[0]
+      .deps
+      .dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+  }
+
+  #[test]
+  fn test_plan_build_links_dual_crate_type_deps_once() {
+    // Make `cfg-if` a lib which is built both as a library and as a proc-macro
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "cfg-if" {
+        for target in package.targets.iter_mut() {
+          target.kind = vec!["lib".to_owned(), "proc-macro".to_owned()];
+          target.crate_types = vec!["lib".to_owned(), "proc-macro".to_owned()];
+        }
+      }
+    }
+
+    let planner = BuildPlannerImpl::new(raze_metadata, dummy_raze_settings());
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let cfg_if = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "cfg-if")
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    // Dependents link `cfg-if` the way its own rules build it
+    assert!(!cfg_if.is_proc_macro);
+    let is_cfg_if = |dep: &&BuildableDependency| dep.name == "cfg-if";
+    assert_eq!(
+      log
+        .default_deps
+        .dependencies
+        .iter()
+        .filter(is_cfg_if)
+        .count(),
+      1
+    );
+    assert!(!log
+      .default_deps
+      .proc_macro_dependencies
+      .iter()
+      .any(|dep| dep.name == "cfg-if"));
+  }
+
+  #[test]
+  fn test_plan_build_constrains_crates_exclusive_to_one_os() {
+    // Make `log 0.4.13` only depend on `cfg-if` for windows
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.target = Some("cfg(windows)".parse().unwrap());
+          }
+        }
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.target = None;
+    settings.targets = Some(vec![
+      "i686-pc-windows-msvc".to_owned(),
+      "x86_64-pc-windows-msvc".to_owned(),
+      "x86_64-unknown-linux-gnu".to_owned(),
+    ]);
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let compatibility = |name: &str| {
+      planned_build
+        .crate_contexts
+        .iter()
+        .find(|ctx| ctx.pkg_name == name)
+        .map(|ctx| ctx.target_compatible_with.clone())
+        .unwrap()
+    };
+    assert_eq!(compatibility("cfg-if"), vec!["@platforms//os:windows"]);
+    assert!(compatibility("log").is_empty());
+
+    let root = PathBuf::from("/some/cargo/root");
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let file_outputs = render_files(&settings, &render_details, &planned_build).unwrap();
+    let build_file = |file_name: &str| {
+      file_outputs
+        .iter()
+        .find(|(path, _)| path.ends_with(file_name))
+        .map(|(_, contents)| contents)
+        .unwrap()
+    };
+    assert!(
+      build_file("remote/BUILD.cfg-if-0.1.10.bazel").contains(concat!(
+        "    target_compatible_with = [\n",
+        "        \"@platforms//os:windows\",\n",
+        "    ],\n",
+      ))
+    );
+    assert!(!build_file("remote/BUILD.log-0.4.13.bazel").contains("target_compatible_with"));
+  }
+
+  #[test]
+  fn test_plan_build_resolves_platform_gated_features_per_triple() {
+    // Make `log 0.4.13` request a different feature of `cfg-if` on unix and windows
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        let cfg_if_idx = package
+          .dependencies
+          .iter()
+          .position(|dep| dep.name == "cfg-if")
+          .unwrap();
+        let mut windows_dep = package.dependencies[cfg_if_idx].clone();
+        windows_dep.target = Some("cfg(windows)".parse().unwrap());
+        windows_dep.features = vec!["windows_feature".to_owned()];
+        let unix_dep = &mut package.dependencies[cfg_if_idx];
+        unix_dep.target = Some("cfg(unix)".parse().unwrap());
+        unix_dep.features = vec!["unix_feature".to_owned()];
+        package.dependencies.push(windows_dep);
+      }
+      if package.name == "cfg-if" {
+        for feature in &["unix_feature", "windows_feature"] {
+          package.features.insert(feature.to_string(), Vec::new());
+        }
+      }
+    }
+    for node in raze_metadata
+      .metadata
+      .resolve
+      .as_mut()
+      .unwrap()
+      .nodes
+      .iter_mut()
+    {
+      if node.id.repr.starts_with("cfg-if ") {
+        node.features.push("unix_feature".to_owned());
+        node.features.push("windows_feature".to_owned());
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.targets = Some(vec![
+      "x86_64-apple-darwin".to_owned(),
+      "x86_64-pc-windows-msvc".to_owned(),
+      "x86_64-unknown-linux-gnu".to_owned(),
+    ]);
+    let planner = BuildPlannerImpl::new(raze_metadata, settings);
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let cfg_if = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "cfg-if")
+      .unwrap();
+
+    // Neither feature is enabled on every triple
+    assert!(!cfg_if.features.contains(&"unix_feature".to_owned()));
+    assert!(!cfg_if.features.contains(&"windows_feature".to_owned()));
+
+    let branches: Vec<(Vec<String>, Vec<String>)> = cfg_if
+      .targeted_deps
+      .iter()
+      .map(|branch| (branch.conditions.clone(), branch.features.clone()))
+      .collect();
+    assert_eq!(
+      branches,
+      vec![
+        (
+          vec![
+            "@rules_rust//rust/platform:x86_64-apple-darwin".to_owned(),
+            "@rules_rust//rust/platform:x86_64-unknown-linux-gnu".to_owned(),
+          ],
+          vec!["unix_feature".to_owned()],
+        ),
+        (
+          vec!["@rules_rust//rust/platform:x86_64-pc-windows-msvc".to_owned()],
+          vec!["windows_feature".to_owned()],
+        ),
+      ]
+    );
+    assert_eq!(cfg_if.targeted_deps[0].target, "cfg(unix)");
+    assert_eq!(cfg_if.targeted_deps[1].target, "cfg(windows)");
+
+    // Every configured triple is covered by exactly one branch
+    let mut covered_conditions: Vec<&String> = cfg_if
+      .targeted_deps
+      .iter()
+      .flat_map(|branch| branch.conditions.iter())
+      .collect();
+    covered_conditions.sort();
+    assert_eq!(covered_conditions.len(), 3);
+    covered_conditions.dedup();
+    assert_eq!(covered_conditions.len(), 3);
+  }
+
+  #[test]
+  fn test_plan_build_omits_optional_dependencies_no_feature_activates() {
+    // Make the resolved `cfg-if` dependency of `log 0.4.13` optional without enabling it
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.optional = true;
+          }
+        }
+      }
+    }
+
+    let planner = BuildPlannerImpl::new(raze_metadata, dummy_raze_settings());
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    assert!(!log
+      .default_deps
+      .dependencies
+      .iter()
+      .chain(
+        log
+          .targeted_deps
+          .iter()
+          .flat_map(|t| t.deps.dependencies.iter())
+      )
+      .any(|dep| dep.name == "cfg-if"));
+    // Nothing else depends on `cfg-if`, so it is not planned at all
+    assert!(!planned_build
+      .crate_contexts
+      .iter()
+      .any(|ctx| ctx.pkg_name == "cfg-if"));
+  }
+
+  #[test]
+  fn test_plan_build_includes_the_closure_of_root_build_dependencies() {
+    // Make `cargo-raze-alias-test` a build dependency of the workspace member
+    let mut raze_metadata =
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    let workspace_members = raze_metadata.metadata.workspace_members.clone();
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if workspace_members.contains(&package.id) {
+        for dep in package.dependencies.iter_mut() {
+          dep.kind = DependencyKind::Build;
+        }
+      }
+    }
+
+    let planner = BuildPlannerImpl::new(raze_metadata, dummy_raze_settings());
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    let build_dependency = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "cargo-raze-alias-test")
+      .unwrap();
+    assert!(build_dependency.is_workspace_member_dependency);
+    assert_eq!(
+      build_dependency.workspace_member_build_dependents,
+      vec![PathBuf::from("")]
+    );
+    assert!(build_dependency.workspace_member_dependents.is_empty());
+
+    // The transitive dependencies of the build dependency are planned as well
+    for (name, version) in &[("log", "0.3.9"), ("log", "0.4.13"), ("cfg-if", "0.1.10")] {
+      let ctx = planned_build
+        .crate_contexts
+        .iter()
+        .find(|ctx| ctx.pkg_name == *name && ctx.pkg_version.to_string() == *version)
+        .unwrap();
+      assert!(!ctx.is_workspace_member_dependency);
+    }
+  }
+
+  #[test]
+  fn test_plan_build_omits_optional_dependencies_only_weak_features_name() {
+    // Make the `cfg-if` dependency of `log 0.4.13` optional, named by its resolved `std` feature
+    let plans_cfg_if = |std_feature: &str| {
+      let mut raze_metadata =
+        dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+      for package in raze_metadata.metadata.packages.iter_mut() {
+        if package.name == "log" && package.version == Version::new(0, 4, 13) {
+          for dep in package.dependencies.iter_mut() {
+            if dep.name == "cfg-if" {
+              dep.optional = true;
+            }
+          }
+          package
+            .features
+            .insert("std".to_owned(), vec![std_feature.to_owned()]);
+        }
+      }
+
+      BuildPlannerImpl::new(raze_metadata, dummy_raze_settings())
+        .plan_build(Some(PlatformDetails::new(
+          "some_target_triple".to_owned(),
+          Vec::new(), /* attrs */
+        )))
+        .unwrap()
+        .crate_contexts
+        .iter()
+        .any(|ctx| ctx.pkg_name == "cfg-if")
+    };
+
+    assert!(plans_cfg_if("cfg-if/std"));
+    // A weak dependency feature only applies once something else activates `cfg-if`
+    assert!(!plans_cfg_if("cfg-if?/std"));
+  }
+
+  #[test]
+  fn test_plan_build_gives_integration_tests_the_dev_resolved_features() {
+    // Give `log 0.4.13` a dev-dependency on itself which enables its `test-utils` feature
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        let mut self_dep = package.dependencies[0].clone();
+        self_dep.name = "log".to_owned();
+        self_dep.req = VersionReq::parse("=0.4.13").unwrap();
+        self_dep.kind = DependencyKind::Development;
+        self_dep.target = None;
+        self_dep.features = vec!["test-utils".to_owned()];
+        self_dep.uses_default_features = false;
+        package.dependencies.push(self_dep);
+        package.features.insert("test-utils".to_owned(), Vec::new());
+      }
+    }
+
+    let planner = BuildPlannerImpl::new(raze_metadata, dummy_raze_settings());
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    // The lib and its unit tests share the crate's resolved features
+    assert!(!log.features.contains(&"test-utils".to_owned()));
+    for feature in log.features.iter() {
+      assert!(log.dev_features.contains(feature));
+    }
+    assert!(log.dev_features.contains(&"test-utils".to_owned()));
+    assert_eq!(log.dev_features.len(), log.features.len() + 1);
+  }
+
+  #[test]
+  fn test_plan_build_produces_self_aliases() {
+    // Give `log 0.4.13` a renamed dev-dependency on itself
+    let mut raze_metadata =
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    let mut log_id = None;
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version == Version::new(0, 4, 13) {
+        let mut self_dep = package.dependencies[0].clone();
+        self_dep.name = "log".to_owned();
+        self_dep.req = VersionReq::parse("=0.4.13").unwrap();
+        self_dep.rename = Some("log_self".to_owned());
+        self_dep.kind = DependencyKind::Development;
+        self_dep.target = None;
+        package.dependencies.push(self_dep);
+        log_id = Some(package.id.clone());
+      }
+    }
+    let log_id = log_id.unwrap();
+    for node in raze_metadata
+      .metadata
+      .resolve
+      .as_mut()
+      .unwrap()
+      .nodes
+      .iter_mut()
+    {
+      if node.id == log_id {
+        node.dependencies.push(log_id.clone());
+      }
+    }
+
+    let planner = BuildPlannerImpl::new(raze_metadata, dummy_raze_settings());
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+
+    assert_eq!(log.self_alias, Some("log_self".to_owned()));
+    // The crate does not depend on itself
+    assert!(!log.default_deps.contains("log", Version::new(0, 4, 13)));
+    assert!(log.default_deps.aliased_dependencies.is_empty());
+  }
+
+  #[test]
+  fn test_plan_build_honors_crate_gen_mode_overrides() {
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    settings.crates.insert("log".to_owned(), {
+      let mut versions = HashMap::new();
+      versions.insert(
+        VersionReq::parse("*").unwrap(),
+        crate::settings::CrateSettings {
+          gen_mode: Some(GenMode::Vendored),
+          ..Default::default()
+        },
+      );
+      // The more specific requirement takes precedence over `*`
+      versions.insert(
+        VersionReq::parse("0.4.13").unwrap(),
+        crate::settings::CrateSettings {
+          gen_mode: Some(GenMode::Remote),
+          ..Default::default()
+        },
+      );
+      versions
+    });
+
+    let planner = BuildPlannerImpl::new(
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings,
+    );
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    let find_crate = |name: &str, version: Version| {
+      planned_build
+        .crate_contexts
+        .iter()
+        .find(|ctx| ctx.pkg_name == name && ctx.pkg_version == version)
+        .unwrap()
+    };
+
+    let vendored_log = find_crate("log", Version::new(0, 3, 9));
+    assert_eq!(vendored_log.raze_settings.gen_mode, Some(GenMode::Vendored));
+    assert_eq!(vendored_log.expected_build_path, "vendor/log-0.3.9/BUILD");
+    assert_eq!(
+      vendored_log.workspace_path_to_crate,
+      "//cargo/vendor/log-0.3.9"
+    );
+
+    let remote_log = find_crate("log", Version::new(0, 4, 13));
+    assert_eq!(remote_log.raze_settings.gen_mode, Some(GenMode::Remote));
+    assert_eq!(
+      remote_log.expected_build_path,
+      "remote/BUILD.log-0.4.13.bazel"
+    );
+
+    // Dependents reference the vendored crate by its vendored label
+    let alias_test = find_crate("cargo-raze-alias-test", Version::new(0, 1, 0));
+    assert_eq!(
+      alias_test.default_deps.aliased_dependencies[0].target,
+      "//cargo/vendor/log-0.3.9:log"
+    );
+  }
+
+  #[test]
+  fn test_plan_build_downloads_crates_from_their_configured_registry() {
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    settings.crates.insert("log".to_owned(), {
+      let mut versions = HashMap::new();
+      versions.insert(
+        VersionReq::parse("0.4.13").unwrap(),
+        crate::settings::CrateSettings {
+          registry: Some(
+            "https://artifactory.example.com/api/cargo/crates/{crate}/{version}/download"
+              .to_owned(),
+          ),
+          ..Default::default()
+        },
+      );
+      versions
+    });
+    settings.source_auth.insert(
+      "artifactory.example.com".to_owned(),
+      crate::settings::SourceAuth {
+        netrc: None,
+        auth_pattern: Some("Bearer <password>".to_owned()),
+      },
+    );
+
+    let planned_build = BuildPlannerImpl::new(
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings.clone(),
+    )
+    .plan_build(Some(PlatformDetails::new(
+      "some_target_triple".to_owned(),
+      Vec::new(), /* attrs */
+    )))
+    .unwrap();
+    let registry_url = |version: Version| {
+      planned_build
+        .crate_contexts
+        .iter()
+        .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == version)
+        .unwrap()
+        .registry_url
+        .clone()
+    };
+    assert_eq!(
+      registry_url(Version::new(0, 4, 13)),
+      "https://artifactory.example.com/api/cargo/crates/log/0.4.13/download"
+    );
+    assert_eq!(
+      registry_url(Version::new(0, 3, 9)),
+      "https://crates.io/api/v1/crates/log/0.3.9/download"
+    );
+
+    let root = PathBuf::from("/some/cargo/root");
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let files = render_files(&settings, &render_details, &planned_build).unwrap();
+    let (_, crates_bzl) = files
+      .iter()
+      .find(|(path, _)| path.ends_with("crates.bzl"))
+      .unwrap();
+    assert!(crates_bzl.contains(concat!(
+      "        url = \"https://artifactory.example.com/api/cargo/crates/log/0.4.13/download\",\n",
+      "        type = \"tar.gz\",\n",
+    )));
+    assert!(crates_bzl.contains(concat!(
+      "        auth_patterns = {\n",
+      "            \"artifactory.example.com\": \"Bearer <password>\",\n",
+      "        },\n",
+    )));
+    assert_eq!(crates_bzl.matches("auth_patterns").count(), 1);
+  }
+
+  #[test]
+  fn test_plan_build_reports_dangling_dependency_labels() {
+    let planner = BuildPlannerImpl::new(
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      dummy_raze_settings(),
     );
     let planned_build = planner
       .plan_build(Some(PlatformDetails::new(
@@ -279,38 +3295,108 @@ mod tests {
         Vec::new(), /* attrs */
       )))
       .unwrap();
+    assert!(subplanners::find_dangling_dependency_labels(&planned_build.crate_contexts).is_empty());
 
-    let serde = planned_build
+    // Dropping `cfg-if` from the plan leaves `log 0.4.13` depending on a missing crate
+    let crate_contexts: Vec<CrateContext> = planned_build
       .crate_contexts
-      .iter()
-      .find(|ctx| ctx.pkg_name == "serde")
-      .unwrap();
-
-    let serde_derive_proc_macro_deps: Vec<_> = serde
-      .default_deps
-      .proc_macro_dependencies
-      .iter()
-      .filter(|dep| dep.name == "serde_derive")
-      .collect();
-    assert_eq!(serde_derive_proc_macro_deps.len(), 1);
-
-    let serde_derive_normal_deps: Vec<_> = serde
-      .default_deps
-      .dependencies
-      .iter()
-      .filter(|dep| dep.name == "serde_derive")
+      .into_iter()
+      .filter(|ctx| ctx.pkg_name != "cfg-if")
       .collect();
-    assert_eq!(serde_derive_normal_deps.len(), 0);
+    assert_eq!(
+      subplanners::find_dangling_dependency_labels(&crate_contexts),
+      vec![
+        "`log-0.4.13` depends on `@raze_test__cfg_if__0_1_10//:cfg_if`, which does not refer to \
+            any planned crate"
+          .to_owned()
+      ]
+    );
   }
 
   #[test]
-  fn test_plan_build_produces_build_proc_macro_dependencies() {
+  fn test_plan_build_warns_of_build_scripts_without_rules() {
+    let plan = |settings: RazeSettings| {
+      BuildPlannerImpl::new(
+        template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+        settings,
+      )
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap()
+    };
+
     let mut settings = dummy_raze_settings();
-    settings.genmode = GenMode::Remote;
+    let planned_build = plan(settings.clone());
+    assert!(subplanners::find_skipped_build_scripts(&planned_build.crate_contexts).is_empty());
+
+    // Build scripts are no longer generated by default
+    settings.default_gen_buildrs = false;
+    let planned_build = plan(settings.clone());
+    assert_eq!(
+      subplanners::find_skipped_build_scripts(&planned_build.crate_contexts),
+      vec![
+        "`log-0.4.13` has a build script, but no `cargo_build_script` rule is generated for \
+            it. Set its `gen_buildrs` setting to generate one or to silence this warning"
+          .to_owned()
+      ]
+    );
+
+    // Explicitly skipping the build script silences the warning
+    let log_settings = CrateSettings {
+      gen_buildrs: Some(false),
+      ..Default::default()
+    };
+    settings.crates.insert(
+      "log".to_owned(),
+      vec![(VersionReq::parse("0.4.13").unwrap(), log_settings)]
+        .into_iter()
+        .collect(),
+    );
+    let planned_build = plan(settings);
+    assert!(subplanners::find_skipped_build_scripts(&planned_build.crate_contexts).is_empty());
+  }
+
+  #[test]
+  fn test_dependency_aliases_are_deduplicated_and_sorted() {
+    let alias = |target: &str, alias: &str| DependencyAlias {
+      target: target.to_owned(),
+      alias: alias.to_owned(),
+    };
+
+    let (aliases, conflicts) = subplanners::dedup_dependency_aliases(
+      "some-crate-1.0.0",
+      vec![
+        alias("@raze__log__0_4_13//:log", "log_04"),
+        alias("@raze__cfg_if__0_1_10//:cfg_if", "cfg"),
+        alias("@raze__log__0_4_13//:log", "log_04"),
+        alias("@raze__log__0_4_13//:log", "log_latest"),
+      ],
+    );
+
+    assert_eq!(
+      aliases,
+      vec![
+        alias("@raze__cfg_if__0_1_10//:cfg_if", "cfg"),
+        alias("@raze__log__0_4_13//:log", "log_latest"),
+      ]
+    );
+    assert_eq!(
+      conflicts,
+      vec![
+        "`some-crate-1.0.0` aliases `@raze__log__0_4_13//:log` as both `log_04` and \
+            `log_latest`, `log_latest` is used"
+          .to_owned()
+      ]
+    );
+  }
 
+  #[test]
+  fn test_plan_build_reports_excessive_dependency_depth() {
     let planner = BuildPlannerImpl::new(
-      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_BUILD_PROC_MACRO_DEPENDENCIES),
-      settings,
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      dummy_raze_settings(),
     );
     let planned_build = planner
       .plan_build(Some(PlatformDetails::new(
@@ -319,45 +3405,41 @@ mod tests {
       )))
       .unwrap();
 
-    let markup = planned_build
-      .crate_contexts
-      .iter()
-      .find(|ctx| ctx.pkg_name == "markup5ever")
-      .unwrap();
-
-    let markup_proc_macro_deps: Vec<_> = markup
-      .default_deps
-      .proc_macro_dependencies
-      .iter()
-      .filter(|dep| dep.name == "serde_derive")
-      .collect();
-    assert_eq!(markup_proc_macro_deps.len(), 0);
-
-    let markup_build_proc_macro_deps: Vec<_> = markup
-      .default_deps
-      .build_proc_macro_dependencies
-      .iter()
-      .filter(|dep| dep.name == "serde_derive")
-      .collect();
-    assert_eq!(markup_build_proc_macro_deps.len(), 1);
+    // The deepest chain is `cargo-raze-alias-test` -> `log 0.3.9` -> `log 0.4.13` -> `cfg-if`
+    assert_eq!(
+      subplanners::find_excessive_dependency_depth(&planned_build.crate_contexts, 4),
+      None
+    );
+    assert_eq!(
+      subplanners::find_excessive_dependency_depth(&planned_build.crate_contexts, 3),
+      Some(
+        "The dependency graph is 4 crates deep, exceeding the `max_dependency_depth` of 3: \
+         cargo-raze-alias-test-0.1.0 -> log-0.3.9 -> log-0.4.13 -> cfg-if-0.1.10"
+          .to_owned()
+      )
+    );
   }
 
   #[test]
-  fn test_subplan_produces_crate_root_with_forward_slash() {
+  fn test_plan_build_fails_for_crates_outside_of_the_allowlist() {
+    let mut settings = dummy_raze_settings();
+    settings.allowed_crates = vec!["cargo-raze-alias-test".to_owned(), "log >=0.3".to_owned()];
     let planner = BuildPlannerImpl::new(
-      dummy_workspace_crate_metadata(templates::SUBPLAN_PRODUCES_CRATE_ROOT_WITH_FORWARD_SLASH),
-      dummy_raze_settings(),
+      dummy_workspace_crate_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings,
     );
-    let planned_build = planner
+    let err = planner
       .plan_build(Some(PlatformDetails::new(
         "some_target_triple".to_owned(),
         Vec::new(), /* attrs */
       )))
-      .unwrap();
+      .unwrap_err();
 
     assert_eq!(
-      planned_build.crate_contexts[0].targets[0].path,
-      "src/lib.rs"
+      err.to_string(),
+      "Raze failed to plan crate \"cfg-if\" with cause: \"Crates outside of `allowed_crates` were \
+       resolved: cfg-if-0.1.10 (build_produces_aliased_dependencies-0.1.0 -> \
+       cargo-raze-alias-test-0.1.0 -> log-0.4.13 -> cfg-if-0.1.10)\""
     );
   }
 
@@ -376,11 +3458,12 @@ mod tests {
     fetcher.set_metadata_fetcher(Box::new(dummy_metadata_fetcher));
 
     let mock = mock_remote_crate("some-binary-crate", "3.3.3", &server);
-    let dir = mock_crate_index(
-      &to_index_crates_map(vec![("some-binary-crate", "3.3.3")]),
-      Some(index_dir.as_ref()),
+    mock_crate_index_entry(
+      index_dir.as_ref(),
+      "some-binary-crate",
+      "3.3.3",
+      &mock.checksum,
     );
-    assert!(dir.is_none());
 
     let mut settings = dummy_raze_settings();
     settings.binary_deps.insert(
@@ -434,6 +3517,61 @@ mod tests {
     assert!(context.sha256.is_some());
   }
 
+  #[test]
+  fn test_binary_dependencies_render_a_binary_per_bin_target() {
+    let (raze_metadata, mut settings) =
+      dummy_binary_dependency_metadata(/*is_remote_genmode=*/ true);
+    settings.genmode = GenMode::Remote;
+
+    let planned_build = BuildPlannerImpl::new(raze_metadata.clone(), settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let render_details = RenderDetails::new(
+      &settings,
+      raze_metadata.cargo_workspace_root.clone(),
+      raze_metadata.cargo_workspace_root.clone(),
+    );
+    let files = render_files(&settings, &render_details, &planned_build).unwrap();
+
+    let (_, build_file) = files
+      .iter()
+      .find(|(path, _)| path.ends_with("remote/BUILD.some-binary-crate-3.3.3.bazel"))
+      .unwrap();
+    let rule = |kind: &str, name: &str| {
+      let name_attr = format!("    name = \"{}\",\n", name);
+      build_file
+        .split("\n\n")
+        .find(|rule| rule.starts_with(kind) && rule.contains(&name_attr))
+        .unwrap_or_else(|| panic!("No {} named {} in {}", kind, name, build_file))
+    };
+    // Binaries are named after their bin target and link against the crate's lib
+    let main_binary = rule("rust_binary(", "cargo_bin_some_binary_crate");
+    assert!(main_binary.contains("    crate_root = \"src/main.rs\",\n"));
+    assert!(main_binary.contains("        \":some_binary_crate\",\n"));
+    let tool_binary = rule("rust_binary(", "cargo_bin_some_tool");
+    assert!(tool_binary.contains("    crate_root = \"src/bin/some_tool.rs\",\n"));
+    rule("rust_library(", "some_binary_crate");
+
+    // The binaries are exposed through the workspace aliases so they can be used as `tools`
+    let (_, aliases) = files
+      .iter()
+      .find(|(path, _)| path.ends_with("cargo/BUILD.bazel"))
+      .unwrap();
+    for binary in &["cargo_bin_some_binary_crate", "cargo_bin_some_tool"] {
+      assert!(
+        aliases.contains(&format!(
+          "    name = \"{0}\",\n    actual = \"@raze_test__some_binary_crate__3_3_3//:{0}\",\n",
+          binary
+        )),
+        "{}",
+        aliases
+      );
+    }
+  }
+
   #[test]
   fn test_binary_dependencies_vendored_genmode() {
     let (raze_metadata, mut settings) =
@@ -680,6 +3818,129 @@ mod tests {
     };
   }
 
+  #[test]
+  fn test_plan_build_emits_exactly_the_features_pinned_by_the_lockfile() {
+    let dir = make_basic_workspace();
+    let (mut fetcher, _server, _index_dir) = dummy_raze_metadata_fetcher();
+    fetcher.set_metadata_fetcher(Box::new(DummyCargoMetadataFetcher {
+      metadata_template: Some(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES.to_string()),
+    }));
+
+    // Pin `log 0.4.13` to a feature set other than its resolved `std` feature
+    let lockfile = dir.as_ref().join("Cargo.raze.lock");
+    std::fs::write(
+      &lockfile,
+      format!(
+        "{}\n[metadata]\n\"features log 0.4.13\" = \"kv_unstable, serde\"\n",
+        basic_lock_contents()
+      ),
+    )
+    .unwrap();
+    let raze_metadata = fetcher
+      .fetch_metadata(dir.as_ref(), None, Some(lockfile))
+      .unwrap();
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    let planned_build = BuildPlannerImpl::new(raze_metadata, settings)
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(),
+      )))
+      .unwrap();
+
+    let log = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "log" && ctx.pkg_version == Version::new(0, 4, 13))
+      .unwrap();
+    assert_eq!(log.features, vec!["kv_unstable", "serde"]);
+    assert!(log
+      .targeted_deps
+      .iter()
+      .all(|targeted_deps| targeted_deps.features.is_empty()));
+
+    let file_outputs = render_remote_planned_build(&planned_build);
+    let log_build = file_outputs
+      .iter()
+      .find(|output| output.path.ends_with("remote/BUILD.log-0.4.13.bazel"))
+      .unwrap();
+
+    assert!(log_build
+      .contents
+      .contains("crate_features = [\n        \"kv_unstable\",\n        \"serde\",\n    ],"));
+    assert!(!log_build.contents.contains("\"std\""));
+  }
+
+  #[test]
+  fn test_crate_overrides_file_is_merged_into_crate_settings() {
+    let toml_file = indoc! { r#"
+    [package]
+    name = "overrides_toml"
+    version = "0.1.0"
+
+    [lib]
+    path = "not_a_file.rs"
+
+    [dependencies]
+    openssl-sys = "=0.9.24"
+
+    [package.metadata.raze]
+    workspace_path = "//cargo"
+    genmode = "Remote"
+    crate_overrides_file = "raze_overrides.toml"
+
+    [package.metadata.raze.crates.openssl-sys.'0.9.24']
+    additional_deps = [
+      "@//third_party/openssl:crypto",
+    ]
+    "#};
+    let overrides_file = indoc! { r#"
+    [crates.openssl-sys.'0.9.24']
+    additional_deps = [
+      "@//third_party/openssl:ssl",
+    ]
+    "#};
+
+    let temp_dir = make_workspace(toml_file, None);
+    std::fs::write(
+      temp_dir.as_ref().join("raze_overrides.toml"),
+      overrides_file,
+    )
+    .unwrap();
+    let settings =
+      crate::settings::load_settings_from_manifest(temp_dir.as_ref().join("Cargo.toml"), None)
+        .unwrap();
+
+    let planner = BuildPlannerImpl::new(
+      dummy_workspace_crate_metadata(templates::SEMVER_MATCHING),
+      settings,
+    );
+    let planned_build = planner
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(),
+      )))
+      .unwrap();
+
+    let file_outputs = render_remote_planned_build(&planned_build);
+    let openssl_sys_build = file_outputs
+      .iter()
+      .find(|output| {
+        output
+          .path
+          .ends_with("remote/BUILD.openssl-sys-0.9.24.bazel")
+      })
+      .unwrap();
+
+    assert!(openssl_sys_build
+      .contents
+      .contains("\"@//third_party/openssl:crypto\","));
+    assert!(openssl_sys_build
+      .contents
+      .contains("\"@//third_party/openssl:ssl\","));
+  }
+
   fn dummy_workspace_member_toml_contents(name: &str, dep_version: &str) -> String {
     assert!(
       dep_version == "0.2.1" || dep_version == "0.1.0",
@@ -774,6 +4035,176 @@ mod tests {
       .find(|ctx| ctx.pkg_name == "unicode-xid" && ctx.pkg_version == Version::from((0, 2, 1)))
       .is_some());
   }
+  #[test]
+  fn test_plan_build_renders_workspace_members_into_their_packages() {
+    let mut raze_metadata = dummy_workspace_members_metadata();
+    // Make `lib_b` depend on `lib_a`
+    let metadata = &mut raze_metadata.metadata;
+    let lib_a_id = metadata
+      .packages
+      .iter()
+      .find(|package| package.name == "lib_a")
+      .unwrap()
+      .id
+      .clone();
+    for package in metadata.packages.iter_mut() {
+      if package.name == "lib_b" {
+        let mut dep = package.dependencies[0].clone();
+        dep.name = "lib_a".to_owned();
+        dep.req = VersionReq::parse("*").unwrap();
+        dep.source = None;
+        package.dependencies.push(dep);
+      }
+    }
+    for node in metadata.resolve.as_mut().unwrap().nodes.iter_mut() {
+      if node.id.repr.starts_with("lib_b ") {
+        let mut node_dep = node.deps[0].clone();
+        node_dep.name = "lib_a".to_owned();
+        node_dep.pkg = lib_a_id.clone();
+        node.deps.push(node_dep);
+        node.dependencies.push(lib_a_id.clone());
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    settings.render_workspace_members = true;
+    let planned_build = BuildPlannerImpl::new(raze_metadata.clone(), settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    assert!(!planned_build
+      .crate_contexts
+      .iter()
+      .any(|ctx| ctx.pkg_name.starts_with("lib_")));
+    let lib_b = planned_build
+      .workspace_member_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == "lib_b")
+      .unwrap();
+    assert!(lib_b.is_workspace_member);
+    assert_eq!(lib_b.expected_build_path, "lib_b/BUILD");
+    assert_eq!(lib_b.workspace_path_to_crate, "//lib_b");
+    assert_eq!(
+      lib_b
+        .default_deps
+        .dependencies
+        .iter()
+        .map(|dep| dep.buildable_target.as_str())
+        .collect::<Vec<_>>(),
+      vec![
+        "//lib_a:lib_a",
+        "@raze_test__unicode_xid__0_1_0//:unicode_xid"
+      ]
+    );
+    assert_eq!(planned_build.workspace_member_contexts.len(), 2);
+
+    let root = raze_metadata.cargo_workspace_root.clone();
+    let render_details = RenderDetails::new(&settings, root.clone(), root.clone());
+    let files = render_files(&settings, &render_details, &planned_build).unwrap();
+    let lib_b_build = &files[&root.join("lib_b/BUILD")];
+    assert!(lib_b_build.contains("    name = \"lib_b\",\n"));
+    assert!(lib_b_build.contains("        \"//lib_a:lib_a\",\n"));
+    assert!(!lib_b_build.contains("--cap-lints=allow"));
+    assert!(!lib_b_build.contains("\"manual\""));
+    assert!(lib_b_build.contains(concat!(
+      "package(default_visibility = [\n",
+      "    \"//visibility:public\",\n",
+      "])",
+    )));
+    assert!(files.contains_key(&root.join("lib_a/BUILD")));
+  }
+
+  #[test]
+  fn test_workspace_members_share_dependency_with_unified_features() {
+    let mut raze_metadata = dummy_workspace_members_metadata();
+    // Make `lib_b` depend on the same version of `unicode-xid` as `lib_a`, resolved from another
+    // source and without its default features
+    let registry_id = "unicode-xid 0.2.1 (registry+https://github.com/rust-lang/crates.io-index)";
+    let git_id = PackageId {
+      repr: "unicode-xid 0.2.1 (git+https://github.com/unicode-rs/unicode-xid?rev=abc#abc)"
+        .to_owned(),
+    };
+    let metadata = &mut raze_metadata.metadata;
+    let mut git_package = metadata
+      .packages
+      .iter()
+      .find(|package| package.id.repr == registry_id)
+      .unwrap()
+      .clone();
+    git_package.id = git_id.clone();
+    metadata.packages.push(git_package);
+    for package in metadata.packages.iter_mut() {
+      if package.name == "lib_b" {
+        let dep = &mut package.dependencies[0];
+        dep.req = VersionReq::parse("^0.2.1").unwrap();
+        dep.uses_default_features = false;
+        dep.features = vec!["bench".to_owned()];
+      }
+    }
+    let resolve = metadata.resolve.as_mut().unwrap();
+    let mut git_node = resolve
+      .nodes
+      .iter()
+      .find(|node| node.id.repr == registry_id)
+      .unwrap()
+      .clone();
+    git_node.id = git_id.clone();
+    git_node.features = vec!["bench".to_owned()];
+    resolve.nodes.push(git_node);
+    for node in resolve.nodes.iter_mut() {
+      if node.id.repr.starts_with("lib_b ") {
+        node.dependencies = vec![git_id.clone()];
+        node.deps[0].pkg = git_id.clone();
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Remote;
+    let planned_build = BuildPlannerImpl::new(raze_metadata.clone(), settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+
+    // Disabling the default features of `lib_b`'s dependency doesn't suppress those `lib_a` uses
+    let unicode_xid: Vec<&CrateContext> = planned_build
+      .crate_contexts
+      .iter()
+      .filter(|ctx| ctx.pkg_name == "unicode-xid" && ctx.pkg_version == Version::new(0, 2, 1))
+      .collect();
+    assert_eq!(unicode_xid.len(), 1);
+    assert_eq!(unicode_xid[0].features, vec!["bench", "default"]);
+    for ctx in planned_build.crate_contexts.iter() {
+      if ctx.pkg_name == "lib_a" || ctx.pkg_name == "lib_b" {
+        assert_eq!(ctx.default_deps.dependencies.len(), 1);
+      }
+    }
+
+    let render_details = RenderDetails::new(
+      &settings,
+      raze_metadata.cargo_workspace_root.clone(),
+      raze_metadata.cargo_workspace_root.clone(),
+    );
+    let files = render_files(&settings, &render_details, &planned_build).unwrap();
+    let (_, crates_bzl) = files
+      .iter()
+      .find(|(path, _)| path.ends_with("crates.bzl"))
+      .unwrap();
+    assert_eq!(
+      crates_bzl
+        .matches("name = \"raze_test__unicode_xid__0_2_1\",")
+        .count(),
+      1,
+      "{}",
+      crates_bzl
+    );
+  }
+
   // TODO(acmcarther): Add tests:
   // TODO(acmcarther): Extra flags work
   // TODO(acmcarther): Extra deps work
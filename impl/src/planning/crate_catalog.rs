@@ -22,7 +22,7 @@ use cargo_metadata::{Metadata, Node, Package, PackageId};
 
 use crate::{
   error::RazeError,
-  settings::{GenMode, RazeSettings},
+  settings::{crate_genmode, GenMode, RazeSettings},
   util,
   util::package_ident,
 };
@@ -50,7 +50,7 @@ impl CrateCatalogEntry {
     workspace_member_dependents: Vec<PackageId>,
   ) -> Self {
     let sanitized_name = package.name.replace("-", "_");
-    let sanitized_version = util::sanitize_ident(&package.version.clone().to_string());
+    let sanitized_version = util::sanitize_version(&package.version.to_string());
 
     Self {
       package: package.clone(),
@@ -80,7 +80,7 @@ impl CrateCatalogEntry {
 
   /// Yields the expected location of the build file (relative to execution path).
   pub fn local_build_path(&self, settings: &RazeSettings) -> Result<String> {
-    match settings.genmode {
+    match crate_genmode(settings, &self.package) {
       GenMode::Remote => Ok(format!("remote/BUILD.{}.bazel", &self.package_ident,)),
       GenMode::Vendored => Ok(format!(
         "vendor/{}/{}",
@@ -95,7 +95,7 @@ impl CrateCatalogEntry {
 
   /// Yields the precise path to this dependency for the provided settings.
   pub fn workspace_path(&self, settings: &RazeSettings) -> Result<String> {
-    match settings.genmode {
+    match crate_genmode(settings, &self.package) {
       GenMode::Remote => Ok(format!(
         "@{}__{}__{}//",
         &settings.gen_workspace_prefix, &self.sanitized_name, &self.sanitized_version
@@ -120,27 +120,36 @@ impl CrateCatalogEntry {
     }
   }
 
+  /// The name of the rule this crate's library is rendered as, which dependents refer to it by.
+  pub fn default_target_name(&self, settings: &RazeSettings) -> String {
+    match &settings.library_target_name {
+      Some(library_target_name) => library_target_name.replace("{name}", &self.sanitized_name),
+      None => self.sanitized_name.clone(),
+    }
+  }
+
   /// Emits a complete path to this dependency and default target using the given settings.
   pub fn workspace_path_and_default_target(&self, settings: &RazeSettings) -> Result<String> {
-    match settings.genmode {
+    let default_target_name = self.default_target_name(settings);
+    match crate_genmode(settings, &self.package) {
       GenMode::Remote => Ok(format!(
         "@{}__{}__{}//:{}",
         &settings.gen_workspace_prefix,
         &self.sanitized_name,
         &self.sanitized_version,
-        &self.sanitized_name
+        &default_target_name
       )),
       GenMode::Vendored => {
         // Convert "settings.workspace_path" to dir. Workspace roots are special cased, no need to append /
         if settings.workspace_path.ends_with("//") {
           Ok(format!(
             "{}vendor/{}:{}",
-            settings.workspace_path, &self.package_ident, &self.sanitized_name
+            settings.workspace_path, &self.package_ident, &default_target_name
           ))
         } else {
           Ok(format!(
             "{}/vendor/{}:{}",
-            settings.workspace_path, &self.package_ident, &self.sanitized_name
+            settings.workspace_path, &self.package_ident, &default_target_name
           ))
         }
       },
@@ -133,6 +133,7 @@ pub fn get_license_from_str(cargo_license_str: &str) -> LicenseData {
           cargo_license_str
         ),
         rating: BazelLicenseType::Restricted.to_bazel_rating().into(),
+        spdx_ids: Vec::new(),
       };
     },
   };
@@ -167,12 +168,20 @@ pub fn get_license_from_str(cargo_license_str: &str) -> LicenseData {
   }
 
   let crate_license = license_stack.pop().unwrap();
+  let mut spdx_ids: Vec<String> = license_expression
+    .requirements()
+    .filter_map(|requirement| requirement.req.license.id())
+    .map(|id| id.name.to_owned())
+    .collect();
+  spdx_ids.sort();
+  spdx_ids.dedup();
   LicenseData {
     name: format!(
       "{} from expression \"{}\"",
       crate_license.name, crate_license.expression
     ),
     rating: crate_license.license.to_bazel_rating().into(),
+    spdx_ids,
   }
 }
 
@@ -609,6 +618,13 @@ mod tests {
     assert_eq!(license.rating, "restricted");
   }
 
+  #[test]
+  fn spdx_ids_list_every_license_of_the_expression() {
+    let license = get_license_from_str("MIT OR (Apache-2.0 AND MIT) OR Zlib");
+    assert_eq!(license.spdx_ids, vec!["Apache-2.0", "MIT", "Zlib"]);
+    assert!(get_license_from_str("MIT5.0").spdx_ids.is_empty());
+  }
+
   #[test]
   fn whitespace_laden_licenses_are_ok() {
     let license = get_license_from_str("MIT / Apache-2.0");
@@ -13,15 +13,15 @@
 // limitations under the License.
 
 use std::{
-  collections::{HashMap, HashSet},
-  io, iter,
+  collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+  fs, io, iter,
   path::{Path, PathBuf},
   str::FromStr,
 };
 
 use anyhow::{anyhow, Context, Result};
 use cargo_lock::SourceId;
-use cargo_metadata::{DependencyKind, Node, Package};
+use cargo_metadata::{DependencyKind, Node, Package, PackageId};
 use cargo_platform::Platform;
 use itertools::Itertools;
 
@@ -32,9 +32,10 @@ use crate::{
     WorkspaceContext,
   },
   error::{RazeError, PLEASE_FILE_A_BUG},
+  incremental::{CacheKey, CrateCache},
   metadata::RazeMetadata,
   planning::license,
-  settings::{format_registry_url, CrateSettings, GenMode, RazeSettings},
+  settings::{self, crate_genmode, format_registry_url, CrateSettings, GenMode, RazeSettings},
   util,
 };
 
@@ -43,6 +44,13 @@ use super::{
   PlannedBuild,
 };
 
+/// The crate types of library targets, ordered by preference for the rule which the crate's
+/// dependents link against.
+const LIBRARY_CRATE_TYPES: &[&str] = &["lib", "rlib", "proc-macro", "dylib", "cdylib", "staticlib"];
+
+/// The crate types of library targets which other Rust crates can link against.
+const LINKABLE_CRATE_TYPES: &[&str] = &["lib", "rlib", "proc-macro", "dylib"];
+
 /// A set of named dependencies (without version) derived from a package manifest.
 struct DependencyNames {
   // Dependencies that are required for all buildable targets of this crate
@@ -51,8 +59,11 @@ struct DependencyNames {
   build_dep_names: Vec<String>,
   // Dependencies that are required for tests
   dev_dep_names: Vec<String>,
-  // Dependencies that have been renamed and need to be aliased in the build rule
-  aliased_dep_names: HashMap<String, (semver::VersionReq, String)>,
+  // Dependencies that have been renamed and need to be aliased in the build rule, keyed by package
+  // name. A package may be renamed more than once, eg: to depend on several of its versions.
+  aliased_dep_names: HashMap<String, Vec<(semver::VersionReq, String)>>,
+  // Build dependencies that have been renamed and need to be aliased in the build script rule
+  build_aliased_dep_names: HashMap<String, Vec<(semver::VersionReq, String)>>,
 }
 
 // TODO(acmcarther): Remove this struct -- move it into CrateContext.
@@ -69,6 +80,8 @@ struct DependencySet {
   dev_deps: Vec<BuildableDependency>,
   // Dependencies that have been renamed and need to be aliased in the build rule
   aliased_deps: Vec<DependencyAlias>,
+  // Build dependencies that have been renamed and need to be aliased in the build script rule
+  build_aliased_deps: Vec<DependencyAlias>,
 }
 
 /// A set of dependencies that a crate has for a specific target/cfg
@@ -77,6 +90,14 @@ struct TargetedDependencySet {
   dependencies: DependencySet,
 }
 
+/// Features requested of a crate by one of its dependents.
+struct FeatureRequest {
+  // The target and matching configured triples the request is limited to, if it does not apply to
+  // all configured triples.
+  gate: Option<(String, Vec<String>)>,
+  features: Vec<String>,
+}
+
 /// An internal working planner for generating context for an individual crate.
 struct CrateSubplanner<'planner> {
   // Workspace-Wide details
@@ -89,6 +110,14 @@ struct CrateSubplanner<'planner> {
   node: &'planner Node,
   crate_settings: Option<&'planner CrateSettings>,
   sha256: &'planner Option<String>,
+  // The exact features pinned for the crate by the lockfile, taking precedence over resolution
+  locked_features: Option<&'planner Vec<String>>,
+  // The download url of a crate from a registry other than the default one
+  download_url: Option<&'planner String>,
+  // The repository a binary dependency was cloned from, as it failed to download
+  git_fallback: Option<&'planner GitRepo>,
+  // The Bazel packages of the workspace members which are rendered into their own packages
+  workspace_member_packages: &'planner HashMap<PackageId, String>,
 }
 
 /// An internal working planner for generating context for a whole workspace.
@@ -97,21 +126,167 @@ pub struct WorkspaceSubplanner<'planner> {
   pub(super) platform_details: &'planner Option<util::PlatformDetails>,
   pub(super) crate_catalog: &'planner CrateCatalog,
   pub(super) metadata: &'planner RazeMetadata,
+  pub(super) crate_cache: Option<&'planner CrateCache>,
 }
 
 impl<'planner> WorkspaceSubplanner<'planner> {
   /// Produces a planned build using internal state.
   pub fn produce_planned_build(&self) -> Result<PlannedBuild> {
     // Produce planned build
-    let crate_contexts = self.produce_crate_contexts()?;
+    check_allowed_crates(self.crate_catalog, &self.settings.allowed_crates)?;
+    let (workspace_member_contexts, mut crate_contexts): (Vec<_>, Vec<_>) = self
+      .produce_crate_contexts()?
+      .into_iter()
+      .partition(|ctx| ctx.is_workspace_member);
+    self.constrain_platform_exclusive_crates(&mut crate_contexts)?;
+    check_dependency_cycles(&crate_contexts)?;
+    if let Some(max_depth) = self.settings.max_dependency_depth {
+      if let Some(warning) = find_excessive_dependency_depth(&crate_contexts, max_depth) {
+        eprintln!("WARNING: {}", warning);
+      }
+    }
+    for dangling_label in find_dangling_dependency_labels(&crate_contexts) {
+      eprintln!("WARNING: {}", dangling_label);
+    }
+    for skipped_build_script in find_skipped_build_scripts(&crate_contexts) {
+      eprintln!("WARNING: {}", skipped_build_script);
+    }
 
     Ok(PlannedBuild {
       workspace_context: self.produce_workspace_context(),
       crate_contexts,
+      workspace_member_contexts,
       lockfile: self.metadata.lockfile.clone(),
     })
   }
 
+  /// Limits the crates which are only reachable on platforms of a single operating system, eg:
+  /// through `cfg(windows)` dependencies, to that operating system. Such crates often fail to
+  /// build anywhere else, which would otherwise break wildcard builds like `bazel build //...`.
+  fn constrain_platform_exclusive_crates(&self, crate_contexts: &mut [CrateContext]) -> Result<()> {
+    let mut configured_triples = util::get_supported_bazel_triples(&self.settings.custom_targets);
+    util::filter_bazel_triples(
+      &mut configured_triples,
+      self
+        .settings
+        .targets
+        .as_ref()
+        .unwrap_or(&Vec::<String>::new()),
+    );
+    let all_triples: BTreeSet<String> = configured_triples.into_iter().collect();
+    let matching_triples = |target: &str| -> Result<BTreeSet<String>> {
+      Ok(
+        util::get_matching_bazel_triples(target, &self.settings.custom_targets)?
+          .into_iter()
+          .filter(|triple| all_triples.contains(triple))
+          .collect(),
+      )
+    };
+
+    // The workspace members' dependencies, and binary dependencies, are the roots of the graph
+    let mut pending: Vec<(usize, BTreeSet<String>)> = Vec::new();
+    for member_id in self.crate_catalog.metadata.workspace_members.iter() {
+      let member = match self.crate_catalog.entry_for_package_id(member_id) {
+        Some(entry) => entry.package(),
+        None => continue,
+      };
+      for dep in member.dependencies.iter() {
+        let triples = match (&dep.kind, &dep.target) {
+          (DependencyKind::Build, _) | (_, None) => all_triples.clone(),
+          (_, Some(target)) => matching_triples(&target.to_string())?,
+        };
+        for (idx, _) in crate_contexts
+          .iter()
+          .enumerate()
+          .filter(|(_, ctx)| ctx.pkg_name == dep.name && dep.req.matches(&ctx.pkg_version))
+        {
+          pending.push((idx, triples.clone()));
+        }
+      }
+    }
+    for (idx, ctx) in crate_contexts.iter().enumerate() {
+      if ctx.is_binary_dependency {
+        pending.push((idx, all_triples.clone()));
+      }
+    }
+
+    let platform_prefix = format!(
+      "@{}//rust/platform:",
+      self.settings.rust_rules_workspace_name
+    );
+    let label_to_idx = crate_label_indices(crate_contexts);
+    let mut reachable_triples = vec![BTreeSet::new(); crate_contexts.len()];
+    while let Some((idx, triples)) = pending.pop() {
+      let ctx = &crate_contexts[idx];
+      // Proc macros, like build scripts, are built for the execution platform
+      let triples = match ctx.is_proc_macro {
+        true => all_triples.clone(),
+        false => triples,
+      };
+      if triples.is_subset(&reachable_triples[idx]) {
+        continue;
+      }
+      reachable_triples[idx].extend(triples.iter().cloned());
+
+      let mut dep_contexts = vec![(&ctx.default_deps, triples.clone())];
+      for targeted_deps in ctx.targeted_deps.iter() {
+        let condition_triples: Option<BTreeSet<String>> = targeted_deps
+          .conditions
+          .iter()
+          .map(|condition| {
+            let custom_target = self
+              .settings
+              .custom_targets
+              .iter()
+              .find(|(_, custom_target)| &custom_target.condition == condition);
+            match custom_target {
+              Some((name, _)) => Some(name.clone()),
+              None => condition.strip_prefix(&platform_prefix).map(str::to_owned),
+            }
+          })
+          .collect();
+        // Conditions other than platforms, eg: feature toggles, don't narrow the platforms down
+        let targeted_triples = match condition_triples {
+          Some(condition_triples) => triples.intersection(&condition_triples).cloned().collect(),
+          None => triples.clone(),
+        };
+        dep_contexts.push((&targeted_deps.deps, targeted_triples));
+      }
+      for (deps, dep_triples) in dep_contexts {
+        let lib_deps = deps
+          .dependencies
+          .iter()
+          .chain(deps.proc_macro_dependencies.iter())
+          .map(|dep| (dep, &dep_triples));
+        let build_deps = deps
+          .build_dependencies
+          .iter()
+          .chain(deps.build_proc_macro_dependencies.iter())
+          .map(|dep| (dep, &all_triples));
+        for (dep, triples) in lib_deps.chain(build_deps) {
+          if let Some(dep_idx) = label_to_idx.get(&dep.buildable_target) {
+            pending.push((*dep_idx, triples.clone()));
+          }
+        }
+      }
+    }
+
+    for (ctx, triples) in crate_contexts.iter_mut().zip(reachable_triples) {
+      if triples.is_empty() || triples == all_triples {
+        continue;
+      }
+      let oses: BTreeSet<Option<String>> = triples
+        .iter()
+        .map(|triple| util::get_triple_os(triple, &self.settings.custom_targets))
+        .collect();
+      if let (1, Some(Some(os))) = (oses.len(), oses.iter().next()) {
+        ctx.target_compatible_with = vec![format!("@platforms//os:{}", os)];
+      }
+    }
+
+    Ok(())
+  }
+
   /// Constructs a workspace context from settings.
   fn produce_workspace_context(&self) -> WorkspaceContext {
     // Gather the workspace member paths for all workspace members
@@ -146,15 +321,64 @@ impl<'planner> WorkspaceSubplanner<'planner> {
     WorkspaceContext {
       workspace_path: self.settings.workspace_path.clone(),
       gen_workspace_prefix: self.settings.gen_workspace_prefix.clone(),
+      repositories_function_name: self
+        .settings
+        .repositories_function_name
+        .clone()
+        .unwrap_or_else(|| format!("{}_fetch_remote_crates", self.settings.gen_workspace_prefix)),
       output_buildfile_suffix: self.settings.output_buildfile_suffix.clone(),
       workspace_members,
+      group_deps: self.settings.group_deps,
+      proc_macro_tags: self.settings.proc_macro_tags.clone(),
+      proc_macro_toolchains: self.settings.proc_macro_toolchains.clone(),
+      license_rules: self.settings.license_rules,
     }
   }
 
+  /// Maps the workspace members to their package within the Bazel workspace containing the cargo
+  /// workspace, when they are rendered into their own packages.
+  fn workspace_member_packages(&self) -> HashMap<PackageId, String> {
+    if !self.settings.render_workspace_members {
+      return HashMap::new();
+    }
+
+    let cargo_workspace_root = &self.metadata.cargo_workspace_root;
+    let cargo_workspace_package = util::find_bazel_workspace_root(cargo_workspace_root)
+      .and_then(|bazel_root| {
+        cargo_workspace_root
+          .strip_prefix(bazel_root)
+          .ok()
+          .map(Path::to_path_buf)
+      })
+      .unwrap_or_default();
+    self
+      .metadata
+      .metadata
+      .packages
+      .iter()
+      .filter(|pkg| self.metadata.metadata.workspace_members.contains(&pkg.id))
+      // Binary dependencies are rendered as any other crate
+      .filter(|pkg| !self.settings.binary_deps.contains_key(&pkg.name))
+      .filter_map(|pkg| {
+        let member_path = util::get_workspace_member_path(
+          &pkg.manifest_path,
+          &self.metadata.metadata.workspace_root,
+        )?;
+        let package = cargo_workspace_package.join(member_path);
+        let package = package.to_string_lossy().replace("\\", "/");
+        Some((
+          pkg.id.clone(),
+          format!("//{}", package.trim_end_matches('/')),
+        ))
+      })
+      .collect()
+  }
+
   fn create_crate_context(
     &self,
     node: &Node,
     catalog: &CrateCatalog,
+    workspace_member_packages: &HashMap<PackageId, String>,
   ) -> Option<Result<CrateContext>> {
     let own_crate_catalog_entry = catalog.entry_for_package_id(&node.id)?;
     let own_package = own_crate_catalog_entry.package();
@@ -165,16 +389,23 @@ impl<'planner> WorkspaceSubplanner<'planner> {
       .keys()
       .any(|key| key == &own_package.name);
 
-    // Skip workspace members unless they are binary dependencies
-    if own_crate_catalog_entry.is_workspace_crate() && !is_binary_dep {
+    // Skip workspace members unless they are binary dependencies or rendered into their packages
+    if own_crate_catalog_entry.is_workspace_crate()
+      && !is_binary_dep
+      && !workspace_member_packages.contains_key(&node.id)
+    {
       return None;
     }
 
     // UNWRAP: Safe given unwrap during serialize step of metadata
-    let own_source_id = own_package
-      .source
-      .as_ref()
-      .map(|s| SourceId::from_url(&s.to_string()).unwrap());
+    let own_source_id = own_package.source.as_ref().map(|s| {
+      // Sparse registries are represented as any other registry
+      let source = match s.repr.strip_prefix("sparse+") {
+        Some(index_url) => format!("registry+{}", index_url),
+        None => s.to_string(),
+      };
+      SourceId::from_url(&source).unwrap()
+    });
 
     let crate_settings = self.crate_settings(&own_package).ok()?;
 
@@ -182,6 +413,19 @@ impl<'planner> WorkspaceSubplanner<'planner> {
       .metadata
       .checksum_for(&own_package.name, &own_package.version.to_string());
 
+    // Crates from local paths may change without their version changing, and are never cached
+    let cache_key = match (self.crate_cache, &own_package.source) {
+      (Some(_), Some(_)) => self
+        .context_cache_key(node, catalog, workspace_member_packages)
+        .ok(),
+      _ => None,
+    };
+    if let (Some(crate_cache), Some(cache_key)) = (self.crate_cache, &cache_key) {
+      if let Some(context) = crate_cache.load("context", cache_key) {
+        return Some(Ok(context));
+      }
+    }
+
     let crate_subplanner = CrateSubplanner {
       crate_catalog: &catalog,
       settings: self.settings,
@@ -191,9 +435,104 @@ impl<'planner> WorkspaceSubplanner<'planner> {
       node: &node,
       crate_settings,
       sha256: &checksum_opt.map(|c| c.to_owned()),
+      locked_features: self
+        .metadata
+        .locked_features_for(&own_package.name, &own_package.version.to_string()),
+      download_url: self
+        .metadata
+        .download_url_for(&own_package.name, &own_package.version.to_string()),
+      git_fallback: self
+        .metadata
+        .git_fallback_for(&own_package.name, &own_package.version.to_string()),
+      workspace_member_packages,
     };
 
-    Some(crate_subplanner.produce_context(&self.metadata.cargo_workspace_root))
+    let context = crate_subplanner.produce_context(&self.metadata.cargo_workspace_root);
+    if let (Some(crate_cache), Some(cache_key), Ok(context)) =
+      (self.crate_cache, &cache_key, &context)
+    {
+      crate_cache.store("context", cache_key, context);
+    }
+    Some(context)
+  }
+
+  /// The key of the context planned for the crate of `node` in the crate cache. This covers the
+  /// crate's resolved features and dependencies, the packages of those dependencies and of the
+  /// workspace members depending on it, how each of its dependents requests its features, the
+  /// global and crate settings, the platform, and the crate's metadata and manifest.
+  fn context_cache_key(
+    &self,
+    node: &Node,
+    catalog: &CrateCatalog,
+    workspace_member_packages: &HashMap<PackageId, String>,
+  ) -> Result<CacheKey> {
+    // UNWRAP: The node's package was found in the catalog by its caller
+    let entry = catalog.entry_for_package_id(&node.id).unwrap();
+    let package = entry.package();
+    let version = package.version.to_string();
+    let related_packages: Vec<&Package> = node
+      .deps
+      .iter()
+      .map(|dep| &dep.pkg)
+      .chain(entry.workspace_member_dependents.iter())
+      .filter_map(|id| catalog.entry_for_package_id(id))
+      .map(|entry| entry.package())
+      .collect();
+    // Every dependent requests features of the crate through its dependency entries on it and the
+    // enabled features of its own which enable them
+    let feature_requests: Vec<_> = resolve_dependents(catalog, package)
+      .into_iter()
+      .map(|(dependent, dependent_node)| {
+        (
+          &dependent.id,
+          dependency_entries_on(dependent, package).collect::<Vec<_>>(),
+          &dependent.features,
+          &dependent_node.features,
+        )
+      })
+      .collect();
+    let platform = self.platform_details.as_ref().map(|platform_details| {
+      (
+        platform_details.target_triple(),
+        platform_details
+          .attrs()
+          .iter()
+          .map(ToString::to_string)
+          .collect::<Vec<_>>(),
+      )
+    });
+
+    // Only the settings of the crate and those it is related to are part of its key, so changing
+    // the settings of another crate does not affect it
+    let mut settings = self.settings.clone();
+    settings.crates.clear();
+    let related_settings = related_packages
+      .iter()
+      .map(|package| self.crate_settings(package))
+      .collect::<Result<Vec<_>>>()?;
+    // Parts of the manifest which cargo_metadata does not expose, such as `package.include`, are
+    // read from the manifest itself
+    let manifest = fs::read_to_string(&package.manifest_path).ok();
+
+    let mut key = CacheKey::new();
+    key
+      .add(node)?
+      .add(package)?
+      .add(&manifest)?
+      .add(&related_packages)?
+      .add(&feature_requests)?
+      .add(&settings)?
+      .add(&self.crate_settings(package)?)?
+      .add(&related_settings)?
+      .add(&platform)?
+      .add(workspace_member_packages)?
+      .add(&self.metadata.cargo_workspace_root)?
+      .add(&self.crate_catalog.metadata.workspace_root)?
+      .add(&self.metadata.checksum_for(&package.name, &version))?
+      .add(&self.metadata.locked_features_for(&package.name, &version))?
+      .add(&self.metadata.download_url_for(&package.name, &version))?
+      .add(&self.metadata.git_fallback_for(&package.name, &version))?;
+    Ok(key)
   }
 
   fn crate_settings(&self, package: &Package) -> Result<Option<&CrateSettings>> {
@@ -202,9 +541,8 @@ impl<'planner> WorkspaceSubplanner<'planner> {
       .crates
       .get(&package.name)
       .map_or(Ok(None), |settings| {
-        let mut versions = settings
-          .iter()
-          .filter(|(ver_req, _)| ver_req.matches(&package.version))
+        let mut versions = settings::matching_version_entries(settings, &package.version)
+          .into_iter()
           .peekable();
 
         match versions.next() {
@@ -226,18 +564,980 @@ impl<'planner> WorkspaceSubplanner<'planner> {
 
   /// Produces a crate context for each declared crate and dependency.
   fn produce_crate_contexts(&self) -> Result<Vec<CrateContext>> {
-    self
+    let resolve = self
       .crate_catalog
       .metadata
       .resolve
       .as_ref()
-      .ok_or_else(|| RazeError::Generic("Missing resolve graph".into()))?
-      .nodes
+      .ok_or_else(|| RazeError::Generic("Missing resolve graph".into()))?;
+    let reachable_ids = self.reachable_package_ids(&resolve.nodes);
+    let workspace_member_packages = self.workspace_member_packages();
+
+    self
+      .unify_crate_nodes(
+        resolve
+          .nodes
+          .iter()
+          .sorted_by_key(|n| &n.id)
+          .filter(|node| reachable_ids.contains(&node.id)),
+      )
       .iter()
-      .sorted_by_key(|n| &n.id)
-      .filter_map(|node| self.create_crate_context(node, &self.crate_catalog))
+      .filter_map(|node| {
+        self.create_crate_context(node, self.crate_catalog, &workspace_member_packages)
+      })
       .collect::<Result<Vec<CrateContext>>>()
   }
+
+  /// Merges the nodes resolving the same version of a crate, eg: from several sources, into the
+  /// first of them. Like cargo, which unifies features across the whole workspace, the merged node
+  /// holds the union of the features and dependencies of each of those resolutions.
+  fn unify_crate_nodes<'a>(&self, nodes: impl Iterator<Item = &'a Node>) -> Vec<Node> {
+    let crate_key = |id: &PackageId| {
+      self.crate_catalog.entry_for_package_id(id).map(|entry| {
+        (
+          entry.package().name.clone(),
+          entry.package().version.clone(),
+        )
+      })
+    };
+
+    let mut unified_nodes: Vec<Node> = Vec::new();
+    let mut unified_idx_by_crate = HashMap::new();
+    for node in nodes {
+      let key = match crate_key(&node.id) {
+        Some(key) => key,
+        None => {
+          unified_nodes.push(node.clone());
+          continue;
+        },
+      };
+      let unified = match unified_idx_by_crate.get(&key) {
+        Some(idx) => &mut unified_nodes[*idx],
+        None => {
+          unified_idx_by_crate.insert(key, unified_nodes.len());
+          unified_nodes.push(node.clone());
+          continue;
+        },
+      };
+
+      let features: BTreeSet<String> = unified
+        .features
+        .iter()
+        .chain(node.features.iter())
+        .cloned()
+        .collect();
+      unified.features = features.into_iter().collect();
+      for dep_id in node.dependencies.iter() {
+        // Skip dependencies which are present already, including under another resolution
+        let dep_key = crate_key(dep_id);
+        if !unified
+          .dependencies
+          .iter()
+          .any(|id| id == dep_id || (dep_key.is_some() && crate_key(id) == dep_key))
+        {
+          unified.dependencies.push(dep_id.clone());
+          unified.deps.extend(
+            node
+              .deps
+              .iter()
+              .filter(|node_dep| node_dep.pkg == *dep_id)
+              .cloned(),
+          );
+        }
+      }
+    }
+    unified_nodes
+  }
+
+  /// Collects the resolved packages the workspace members depend on, directly or transitively,
+  /// without following optional dependencies that none of the dependent's features activate.
+  fn reachable_package_ids<'a>(&self, nodes: &'a [Node]) -> HashSet<&'a PackageId> {
+    let nodes_by_id: HashMap<&PackageId, &Node> =
+      nodes.iter().map(|node| (&node.id, node)).collect();
+
+    let mut reachable_ids = HashSet::new();
+    let mut pending: Vec<&PackageId> = nodes
+      .iter()
+      .map(|node| &node.id)
+      .filter(|id| self.crate_catalog.metadata.workspace_members.contains(id))
+      .collect();
+    while let Some(id) = pending.pop() {
+      if !reachable_ids.insert(id) {
+        continue;
+      }
+      // Only ids of nodes in the resolve graph are ever pending a visit
+      let node = nodes_by_id[id];
+      let package = match self.crate_catalog.entry_for_package_id(id) {
+        Some(entry) => entry.package(),
+        None => continue,
+      };
+      for dep_id in node.dependencies.iter() {
+        let is_inactive = self
+          .crate_catalog
+          .entry_for_package_id(dep_id)
+          .map(|dep| is_inactive_optional_dependency(package, &node.features, dep.package()))
+          .unwrap_or(false);
+        if !is_inactive && nodes_by_id.contains_key(dep_id) {
+          pending.push(dep_id);
+        }
+      }
+    }
+    reachable_ids
+  }
+}
+
+/// The label dependents refer to the library target of `ctx` by, which is named by the
+/// `library_target_name` setting when it is set.
+fn library_label(ctx: &CrateContext) -> String {
+  let target_name = match &ctx.library_target_name {
+    Some(library_target_name) => library_target_name.clone(),
+    None => ctx.pkg_name.replace("-", "_"),
+  };
+  format!("{}:{}", ctx.workspace_path_to_crate, target_name)
+}
+
+/// The nodes of the resolve which depend on any resolution of this version of `package`, along
+/// with their packages, all of which may request its features.
+fn resolve_dependents<'a>(
+  catalog: &'a CrateCatalog,
+  package: &'a Package,
+) -> Vec<(&'a Package, &'a Node)> {
+  catalog
+    .metadata
+    .resolve
+    .iter()
+    .flat_map(|resolve| resolve.nodes.iter())
+    .filter(|node| {
+      node
+        .dependencies
+        .iter()
+        .filter_map(|id| catalog.entry_for_package_id(id))
+        .any(|entry| {
+          entry.package().name == package.name && entry.package().version == package.version
+        })
+    })
+    .filter_map(|node| {
+      catalog
+        .entry_for_package_id(&node.id)
+        .map(|entry| (entry.package(), node))
+    })
+    .collect()
+}
+
+/// The entries of the dependencies of `dependent` which `package` resolves
+fn dependency_entries_on<'a>(
+  dependent: &'a Package,
+  package: &'a Package,
+) -> impl Iterator<Item = &'a cargo_metadata::Dependency> {
+  dependent
+    .dependencies
+    .iter()
+    .filter(move |dep| dep.name == package.name && dep.req.matches(&package.version))
+}
+
+/// The features which the features enabled on the `dependent` node request through `dep`, with
+/// `dep/feature` or `dep?/feature` entries of the dependent's `[features]` table.
+fn features_requested_through(
+  dependent: &Package,
+  dependent_node: &Node,
+  dep: &cargo_metadata::Dependency,
+) -> Vec<String> {
+  let dep_name = dep.rename.as_ref().unwrap_or(&dep.name);
+  dependent_node
+    .features
+    .iter()
+    .filter_map(|feature| dependent.features.get(feature))
+    .flatten()
+    .filter_map(|entry| {
+      let (name, feature) = entry.split_once('/')?;
+      (name.trim_end_matches('?') == dep_name).then(|| feature.to_owned())
+    })
+    .collect()
+}
+
+/// Indexes the planned crates by the label of their library target.
+fn crate_label_indices(crate_contexts: &[CrateContext]) -> HashMap<String, usize> {
+  crate_contexts
+    .iter()
+    .enumerate()
+    .map(|(idx, ctx)| (library_label(ctx), idx))
+    .collect()
+}
+
+/// Collects the edges between the planned crates as, for each crate, a list of (dependency index,
+/// whether the edge belongs to a build script) pairs.
+fn dependency_edges(crate_contexts: &[CrateContext]) -> Vec<Vec<(usize, bool)>> {
+  let label_to_idx = crate_label_indices(crate_contexts);
+
+  crate_contexts
+    .iter()
+    .map(|ctx| {
+      let dep_contexts =
+        iter::once(&ctx.default_deps).chain(ctx.targeted_deps.iter().map(|t| &t.deps));
+      let mut lib_labels = ctx.raze_settings.additional_deps.clone();
+      let mut build_labels = ctx.raze_settings.buildrs_additional_deps.clone();
+      for deps in dep_contexts {
+        lib_labels.extend(
+          deps
+            .dependencies
+            .iter()
+            .chain(deps.proc_macro_dependencies.iter())
+            .map(|dep| dep.buildable_target.clone()),
+        );
+        build_labels.extend(
+          deps
+            .build_dependencies
+            .iter()
+            .chain(deps.build_proc_macro_dependencies.iter())
+            .map(|dep| dep.buildable_target.clone()),
+        );
+      }
+
+      lib_labels
+        .iter()
+        .map(|label| (label, false))
+        .chain(build_labels.iter().map(|label| (label, true)))
+        .filter_map(|(label, is_build)| label_to_idx.get(label).map(|idx| (*idx, is_build)))
+        .sorted()
+        .dedup()
+        .collect()
+    })
+    .collect()
+}
+
+/// Ensures the dependencies between the planned crates, including those of their build scripts,
+/// do not form a cycle. Bazel is unable to build such a graph and reports it in terms of the
+/// generated targets, so the cycle is reported here using crate names instead.
+fn check_dependency_cycles(crate_contexts: &[CrateContext]) -> Result<()> {
+  let edges = dependency_edges(crate_contexts);
+
+  fn visit(
+    idx: usize,
+    edges: &[Vec<(usize, bool)>],
+    visited: &mut Vec<bool>,
+    path: &mut Vec<(usize, bool)>,
+  ) -> Option<Vec<(usize, bool)>> {
+    visited[idx] = true;
+    for &(dep_idx, is_build) in edges[idx].iter() {
+      path.push((idx, is_build));
+      if let Some(start) = path.iter().position(|(path_idx, _)| *path_idx == dep_idx) {
+        return Some(path[start..].to_vec());
+      }
+      if !visited[dep_idx] {
+        if let Some(cycle) = visit(dep_idx, edges, visited, path) {
+          return Some(cycle);
+        }
+      }
+      path.pop();
+    }
+    None
+  }
+
+  let mut visited = vec![false; crate_contexts.len()];
+  for idx in 0..crate_contexts.len() {
+    if visited[idx] {
+      continue;
+    }
+
+    if let Some(cycle) = visit(idx, &edges, &mut visited, &mut Vec::new()) {
+      let describe = |idx: usize| {
+        util::package_ident(
+          &crate_contexts[idx].pkg_name,
+          &crate_contexts[idx].pkg_version.to_string(),
+        )
+      };
+      let mut message = String::from("Dependency cycle detected: ");
+      for (idx, is_build) in cycle.iter() {
+        message += &describe(*idx);
+        if *is_build {
+          message += " (build script)";
+        }
+        message += " -> ";
+      }
+      message += &describe(cycle[0].0);
+
+      return Err(
+        RazeError::Planning {
+          dependency_name_opt: Some(crate_contexts[cycle[0].0].pkg_name.clone()),
+          message,
+        }
+        .into(),
+      );
+    }
+  }
+
+  Ok(())
+}
+
+/// Ensures every resolved crate, besides the workspace members, is on the `allowed_crates` list
+/// when one is configured. Each disallowed crate is reported along with the shortest chain of
+/// dependencies through which a workspace member pulls it in.
+fn check_allowed_crates(crate_catalog: &CrateCatalog, allowed_crates: &[String]) -> Result<()> {
+  if allowed_crates.is_empty() {
+    return Ok(());
+  }
+
+  let allowed_crates = allowed_crates
+    .iter()
+    .map(|entry| settings::parse_allowed_crate(entry))
+    .collect::<Result<Vec<_>, RazeError>>()?;
+  let is_allowed = |entry: &CrateCatalogEntry| {
+    entry.is_workspace_crate()
+      || allowed_crates.iter().any(|(name, version_req)| {
+        *name == entry.package().name && version_req.matches(&entry.package().version)
+      })
+  };
+
+  let resolve = crate_catalog
+    .metadata
+    .resolve
+    .as_ref()
+    .ok_or_else(|| RazeError::Generic("Missing resolve graph".into()))?;
+  let nodes: HashMap<&PackageId, &Node> =
+    resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+
+  // Walk the graph breadth first from the workspace members, remembering how each crate was reached
+  let mut reached_from: HashMap<&PackageId, Option<&PackageId>> = HashMap::new();
+  let mut queue: VecDeque<&PackageId> = VecDeque::new();
+  for member in crate_catalog.metadata.workspace_members.iter().sorted() {
+    reached_from.insert(member, None);
+    queue.push_back(member);
+  }
+  while let Some(id) = queue.pop_front() {
+    for dep in nodes.get(id).into_iter().flat_map(|node| node.deps.iter()) {
+      if !reached_from.contains_key(&dep.pkg) {
+        reached_from.insert(&dep.pkg, Some(id));
+        queue.push_back(&dep.pkg);
+      }
+    }
+  }
+
+  let describe = |id: &PackageId| {
+    crate_catalog
+      .entry_for_package_id(id)
+      .map(|entry| entry.package_ident.clone())
+      .unwrap_or_else(|| id.repr.clone())
+  };
+  let disallowed = crate_catalog
+    .entries
+    .iter()
+    .filter(|entry| !is_allowed(entry))
+    .sorted_by(|lhs, rhs| lhs.package_ident.cmp(&rhs.package_ident))
+    .collect::<Vec<_>>();
+  let first_disallowed = match disallowed.first() {
+    Some(entry) => entry.package().name.clone(),
+    None => return Ok(()),
+  };
+
+  let descriptions = disallowed.iter().map(|entry| {
+    let mut path = vec![&entry.package().id];
+    while let Some(Some(parent)) = reached_from.get(path[path.len() - 1]) {
+      path.push(parent);
+    }
+    format!(
+      "{} ({})",
+      entry.package_ident,
+      path.iter().rev().map(|id| describe(id)).join(" -> ")
+    )
+  });
+
+  Err(
+    RazeError::Planning {
+      dependency_name_opt: Some(first_disallowed),
+      message: format!(
+        "Crates outside of `allowed_crates` were resolved: {}",
+        descriptions.collect::<Vec<_>>().join(", ")
+      ),
+    }
+    .into(),
+  )
+}
+
+/// Describes the longest chain of dependencies between the planned crates when it is more than
+/// `max_depth` crates deep. The graph is expected to be free of cycles.
+pub(crate) fn find_excessive_dependency_depth(
+  crate_contexts: &[CrateContext],
+  max_depth: usize,
+) -> Option<String> {
+  let edges = dependency_edges(crate_contexts);
+
+  // The longest chain starting at each crate, in reverse order
+  fn longest_chain(idx: usize, edges: &[Vec<(usize, bool)>], memo: &mut Vec<Option<Vec<usize>>>) {
+    if memo[idx].is_some() {
+      return;
+    }
+    let mut longest: Vec<usize> = Vec::new();
+    for &(dep_idx, _) in edges[idx].iter() {
+      longest_chain(dep_idx, edges, memo);
+      // UNWRAP: Populated by the call above
+      let chain = memo[dep_idx].as_ref().unwrap();
+      if chain.len() > longest.len() {
+        longest = chain.clone();
+      }
+    }
+    longest.push(idx);
+    memo[idx] = Some(longest);
+  }
+
+  let mut memo = vec![None; crate_contexts.len()];
+  let mut deepest: Vec<usize> = Vec::new();
+  for idx in 0..crate_contexts.len() {
+    longest_chain(idx, &edges, &mut memo);
+    // UNWRAP: Populated by the call above
+    let chain = memo[idx].as_ref().unwrap();
+    if chain.len() > deepest.len() {
+      deepest = chain.clone();
+    }
+  }
+
+  if deepest.len() <= max_depth {
+    return None;
+  }
+
+  let path = deepest
+    .iter()
+    .rev()
+    .map(|idx| {
+      util::package_ident(
+        &crate_contexts[*idx].pkg_name,
+        &crate_contexts[*idx].pkg_version.to_string(),
+      )
+    })
+    .join(" -> ");
+  Some(format!(
+    "The dependency graph is {} crates deep, exceeding the `max_dependency_depth` of {}: {}",
+    deepest.len(),
+    max_depth,
+    path
+  ))
+}
+
+/// Finds crates with a build script for which no `cargo_build_script` rule was generated, leaving
+/// their compilation without the cfgs and outputs of that build script. Crates with an explicit
+/// `gen_buildrs` or with `static_rustc_cfgs` replicating their build script are not reported, nor
+/// are build scripts detected to have no effect.
+pub(crate) fn find_skipped_build_scripts(crate_contexts: &[CrateContext]) -> Vec<String> {
+  crate_contexts
+    .iter()
+    .filter(|ctx| {
+      ctx.build_script_target.is_none()
+        && ctx
+          .targets
+          .iter()
+          .any(|target| target.kind == "custom-build")
+        && ctx.raze_settings.gen_buildrs.is_none()
+        && ctx.raze_settings.static_rustc_cfgs.is_empty()
+    })
+    .map(|ctx| {
+      format!(
+        "`{}` has a build script, but no `cargo_build_script` rule is generated for it. Set its \
+         `gen_buildrs` setting to generate one or to silence this warning",
+        util::package_ident(&ctx.pkg_name, &ctx.pkg_version.to_string())
+      )
+    })
+    .collect()
+}
+
+/// Finds generated dependency labels which do not refer to any of the planned crates. Labels the
+/// user explicitly provided (eg: `additional_deps`) are not checked, as they may refer to targets
+/// outside of the generated graph.
+pub(crate) fn find_dangling_dependency_labels(crate_contexts: &[CrateContext]) -> Vec<String> {
+  let planned_labels: HashSet<String> = crate_contexts.iter().map(library_label).collect();
+
+  let mut dangling_labels = Vec::new();
+  for ctx in crate_contexts.iter() {
+    let dep_contexts =
+      iter::once(&ctx.default_deps).chain(ctx.targeted_deps.iter().map(|t| &t.deps));
+    let labels = dep_contexts
+      .flat_map(|deps| {
+        deps
+          .dependencies
+          .iter()
+          .chain(deps.proc_macro_dependencies.iter())
+          .chain(deps.build_dependencies.iter())
+          .chain(deps.build_proc_macro_dependencies.iter())
+          .chain(deps.dev_dependencies.iter())
+          .map(|dep| dep.buildable_target.clone())
+          .chain(
+            deps
+              .aliased_dependencies
+              .iter()
+              .map(|dep| dep.target.clone()),
+          )
+          .chain(
+            deps
+              .build_aliased_dependencies
+              .iter()
+              .map(|dep| dep.target.clone()),
+          )
+      })
+      .filter(|label| !planned_labels.contains(label))
+      .sorted()
+      .dedup();
+
+    for label in labels {
+      dangling_labels.push(format!(
+        "`{}` depends on `{}`, which does not refer to any planned crate",
+        util::package_ident(&ctx.pkg_name, &ctx.pkg_version.to_string()),
+        label
+      ));
+    }
+  }
+
+  dangling_labels
+}
+
+/// A set operation applied to each of the dependency lists of a pair of dependency contexts.
+enum SetOperation {
+  Union,
+  Intersection,
+  Difference,
+}
+
+fn apply_set_operation<T: Ord + Clone>(lhs: &[T], rhs: &[T], operation: &SetOperation) -> Vec<T> {
+  let rhs_set: BTreeSet<&T> = rhs.iter().collect();
+  let result: BTreeSet<T> = match operation {
+    SetOperation::Union => lhs.iter().chain(rhs.iter()).cloned().collect(),
+    SetOperation::Intersection => lhs
+      .iter()
+      .filter(|x| rhs_set.contains(x))
+      .cloned()
+      .collect(),
+    SetOperation::Difference => lhs
+      .iter()
+      .filter(|x| !rhs_set.contains(x))
+      .cloned()
+      .collect(),
+  };
+  result.into_iter().collect()
+}
+
+fn combine_dependencies(
+  lhs: &CrateDependencyContext,
+  rhs: &CrateDependencyContext,
+  operation: SetOperation,
+) -> CrateDependencyContext {
+  CrateDependencyContext {
+    dependencies: apply_set_operation(&lhs.dependencies, &rhs.dependencies, &operation),
+    proc_macro_dependencies: apply_set_operation(
+      &lhs.proc_macro_dependencies,
+      &rhs.proc_macro_dependencies,
+      &operation,
+    ),
+    data_dependencies: apply_set_operation(
+      &lhs.data_dependencies,
+      &rhs.data_dependencies,
+      &operation,
+    ),
+    build_dependencies: apply_set_operation(
+      &lhs.build_dependencies,
+      &rhs.build_dependencies,
+      &operation,
+    ),
+    build_proc_macro_dependencies: apply_set_operation(
+      &lhs.build_proc_macro_dependencies,
+      &rhs.build_proc_macro_dependencies,
+      &operation,
+    ),
+    build_data_dependencies: apply_set_operation(
+      &lhs.build_data_dependencies,
+      &rhs.build_data_dependencies,
+      &operation,
+    ),
+    dev_dependencies: apply_set_operation(&lhs.dev_dependencies, &rhs.dev_dependencies, &operation),
+    aliased_dependencies: apply_set_operation(
+      &lhs.aliased_dependencies,
+      &rhs.aliased_dependencies,
+      &operation,
+    ),
+    build_aliased_dependencies: apply_set_operation(
+      &lhs.build_aliased_dependencies,
+      &rhs.build_aliased_dependencies,
+      &operation,
+    ),
+  }
+}
+
+/// Removes the matching dependencies, along with any aliases for them.
+fn remove_dependencies(
+  deps: &mut CrateDependencyContext,
+  is_removed: impl Fn(&BuildableDependency) -> bool,
+) {
+  let mut removed_targets = HashSet::new();
+  for dep_list in [
+    &mut deps.dependencies,
+    &mut deps.proc_macro_dependencies,
+    &mut deps.build_dependencies,
+    &mut deps.build_proc_macro_dependencies,
+    &mut deps.dev_dependencies,
+  ]
+  .iter_mut()
+  {
+    dep_list.retain(|dep| {
+      if is_removed(dep) {
+        removed_targets.insert(dep.buildable_target.clone());
+        return false;
+      }
+      true
+    });
+  }
+  deps
+    .aliased_dependencies
+    .retain(|alias| !removed_targets.contains(&alias.target));
+  deps
+    .build_aliased_dependencies
+    .retain(|alias| !removed_targets.contains(&alias.target));
+}
+
+/// De-duplicates the aliases of a crate's dependencies by their target, where later aliases of a
+/// target take precedence over earlier ones, and sorts them by target.
+///
+/// Yields a message describing each target which was given conflicting aliases.
+pub(crate) fn dedup_dependency_aliases(
+  crate_ident: &str,
+  aliases: Vec<DependencyAlias>,
+) -> (Vec<DependencyAlias>, Vec<String>) {
+  let mut aliases_by_target: BTreeMap<String, String> = BTreeMap::new();
+  let mut conflicts = Vec::new();
+  for DependencyAlias { target, alias } in aliases {
+    if let Some(previous_alias) = aliases_by_target.get(&target) {
+      if *previous_alias != alias {
+        conflicts.push(format!(
+          "`{}` aliases `{}` as both `{}` and `{}`, `{}` is used",
+          crate_ident, target, previous_alias, alias, alias
+        ));
+      }
+    }
+    aliases_by_target.insert(target, alias);
+  }
+
+  let aliases = aliases_by_target
+    .into_iter()
+    .map(|(target, alias)| DependencyAlias { target, alias })
+    .collect();
+  (aliases, conflicts)
+}
+
+fn is_empty_dependency_context(deps: &CrateDependencyContext) -> bool {
+  deps.dependencies.is_empty()
+    && deps.proc_macro_dependencies.is_empty()
+    && deps.data_dependencies.is_empty()
+    && deps.build_dependencies.is_empty()
+    && deps.build_proc_macro_dependencies.is_empty()
+    && deps.build_data_dependencies.is_empty()
+    && deps.dev_dependencies.is_empty()
+    && deps.aliased_dependencies.is_empty()
+    && deps.build_aliased_dependencies.is_empty()
+}
+
+/// Expands the features requested of a package into all features they enable, which includes the
+/// names of the optional dependencies they activate.
+fn expand_package_features<'a>(
+  package: &Package,
+  requests: impl Iterator<Item = &'a String>,
+) -> HashSet<String> {
+  let mut enabled = HashSet::new();
+  let mut pending: Vec<String> = requests.cloned().collect();
+  while let Some(feature) = pending.pop() {
+    let feature = feature.trim_start_matches("dep:").to_owned();
+    if !enabled.insert(feature.clone()) {
+      continue;
+    }
+    for enabled_by in package.features.get(&feature).into_iter().flatten() {
+      // Features of dependencies (`dep/feature`) also enable the optional dependency `dep`, unlike
+      // weak ones (`dep?/feature`), which only apply if something else enables `dep`
+      let enabled_by = match enabled_by.find('/') {
+        Some(idx) if enabled_by[..idx].ends_with('?') => continue,
+        Some(idx) => &enabled_by[..idx],
+        None => enabled_by.as_str(),
+      };
+      pending.push(enabled_by.to_owned());
+    }
+  }
+  enabled
+}
+
+/// Determines whether every declaration of a dependency by a package is optional and none of the
+/// package's resolved features activate it, in which case cargo would not build the dependency.
+fn is_inactive_optional_dependency(
+  package: &Package,
+  features: &[String],
+  dep_package: &Package,
+) -> bool {
+  let declarations = package
+    .dependencies
+    .iter()
+    .filter(|dep| dep.name == dep_package.name && dep.req.matches(&dep_package.version))
+    .collect::<Vec<_>>();
+  if declarations.is_empty() || declarations.iter().any(|dep| !dep.optional) {
+    return false;
+  }
+
+  let enabled = expand_package_features(package, features.iter());
+  !declarations
+    .iter()
+    .any(|dep| enabled.contains(dep.rename.as_ref().unwrap_or(&dep.name)))
+}
+
+/// Lists the files of sibling crates, named by their `{name}-{version}` identifier in
+/// `sibling_idents`, which symlinks in `crate_dir` point to. They are listed in the
+/// `../{name}-{version}/{path}` form of `data_dependencies`. Any other symlink to a file is left to
+/// the globs of the crate's rules.
+///
+/// Dangling symlinks would fail the build as soon as a glob matches them, so they are rejected.
+fn resolve_symlinked_sibling_files(
+  crate_name: &str,
+  crate_dir: &Path,
+  sibling_idents: &HashSet<String>,
+) -> Result<Vec<String>> {
+  let crate_dir = match crate_dir.canonicalize() {
+    Ok(crate_dir) => crate_dir,
+    // The sources of crates which are not vendored may not be available
+    Err(_) => return Ok(Vec::new()),
+  };
+  let siblings_dir = match crate_dir.parent() {
+    Some(siblings_dir) => siblings_dir,
+    None => return Ok(Vec::new()),
+  };
+
+  let mut sibling_files = BTreeSet::new();
+  let mut pending_dirs = vec![crate_dir.clone()];
+  while let Some(dir) = pending_dirs.pop() {
+    for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+      let entry = entry?;
+      let file_type = entry.file_type()?;
+      if file_type.is_dir() {
+        pending_dirs.push(entry.path());
+        continue;
+      }
+      if !file_type.is_symlink() {
+        continue;
+      }
+
+      let link = entry.path();
+      let target = link.canonicalize().map_err(|_| {
+        let link_target = fs::read_link(&link).unwrap_or_default();
+        RazeError::Planning {
+          dependency_name_opt: Some(crate_name.to_owned()),
+          message: format!(
+            "{} is a dangling symlink to {}",
+            link.display(),
+            link_target.display()
+          ),
+        }
+      })?;
+      if target.starts_with(&crate_dir) || !target.is_file() {
+        continue;
+      }
+      let relative_target = match target.strip_prefix(siblings_dir) {
+        Ok(relative_target) => relative_target,
+        Err(_) => continue,
+      };
+      let is_sibling_crate = matches!(
+        relative_target.components().next(),
+        Some(dir) if sibling_idents.contains(&*dir.as_os_str().to_string_lossy())
+      );
+      if is_sibling_crate {
+        sibling_files.insert(format!(
+          "../{}",
+          relative_target.to_string_lossy().replace("\\", "/")
+        ));
+      }
+    }
+  }
+
+  Ok(sibling_files.into_iter().collect())
+}
+
+/// Checks whether a build script's source only ever emits `cargo:rerun-if-*` directives and never
+/// touches `OUT_DIR`, meaning it produces nothing the crate's compilation depends on.
+fn is_noop_build_script(source: &str) -> bool {
+  !source.contains("OUT_DIR")
+    && source.match_indices("cargo:").all(|(idx, prefix)| {
+      source[idx + prefix.len()..]
+        .trim_start_matches(':')
+        .starts_with("rerun-if-")
+    })
+}
+
+/// Checks whether a file is named like the license texts crates conventionally ship, such as
+/// `LICENSE`, `LICENSE-MIT` or `COPYING.txt`.
+fn is_license_file_name(path: &Path) -> bool {
+  path
+    .file_name()
+    .map(|name| name.to_string_lossy().to_uppercase())
+    .map(|name| {
+      ["LICENSE", "LICENCE", "COPYING"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+    })
+    .unwrap_or(false)
+}
+
+/// Whether dependents link against the lib of `package` as a proc-macro. Like for the crate's own
+/// rules, this is decided by the most preferred crate type of the lib, so a lib which is built as
+/// several crate types is only ever depended upon one way.
+fn links_as_proc_macro(package: &Package) -> bool {
+  LIBRARY_CRATE_TYPES
+    .iter()
+    .find(|crate_type| {
+      package
+        .targets
+        .iter()
+        .any(|target| target.kind.iter().any(|kind| kind == **crate_type))
+    })
+    .filter(|crate_type| LINKABLE_CRATE_TYPES.contains(crate_type))
+    == Some(&"proc-macro")
+}
+
+/// The directory of a crate relative to the Bazel execution root, where the sources of remote
+/// crates are in `external/{repository}`, eg: `external/raze__log__0_4_13`.
+fn execroot_crate_dir(workspace_path_to_crate: &str) -> String {
+  match workspace_path_to_crate.strip_prefix('@') {
+    Some(repository) if !repository.starts_with("//") => {
+      format!("external/{}", repository.trim_end_matches("//"))
+    },
+    _ => workspace_path_to_crate
+      .trim_start_matches('@')
+      .trim_start_matches("//")
+      .to_owned(),
+  }
+}
+
+/// Produces the `{from}={to}` mapping of a crate's `--remap-path-prefix` flag. Rustc is passed the
+/// crate's sources relative to the execution root.
+fn produce_remap_path_prefix(
+  placeholder: &str,
+  package: &Package,
+  workspace_path_to_crate: &str,
+) -> String {
+  let source_dir = execroot_crate_dir(workspace_path_to_crate);
+  let placeholder = placeholder
+    .replace("{name}", &package.name)
+    .replace("{version}", &package.version.to_string());
+  format!("{}={}", source_dir, placeholder)
+}
+
+/// Translates the `rust` and `clippy` lint levels of a `[lints]` table into rustc flags, eg:
+/// `-Dclippy::enum_glob_use` for `enum_glob_use = "deny"`. Like cargo, lints of a lower
+/// `priority` are passed first so that those of a higher one override them.
+fn produce_lint_flags(lints: &toml::Value) -> Vec<String> {
+  let mut flags = Vec::new();
+  for (tool, prefix) in &[("rust", ""), ("clippy", "clippy::")] {
+    let tool_lints = match lints.get(tool).and_then(toml::Value::as_table) {
+      Some(tool_lints) => tool_lints,
+      None => continue,
+    };
+    for (lint, config) in tool_lints.iter() {
+      let level = config
+        .as_str()
+        .or_else(|| config.get("level").and_then(toml::Value::as_str));
+      let flag = match level {
+        Some("allow") => "A",
+        Some("warn") => "W",
+        Some("deny") => "D",
+        Some("forbid") => "F",
+        _ => continue,
+      };
+      let priority = config
+        .get("priority")
+        .and_then(toml::Value::as_integer)
+        .unwrap_or(0);
+      flags.push((priority, format!("{}{}", prefix, lint), flag));
+    }
+  }
+  flags.sort();
+  flags
+    .into_iter()
+    .map(|(_, lint, flag)| format!("-{}{}", flag, lint))
+    .collect()
+}
+
+/// Determines the sources of a build script given the path to its entry point, along with the files
+/// the crate's manifest `include`s. Yields the glob patterns of the sources, and those of the files
+/// excluded from them.
+///
+/// Build scripts at the crate root are expected to be a single file. Build scripts nested in their
+/// own directory (eg: `build/main.rs`) may span any sources within that directory, unless the
+/// directory's name can't be used in a glob pattern.
+pub(crate) fn produce_build_script_srcs(
+  build_script_path: &str,
+  manifest_includes: &[String],
+) -> (Vec<String>, Vec<String>) {
+  let mut srcs = match build_script_path.rfind('/') {
+    Some(idx) if !build_script_path[..idx].contains('*') => {
+      vec![format!("{}/**/*.rs", &build_script_path[..idx])]
+    },
+    _ => vec![build_script_path.to_owned()],
+  };
+  let mut excludes = Vec::new();
+  match translate_manifest_includes(manifest_includes) {
+    Some((includes, translated_excludes)) => {
+      for include in includes {
+        if !srcs.contains(&include) {
+          srcs.push(include);
+        }
+      }
+      excludes = translated_excludes;
+    },
+    // The build script may read any of the files the crate includes
+    None => srcs.push("**".to_owned()),
+  }
+  (srcs, excludes)
+}
+
+/// Translates the gitignore style `package.include` patterns of a manifest into Bazel glob
+/// patterns relative to the package root, split into the patterns to include and those negated
+/// with `!` to exclude. Patterns without a `/` other than a trailing one match at any depth, and
+/// patterns which may name a directory match the files within it as well.
+///
+/// Returns `None` if a pattern uses syntax Bazel globs can't express, eg: `?` or `[a-z]`.
+fn translate_manifest_includes(includes: &[String]) -> Option<(Vec<String>, Vec<String>)> {
+  let mut globs = (Vec::new(), Vec::new());
+  for include in includes {
+    // Comments are skipped, while a leading `\` escapes a literal `#` or `!`
+    if include.starts_with('#') {
+      continue;
+    }
+    let (pattern, negated) = match include.strip_prefix('!') {
+      Some(pattern) => (pattern, true),
+      None => (include.as_str(), false),
+    };
+    let pattern = pattern.strip_prefix('\\').unwrap_or(pattern);
+    let (pattern, is_dir) = match pattern.strip_suffix('/') {
+      Some(pattern) => (pattern, true),
+      None => (pattern, false),
+    };
+    if pattern.trim_start_matches('/').is_empty() {
+      continue;
+    }
+    if pattern.contains(&['?', '[', ']', '\\'][..])
+      || pattern
+        .split('/')
+        .any(|segment| segment.contains("**") && segment != "**")
+    {
+      return None;
+    }
+
+    let pattern = if pattern.contains('/') {
+      pattern.trim_start_matches('/').to_owned()
+    } else {
+      format!("**/{}", pattern)
+    };
+    let patterns = if is_dir || pattern.ends_with("/**") {
+      vec![format!("{}/**", pattern.trim_end_matches("/**"))]
+    } else {
+      vec![pattern.clone(), format!("{}/**", pattern)]
+    };
+
+    let globs = if negated { &mut globs.1 } else { &mut globs.0 };
+    for pattern in patterns {
+      if !globs.contains(&pattern) {
+        globs.push(pattern);
+      }
+    }
+  }
+  Some(globs)
 }
 
 impl<'planner> CrateSubplanner<'planner> {
@@ -251,6 +1551,7 @@ impl<'planner> CrateSubplanner<'planner> {
         dev_deps,
         normal_deps,
         aliased_deps,
+        build_aliased_deps,
       },
       targeted_deps,
     ) = self.produce_deps()?;
@@ -263,43 +1564,56 @@ impl<'planner> CrateSubplanner<'planner> {
 
     let mut targets = self.produce_targets(&package_root)?;
     let build_script_target_opt = self.take_build_script_target(&mut targets);
+    let (build_script_srcs, build_script_srcs_exclude) = build_script_target_opt
+      .as_ref()
+      .map(|target| produce_build_script_srcs(&target.path, &self.produce_manifest_includes()))
+      .unwrap_or_default();
 
-    let mut lib_target_name = None;
-    let mut is_proc_macro = false;
-    {
-      for target in &targets {
-        if target.kind == "lib" {
-          lib_target_name = Some(target.name.clone());
-          break;
-        }
-        if target.kind == "proc-macro" {
-          is_proc_macro = true;
-          lib_target_name = Some(target.name.clone());
-          break;
-        }
-      }
-    }
+    // Each crate type of the lib is rendered as a rule, of which the most preferred one is linked
+    // against by the crate's dependents
+    let lib_crate_types: Vec<String> = LIBRARY_CRATE_TYPES
+      .iter()
+      .filter(|crate_type| targets.iter().any(|target| target.kind == **crate_type))
+      .map(|crate_type| crate_type.to_string())
+      .collect();
+    let linked_crate_type = lib_crate_types
+      .first()
+      .filter(|crate_type| LINKABLE_CRATE_TYPES.contains(&crate_type.as_str()));
+    let is_proc_macro = linked_crate_type.map(String::as_str) == Some("proc-macro");
+    let lib_target_name = linked_crate_type.and_then(|crate_type| {
+      targets
+        .iter()
+        .find(|target| target.kind == *crate_type)
+        .map(|target| target.name.clone())
+    });
+
+    let library_target_name = lib_target_name
+      .as_ref()
+      .and(self.settings.library_target_name.as_ref())
+      .map(|_| self.crate_catalog_entry.default_target_name(self.settings));
+
+    // A crate may refer to its own lib under another name through a renamed dependency on itself
+    let self_alias = lib_target_name.as_ref().and_then(|lib_name| {
+      package
+        .dependencies
+        .iter()
+        .filter(|dep| dep.name == package.name && dep.req.matches(&package.version))
+        .filter_map(|dep| dep.rename.clone())
+        .find(|alias| alias.replace("-", "_") != lib_name.replace("-", "_"))
+    });
 
     // Build a list of dependencies while addression a potential whitelist of target triples
     let mut filtered_deps = Vec::new();
     for dep_set in targeted_deps.iter() {
-      let mut target_triples = util::get_matching_bazel_triples(&dep_set.target)?;
-      util::filter_bazel_triples(
-        &mut target_triples,
-        self
-          .settings
-          .targets
-          .as_ref()
-          .unwrap_or(&Vec::<String>::new()),
-      );
-
+      let target_triples = self.matching_triples(&dep_set.target)?;
       if target_triples.is_empty() {
         continue;
       }
 
-      filtered_deps.push(CrateTargetedDepContext {
-        target: dep_set.target.clone(),
-        deps: CrateDependencyContext {
+      filtered_deps.push((
+        dep_set.target.clone(),
+        target_triples,
+        CrateDependencyContext {
           dependencies: dep_set.dependencies.normal_deps.clone(),
           proc_macro_dependencies: dep_set.dependencies.proc_macro_deps.clone(),
           data_dependencies: vec![],
@@ -308,18 +1622,39 @@ impl<'planner> CrateSubplanner<'planner> {
           build_data_dependencies: vec![],
           dev_dependencies: dep_set.dependencies.dev_deps.clone(),
           aliased_dependencies: dep_set.dependencies.aliased_deps.clone(),
+          build_aliased_dependencies: dep_set.dependencies.build_aliased_deps.clone(),
         },
-        conditions: util::generate_bazel_conditions(
-          &self.settings.rust_rules_workspace_name,
-          &target_triples,
-        )?,
-      });
+      ));
     }
 
-    filtered_deps.sort();
+    let (features, default_deps, filtered_deps) = self.produce_platform_branches(
+      CrateDependencyContext {
+        dependencies: normal_deps,
+        proc_macro_dependencies: proc_macro_deps,
+        data_dependencies: vec![],
+        build_dependencies: build_deps,
+        build_proc_macro_dependencies: build_proc_macro_deps,
+        build_data_dependencies: vec![],
+        dev_dependencies: dev_deps,
+        aliased_dependencies: aliased_deps,
+        build_aliased_dependencies: build_aliased_deps,
+      },
+      filtered_deps,
+    )?;
+    let (mut features, default_deps, mut filtered_deps, config_setting_groups) =
+      self.produce_feature_toggle_branches(features, default_deps, filtered_deps);
+    if let Some(locked_features) = self.locked_features {
+      features = locked_features.clone();
+      for targeted_deps in filtered_deps.iter_mut() {
+        targeted_deps.features.clear();
+      }
+      filtered_deps.retain(|targeted_deps| !is_empty_dependency_context(&targeted_deps.deps));
+    }
+    let dev_features = self.produce_dev_features(&features);
 
     let mut workspace_member_dependents: Vec<PathBuf> = Vec::new();
     let mut workspace_member_dev_dependents: Vec<PathBuf> = Vec::new();
+    let mut workspace_member_build_dependents: Vec<PathBuf> = Vec::new();
 
     for pkg_id in self.crate_catalog_entry.workspace_member_dependents.iter() {
       let workspace_member = self
@@ -327,16 +1662,9 @@ impl<'planner> CrateSubplanner<'planner> {
         .metadata
         .packages
         .iter()
-        .find(|pkg| pkg.id == *pkg_id);
-
-      if let Some(member) = workspace_member {
-        // UNWRAP: This should always return a dependency
-        let current_dependency = member
-          .dependencies
-          .iter()
-          .find(|dep| dep.name == package.name)
-          .unwrap();
+        .find(|pkg| pkg.id == *pkg_id);
 
+      if let Some(member) = workspace_member {
         let workspace_member_path = util::get_workspace_member_path(
           &member.manifest_path,
           &self.crate_catalog.metadata.workspace_root,
@@ -349,25 +1677,65 @@ impl<'planner> CrateSubplanner<'planner> {
           )
         })?;
 
-        match current_dependency.kind {
-          DependencyKind::Development => {
-            workspace_member_dev_dependents.push(workspace_member_path)
-          },
-          DependencyKind::Normal => workspace_member_dependents.push(workspace_member_path),
-          /* TODO: For now only Development and Normal dependencies are
-          needed but Build surely has it's use as well */
-          _ => {},
+        // A member may depend on the crate in more than one way, eg: as a normal and a build
+        // dependency
+        let dependency_kinds = member
+          .dependencies
+          .iter()
+          .filter(|dep| dep.name == package.name)
+          .map(|dep| &dep.kind)
+          .unique();
+        for kind in dependency_kinds {
+          let dependents = match kind {
+            DependencyKind::Normal => &mut workspace_member_dependents,
+            DependencyKind::Development => &mut workspace_member_dev_dependents,
+            DependencyKind::Build => &mut workspace_member_build_dependents,
+            _ => continue,
+          };
+          dependents.push(workspace_member_path.clone());
         }
       }
     }
 
-    let is_workspace_member_dependency =
-      !&workspace_member_dependents.is_empty() || !&workspace_member_dev_dependents.is_empty();
+    let is_workspace_member_dependency = !&workspace_member_dependents.is_empty()
+      || !&workspace_member_dev_dependents.is_empty()
+      || !&workspace_member_build_dependents.is_empty();
     let is_binary_dependency = self.settings.binary_deps.contains_key(&package.name);
 
+    let mut raze_settings = self.crate_settings.cloned().unwrap_or_default();
+
+    // Crate specific environment variables take precedence over global ones
+    for (key, value) in self.settings.global_rustc_env.iter() {
+      raze_settings
+        .additional_env
+        .entry(key.clone())
+        .or_insert_with(|| value.clone());
+    }
+
+    // Resolve the effective genmode so renderers know how this crate's sources are provided
+    raze_settings.gen_mode = Some(crate_genmode(self.settings, package));
+
+    // Files symlinked from sibling crates are outside of the crate's package, so the globs of its
+    // rules only pick up the links and the files they point to must be depended upon explicitly
+    if let Some(crate_dir) = manifest_path.parent() {
+      let sibling_idents: HashSet<String> = self
+        .crate_catalog
+        .metadata
+        .packages
+        .iter()
+        .map(|pkg| util::package_ident(&pkg.name, &pkg.version.to_string()))
+        .collect();
+      for data_dependency in
+        resolve_symlinked_sibling_files(&package.name, crate_dir, &sibling_idents)?
+      {
+        if !raze_settings.data_dependencies.contains(&data_dependency) {
+          raze_settings.data_dependencies.push(data_dependency);
+        }
+      }
+    }
+
     // Generate canonicalized paths to additional build files so they're guaranteed to exist
     // and always locatable.
-    let raze_settings = self.crate_settings.cloned().unwrap_or_default();
     let canonical_additional_build_file = match &raze_settings.additional_build_file {
       Some(build_file) => Some(
         cargo_workspace_root
@@ -383,65 +1751,292 @@ impl<'planner> CrateSubplanner<'planner> {
       None => None,
     };
 
+    let categories = package
+      .categories
+      .iter()
+      .chain(raze_settings.categories.iter())
+      .cloned()
+      .sorted()
+      .dedup()
+      .collect();
+
+    let member_package = self.workspace_member_packages.get(&self.node.id);
+    let workspace_path_to_crate = match member_package {
+      Some(package) => package.clone(),
+      None => self.crate_catalog_entry.workspace_path(self.settings)?,
+    };
+    // The BUILD files of members are placed in their directory of the cargo workspace
+    let expected_build_path = match member_package {
+      Some(_) => util::get_workspace_member_path(
+        &package.manifest_path,
+        &self.crate_catalog.metadata.workspace_root,
+      )
+      .unwrap_or_default()
+      .join(&self.settings.output_buildfile_suffix)
+      .to_string_lossy()
+      .to_string(),
+      None => self.crate_catalog_entry.local_build_path(self.settings)?,
+    };
+
+    // The absolute path of the execution root is only known to Bazel, which substitutes `${pwd}`
+    if !raze_settings.manifest_dir_data.is_empty() {
+      let manifest_dir = format!("${{pwd}}/{}", execroot_crate_dir(&workspace_path_to_crate));
+      raze_settings
+        .additional_env
+        .entry("CARGO_MANIFEST_DIR".to_owned())
+        .or_insert(manifest_dir);
+    }
+
+    let remap_path_prefix =
+      self.settings.remap_path_prefix.as_ref().map(|placeholder| {
+        produce_remap_path_prefix(placeholder, package, &workspace_path_to_crate)
+      });
+
+    // A registry configured for the crate takes precedence over the one it was resolved from
+    let registry_url = match (&raze_settings.registry, self.download_url) {
+      (Some(registry), _) => {
+        format_registry_url(registry, &package.name, &package.version.to_string())
+      },
+      (None, Some(download_url)) => download_url.clone(),
+      (None, None) => format_registry_url(
+        &self.settings.registry,
+        &package.name,
+        &package.version.to_string(),
+      ),
+    };
+
     let context = CrateContext {
       pkg_name: package.name.clone(),
       pkg_version: package.version.clone(),
       edition: package.edition.clone(),
       license: self.produce_license(),
-      features: self.node.features.clone(),
+      license_text: self.produce_license_text(&manifest_path)?,
+      features,
+      dev_features,
+      lint_flags: self.produce_lint_flags(),
+      remap_path_prefix,
+      targeted_rustc_flags: self.produce_targeted_rustc_flags(&raze_settings)?,
+      targeted_compile_data: self.produce_targeted_values(&raze_settings.compile_data_attrs)?,
+      config_setting_groups,
+      targeted_rustc_env: self.produce_targeted_env(&raze_settings.rustc_env)?,
+      targeted_build_script_env: self.produce_targeted_env(&raze_settings.build_script_env)?,
+      targeted_editions: self.produce_targeted_editions(&raze_settings)?,
+      categories,
       workspace_member_dependents,
       workspace_member_dev_dependents,
+      workspace_member_build_dependents,
       is_workspace_member_dependency,
       is_binary_dependency,
+      is_workspace_member: member_package.is_some(),
       is_proc_macro,
-      default_deps: CrateDependencyContext {
-        dependencies: normal_deps,
-        proc_macro_dependencies: proc_macro_deps,
-        data_dependencies: vec![],
-        build_dependencies: build_deps,
-        build_proc_macro_dependencies: build_proc_macro_deps,
-        build_data_dependencies: vec![],
-        dev_dependencies: dev_deps,
-        aliased_dependencies: aliased_deps,
-      },
+      default_deps,
       targeted_deps: filtered_deps,
-      workspace_path_to_crate: self.crate_catalog_entry.workspace_path(&self.settings)?,
+      workspace_path_to_crate,
       build_script_target: build_script_target_opt,
+      build_script_srcs,
+      build_script_srcs_exclude,
       links: package.links.clone(),
       raze_settings,
       canonical_additional_build_file,
-      source_details: self.produce_source_details(&package, &package_root),
-      expected_build_path: self.crate_catalog_entry.local_build_path(&self.settings)?,
+      source_details: self.produce_source_details(package, &package_root, cargo_workspace_root),
+      expected_build_path,
       sha256: self.sha256.clone(),
-      registry_url: format_registry_url(
-        &self.settings.registry,
-        &package.name,
-        &package.version.to_string(),
-      ),
+      registry_url,
       lib_target_name,
+      library_target_name,
+      lib_crate_types,
+      self_alias,
+      // Filled in once the platforms the crate is reachable on are known
+      target_compatible_with: Vec::new(),
       targets,
     };
 
     Ok(context)
   }
 
-  /// Generates license data from internal crate details.
+  /// The `package.include` patterns of the crate's manifest, which cargo_metadata does not expose.
+  fn produce_manifest_includes(&self) -> Vec<String> {
+    let package = self.crate_catalog_entry.package();
+    fs::read_to_string(&package.manifest_path)
+      .ok()
+      .and_then(|contents| contents.parse::<toml::Value>().ok())
+      .and_then(|manifest| manifest.get("package")?.get("include")?.as_array().cloned())
+      .unwrap_or_default()
+      .iter()
+      .filter_map(toml::Value::as_str)
+      .map(str::to_owned)
+      .collect()
+  }
+
+  /// Translates the `[lints]` table of the crate's manifest, or the `[workspace.lints]` table it
+  /// inherits, into rustc flags.
+  fn produce_lint_flags(&self) -> Vec<String> {
+    let read_manifest = |path: &Path| -> Option<toml::Value> {
+      fs::read_to_string(path).ok()?.parse::<toml::Value>().ok()
+    };
+
+    let package = self.crate_catalog_entry.package();
+    let lints = match read_manifest(&package.manifest_path).and_then(|m| m.get("lints").cloned()) {
+      Some(lints) => lints,
+      None => return Vec::new(),
+    };
+    if lints.get("workspace").and_then(toml::Value::as_bool) == Some(true) {
+      let workspace_manifest = self
+        .crate_catalog
+        .metadata
+        .workspace_root
+        .join("Cargo.toml");
+      return read_manifest(&workspace_manifest)
+        .as_ref()
+        .and_then(|manifest| manifest.get("workspace")?.get("lints"))
+        .map(produce_lint_flags)
+        .unwrap_or_default();
+    }
+    produce_lint_flags(&lints)
+  }
+
+  /// Produces the rustc flags of the configured target sysroots and of the crate's `rustc_flags`,
+  /// keyed by the condition matching the platform of each sysroot or triple.
+  fn produce_targeted_rustc_flags(
+    &self,
+    raze_settings: &CrateSettings,
+  ) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut targeted_rustc_flags: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (triple, target_sysroot) in &self.settings.target_sysroot {
+      let mut flags = vec![format!("--sysroot={}", target_sysroot.sysroot)];
+      flags.extend(
+        target_sysroot
+          .link_args
+          .iter()
+          .map(|link_arg| format!("-Clink-arg={}", link_arg)),
+      );
+      for condition in util::generate_bazel_conditions(
+        &self.settings.rust_rules_workspace_name,
+        std::slice::from_ref(triple),
+        &self.settings.custom_targets,
+      )? {
+        targeted_rustc_flags.insert(condition, flags.clone());
+      }
+    }
+    for (condition, flags) in self.produce_targeted_values(&raze_settings.rustc_flags)? {
+      targeted_rustc_flags
+        .entry(condition)
+        .or_default()
+        .extend(flags);
+    }
+    Ok(targeted_rustc_flags)
+  }
+
+  /// Produces sorted environment variables from a setting mapping target triples to them, eg:
+  /// `rustc_env`, keyed by the condition matching the platform of each triple.
+  fn produce_targeted_env(
+    &self,
+    env_per_target: &HashMap<String, HashMap<String, String>>,
+  ) -> Result<BTreeMap<String, BTreeMap<String, String>>> {
+    Ok(
+      self
+        .produce_targeted_values(env_per_target)?
+        .into_iter()
+        .map(|(condition, env)| (condition, env.into_iter().collect()))
+        .collect(),
+    )
+  }
+
+  /// Keys the values of a setting mapping target triples to values, eg: `rustc_env`, by the
+  /// condition matching the platform of each triple.
+  fn produce_targeted_values<T: Clone>(
+    &self,
+    values_per_target: &HashMap<String, T>,
+  ) -> Result<BTreeMap<String, T>> {
+    let mut targeted_values = BTreeMap::new();
+    for (triple, value) in values_per_target {
+      for condition in util::generate_bazel_conditions(
+        &self.settings.rust_rules_workspace_name,
+        std::slice::from_ref(triple),
+        &self.settings.custom_targets,
+      )? {
+        targeted_values.insert(condition, value.clone());
+      }
+    }
+    Ok(targeted_values)
+  }
+
+  /// Produces the editions of `edition_per_target`, keyed by the condition matching the platform
+  /// of each triple. Nothing is produced when they all match the crate's own edition.
+  fn produce_targeted_editions(
+    &self,
+    raze_settings: &CrateSettings,
+  ) -> Result<BTreeMap<String, String>> {
+    let package_edition = &self.crate_catalog_entry.package().edition;
+    if raze_settings
+      .edition_per_target
+      .values()
+      .all(|edition| edition == package_edition)
+    {
+      return Ok(BTreeMap::new());
+    }
+
+    self.produce_targeted_values(&raze_settings.edition_per_target)
+  }
+
+  /// Generates license data from internal crate details, or from the crate's entry in
+  /// `license_overrides` if it has one.
   fn produce_license(&self) -> LicenseData {
+    let package = self.crate_catalog_entry.package();
     let licenses_str = self
-      .crate_catalog_entry
-      .package()
-      .license
-      .as_ref()
+      .settings
+      .license_overrides
+      .get(&package.name)
+      .or(package.license.as_ref())
       .map_or("", String::as_str);
 
     license::get_license_from_str(licenses_str)
   }
 
+  /// Reads the license texts shipped alongside the crate's manifest, when they are aggregated into
+  /// a `NOTICE` file. These are the `license-file` of the package if it has one, or otherwise any
+  /// file named like `LICENSE*`, `LICENCE*` or `COPYING*`, joined in the order of their names.
+  fn produce_license_text(&self, manifest_path: &Path) -> Result<Option<String>> {
+    if !self.settings.notice_file {
+      return Ok(None);
+    }
+
+    // UNWRAP: Manifest paths are guaranteed to be absolute paths to files
+    let manifest_dir = manifest_path.parent().unwrap();
+    let license_files: Vec<PathBuf> = match &self.crate_catalog_entry.package().license_file {
+      Some(license_file) => vec![manifest_dir.join(license_file)],
+      None => match fs::read_dir(manifest_dir) {
+        Ok(entries) => entries
+          .filter_map(|entry| entry.ok())
+          .map(|entry| entry.path())
+          .filter(|path| path.is_file() && is_license_file_name(path))
+          .sorted()
+          .collect(),
+        // Crates whose sources are not available locally have no license text to aggregate
+        Err(_) => Vec::new(),
+      },
+    };
+
+    let mut texts = Vec::new();
+    for license_file in license_files {
+      let text = fs::read_to_string(&license_file)
+        .with_context(|| format!("Failed to read license file {}", license_file.display()))?;
+      texts.push(text.trim_end().to_owned());
+    }
+
+    Ok(if texts.is_empty() {
+      None
+    } else {
+      Some(texts.join("\n\n"))
+    })
+  }
+
   fn _produce_deps(&self, names: &DependencyNames) -> Result<DependencySet> {
     let build_dep_names = &names.build_dep_names;
     let dev_dep_names = &names.dev_dep_names;
     let normal_dep_names = &names.normal_dep_names;
     let aliased_dep_names = &names.aliased_dep_names;
+    let build_aliased_dep_names = &names.build_aliased_dep_names;
 
     let mut dep_set = DependencySet {
       build_deps: Vec::new(),
@@ -450,6 +2045,7 @@ impl<'planner> CrateSubplanner<'planner> {
       dev_deps: Vec::new(),
       normal_deps: Vec::new(),
       aliased_deps: Vec::new(),
+      build_aliased_deps: Vec::new(),
     };
 
     let all_skipped_deps = self
@@ -466,6 +2062,21 @@ impl<'planner> CrateSubplanner<'planner> {
         .unwrap()
         .package();
 
+      // A crate depending on itself (eg: through a renamed dev-dependency) is handled by
+      // `self_alias` as the crate's targets can't depend on themselves.
+      if *dep_id == self.node.id {
+        continue;
+      }
+
+      // Optional dependencies only belong to the crate when one of its features activates them
+      if is_inactive_optional_dependency(
+        self.crate_catalog_entry.package(),
+        &self.node.features,
+        dep_package,
+      ) {
+        continue;
+      }
+
       // Skip settings-indicated deps to skip
       if all_skipped_deps.contains(&util::package_ident(
         &dep_package.name,
@@ -475,31 +2086,26 @@ impl<'planner> CrateSubplanner<'planner> {
       }
 
       // UNWRAP: Guaranteed to exist by checks in WorkspaceSubplanner#produce_build_plan
-      let buildable_target = self
-        .crate_catalog
-        .entry_for_package_id(dep_id)
-        .unwrap()
-        .workspace_path_and_default_target(&self.settings)?;
+      let dep_entry = self.crate_catalog.entry_for_package_id(dep_id).unwrap();
+      let buildable_target = match self.workspace_member_packages.get(dep_id) {
+        Some(package) => format!(
+          "{}:{}",
+          package,
+          dep_entry.default_target_name(self.settings)
+        ),
+        None => dep_entry.workspace_path_and_default_target(self.settings)?,
+      };
 
       // Implicitly dependencies are on the [lib] target from Cargo.toml (of which there is
-      // guaranteed to be at most one).
-      // In this function, we don't explicitly narrow to be considering only the [lib] Target - we
-      // rely on the fact that only one [lib] is allowed in a Package, and so treat the Package
-      // synonymously with the [lib] Target therein.
-      // Only the [lib] target is allowed to be labelled as a proc-macro, so checking if "any"
-      // target is a proc-macro is equivalent to checking if the [lib] target is a proc-macro (and
-      // accordingly, whether we need to treat this dep like a proc-macro).
-      let is_proc_macro = dep_package
-        .targets
-        .iter()
-        .flat_map(|target| target.crate_types.iter())
-        .any(|crate_type| crate_type.as_str() == "proc-macro");
+      // guaranteed to be at most one), which is linked as the crate's own rules are rendered.
+      let is_proc_macro = links_as_proc_macro(dep_package);
 
       let buildable_dependency = BuildableDependency {
         name: dep_package.name.clone(),
         version: dep_package.version.clone(),
         buildable_target: buildable_target.clone(),
         is_proc_macro,
+        is_first_party: dep_package.source.is_none(),
       };
 
       if build_dep_names.contains(&dep_package.name) {
@@ -510,6 +2116,13 @@ impl<'planner> CrateSubplanner<'planner> {
         } else {
           dep_set.build_deps.push(buildable_dependency.clone());
         }
+        // Renamed build dependencies are only aliased in the build script rule of this crate
+        for alias in self.dependency_aliases(build_aliased_dep_names, dep_id, dep_package) {
+          dep_set.build_aliased_deps.push(DependencyAlias {
+            target: buildable_target.clone(),
+            alias,
+          })
+        }
       }
 
       if dev_dep_names.contains(&dep_package.name) {
@@ -528,20 +2141,40 @@ impl<'planner> CrateSubplanner<'planner> {
           dep_set.normal_deps.push(buildable_dependency);
         }
         // Only add aliased normal deps to the Vec
-        if let Some(alias_pair) = aliased_dep_names.get(&dep_package.name) {
-          // Check whether the package's version matches the semver requirement
-          // of the package that had an alias
-          if alias_pair.0.matches(&dep_package.version) {
-            dep_set.aliased_deps.push(DependencyAlias {
-              target: buildable_target.clone(),
-              alias: (&alias_pair.1).replace("-", "_"),
-            })
-          }
+        let aliases = self.dependency_aliases(aliased_dep_names, dep_id, dep_package);
+        for alias in aliases {
+          dep_set.aliased_deps.push(DependencyAlias {
+            target: buildable_target.clone(),
+            alias,
+          })
         }
       }
     }
 
-    dep_set.aliased_deps.sort();
+    let package = self.crate_catalog_entry.package();
+    let crate_ident = util::package_ident(&package.name, &package.version.to_string());
+    let (aliased_deps, alias_conflicts) =
+      dedup_dependency_aliases(&crate_ident, dep_set.aliased_deps);
+    let (build_aliased_deps, build_alias_conflicts) =
+      dedup_dependency_aliases(&crate_ident, dep_set.build_aliased_deps);
+    for alias_conflict in alias_conflicts.into_iter().chain(build_alias_conflicts) {
+      eprintln!("WARNING: {}", alias_conflict);
+    }
+    dep_set.aliased_deps = aliased_deps;
+    dep_set.build_aliased_deps = build_aliased_deps;
+    // rules_rust rejects a dependency which is listed both as a proc-macro and as a library
+    for (deps, proc_macro_deps) in [
+      (&mut dep_set.normal_deps, &dep_set.proc_macro_deps),
+      (&mut dep_set.build_deps, &dep_set.build_proc_macro_deps),
+    ]
+    .iter_mut()
+    {
+      deps.retain(|dep| {
+        !proc_macro_deps
+          .iter()
+          .any(|proc_macro_dep| proc_macro_dep.buildable_target == dep.buildable_target)
+      });
+    }
     dep_set.build_deps.sort();
     dep_set.build_proc_macro_deps.sort();
     dep_set.dev_deps.sort();
@@ -551,6 +2184,391 @@ impl<'planner> CrateSubplanner<'planner> {
     Ok(dep_set)
   }
 
+  /// Yields the aliases under which the crate refers to the dependency `dep_id`, checking whether
+  /// the package's version matches the semver requirement of each rename of the package.
+  fn dependency_aliases(
+    &self,
+    aliased_dep_names: &HashMap<String, Vec<(semver::VersionReq, String)>>,
+    dep_id: &PackageId,
+    dep_package: &Package,
+  ) -> Vec<String> {
+    let mut aliases: Vec<String> = aliased_dep_names
+      .get(&dep_package.name)
+      .into_iter()
+      .flatten()
+      .filter(|(req, _)| req.matches(&dep_package.version))
+      .map(|(_, alias)| alias.replace("-", "_"))
+      .collect();
+    // When the requirements of several renames match this version, the name the resolver
+    // gave the dependency decides which of them refers to it
+    if aliases.len() > 1 {
+      let resolved_aliases: Vec<String> = aliases
+        .iter()
+        .filter(|alias| {
+          self
+            .node
+            .deps
+            .iter()
+            .any(|node_dep| node_dep.pkg == *dep_id && node_dep.name == **alias)
+        })
+        .cloned()
+        .collect();
+      if !resolved_aliases.is_empty() {
+        aliases = resolved_aliases;
+      }
+    }
+    aliases
+  }
+
+  /// Yields the configured triples, in sorted order, which match the given target.
+  fn matching_triples(&self, target: &str) -> Result<Vec<String>> {
+    let mut target_triples =
+      util::get_matching_bazel_triples(target, &self.settings.custom_targets)?;
+    util::filter_bazel_triples(
+      &mut target_triples,
+      self
+        .settings
+        .targets
+        .as_ref()
+        .unwrap_or(&Vec::<String>::new()),
+    );
+    target_triples.sort();
+
+    Ok(target_triples)
+  }
+
+  /// Collects the features requested of this crate by each of its dependents.
+  fn produce_feature_requests(&self, configured_triples: &[String]) -> Result<Vec<FeatureRequest>> {
+    let package = self.crate_catalog_entry.package();
+
+    let mut requests = Vec::new();
+    for (dependent, dependent_node) in resolve_dependents(self.crate_catalog, package) {
+      for dep in dependency_entries_on(dependent, package) {
+        let gate = match &dep.target {
+          Some(target) => {
+            let target = target.to_string();
+            let target_triples = self.matching_triples(&target)?;
+            // Requests applying to every configured triple are not gated at all
+            if target_triples == configured_triples {
+              None
+            } else {
+              Some((target, target_triples))
+            }
+          },
+          None => None,
+        };
+
+        let mut features = dep.features.clone();
+        features.extend(features_requested_through(dependent, dependent_node, dep));
+        if dep.uses_default_features {
+          features.push("default".to_owned());
+        }
+        requests.push(FeatureRequest { gate, features });
+      }
+    }
+
+    Ok(requests)
+  }
+
+  /// Expands requested features into all features they enable
+  fn expand_features<'a>(&self, requests: impl Iterator<Item = &'a String>) -> HashSet<String> {
+    expand_package_features(self.crate_catalog_entry.package(), requests)
+  }
+
+  /// Resolves the exact features and dependencies of the crate on each configured triple.
+  ///
+  /// Yields the features and dependencies shared by all configured triples along with a set of
+  /// non-overlapping branches, each holding what a group of triples needs in addition to those.
+  /// Features that are only requested by dependents on some platforms (and the optional
+  /// dependencies they enable) are confined to the branches of those platforms.
+  fn produce_platform_branches(
+    &self,
+    default_deps: CrateDependencyContext,
+    targeted_deps: Vec<(String, Vec<String>, CrateDependencyContext)>,
+  ) -> Result<(
+    Vec<String>,
+    CrateDependencyContext,
+    Vec<CrateTargetedDepContext>,
+  )> {
+    let package = self.crate_catalog_entry.package();
+
+    let mut configured_triples = util::get_supported_bazel_triples(&self.settings.custom_targets);
+    util::filter_bazel_triples(
+      &mut configured_triples,
+      self
+        .settings
+        .targets
+        .as_ref()
+        .unwrap_or(&Vec::<String>::new()),
+    );
+    configured_triples.sort();
+
+    let requests = self.produce_feature_requests(&configured_triples)?;
+    let gated_features = self.expand_features(
+      requests
+        .iter()
+        .filter(|request| request.gate.is_some())
+        .flat_map(|request| request.features.iter()),
+    );
+
+    let mut resolutions = Vec::new();
+    for triple in configured_triples.iter() {
+      let applies_to_triple = |request: &&FeatureRequest| match &request.gate {
+        Some((_, gate_triples)) => gate_triples.contains(triple),
+        None => true,
+      };
+      let enabled_features = self.expand_features(
+        requests
+          .iter()
+          .filter(applies_to_triple)
+          .flat_map(|request| request.features.iter()),
+      );
+      let is_enabled =
+        |feature: &String| !gated_features.contains(feature) || enabled_features.contains(feature);
+
+      let features: BTreeSet<String> = self
+        .node
+        .features
+        .iter()
+        .filter(|feature| is_enabled(feature))
+        .cloned()
+        .collect();
+
+      let mut targets: BTreeSet<String> = requests
+        .iter()
+        .filter(applies_to_triple)
+        .filter_map(|request| request.gate.as_ref().map(|(target, _)| target.clone()))
+        .collect();
+      let mut deps = default_deps.clone();
+      for (target, target_triples, targeted) in targeted_deps.iter() {
+        if target_triples.contains(triple) {
+          deps = combine_dependencies(&deps, targeted, SetOperation::Union);
+          targets.insert(target.clone());
+        }
+      }
+
+      // Optional dependencies are only enabled alongside their implicit feature
+      let disabled_deps: Vec<_> = package
+        .dependencies
+        .iter()
+        .filter(|dep| dep.optional && !is_enabled(dep.rename.as_ref().unwrap_or(&dep.name)))
+        .collect();
+      remove_dependencies(&mut deps, |dep| {
+        disabled_deps
+          .iter()
+          .any(|disabled| disabled.name == dep.name && disabled.req.matches(&dep.version))
+      });
+
+      resolutions.push((triple, features, deps, targets));
+    }
+
+    let (common_features, common_deps) = match resolutions.split_first() {
+      Some(((_, features, deps, _), rest)) => rest.iter().fold(
+        (features.clone(), deps.clone()),
+        |(common_features, common_deps), (_, features, deps, _)| {
+          (
+            common_features.intersection(features).cloned().collect(),
+            combine_dependencies(&common_deps, deps, SetOperation::Intersection),
+          )
+        },
+      ),
+      // None of the configured triples are supported by Bazel
+      None => return Ok((self.node.features.clone(), default_deps, Vec::new())),
+    };
+
+    // Group the triples which need the same features and dependencies beyond the common ones
+    let mut branches: BTreeMap<_, (Vec<String>, BTreeSet<String>)> = BTreeMap::new();
+    for (triple, features, deps, targets) in resolutions.into_iter() {
+      let extra_features: Vec<String> = features.difference(&common_features).cloned().collect();
+      let extra_deps = combine_dependencies(&deps, &common_deps, SetOperation::Difference);
+      if extra_features.is_empty() && is_empty_dependency_context(&extra_deps) {
+        continue;
+      }
+
+      let branch = branches.entry((extra_features, extra_deps)).or_default();
+      branch.0.push(triple.clone());
+      branch.1.extend(targets);
+    }
+
+    let mut platform_branches = branches
+      .into_iter()
+      .map(|((features, deps), (triples, targets))| {
+        Ok(CrateTargetedDepContext {
+          target: targets.iter().join(", "),
+          deps,
+          conditions: util::generate_bazel_conditions(
+            &self.settings.rust_rules_workspace_name,
+            &triples,
+            &self.settings.custom_targets,
+          )?,
+          features,
+        })
+      })
+      .collect::<Result<Vec<_>>>()?;
+    platform_branches.sort();
+
+    Ok((
+      common_features.into_iter().collect(),
+      common_deps,
+      platform_branches,
+    ))
+  }
+
+  /// Moves the features which are toggled at build time out of the common features, along with
+  /// the features and optional dependencies only they enable, into branches keyed on their
+  /// `config_setting`.
+  ///
+  /// Anything enabled by several toggled features is left in the common features and dependencies.
+  /// Optional dependencies of specific platforms move into branches matching both the toggle and
+  /// the platform, through the yielded `config_setting_group`s.
+  fn produce_feature_toggle_branches(
+    &self,
+    mut features: Vec<String>,
+    mut default_deps: CrateDependencyContext,
+    mut branches: Vec<CrateTargetedDepContext>,
+  ) -> (
+    Vec<String>,
+    CrateDependencyContext,
+    Vec<CrateTargetedDepContext>,
+    BTreeMap<String, Vec<String>>,
+  ) {
+    let package = self.crate_catalog_entry.package();
+    let toggles = match self.crate_settings {
+      Some(settings) if !settings.feature_config_settings.is_empty() => {
+        &settings.feature_config_settings
+      },
+      _ => return (features, default_deps, branches, BTreeMap::new()),
+    };
+
+    let retained = self.expand_features(
+      features
+        .iter()
+        .filter(|feature| !toggles.contains_key(*feature)),
+    );
+    let claims: Vec<(&String, &String, HashSet<String>)> = toggles
+      .iter()
+      .filter(|(feature, _)| features.contains(feature))
+      .sorted()
+      .map(|(feature, config_setting)| {
+        let claimed = self
+          .expand_features(std::iter::once(feature))
+          .into_iter()
+          .filter(|claimed| !retained.contains(claimed))
+          .collect();
+        (feature, config_setting, claimed)
+      })
+      .collect();
+
+    let mut config_setting_groups = BTreeMap::new();
+    let mut toggled_platform_branches = Vec::new();
+    for (feature, config_setting, claimed) in claims.iter() {
+      let exclusive: HashSet<&String> = claimed
+        .iter()
+        .filter(|claimed| {
+          claims
+            .iter()
+            .filter(|(_, _, other)| other.contains(*claimed))
+            .count()
+            == 1
+        })
+        .collect();
+      if exclusive.is_empty() {
+        continue;
+      }
+
+      let toggled_features: Vec<String> = features
+        .iter()
+        .filter(|feature| exclusive.contains(feature))
+        .cloned()
+        .collect();
+      features.retain(|feature| !exclusive.contains(feature));
+
+      let toggled_deps: Vec<_> = package
+        .dependencies
+        .iter()
+        .filter(|dep| dep.optional && exclusive.contains(dep.rename.as_ref().unwrap_or(&dep.name)))
+        .collect();
+      let is_toggled = |dep: &BuildableDependency| {
+        toggled_deps
+          .iter()
+          .any(|toggled| toggled.name == dep.name && toggled.req.matches(&dep.version))
+      };
+      let all_deps = default_deps.clone();
+      remove_dependencies(&mut default_deps, is_toggled);
+
+      for branch in branches.iter_mut() {
+        let all_branch_deps = branch.deps.clone();
+        remove_dependencies(&mut branch.deps, is_toggled);
+        let toggled_branch_deps =
+          combine_dependencies(&all_branch_deps, &branch.deps, SetOperation::Difference);
+        if is_empty_dependency_context(&toggled_branch_deps) {
+          continue;
+        }
+
+        let conditions = branch
+          .conditions
+          .iter()
+          .map(|condition| {
+            let platform = condition.rsplit([':', '/']).next().unwrap_or(condition);
+            let group_name = format!("feature_{}_{}", feature.replace('-', "_"), platform);
+            config_setting_groups.insert(
+              group_name.clone(),
+              vec![(*config_setting).clone(), condition.clone()],
+            );
+            format!(":{}", group_name)
+          })
+          .collect();
+        toggled_platform_branches.push(CrateTargetedDepContext {
+          target: format!("all({}, feature = \"{}\")", branch.target, feature),
+          deps: toggled_branch_deps,
+          conditions,
+          features: Vec::new(),
+        });
+      }
+
+      branches.push(CrateTargetedDepContext {
+        target: format!("feature = \"{}\"", feature),
+        deps: combine_dependencies(&all_deps, &default_deps, SetOperation::Difference),
+        conditions: vec![(*config_setting).clone()],
+        features: toggled_features,
+      });
+    }
+
+    branches
+      .retain(|branch| !is_empty_dependency_context(&branch.deps) || !branch.features.is_empty());
+    branches.extend(toggled_platform_branches);
+    (features, default_deps, branches, config_setting_groups)
+  }
+
+  /// Yields the features of the crate's lib as seen by its integration tests, which additionally
+  /// enable the features requested by any dev-dependencies of the crate on itself.
+  fn produce_dev_features(&self, features: &[String]) -> Vec<String> {
+    let package = self.crate_catalog_entry.package();
+
+    let requested: Vec<String> = package
+      .dependencies
+      .iter()
+      .filter(|dep| dep.kind == DependencyKind::Development)
+      .filter(|dep| dep.name == package.name && dep.req.matches(&package.version))
+      .flat_map(|dep| {
+        let mut features = dep.features.clone();
+        if dep.uses_default_features {
+          features.push("default".to_owned());
+        }
+        features
+      })
+      .collect();
+
+    let mut dev_features: BTreeSet<String> = features.iter().cloned().collect();
+    dev_features.extend(
+      self
+        .expand_features(requested.iter())
+        .into_iter()
+        .filter(|feature| package.features.contains_key(feature)),
+    );
+    dev_features.into_iter().collect()
+  }
+
   /// Generates the set of dependencies for the contained crate.
   fn produce_deps(&self) -> Result<(DependencySet, Vec<TargetedDependencySet>)> {
     let (default_deps, targeted_deps) = self.identify_named_deps()?;
@@ -574,6 +2592,7 @@ impl<'planner> CrateSubplanner<'planner> {
       dev_dep_names: Vec::new(),
       normal_dep_names: Vec::new(),
       aliased_dep_names: HashMap::new(),
+      build_aliased_dep_names: HashMap::new(),
     };
 
     let mut targeted_dep_names: HashMap<String, DependencyNames> = HashMap::new();
@@ -601,7 +2620,7 @@ impl<'planner> CrateSubplanner<'planner> {
         }
 
         let (is_bazel_platform, matches_all_platforms) =
-          util::is_bazel_supported_platform(&target_str);
+          util::is_bazel_supported_platform(&target_str, &self.settings.custom_targets);
         // If the target is not supported by Bazel, we ignore it
         if !is_bazel_platform {
           continue;
@@ -621,6 +2640,7 @@ impl<'planner> CrateSubplanner<'planner> {
                   build_dep_names: Vec::new(),
                   dev_dep_names: Vec::new(),
                   aliased_dep_names: HashMap::new(),
+                  build_aliased_dep_names: HashMap::new(),
                 },
               );
               // UNWRAP: This unwrap should be safe given the insert above
@@ -647,11 +2667,19 @@ impl<'planner> CrateSubplanner<'planner> {
         },
       }
 
-      // Check if the dependency has been renamed
-      if let Some(alias) = dep.rename.as_ref() {
-        dep_names
-          .aliased_dep_names
-          .insert(dep.name.clone(), (dep.req.clone(), alias.clone()));
+      // Check if the dependency has been renamed. Normal dependencies are aliased in the build
+      // rule and build dependencies in the build script rule, so renames of one kind must not
+      // leak into the rule of the other.
+      let aliased_dep_names = match (dep.rename.as_ref(), dep.kind) {
+        (Some(_), DependencyKind::Normal) => Some(&mut dep_names.aliased_dep_names),
+        (Some(_), DependencyKind::Build) => Some(&mut dep_names.build_aliased_dep_names),
+        _ => None,
+      };
+      if let (Some(aliased_dep_names), Some(alias)) = (aliased_dep_names, dep.rename.as_ref()) {
+        aliased_dep_names
+          .entry(dep.name.clone())
+          .or_default()
+          .push((dep.req.clone(), alias.clone()));
       }
     }
 
@@ -659,22 +2687,45 @@ impl<'planner> CrateSubplanner<'planner> {
   }
 
   /// Generates source details for internal crate.
-  fn produce_source_details(&self, package: &Package, package_root: &Path) -> SourceDetails {
+  fn produce_source_details(
+    &self,
+    package: &Package,
+    package_root: &Path,
+    cargo_workspace_root: &Path,
+  ) -> SourceDetails {
+    // Crates without a source are path dependencies, except for the binary dependencies injected
+    // into the workspace as members
+    let is_path_dependency =
+      self.source_id.is_none() && !self.crate_catalog_entry.is_workspace_crate();
     SourceDetails {
-      git_data: self.source_id.as_ref().filter(|id| id.is_git()).map(|id| {
-        let manifest_parent = package.manifest_path.parent().unwrap();
-        let path_to_crate_root = manifest_parent.strip_prefix(package_root).unwrap();
-        let path_to_crate_root = if path_to_crate_root.components().next().is_some() {
-          Some(path_to_crate_root.to_string_lossy().to_string())
-        } else {
-          None
-        };
-        GitRepo {
-          remote: id.url().to_string(),
-          commit: id.precise().unwrap().to_owned(),
-          path_to_crate_root,
-        }
-      }),
+      git_data: self
+        .source_id
+        .as_ref()
+        .filter(|id| id.is_git())
+        .map(|id| {
+          let manifest_parent = package.manifest_path.parent().unwrap();
+          let path_to_crate_root = manifest_parent.strip_prefix(package_root).unwrap();
+          let path_to_crate_root = if path_to_crate_root.components().next().is_some() {
+            Some(path_to_crate_root.to_string_lossy().to_string())
+          } else {
+            None
+          };
+          GitRepo {
+            remote: id.url().to_string(),
+            commit: id.precise().unwrap().to_owned(),
+            path_to_crate_root,
+          }
+        })
+        .or_else(|| self.git_fallback.cloned()),
+      // Metadata is fetched from a copy of the workspace, so map paths inside of it back
+      local_path: Some(package_root)
+        .filter(|_| is_path_dependency)
+        .map(
+          |root| match root.strip_prefix(&self.crate_catalog.metadata.workspace_root) {
+            Ok(relative_root) => cargo_workspace_root.join(relative_root),
+            Err(_) => root.to_path_buf(),
+          },
+        ),
     }
   }
 
@@ -686,18 +2737,91 @@ impl<'planner> CrateSubplanner<'planner> {
     &self,
     all_targets: &mut Vec<BuildableTarget>,
   ) -> Option<BuildableTarget> {
-    if !self
-      .crate_settings
-      .and_then(|x| x.gen_buildrs)
-      .unwrap_or(self.settings.default_gen_buildrs)
+    let explicit_gen_buildrs = self.crate_settings.and_then(|x| x.gen_buildrs);
+
+    // Sources generated into `OUT_DIR` are only available to the library through its build script
+    if !explicit_gen_buildrs.unwrap_or(self.settings.default_gen_buildrs)
+      && (explicit_gen_buildrs.is_some() || !self.lib_includes_build_script_outputs())
     {
       return None;
     }
 
-    all_targets
+    let build_script_target = all_targets
       .iter()
       .position(|t| t.kind == "custom-build")
-      .map(|idx| all_targets.remove(idx))
+      .map(|idx| all_targets.remove(idx))?;
+
+    // Build scripts are kept when explicitly requested, even if they appear to do nothing or
+    // their cfgs are statically replicated
+    if explicit_gen_buildrs.is_some() {
+      return Some(build_script_target);
+    }
+    let has_static_rustc_cfgs = self
+      .crate_settings
+      .map(|x| !x.static_rustc_cfgs.is_empty())
+      .unwrap_or(false);
+    if (has_static_rustc_cfgs && !self.lib_includes_build_script_outputs())
+      || self.has_noop_build_script()
+    {
+      return None;
+    }
+
+    Some(build_script_target)
+  }
+
+  /// Determines whether the sources of the crate's library include files generated into `OUT_DIR`,
+  /// eg: through `include!(concat!(env!("OUT_DIR"), "/generated.rs"))`, in which case the library
+  /// can't be compiled without the outputs of its build script.
+  fn lib_includes_build_script_outputs(&self) -> bool {
+    let package = self.crate_catalog_entry.package();
+    let lib_src_dir = package
+      .targets
+      .iter()
+      .find(|target| {
+        target
+          .kind
+          .iter()
+          .any(|kind| LIBRARY_CRATE_TYPES.contains(&kind.as_str()))
+      })
+      .and_then(|target| target.src_path.parent());
+    let lib_src_dir = match lib_src_dir {
+      Some(lib_src_dir) => lib_src_dir,
+      None => return false,
+    };
+
+    glob::glob(&format!("{}/**/*.rs", lib_src_dir.display()))
+      .map(|paths| {
+        paths
+          .filter_map(|path| path.ok())
+          .filter_map(|path| fs::read_to_string(path).ok())
+          .any(|source| source.contains("env!(\"OUT_DIR\")"))
+      })
+      .unwrap_or(false)
+  }
+
+  /// Determines whether the crate's build script has no effect on the crate's compilation, in
+  /// which case its `cargo_build_script` rule would be pure overhead.
+  ///
+  /// Build scripts of crates with a `links` key or with build dependencies are never considered
+  /// to be no-ops, as they may pass along metadata or generate outputs through those.
+  fn has_noop_build_script(&self) -> bool {
+    let package = self.crate_catalog_entry.package();
+    if package.links.is_some()
+      || package
+        .dependencies
+        .iter()
+        .any(|dep| dep.kind == DependencyKind::Build)
+    {
+      return false;
+    }
+
+    package
+      .targets
+      .iter()
+      .find(|target| target.kind.iter().any(|kind| kind == "custom-build"))
+      .and_then(|target| fs::read_to_string(&target.src_path).ok())
+      .map(|source| is_noop_build_script(&source))
+      .unwrap_or(false)
   }
 
   /// Produces the complete set of build targets specified by this crate.
@@ -736,6 +2860,8 @@ impl<'planner> CrateSubplanner<'planner> {
           path: package_root_path_str.clone(),
           kind: kind.clone(),
           edition: target.edition.clone(),
+          test: target.test,
+          doctest: target.doctest,
         });
       }
     }
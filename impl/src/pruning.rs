@@ -0,0 +1,323 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use glob::Pattern;
+
+use crate::{
+  context::CrateContext,
+  planning::PlannedBuild,
+  rendering::RenderDetails,
+  settings::{GenMode, RazeSettings},
+};
+
+/// The files `filter_vendored_sources` removes from every vendored crate
+pub const DEFAULT_VENDOR_EXCLUDE_GLOBS: &[&str] = &["tests/**", "benches/**", "examples/**"];
+
+/// The checksums `cargo vendor` lists the files of a vendored crate in
+const CARGO_CHECKSUM_FILE: &str = ".cargo-checksum.json";
+
+/// Files which are never removed, as cargo and the generated BUILD files rely on them
+const KEPT_FILES: &[&str] = &[CARGO_CHECKSUM_FILE, "Cargo.toml"];
+
+/// Removes the files matching the exclude globs of each vendored crate of `planned_build` from its
+/// directory in the vendor directory. The removed files are dropped from the crate's
+/// `.cargo-checksum.json` as well, so cargo still accepts the vendored sources.
+///
+/// Returns the directory of each crate that files were removed from, along with the number of
+/// removed files.
+pub fn prune_vendored_sources(
+  settings: &RazeSettings,
+  render_details: &RenderDetails,
+  planned_build: &PlannedBuild,
+) -> Result<Vec<(PathBuf, usize)>> {
+  let output_dir = render_details.bazel_root.join(&render_details.path_prefix);
+  let mut pruned_crates = Vec::new();
+  for package in planned_build.crate_contexts.iter().filter(|package| {
+    package.raze_settings.gen_mode == Some(GenMode::Vendored)
+      && !package.raze_settings.vendor_everything
+  }) {
+    let patterns = exclude_patterns(settings, package)?;
+    if patterns.is_empty() {
+      continue;
+    }
+    // UNWRAP: The build file of a vendored crate is always inside of its directory
+    let crate_dir = output_dir
+      .join(&package.expected_build_path)
+      .parent()
+      .unwrap()
+      .to_path_buf();
+    if !crate_dir.is_dir() {
+      continue;
+    }
+
+    let removed_files = remove_matching_files(&crate_dir, &patterns)?;
+    if removed_files.is_empty() {
+      continue;
+    }
+    remove_checksums(&crate_dir, &removed_files)?;
+    pruned_crates.push((crate_dir, removed_files.len()));
+  }
+
+  Ok(pruned_crates)
+}
+
+/// The exclude globs of `package`, leaving out any which match the sources of one of its
+/// generated targets.
+fn exclude_patterns(settings: &RazeSettings, package: &CrateContext) -> Result<Vec<Pattern>> {
+  let crate_settings = &package.raze_settings;
+  let default_globs = match settings.filter_vendored_sources {
+    true => DEFAULT_VENDOR_EXCLUDE_GLOBS,
+    false => &[],
+  };
+  let gen_tests = crate_settings.gen_tests || crate_settings.test_shard_count.is_some();
+  let target_paths: Vec<&str> = package
+    .targets
+    .iter()
+    .filter(|target| {
+      package.lib_crate_types.contains(&target.kind)
+        || target.kind == "bin"
+        || (target.kind == "test" && gen_tests)
+    })
+    .chain(package.build_script_target.iter())
+    .map(|target| target.path.as_str())
+    .collect();
+
+  let mut patterns = Vec::new();
+  for glob in default_globs
+    .iter()
+    .copied()
+    .chain(crate_settings.exclude_globs.iter().map(String::as_str))
+  {
+    let pattern = Pattern::new(glob).with_context(|| {
+      format!(
+        "Invalid `exclude_globs` entry `{}` for {}",
+        glob, package.pkg_name
+      )
+    })?;
+    if !target_paths.iter().any(|path| pattern.matches(path)) {
+      patterns.push(pattern);
+    }
+  }
+  Ok(patterns)
+}
+
+/// Removes the files below `crate_dir` matching any of `patterns`, along with the directories
+/// which are left empty. Yields the paths of the removed files relative to `crate_dir`, with
+/// forward slashes as in `.cargo-checksum.json`.
+fn remove_matching_files(crate_dir: &Path, patterns: &[Pattern]) -> Result<Vec<String>> {
+  let mut files = Vec::new();
+  list_files(crate_dir, &mut files)?;
+  files.sort();
+
+  let mut removed_files = Vec::new();
+  for file in files.iter() {
+    // UNWRAP: The files were listed from within `crate_dir`
+    let relative_path = file
+      .strip_prefix(crate_dir)
+      .unwrap()
+      .to_string_lossy()
+      .replace('\\', "/");
+    if KEPT_FILES.contains(&relative_path.as_str())
+      || !patterns
+        .iter()
+        .any(|pattern| pattern.matches(&relative_path))
+    {
+      continue;
+    }
+
+    fs::remove_file(file).with_context(|| format!("Failed to remove {}", file.display()))?;
+    // Directories which still hold other files fail to be removed, which ends the walk
+    let mut dir = file.parent();
+    while let Some(parent) = dir.filter(|dir| *dir != crate_dir) {
+      if fs::remove_dir(parent).is_err() {
+        break;
+      }
+      dir = parent.parent();
+    }
+    removed_files.push(relative_path);
+  }
+
+  Ok(removed_files)
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    if entry.file_type()?.is_dir() {
+      list_files(&entry.path(), files)?;
+    } else {
+      files.push(entry.path());
+    }
+  }
+  Ok(())
+}
+
+/// Drops `removed_files` from the `.cargo-checksum.json` of `crate_dir`, if it has one. Cargo
+/// fails on files which are listed there but missing, though not on files which are unlisted.
+fn remove_checksums(crate_dir: &Path, removed_files: &[String]) -> Result<()> {
+  let checksum_path = crate_dir.join(CARGO_CHECKSUM_FILE);
+  if !checksum_path.exists() {
+    return Ok(());
+  }
+
+  let mut checksums: serde_json::Value = serde_json::from_str(&fs::read_to_string(&checksum_path)?)
+    .with_context(|| format!("Failed to parse {}", checksum_path.display()))?;
+  if let Some(files) = checksums
+    .get_mut("files")
+    .and_then(|files| files.as_object_mut())
+  {
+    for removed_file in removed_files.iter() {
+      files.remove(removed_file);
+    }
+  }
+  fs::write(&checksum_path, serde_json::to_string(&checksums)?)
+    .with_context(|| format!("Failed to write {}", checksum_path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use semver::VersionReq;
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::{
+    planning::{BuildPlanner, BuildPlannerImpl},
+    settings::{tests::dummy_raze_settings, CrateSettings},
+    testing::*,
+    util::PlatformDetails,
+  };
+
+  fn plan(settings: &RazeSettings) -> PlannedBuild {
+    BuildPlannerImpl::new(
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES),
+      settings.clone(),
+    )
+    .plan_build(Some(PlatformDetails::new(
+      "some_target_triple".to_owned(),
+      Vec::new(), /* attrs */
+    )))
+    .unwrap()
+  }
+
+  fn write_vendored_files(crate_dir: &Path) {
+    for file in &[
+      "src/lib.rs",
+      "build.rs",
+      "tests/macros.rs",
+      "tests/data/fixture.json",
+      "benches/bench.rs",
+      "testdata/input.txt",
+    ] {
+      let path = crate_dir.join(file);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, "").unwrap();
+    }
+    fs::write(
+      crate_dir.join(CARGO_CHECKSUM_FILE),
+      concat!(
+        r#"{"files":{"src/lib.rs":"00","tests/macros.rs":"01","benches/bench.rs":"02"},"#,
+        r#""package":"03"}"#
+      ),
+    )
+    .unwrap();
+  }
+
+  #[test]
+  fn test_unneeded_sources_are_removed_from_vendored_crates() {
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Vendored;
+    settings.filter_vendored_sources = true;
+    let mut versions = HashMap::new();
+    versions.insert(
+      VersionReq::parse("0.4.13").unwrap(),
+      CrateSettings {
+        exclude_globs: vec!["testdata/**".to_owned()],
+        ..Default::default()
+      },
+    );
+    settings.crates.insert("log".to_owned(), versions);
+    let planned_build = plan(&settings);
+
+    let bazel_root = TempDir::new().unwrap();
+    let crate_dir = bazel_root.as_ref().join("cargo/vendor/log-0.4.13");
+    write_vendored_files(&crate_dir);
+    let render_details = RenderDetails::new(
+      &settings,
+      PathBuf::from("/some/cargo/root"),
+      bazel_root.as_ref().to_path_buf(),
+    );
+
+    assert_eq!(
+      prune_vendored_sources(&settings, &render_details, &planned_build).unwrap(),
+      vec![(crate_dir.clone(), 4)]
+    );
+    assert!(crate_dir.join("src/lib.rs").exists());
+    assert!(crate_dir.join("build.rs").exists());
+    for removed_dir in &["tests", "benches", "testdata"] {
+      assert!(!crate_dir.join(removed_dir).exists(), "{}", removed_dir);
+    }
+    assert_eq!(
+      fs::read_to_string(crate_dir.join(CARGO_CHECKSUM_FILE)).unwrap(),
+      r#"{"files":{"src/lib.rs":"00"},"package":"03"}"#
+    );
+
+    // Nothing is left to remove when pruning again
+    assert!(
+      prune_vendored_sources(&settings, &render_details, &planned_build)
+        .unwrap()
+        .is_empty()
+    );
+  }
+
+  #[test]
+  fn test_vendor_everything_keeps_all_sources() {
+    let mut settings = dummy_raze_settings();
+    settings.genmode = GenMode::Vendored;
+    settings.filter_vendored_sources = true;
+    let mut versions = HashMap::new();
+    versions.insert(
+      VersionReq::parse("0.4.13").unwrap(),
+      CrateSettings {
+        vendor_everything: true,
+        ..Default::default()
+      },
+    );
+    settings.crates.insert("log".to_owned(), versions);
+    let planned_build = plan(&settings);
+
+    let bazel_root = TempDir::new().unwrap();
+    let crate_dir = bazel_root.as_ref().join("cargo/vendor/log-0.4.13");
+    write_vendored_files(&crate_dir);
+    let render_details = RenderDetails::new(
+      &settings,
+      PathBuf::from("/some/cargo/root"),
+      bazel_root.as_ref().to_path_buf(),
+    );
+
+    assert!(
+      prune_vendored_sources(&settings, &render_details, &planned_build)
+        .unwrap()
+        .is_empty()
+    );
+    assert!(crate_dir.join("tests/data/fixture.json").exists());
+  }
+}
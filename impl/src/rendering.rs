@@ -14,9 +14,18 @@
 
 pub mod bazel;
 
-use crate::planning::PlannedBuild;
+use crate::{
+  incremental::CrateCache,
+  planning::PlannedBuild,
+  settings::{GenMode, RazeSettings, SourceAuth, RUST_RULES},
+};
 use anyhow::Result;
-use std::path::PathBuf;
+use bazel::BazelRenderer;
+use semver::Version;
+use std::{
+  collections::{BTreeMap, HashMap},
+  path::PathBuf,
+};
 
 pub trait BuildRenderer {
   fn render_planned_build(
@@ -45,6 +54,112 @@ pub struct RenderDetails {
   pub vendored_buildfile_name: String,
   pub bazel_root: PathBuf,
   pub rust_rules_workspace_name: String,
+  pub rust_rules_bzl: Option<String>,
+  pub cargo_build_script_bzl: Option<String>,
+  pub rule_names: HashMap<String, String>,
+  pub rules_rust_version: Option<Version>,
   pub experimental_api: bool,
   pub render_package_aliases: bool,
+  pub allowed_source_hosts: Vec<String>,
+  pub checksum_manifest: bool,
+  pub notice_file: bool,
+  pub defs_bzl: bool,
+  pub bzlmod: bool,
+  pub source_auth: HashMap<String, SourceAuth>,
+  /// Reuse the BUILD files rendered for unchanged crates by previous runs from this cache
+  pub crate_cache: Option<CrateCache>,
+}
+
+impl RenderDetails {
+  /// Render details for the `settings` of the cargo workspace at `cargo_root`, whose outputs are
+  /// placed relative to the Bazel workspace at `bazel_root`.
+  pub fn new(settings: &RazeSettings, cargo_root: PathBuf, bazel_root: PathBuf) -> Self {
+    Self {
+      cargo_root,
+      path_prefix: PathBuf::from(&settings.workspace_path.trim_start_matches('/')),
+      package_aliases_dir: settings.package_aliases_dir.clone(),
+      vendored_buildfile_name: settings.output_buildfile_suffix.clone(),
+      bazel_root,
+      rust_rules_workspace_name: settings.rust_rules_workspace_name.clone(),
+      rust_rules_bzl: settings.rust_rules_bzl.clone(),
+      cargo_build_script_bzl: settings.cargo_build_script_bzl.clone(),
+      rule_names: settings.rule_names.clone(),
+      rules_rust_version: settings.rules_rust_version.clone(),
+      experimental_api: settings.experimental_api,
+      render_package_aliases: settings.render_package_aliases,
+      allowed_source_hosts: settings.allowed_source_hosts.clone(),
+      checksum_manifest: settings.checksum_manifest,
+      notice_file: settings.notice_file,
+      defs_bzl: settings.defs_bzl,
+      bzlmod: settings.bzlmod,
+      source_auth: settings.source_auth.clone(),
+      crate_cache: None,
+    }
+  }
+}
+
+impl RenderDetails {
+  /// The label the `rust_*` rules are loaded from
+  pub fn rust_rules_bzl_label(&self) -> String {
+    self
+      .rust_rules_bzl
+      .clone()
+      .unwrap_or_else(|| format!("@{}//rust:rust.bzl", self.rust_rules_workspace_name))
+  }
+
+  /// The label `cargo_build_script` is loaded from
+  pub fn cargo_build_script_bzl_label(&self) -> String {
+    self.cargo_build_script_bzl.clone().unwrap_or_else(|| {
+      format!(
+        "@{}//cargo:cargo_build_script.bzl",
+        self.rust_rules_workspace_name
+      )
+    })
+  }
+
+  /// Whether `rust_test` and `rust_doc_test` are passed the lib they test as their `crate`, as
+  /// with `rules_rust` 0.3.0 and later, rather than compiling its sources or taking it as `dep`
+  pub fn uses_crate_attr(&self) -> bool {
+    match &self.rules_rust_version {
+      Some(version) => *version >= Version::new(0, 3, 0),
+      None => false,
+    }
+  }
+
+  /// The name each rule of `rules_rust` is loaded and called by in the generated files
+  pub fn resolved_rule_names(&self) -> BTreeMap<String, String> {
+    RUST_RULES
+      .iter()
+      .map(|rule| {
+        let name = self.rule_names.get(*rule).map_or(*rule, String::as_str);
+        (rule.to_string(), name.to_owned())
+      })
+      .collect()
+  }
+}
+
+/// Renders `planned_build` in the `genmode` of `settings` without writing anything to disk,
+/// returning the contents of each file keyed by the path it belongs at.
+pub fn render_files(
+  settings: &RazeSettings,
+  render_details: &RenderDetails,
+  planned_build: &PlannedBuild,
+) -> Result<BTreeMap<PathBuf, String>> {
+  let mut bazel_renderer = BazelRenderer::new();
+  if let Some(templates_dir) = &settings.templates_dir {
+    bazel_renderer.add_templates_dir(&render_details.cargo_root.join(templates_dir))?;
+  }
+  let file_outputs = match &settings.genmode {
+    GenMode::Vendored => bazel_renderer.render_planned_build(render_details, planned_build)?,
+    GenMode::Remote => bazel_renderer.render_remote_planned_build(render_details, planned_build)?,
+    // There are no file outputs to produce if `genmode` is Unspecified
+    GenMode::Unspecified => Vec::new(),
+  };
+
+  Ok(
+    file_outputs
+      .into_iter()
+      .map(|output| (output.path, output.contents))
+      .collect(),
+  )
 }
@@ -13,17 +13,28 @@
 // limitations under the License.
 
 use anyhow::Result;
+use itertools::Itertools;
 use pathdiff::diff_paths;
 use tera::{self, Context, Tera};
 
 use crate::{
   context::{CrateContext, WorkspaceContext},
   error::RazeError,
+  incremental::CacheKey,
   planning::PlannedBuild,
   rendering::{BuildRenderer, FileOutputs, RenderDetails},
+  settings::GenMode,
+  util::{package_ident, sanitize_version},
 };
 
-use std::{error::Error, path::Path};
+use std::{
+  collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap},
+  error::Error,
+  fs,
+  hash::Hasher,
+  path::Path,
+};
+use url::Url;
 
 macro_rules! unwind_tera_error {
   ($err:ident) => {{
@@ -51,6 +62,8 @@ exports_files(
 #[derive(Default)]
 pub struct BazelRenderer {
   internal_renderer: Tera,
+  // A hash of the sources of the templates, which is part of the crate cache keys of BUILD files
+  templates_digest: u64,
 }
 
 /// Generate the expected Bazel package name
@@ -66,70 +79,192 @@ impl BazelRenderer {
   pub fn new() -> Self {
     // Configure tera with a bogus template dir: We don't want any runtime template support
     let mut internal_renderer = Tera::new("/tmp/cargo-raze/doesnt/exist/*").unwrap();
-    internal_renderer
-      .add_raw_templates(vec![
-        (
-          "templates/crate.BUILD.template",
-          include_str!("templates/crate.BUILD.template"),
-        ),
-        (
-          "templates/partials/build_script.template",
-          include_str!("templates/partials/build_script.template"),
-        ),
-        (
-          "templates/partials/common_attrs.template",
-          include_str!("templates/partials/common_attrs.template"),
-        ),
-        (
-          "templates/partials/crates_macro.template",
-          include_str!("templates/partials/crates_macro.template"),
-        ),
-        (
-          "templates/partials/header.template",
-          include_str!("templates/partials/header.template"),
-        ),
-        (
-          "templates/partials/remote_crates_patch.template",
-          include_str!("templates/partials/remote_crates_patch.template"),
-        ),
-        (
-          "templates/partials/rust_binary.template",
-          include_str!("templates/partials/rust_binary.template"),
-        ),
-        (
-          "templates/partials/rust_library.template",
-          include_str!("templates/partials/rust_library.template"),
-        ),
-        (
-          "templates/partials/targeted_dependencies.template",
-          include_str!("templates/partials/targeted_dependencies.template"),
-        ),
-        (
-          "templates/remote_crates.bzl.template",
-          include_str!("templates/remote_crates.bzl.template"),
-        ),
-        (
-          "templates/workspace.BUILD.template",
-          include_str!("templates/workspace.BUILD.template"),
-        ),
-      ])
-      .unwrap();
+    let templates = vec![
+      (
+        "templates/crate.BUILD.template",
+        include_str!("templates/crate.BUILD.template"),
+      ),
+      (
+        "templates/crates.MODULE.bazel.template",
+        include_str!("templates/crates.MODULE.bazel.template"),
+      ),
+      (
+        "templates/defs.bzl.template",
+        include_str!("templates/defs.bzl.template"),
+      ),
+      (
+        "templates/extensions.bzl.template",
+        include_str!("templates/extensions.bzl.template"),
+      ),
+      (
+        "templates/partials/build_script.template",
+        include_str!("templates/partials/build_script.template"),
+      ),
+      (
+        "templates/partials/common_attrs.template",
+        include_str!("templates/partials/common_attrs.template"),
+      ),
+      (
+        "templates/partials/crates_macro.template",
+        include_str!("templates/partials/crates_macro.template"),
+      ),
+      (
+        "templates/partials/dependencies.template",
+        include_str!("templates/partials/dependencies.template"),
+      ),
+      (
+        "templates/partials/header.template",
+        include_str!("templates/partials/header.template"),
+      ),
+      (
+        "templates/partials/remote_crates_auth.template",
+        include_str!("templates/partials/remote_crates_auth.template"),
+      ),
+      (
+        "templates/partials/remote_crates_patch.template",
+        include_str!("templates/partials/remote_crates_patch.template"),
+      ),
+      (
+        "templates/partials/remote_crates_repository.template",
+        include_str!("templates/partials/remote_crates_repository.template"),
+      ),
+      (
+        "templates/partials/rust_binary.template",
+        include_str!("templates/partials/rust_binary.template"),
+      ),
+      (
+        "templates/partials/rust_doc_test.template",
+        include_str!("templates/partials/rust_doc_test.template"),
+      ),
+      (
+        "templates/partials/rust_library.template",
+        include_str!("templates/partials/rust_library.template"),
+      ),
+      (
+        "templates/partials/rust_test.template",
+        include_str!("templates/partials/rust_test.template"),
+      ),
+      (
+        "templates/partials/targeted_aliases.template",
+        include_str!("templates/partials/targeted_aliases.template"),
+      ),
+      (
+        "templates/partials/targeted_conditions.template",
+        include_str!("templates/partials/targeted_conditions.template"),
+      ),
+      (
+        "templates/partials/targeted_dependencies.template",
+        include_str!("templates/partials/targeted_dependencies.template"),
+      ),
+      (
+        "templates/partials/targeted_proc_macro_dependencies.template",
+        include_str!("templates/partials/targeted_proc_macro_dependencies.template"),
+      ),
+      (
+        "templates/partials/targeted_env.template",
+        include_str!("templates/partials/targeted_env.template"),
+      ),
+      (
+        "templates/partials/targeted_features.template",
+        include_str!("templates/partials/targeted_features.template"),
+      ),
+      (
+        "templates/remote_crates.bzl.template",
+        include_str!("templates/remote_crates.bzl.template"),
+      ),
+      (
+        "templates/workspace.BUILD.template",
+        include_str!("templates/workspace.BUILD.template"),
+      ),
+    ];
+    let mut hasher = DefaultHasher::new();
+    for (name, source) in templates.iter() {
+      hasher.write(name.as_bytes());
+      hasher.write(source.as_bytes());
+    }
+    internal_renderer.add_raw_templates(templates).unwrap();
+    internal_renderer.register_filter("starlark_escape", starlark_escape_filter);
+    internal_renderer.register_filter("url_host", url_host_filter);
+    internal_renderer.register_filter("sanitize_version", sanitize_version_filter);
 
     Self {
       internal_renderer,
+      templates_digest: hasher.finish(),
+    }
+  }
+
+  /// Registers the `.template` files below `templates_dir` under their path relative to it, eg:
+  /// `crate.BUILD.template` or `partials/common_attrs.template`. These replace the built-in
+  /// templates of the same path, and any of them may be included by the others.
+  pub fn add_templates_dir(&mut self, templates_dir: &Path) -> Result<()> {
+    if !templates_dir.is_dir() {
+      return Err(
+        RazeError::Config {
+          field_path_opt: Some("raze.templates_dir".to_owned()),
+          message: format!("{} is not a directory", templates_dir.display()),
+        }
+        .into(),
+      );
     }
+
+    let pattern = format!("{}/**/*.template", templates_dir.display());
+    let mut template_files = Vec::new();
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u64(self.templates_digest);
+    for entry in glob::glob(&pattern)? {
+      let path = entry?;
+      // UNWRAP: Globbed paths are always below `templates_dir`
+      let relative_path = path.strip_prefix(templates_dir).unwrap();
+      let name = format!(
+        "templates/{}",
+        relative_path.display().to_string().replace("\\", "/")
+      );
+      hasher.write(name.as_bytes());
+      hasher.write(&fs::read(&path)?);
+      template_files.push((path, Some(name)));
+    }
+    self.templates_digest = hasher.finish();
+
+    self
+      .internal_renderer
+      .add_template_files(template_files)
+      .map_err(|e| {
+        RazeError::Rendering {
+          crate_name_opt: None,
+          message: unwind_tera_error!(e),
+        }
+        .into()
+      })
   }
 
   pub fn render_crate(
     &self,
     workspace_context: &WorkspaceContext,
     package: &CrateContext,
-    rust_rules_workspace_name: &str,
+    render_details: &RenderDetails,
+    defs_bzl_label: Option<&str>,
   ) -> Result<String, tera::Error> {
     let mut context = Context::new();
     context.insert("workspace", &workspace_context);
     context.insert("crate", &package);
-    context.insert("rust_rules_workspace_name", rust_rules_workspace_name);
+    context.insert(
+      "rust_rules_workspace_name",
+      &render_details.rust_rules_workspace_name,
+    );
+    context.insert("rule_names", &render_details.resolved_rule_names());
+    context.insert("use_crate_attr", &render_details.uses_crate_attr());
+    context.insert(
+      "loads",
+      &crate_loads(package, render_details, defs_bzl_label),
+    );
+    context.insert(
+      "license_labels",
+      &license_labels(
+        workspace_context,
+        package,
+        /*is_remote_genmode=*/ false,
+      ),
+    );
     self
       .internal_renderer
       .render("templates/crate.BUILD.template", &context)
@@ -153,12 +288,26 @@ impl BazelRenderer {
     &self,
     workspace_context: &WorkspaceContext,
     package: &CrateContext,
-    rust_rules_workspace_name: &str,
+    render_details: &RenderDetails,
+    defs_bzl_label: Option<&str>,
   ) -> Result<String, tera::Error> {
     let mut context = Context::new();
     context.insert("workspace", &workspace_context);
     context.insert("crate", &package);
-    context.insert("rust_rules_workspace_name", rust_rules_workspace_name);
+    context.insert(
+      "rust_rules_workspace_name",
+      &render_details.rust_rules_workspace_name,
+    );
+    context.insert("rule_names", &render_details.resolved_rule_names());
+    context.insert("use_crate_attr", &render_details.uses_crate_attr());
+    context.insert(
+      "loads",
+      &crate_loads(package, render_details, defs_bzl_label),
+    );
+    context.insert(
+      "license_labels",
+      &license_labels(workspace_context, package, /*is_remote_genmode=*/ true),
+    );
     self
       .internal_renderer
       .render("templates/crate.BUILD.template", &context)
@@ -182,21 +331,64 @@ impl BazelRenderer {
     &self,
     workspace_context: &WorkspaceContext,
     all_packages: &[CrateContext],
-    bazel_package_name: &str,
+    render_details: &RenderDetails,
     is_remote_genmode: bool,
-    experimental_api: bool,
+    inline_build_files: &BTreeMap<String, String>,
   ) -> Result<String, tera::Error> {
-    let mut context = Context::new();
-    context.insert("workspace", &workspace_context);
-    context.insert("crates", &all_packages);
-    context.insert("bazel_package_name", &bazel_package_name);
+    let mut context = crates_bzl_context(
+      workspace_context,
+      all_packages,
+      render_details,
+      inline_build_files,
+    );
     context.insert("is_remote_genmode", &is_remote_genmode);
-    context.insert("experimental_api", &experimental_api);
+    context.insert("experimental_api", &render_details.experimental_api);
     self
       .internal_renderer
       .render("templates/remote_crates.bzl.template", &context)
   }
 
+  /// Renders the `extensions.bzl` module extension declaring the repositories of the remote
+  /// crates, and the `crates.MODULE.bazel` snippet bringing them into the scope of the root module.
+  fn render_module_extension(
+    &self,
+    path_prefix: &Path,
+    workspace_context: &WorkspaceContext,
+    all_packages: &[CrateContext],
+    render_details: &RenderDetails,
+    inline_build_files: &BTreeMap<String, String>,
+  ) -> Result<Vec<FileOutputs>> {
+    let mut context = crates_bzl_context(
+      workspace_context,
+      all_packages,
+      render_details,
+      inline_build_files,
+    );
+    context.insert("is_module_extension", &true);
+
+    let mut file_outputs = Vec::new();
+    for (template, file_name) in &[
+      ("templates/extensions.bzl.template", "extensions.bzl"),
+      (
+        "templates/crates.MODULE.bazel.template",
+        "crates.MODULE.bazel",
+      ),
+    ] {
+      let contents = self
+        .internal_renderer
+        .render(template, &context)
+        .map_err(|e| RazeError::Rendering {
+          crate_name_opt: None,
+          message: unwind_tera_error!(e),
+        })?;
+      file_outputs.push(FileOutputs {
+        path: path_prefix.join(file_name),
+        contents,
+      });
+    }
+    Ok(file_outputs)
+  }
+
   pub fn render_aliases(
     &self,
     planned_build: &PlannedBuild,
@@ -212,6 +404,7 @@ impl BazelRenderer {
           ctx.is_binary_dependency
             || ctx.workspace_member_dependents.contains(member_path)
             || ctx.workspace_member_dev_dependents.contains(member_path)
+            || ctx.workspace_member_build_dependents.contains(member_path)
         })
         .cloned()
         .collect();
@@ -283,6 +476,205 @@ impl BazelRenderer {
       contents,
     }))
   }
+
+  /// Renders the `defs.bzl` file of the output directory, which re-exports every rule and macro
+  /// the generated BUILD files load.
+  fn render_defs_bzl_file(
+    &self,
+    path_prefix: &Path,
+    render_details: &RenderDetails,
+  ) -> Result<FileOutputs> {
+    let mut context = Context::new();
+    context.insert(
+      "rust_rules_workspace_name",
+      &render_details.rust_rules_workspace_name,
+    );
+    context.insert("rust_rules_bzl", &render_details.rust_rules_bzl_label());
+    context.insert(
+      "cargo_build_script_bzl",
+      &render_details.cargo_build_script_bzl_label(),
+    );
+    context.insert("rule_names", &render_details.resolved_rule_names());
+    let contents = self
+      .internal_renderer
+      .render("templates/defs.bzl.template", &context)
+      .map_err(|e| RazeError::Rendering {
+        crate_name_opt: None,
+        message: unwind_tera_error!(e),
+      })?;
+
+    Ok(FileOutputs {
+      path: path_prefix.join("defs.bzl"),
+      contents,
+    })
+  }
+
+  /// Renders the BUILD file of `package`, reusing the file rendered by a previous run from the
+  /// crate cache of `render_details`, if any, when nothing it is rendered from changed.
+  fn render_crate_cached(
+    &self,
+    render_details: &RenderDetails,
+    workspace_context: &WorkspaceContext,
+    package: &CrateContext,
+    defs_bzl_label: Option<&str>,
+    is_remote_genmode: bool,
+  ) -> Result<String> {
+    let render = || -> Result<String> {
+      let rendered = match is_remote_genmode {
+        true => {
+          self.render_remote_crate(workspace_context, package, render_details, defs_bzl_label)
+        },
+        false => self.render_crate(workspace_context, package, render_details, defs_bzl_label),
+      };
+      Ok(rendered.map_err(|e| RazeError::Rendering {
+        crate_name_opt: Some(package.pkg_name.to_owned()),
+        message: unwind_tera_error!(e),
+      })?)
+    };
+
+    let crate_cache = match &render_details.crate_cache {
+      Some(crate_cache) => crate_cache,
+      None => return render(),
+    };
+    let mut key = CacheKey::new();
+    key
+      .add(&self.templates_digest)?
+      .add(workspace_context)?
+      .add(package)?
+      .add(&render_details.rust_rules_workspace_name)?
+      .add(&render_details.rust_rules_bzl_label())?
+      .add(&render_details.cargo_build_script_bzl_label())?
+      .add(&render_details.resolved_rule_names())?
+      .add(&render_details.uses_crate_attr())?
+      .add(&defs_bzl_label)?
+      .add(&is_remote_genmode)?;
+    if let Some(contents) = crate_cache.load("build_file", &key) {
+      return Ok(contents);
+    }
+
+    let contents = render()?;
+    crate_cache.store("build_file", &key, &contents);
+    Ok(contents)
+  }
+
+  /// Renders the BUILD file of each workspace member into its directory of the cargo workspace.
+  fn render_workspace_members(
+    &self,
+    render_details: &RenderDetails,
+    planned_build: &PlannedBuild,
+  ) -> Result<Vec<FileOutputs>> {
+    // Members belong to the main repository, from which `defs.bzl` is loaded
+    let defs_bzl_label = render_details.defs_bzl.then(|| {
+      defs_bzl_label(
+        &planned_build.workspace_context,
+        /*is_remote_genmode=*/ false,
+      )
+    });
+
+    let mut file_outputs = Vec::new();
+    for package in planned_build.workspace_member_contexts.iter() {
+      let rendered_crate_build_file = self
+        .render_crate(
+          &planned_build.workspace_context,
+          package,
+          render_details,
+          defs_bzl_label.as_deref(),
+        )
+        .map_err(|e| RazeError::Rendering {
+          crate_name_opt: Some(package.pkg_name.to_owned()),
+          message: unwind_tera_error!(e),
+        })?;
+
+      file_outputs.push(FileOutputs {
+        path: render_details.cargo_root.join(&package.expected_build_path),
+        contents: include_additional_build_file(package, rendered_crate_build_file)?,
+      });
+    }
+    Ok(file_outputs)
+  }
+
+  /// Appends an `all_tests` test suite to the `BUILD.bazel` file of the output directory,
+  /// creating the file if it has not been rendered.
+  fn render_test_suite(
+    &self,
+    path_prefix: &Path,
+    crate_contexts: &[CrateContext],
+    file_outputs: &mut Vec<FileOutputs>,
+  ) -> Result<()> {
+    let test_labels = generated_test_labels(crate_contexts);
+    if test_labels.is_empty() {
+      return Ok(());
+    }
+
+    let mut test_suite = String::from(concat!(
+      "\n# All tests generated by cargo-raze\n",
+      "test_suite(\n",
+      "    name = \"all_tests\",\n",
+      "    tests = [\n",
+    ));
+    for label in test_labels {
+      test_suite += &format!("        \"{}\",\n", label);
+    }
+    test_suite += "    ],\n)\n";
+
+    let output_pkg_file = path_prefix.join("BUILD.bazel");
+    match file_outputs
+      .iter_mut()
+      .find(|output| output.path == output_pkg_file)
+    {
+      Some(output) => output.contents += &test_suite,
+      None => {
+        let mut contents = self
+          .internal_renderer
+          .render("templates/partials/header.template", &tera::Context::new())?;
+        contents += &test_suite;
+        file_outputs.push(FileOutputs {
+          path: output_pkg_file,
+          contents,
+        });
+      },
+    }
+    Ok(())
+  }
+}
+
+/// Lists the labels of all `rust_test` and `rust_doc_test` rules rendered for the given crates.
+fn generated_test_labels(crate_contexts: &[CrateContext]) -> Vec<String> {
+  let mut labels = Vec::new();
+  for package in crate_contexts {
+    let settings = &package.raze_settings;
+    let gen_tests = settings.gen_tests || settings.test_shard_count.is_some();
+    let gen_doc_tests = settings.gen_doc_tests.unwrap_or(settings.gen_tests);
+    if !gen_tests && !gen_doc_tests {
+      continue;
+    }
+    for target in &package.targets {
+      let test_names: &[&str] = match target.kind.as_str() {
+        // Unit tests are only generated on request, not for `test_shard_count` alone
+        "lib" => match (
+          target.test && settings.gen_tests,
+          target.doctest && gen_doc_tests,
+        ) {
+          (true, true) => &["cargo_unit_test", "cargo_doc_test"],
+          (true, false) => &["cargo_unit_test"],
+          (false, true) => &["cargo_doc_test"],
+          (false, false) => &[],
+        },
+        "test" if gen_tests => &["cargo_test"],
+        _ => continue,
+      };
+      for test_name in test_names {
+        labels.push(format!(
+          "{}:{}_{}",
+          package.workspace_path_to_crate,
+          test_name,
+          target.name.replace('-', "_")
+        ));
+      }
+    }
+  }
+  labels.sort();
+  labels
 }
 
 fn include_additional_build_file(
@@ -323,6 +715,394 @@ fn include_additional_build_file(
   }
 }
 
+/// Yields the url a crate will be fetched from
+fn source_url(package: &CrateContext) -> &str {
+  match &package.source_details.git_data {
+    Some(git_data) => &git_data.remote,
+    None => &package.registry_url,
+  }
+}
+
+/// Ensures the url a crate will be fetched from points at one of the allowed hosts
+fn check_source_host_allowed(package: &CrateContext, allowed_hosts: &[String]) -> Result<()> {
+  // Path dependencies are not fetched from any host
+  if allowed_hosts.is_empty() || package.source_details.local_path.is_some() {
+    return Ok(());
+  }
+
+  let source_url = source_url(package);
+
+  let source_host = Url::parse(source_url)
+    .ok()
+    .and_then(|url| url.host_str().map(str::to_owned));
+  let is_allowed = match source_host {
+    Some(host) => allowed_hosts.iter().any(|allowed| allowed == &host),
+    None => false,
+  };
+
+  if !is_allowed {
+    return Err(
+      RazeError::Rendering {
+        crate_name_opt: Some(package.pkg_name.to_owned()),
+        message: format!(
+          "The source url '{}' does not point to any of the allowed source hosts: {:?}",
+          source_url, allowed_hosts
+        ),
+      }
+      .into(),
+    );
+  }
+
+  Ok(())
+}
+
+/// Renders a manifest listing the version, sha256 and source url of every planned crate, for
+/// auditing the exact artifacts a build depends on.
+fn render_checksum_manifest(crate_contexts: &[CrateContext]) -> String {
+  let mut contents = String::from("# crate version sha256 source-url\n");
+  for package in crate_contexts
+    .iter()
+    .sorted_by_key(|package| (&package.pkg_name, &package.pkg_version))
+  {
+    contents += &format!(
+      "{} {} {} {}\n",
+      package.pkg_name,
+      package.pkg_version,
+      package.sha256.as_deref().unwrap_or("-"),
+      source_url(package)
+    );
+  }
+  contents
+}
+
+/// Renders a `NOTICE` file aggregating the license text of every planned crate shipping one, for
+/// attribution when distributing the build's outputs.
+fn render_notice(crate_contexts: &[CrateContext]) -> String {
+  let mut contents = String::from(
+    "This product includes the following third-party crates, along with their licenses.\n",
+  );
+  for package in crate_contexts
+    .iter()
+    .sorted_by_key(|package| (&package.pkg_name, &package.pkg_version))
+  {
+    if let Some(license_text) = &package.license_text {
+      contents += &format!(
+        "\n{rule}\n{} {}\n{rule}\n\n{}\n",
+        package.pkg_name,
+        package.pkg_version,
+        license_text,
+        rule = "=".repeat(80)
+      );
+    }
+  }
+  contents
+}
+
+/// Renders the `licenses/BUILD.bazel` file holding a `license` rule, with the `rules_license`
+/// kinds of the SPDX identifiers in its license expression, and a `package_info` rule for every
+/// planned crate.
+fn render_license_rules(crate_contexts: &[CrateContext]) -> String {
+  let mut contents = String::from(concat!(
+    "\"\"\"\n",
+    "@generated\n",
+    "cargo-raze generated license rules.\n",
+    "\n",
+    "DO NOT EDIT! Replaced on runs of cargo-raze\n",
+    "\"\"\"\n",
+    "\n",
+    "load(\"@rules_license//rules:license.bzl\", \"license\")\n",
+    "load(\"@rules_license//rules:package_info.bzl\", \"package_info\")\n",
+    "\n",
+    "package(default_visibility = [\"//visibility:public\"])\n",
+  ));
+  for package in crate_contexts
+    .iter()
+    .sorted_by_key(|package| (&package.pkg_name, &package.pkg_version))
+  {
+    let (name, package_info_name) = license_rule_names(package);
+    contents += &format!(
+      "\n# {}: {}\nlicense(\n    name = \"{}\",\n    license_kinds = [",
+      name, package.license.name, name
+    );
+    for spdx_id in package.license.spdx_ids.iter() {
+      contents += &format!("\n        \"@rules_license//licenses/spdx:{}\",", spdx_id);
+    }
+    if !package.license.spdx_ids.is_empty() {
+      contents += "\n    ";
+    }
+    contents += "],\n)\n";
+
+    contents += &format!(
+      "\npackage_info(\n    name = \"{}\",\n    package_name = \"{}\",\n",
+      package_info_name, package.pkg_name
+    );
+    let package_url = source_url(package);
+    if package.source_details.local_path.is_none() && !package_url.is_empty() {
+      contents += &format!("    package_url = \"{}\",\n", starlark_escape(package_url));
+    }
+    contents += &format!("    package_version = \"{}\",\n)\n", package.pkg_version);
+  }
+  contents
+}
+
+/// Escapes a value for use within a double quoted Starlark string.
+fn starlark_escape(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '\\' => escaped.push_str("\\\\"),
+      '"' => escaped.push_str("\\\""),
+      '\n' => escaped.push_str("\\n"),
+      '\r' => escaped.push_str("\\r"),
+      '\t' => escaped.push_str("\\t"),
+      c if c.is_control() => escaped.push_str(&format!("\\{:03o}", c as u32)),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+/// Escapes a value for use within a `'''` quoted Starlark string, leaving line breaks intact.
+fn starlark_escape_triple_quoted(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+/// A Tera filter escaping strings for use within double quoted Starlark strings.
+fn starlark_escape_filter(
+  value: &tera::Value,
+  _args: &HashMap<String, tera::Value>,
+) -> tera::Result<tera::Value> {
+  let value = tera::try_get_value!("starlark_escape", "value", String, value);
+  Ok(tera::Value::String(starlark_escape(&value)))
+}
+
+/// A Tera filter yielding the host of a url, or an empty string if it has none.
+fn url_host_filter(
+  value: &tera::Value,
+  _args: &HashMap<String, tera::Value>,
+) -> tera::Result<tera::Value> {
+  let value = tera::try_get_value!("url_host", "value", String, value);
+  let host = Url::parse(&value)
+    .ok()
+    .and_then(|url| url.host_str().map(str::to_owned))
+    .unwrap_or_default();
+  Ok(tera::Value::String(host))
+}
+
+/// A Tera filter sanitizing a crate version for use within Bazel repository names.
+fn sanitize_version_filter(
+  value: &tera::Value,
+  _args: &HashMap<String, tera::Value>,
+) -> tera::Result<tera::Value> {
+  let value = tera::try_get_value!("sanitize_version", "value", String, value);
+  Ok(tera::Value::String(sanitize_version(&value)))
+}
+
+/// The context shared by the files declaring the repositories of remote crates
+fn crates_bzl_context(
+  workspace_context: &WorkspaceContext,
+  all_packages: &[CrateContext],
+  render_details: &RenderDetails,
+  inline_build_files: &BTreeMap<String, String>,
+) -> Context {
+  // Everything emitted into `crates.bzl` is sorted so that the file is byte-stable regardless of
+  // the order in which crates and workspace members were planned
+  let mut workspace_context = workspace_context.clone();
+  workspace_context.workspace_members.sort();
+  workspace_context.workspace_members.dedup();
+  let mut all_packages: Vec<&CrateContext> = all_packages.iter().collect();
+  all_packages.sort_by(|a, b| (&a.pkg_name, &a.pkg_version).cmp(&(&b.pkg_name, &b.pkg_version)));
+
+  let mut context = Context::new();
+  context.insert("workspace", &workspace_context);
+  context.insert("crates", &all_packages);
+  context.insert("bazel_package_name", &bazel_package_name(render_details));
+  context.insert("inline_build_files", inline_build_files);
+  context.insert("source_auth", &render_details.source_auth);
+  context.insert("shared_archives", &shared_archives(&all_packages));
+  context
+}
+
+/// Maps the crates downloaded as an archive which is byte-identical (by checksum) to that of an
+/// earlier crate in `all_packages` to the details of that crate's archive. Both repositories then
+/// fetch the same url and checksum, which Bazel's repository cache only downloads once.
+fn shared_archives(
+  all_packages: &[&CrateContext],
+) -> BTreeMap<String, BTreeMap<&'static str, String>> {
+  let mut archive_owners: HashMap<&str, &CrateContext> = HashMap::new();
+  let mut shared_archives = BTreeMap::new();
+  for package in all_packages.iter().filter(|package| {
+    package.source_details.git_data.is_none()
+      && package.source_details.local_path.is_none()
+      && package.raze_settings.gen_mode != Some(GenMode::Vendored)
+  }) {
+    let sha256 = match &package.sha256 {
+      Some(sha256) => sha256.as_str(),
+      None => continue,
+    };
+    let owner = match archive_owners.get(sha256) {
+      Some(owner) => owner,
+      None => {
+        archive_owners.insert(sha256, package);
+        continue;
+      },
+    };
+
+    let owner_ident = format!("{}-{}", owner.pkg_name, owner.pkg_version);
+    let mut shared_archive = BTreeMap::new();
+    shared_archive.insert("url", owner.registry_url.clone());
+    if !owner.raze_settings.archive_has_no_prefix {
+      shared_archive.insert("strip_prefix", owner_ident.clone());
+    }
+    shared_archive.insert("crate_ident", owner_ident);
+    shared_archives.insert(
+      format!("{}-{}", package.pkg_name, package.pkg_version),
+      shared_archive,
+    );
+  }
+  shared_archives
+}
+
+/// Collects the symbols a crate's BUILD file needs from each `.bzl` source so that the template
+/// can emit a single, sorted `load` per source at the top of the file. Rules are loaded by the
+/// names of the `rule_names` of `render_details`. When a `defs.bzl` label is given, every symbol is
+/// loaded from it instead.
+fn crate_loads(
+  package: &CrateContext,
+  render_details: &RenderDetails,
+  defs_bzl_label: Option<&str>,
+) -> BTreeMap<String, BTreeSet<String>> {
+  let mut loads: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+  let mut add_load = |source: &str, symbol: &str| {
+    let source = defs_bzl_label.unwrap_or(source);
+    loads
+      .entry(source.to_owned())
+      .or_default()
+      .insert(symbol.to_owned());
+  };
+  let rule_names = render_details.resolved_rule_names();
+  let rust_rules_bzl = render_details.rust_rules_bzl_label();
+
+  add_load("@bazel_skylib//lib:selects.bzl", "selects");
+  for rule in &["rust_binary", "rust_library", "rust_test"] {
+    add_load(&rust_rules_bzl, &rule_names[*rule]);
+  }
+  let settings = &package.raze_settings;
+  if settings.gen_doc_tests.unwrap_or(settings.gen_tests) {
+    add_load(&rust_rules_bzl, &rule_names["rust_doc_test"]);
+  }
+  if package.build_script_target.is_some() {
+    add_load(
+      &render_details.cargo_build_script_bzl_label(),
+      &rule_names["cargo_build_script"],
+    );
+  }
+
+  loads
+}
+
+/// Generates the label of the `defs.bzl` file rendered into the output directory. BUILD files of
+/// remote crates live in external repositories, so their label must name the main repository.
+fn defs_bzl_label(workspace_context: &WorkspaceContext, is_remote_genmode: bool) -> String {
+  let package = workspace_context.workspace_path.trim_end_matches('/');
+  let package = if package.is_empty() { "//" } else { package };
+  let repository = if is_remote_genmode { "@" } else { "" };
+  format!("{}{}:defs.bzl", repository, package)
+}
+
+/// The package of the `rules_license` rules generated for the crates, eg: `//cargo/licenses`.
+fn licenses_package(workspace_context: &WorkspaceContext, is_remote_genmode: bool) -> String {
+  let package = workspace_context.workspace_path.trim_end_matches('/');
+  let package = if package.is_empty() { "/" } else { package };
+  let repository = if is_remote_genmode { "@" } else { "" };
+  format!("{}{}/licenses", repository, package)
+}
+
+/// The names of the `license` and `package_info` rules generated for `package`.
+fn license_rule_names(package: &CrateContext) -> (String, String) {
+  let name = format!("{}-{}", package.pkg_name, package.pkg_version);
+  let package_info_name = format!("{}_package_info", name);
+  (name, package_info_name)
+}
+
+/// The labels of the `rules_license` rules applying to the rules of `package`, if any are
+/// generated. Workspace members are left to declare their own licenses.
+fn license_labels(
+  workspace_context: &WorkspaceContext,
+  package: &CrateContext,
+  is_remote_genmode: bool,
+) -> Vec<String> {
+  if !workspace_context.license_rules || package.is_workspace_member {
+    return Vec::new();
+  }
+  let licenses_package = licenses_package(workspace_context, is_remote_genmode);
+  let (name, package_info_name) = license_rule_names(package);
+  vec![
+    format!("{}:{}", licenses_package, name),
+    format!("{}:{}", licenses_package, package_info_name),
+  ]
+}
+
+/// Makes the `local_path` of path dependencies relative to the Bazel workspace root, as is
+/// conventional for the `path` of a `new_local_repository`, when they are inside of it.
+fn relativize_local_paths(crate_contexts: &[CrateContext], bazel_root: &Path) -> Vec<CrateContext> {
+  crate_contexts
+    .iter()
+    .cloned()
+    .map(|mut package| {
+      if let Some(local_path) = &package.source_details.local_path {
+        if let Ok(relative_path) = local_path.strip_prefix(bazel_root) {
+          package.source_details.local_path = Some(relative_path.to_path_buf());
+        }
+      }
+      package
+    })
+    .collect()
+}
+
+/// Rewrites `data` references of the form `../{name}-{version}/{path}`, which escape the crate's
+/// package, into labels on the sibling crate that owns the file.
+fn resolve_sibling_data_labels(
+  package: &CrateContext,
+  all_packages: &[CrateContext],
+) -> CrateContext {
+  let resolve = |data_dependency: &String| -> String {
+    let relative_path = match data_dependency.strip_prefix("../") {
+      Some(relative_path) => relative_path,
+      None => return data_dependency.clone(),
+    };
+    let (ident, file) = match relative_path.find('/') {
+      Some(idx) => (&relative_path[..idx], &relative_path[idx + 1..]),
+      None => return data_dependency.clone(),
+    };
+
+    let sibling = all_packages
+      .iter()
+      .find(|pkg| package_ident(&pkg.pkg_name, &pkg.pkg_version.to_string()) == ident);
+    match sibling {
+      Some(sibling) if !file.is_empty() => {
+        format!("{}:{}", sibling.workspace_path_to_crate, file)
+      },
+      _ => data_dependency.clone(),
+    }
+  };
+
+  let mut package = package.clone();
+  package.raze_settings.data_dependencies = package
+    .raze_settings
+    .data_dependencies
+    .iter()
+    .map(resolve)
+    .collect();
+  package.raze_settings.build_data_dependencies = package
+    .raze_settings
+    .build_data_dependencies
+    .iter()
+    .map(resolve)
+    .collect();
+  package
+}
+
 impl BuildRenderer for BazelRenderer {
   fn render_planned_build(
     &mut self,
@@ -340,15 +1120,21 @@ impl BuildRenderer for BazelRenderer {
       .as_path()
       .join(&render_details.path_prefix);
 
-    if render_details.experimental_api {
+    // Crates may individually opt into Remote genmode, in which case they still need to be
+    // fetched by the `crates.bzl` file
+    let has_remote_crates = crate_contexts
+      .iter()
+      .any(|package| package.raze_settings.gen_mode == Some(GenMode::Remote));
+
+    if render_details.experimental_api || has_remote_crates {
       let crates_bzl_file_path = path_prefix.as_path().join("crates.bzl");
       let rendered_crates_bzl_file = self
         .render_crates_bzl(
           &workspace_context,
           &crate_contexts,
-          &bazel_package_name(render_details),
-          /*is_remote_genmode=*/ false,
-          render_details.experimental_api,
+          render_details,
+          /*is_remote_genmode=*/ has_remote_crates,
+          &BTreeMap::new(),
         )
         .map_err(|e| RazeError::Rendering {
           crate_name_opt: None,
@@ -358,32 +1144,48 @@ impl BuildRenderer for BazelRenderer {
         path: crates_bzl_file_path,
         contents: rendered_crates_bzl_file,
       });
+    }
 
-      if render_details.render_package_aliases {
-        file_outputs.extend(self.render_aliases(planned_build, render_details, false)?);
-      }
-
-      // Ensure there is always a `BUILD.bazel` file to accompany `crates.bzl`
-      if let Some(rendered_output) =
+    if has_remote_crates {
+      // N.B. File needs to exist so that contained xyz-1.2.3.BUILD can be referenced
+      file_outputs.push(FileOutputs {
+        path: path_prefix
+          .as_path()
+          .join("remote")
+          .join(&render_details.vendored_buildfile_name),
+        contents: String::new(),
+      });
+    }
+
+    if !render_details.experimental_api || render_details.render_package_aliases {
+      file_outputs.extend(self.render_aliases(planned_build, render_details, false)?);
+    }
+
+    if render_details.defs_bzl {
+      file_outputs.push(self.render_defs_bzl_file(&path_prefix, render_details)?);
+    }
+
+    if render_details.experimental_api || has_remote_crates || render_details.defs_bzl {
+      // Ensure there is always a `BUILD.bazel` file to accompany `crates.bzl` and `defs.bzl`
+      if let Some(rendered_output) =
         self.render_crates_bzl_package_file(&path_prefix, &file_outputs)?
       {
         file_outputs.push(rendered_output);
       }
-    } else {
-      file_outputs.extend(self.render_aliases(planned_build, render_details, false)?);
     }
 
+    let defs_bzl_label = render_details.defs_bzl.then(|| {
+      defs_bzl_label(workspace_context, /*is_remote_genmode=*/ false)
+    });
     for package in crate_contexts {
-      let rendered_crate_build_file = self
-        .render_crate(
-          &workspace_context,
-          &package,
-          &render_details.rust_rules_workspace_name,
-        )
-        .map_err(|e| RazeError::Rendering {
-          crate_name_opt: None,
-          message: unwind_tera_error!(e),
-        })?;
+      let package = &resolve_sibling_data_labels(package, crate_contexts);
+      let rendered_crate_build_file = self.render_crate_cached(
+        render_details,
+        workspace_context,
+        package,
+        defs_bzl_label.as_deref(),
+        /*is_remote_genmode=*/ false,
+      )?;
 
       let final_crate_build_file =
         include_additional_build_file(package, rendered_crate_build_file)?;
@@ -394,6 +1196,30 @@ impl BuildRenderer for BazelRenderer {
       })
     }
 
+    self.render_test_suite(&path_prefix, crate_contexts, &mut file_outputs)?;
+    file_outputs.extend(self.render_workspace_members(render_details, planned_build)?);
+
+    if render_details.checksum_manifest {
+      file_outputs.push(FileOutputs {
+        path: path_prefix.as_path().join("checksums.txt"),
+        contents: render_checksum_manifest(crate_contexts),
+      });
+    }
+
+    if render_details.notice_file {
+      file_outputs.push(FileOutputs {
+        path: path_prefix.as_path().join("NOTICE"),
+        contents: render_notice(crate_contexts),
+      });
+    }
+
+    if workspace_context.license_rules {
+      file_outputs.push(FileOutputs {
+        path: path_prefix.as_path().join("licenses/BUILD.bazel"),
+        contents: render_license_rules(crate_contexts),
+      });
+    }
+
     file_outputs.sort();
     Ok(file_outputs)
   }
@@ -422,21 +1248,38 @@ impl BuildRenderer for BazelRenderer {
       contents: String::new(),
     });
 
+    if render_details.defs_bzl {
+      file_outputs.push(self.render_defs_bzl_file(&path_prefix, render_details)?);
+    }
+    let defs_bzl_label = render_details.defs_bzl.then(|| {
+      defs_bzl_label(workspace_context, /*is_remote_genmode=*/ true)
+    });
+
+    // Crate BUILD files inlined into the rules fetching them, keyed by the crate's identifier
+    let mut inline_build_files = BTreeMap::new();
     for package in crate_contexts {
-      let rendered_crate_build_file = self
-        .render_remote_crate(
-          &workspace_context,
-          &package,
-          &render_details.rust_rules_workspace_name,
-        )
-        .map_err(|e| RazeError::Rendering {
-          crate_name_opt: Some(package.pkg_name.to_owned()),
-          message: unwind_tera_error!(e),
-        })?;
+      check_source_host_allowed(package, &render_details.allowed_source_hosts)?;
+      let package = &resolve_sibling_data_labels(package, crate_contexts);
+
+      let rendered_crate_build_file = self.render_crate_cached(
+        render_details,
+        workspace_context,
+        package,
+        defs_bzl_label.as_deref(),
+        /*is_remote_genmode=*/ true,
+      )?;
 
       let final_crate_build_file =
         include_additional_build_file(package, rendered_crate_build_file)?;
 
+      if package.raze_settings.inline_build_file {
+        inline_build_files.insert(
+          package_ident(&package.pkg_name, &package.pkg_version.to_string()),
+          starlark_escape_triple_quoted(&final_crate_build_file),
+        );
+        continue;
+      }
+
       file_outputs.push(FileOutputs {
         path: path_prefix.as_path().join(&package.expected_build_path),
         contents: final_crate_build_file,
@@ -448,13 +1291,14 @@ impl BuildRenderer for BazelRenderer {
     }
 
     let crates_bzl_file_path = path_prefix.as_path().join("crates.bzl");
+    let remote_crate_contexts = relativize_local_paths(crate_contexts, &render_details.bazel_root);
     let rendered_bzl_fetch_file = self
       .render_crates_bzl(
         &workspace_context,
-        &crate_contexts,
-        &bazel_package_name(render_details),
+        &remote_crate_contexts,
+        render_details,
         /*is_remote_genmode=*/ true,
-        render_details.experimental_api,
+        &inline_build_files,
       )
       .map_err(|e| RazeError::Rendering {
         crate_name_opt: None,
@@ -466,6 +1310,16 @@ impl BuildRenderer for BazelRenderer {
       contents: rendered_bzl_fetch_file,
     });
 
+    if render_details.bzlmod {
+      file_outputs.extend(self.render_module_extension(
+        &path_prefix,
+        workspace_context,
+        &remote_crate_contexts,
+        render_details,
+        &inline_build_files,
+      )?);
+    }
+
     // Optionally write out a unique lockfile for Cargo Raze. This happens in the case
     // where a project has specified binary dependencies.
     if let Some(lockfile) = &planned_build.lockfile {
@@ -482,6 +1336,30 @@ impl BuildRenderer for BazelRenderer {
       file_outputs.push(rendered_output);
     }
 
+    self.render_test_suite(&path_prefix, crate_contexts, &mut file_outputs)?;
+    file_outputs.extend(self.render_workspace_members(render_details, planned_build)?);
+
+    if render_details.checksum_manifest {
+      file_outputs.push(FileOutputs {
+        path: path_prefix.as_path().join("checksums.txt"),
+        contents: render_checksum_manifest(crate_contexts),
+      });
+    }
+
+    if render_details.notice_file {
+      file_outputs.push(FileOutputs {
+        path: path_prefix.as_path().join("NOTICE"),
+        contents: render_notice(crate_contexts),
+      });
+    }
+
+    if workspace_context.license_rules {
+      file_outputs.push(FileOutputs {
+        path: path_prefix.as_path().join("licenses/BUILD.bazel"),
+        contents: render_license_rules(crate_contexts),
+      });
+    }
+
     file_outputs.sort();
     Ok(file_outputs)
   }
@@ -498,7 +1376,7 @@ mod tests {
     context::*,
     planning::PlannedBuild,
     rendering::{FileOutputs, RenderDetails},
-    settings::CrateSettings,
+    settings::{CrateSettings, SourceAuth},
     testing::basic_lock_contents,
   };
 
@@ -514,8 +1392,19 @@ mod tests {
       vendored_buildfile_name: buildfile_suffix.to_owned(),
       bazel_root: PathBuf::from("/some/bazel/root"),
       rust_rules_workspace_name: "rules_rust".to_owned(),
+      rust_rules_bzl: None,
+      cargo_build_script_bzl: None,
+      rule_names: HashMap::new(),
+      rules_rust_version: None,
       experimental_api: true,
       render_package_aliases: true,
+      allowed_source_hosts: Vec::new(),
+      checksum_manifest: false,
+      notice_file: false,
+      defs_bzl: false,
+      bzlmod: false,
+      source_auth: HashMap::new(),
+      crate_cache: None,
     }
   }
 
@@ -524,12 +1413,18 @@ mod tests {
       workspace_context: WorkspaceContext {
         workspace_path: "//workspace/prefix".to_owned(),
         gen_workspace_prefix: "".to_owned(),
+        repositories_function_name: "_fetch_remote_crates".to_owned(),
         output_buildfile_suffix: "BUILD".to_owned(),
         // This will typically resolve to:
         // `/some/cargo/root/some/crate`
         workspace_members: vec![PathBuf::from("some/crate")],
+        group_deps: false,
+        proc_macro_tags: Vec::new(),
+        proc_macro_toolchains: Vec::new(),
+        license_rules: false,
       },
       crate_contexts,
+      workspace_member_contexts: Vec::new(),
       lockfile: None,
     }
   }
@@ -540,8 +1435,19 @@ mod tests {
       pkg_version: Version::parse("1.1.1").unwrap(),
       edition: "2015".to_owned(),
       features: vec!["feature1".to_owned(), "feature2".to_owned()].to_owned(),
+      dev_features: vec!["feature1".to_owned(), "feature2".to_owned()],
+      lint_flags: Vec::new(),
+      remap_path_prefix: None,
+      targeted_rustc_flags: BTreeMap::new(),
+      targeted_compile_data: BTreeMap::new(),
+      config_setting_groups: BTreeMap::new(),
+      targeted_rustc_env: BTreeMap::new(),
+      targeted_build_script_env: BTreeMap::new(),
+      targeted_editions: BTreeMap::new(),
+      categories: Vec::new(),
       expected_build_path: format!("vendor/test-binary-1.1.1/{}", buildfile_suffix),
       license: LicenseData::default(),
+      license_text: None,
       raze_settings: CrateSettings::default(),
       canonical_additional_build_file: CrateSettings::default().additional_build_file,
       default_deps: CrateDependencyContext {
@@ -553,28 +1459,40 @@ mod tests {
         build_data_dependencies: Vec::new(),
         dev_dependencies: Vec::new(),
         aliased_dependencies: Vec::new(),
+        build_aliased_dependencies: Vec::new(),
       },
       targeted_deps: Vec::new(),
       workspace_member_dependents: Vec::new(),
       workspace_member_dev_dependents: Vec::new(),
+      workspace_member_build_dependents: Vec::new(),
       is_workspace_member_dependency: false,
       is_binary_dependency: false,
+      is_workspace_member: false,
       is_proc_macro: false,
+      lib_crate_types: Vec::new(),
+      target_compatible_with: Vec::new(),
       workspace_path_to_crate: "@raze__test_binary__1_1_1//".to_owned(),
       targets: vec![BuildableTarget {
         name: "some_binary".to_owned(),
         kind: "bin".to_owned(),
         path: "bin/main.rs".to_owned(),
         edition: "2015".to_owned(),
+        test: true,
+        doctest: true,
       }],
       build_script_target: None,
+      build_script_srcs: Vec::new(),
+      build_script_srcs_exclude: Vec::new(),
       links: None,
       source_details: SourceDetails {
         git_data: None,
+        local_path: None,
       },
       sha256: None,
       registry_url: "https://crates.io/api/v1/crates/test-binary/1.1.1/download".to_string(),
       lib_target_name: None,
+      library_target_name: None,
+      self_alias: None,
     }
   }
 
@@ -588,9 +1506,20 @@ mod tests {
       pkg_version: Version::parse("1.1.1").unwrap(),
       edition: "2015".to_owned(),
       license: LicenseData::default(),
+      license_text: None,
       raze_settings: CrateSettings::default(),
       canonical_additional_build_file: CrateSettings::default().additional_build_file,
       features: vec!["feature1".to_owned(), "feature2".to_owned()].to_owned(),
+      dev_features: vec!["feature1".to_owned(), "feature2".to_owned()],
+      lint_flags: Vec::new(),
+      remap_path_prefix: None,
+      targeted_rustc_flags: BTreeMap::new(),
+      targeted_compile_data: BTreeMap::new(),
+      config_setting_groups: BTreeMap::new(),
+      targeted_rustc_env: BTreeMap::new(),
+      targeted_build_script_env: BTreeMap::new(),
+      targeted_editions: BTreeMap::new(),
+      categories: Vec::new(),
       expected_build_path: format!("vendor/test-library-1.1.1/{}", buildfile_suffix),
       default_deps: CrateDependencyContext {
         dependencies: Vec::new(),
@@ -601,28 +1530,40 @@ mod tests {
         build_data_dependencies: Vec::new(),
         dev_dependencies: Vec::new(),
         aliased_dependencies: Vec::new(),
+        build_aliased_dependencies: Vec::new(),
       },
       targeted_deps: Vec::new(),
       workspace_member_dependents: Vec::new(),
       workspace_member_dev_dependents: Vec::new(),
+      workspace_member_build_dependents: Vec::new(),
       is_workspace_member_dependency: false,
       is_binary_dependency: false,
+      is_workspace_member: false,
       is_proc_macro: false,
+      lib_crate_types: vec!["lib".to_owned()],
+      target_compatible_with: Vec::new(),
       workspace_path_to_crate: "@raze__test_library__1_1_1//".to_owned(),
       targets: vec![BuildableTarget {
         name: "some_library".to_owned(),
         kind: "lib".to_owned(),
         path: "path/lib.rs".to_owned(),
         edition: "2015".to_owned(),
+        test: true,
+        doctest: true,
       }],
       build_script_target: None,
+      build_script_srcs: Vec::new(),
+      build_script_srcs_exclude: Vec::new(),
       links: Some("ssh2".to_owned()),
       source_details: SourceDetails {
         git_data: None,
+        local_path: None,
       },
       sha256: None,
       registry_url: "https://crates.io/api/v1/crates/test-binary/1.1.1/download".to_string(),
       lib_target_name: Some("test_library".to_owned()),
+      library_target_name: None,
+      self_alias: None,
     }
   }
 
@@ -697,6 +1638,61 @@ mod tests {
     );
   }
 
+  #[test]
+  fn user_templates_replace_and_extend_the_built_in_ones() {
+    let templates_dir = TempDir::new().unwrap();
+    fs::create_dir_all(templates_dir.as_ref().join("macros")).unwrap();
+    fs::write(
+      templates_dir.as_ref().join("crate.BUILD.template"),
+      indoc::indoc! { r#"
+        load("//build:rust.bzl", "monorepo_rust_library")
+        {% include "templates/macros/library.template" %}
+      "# },
+    )
+    .unwrap();
+    fs::write(
+      templates_dir.as_ref().join("macros/library.template"),
+      concat!(
+        "monorepo_rust_library(",
+        "name = \"{{ crate.pkg_name }}\", version = \"{{ crate.pkg_version }}\")",
+      ),
+    )
+    .unwrap();
+
+    let mut renderer = BazelRenderer::new();
+    renderer.add_templates_dir(templates_dir.as_ref()).unwrap();
+    let file_outputs = renderer
+      .render_planned_build(
+        &dummy_render_details("BUILD"),
+        &dummy_planned_build(vec![dummy_library_crate()]),
+      )
+      .unwrap();
+
+    assert_eq!(
+      extract_contents_matching_path(
+        &file_outputs,
+        "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD"
+      ),
+      concat!(
+        "load(\"//build:rust.bzl\", \"monorepo_rust_library\")\n",
+        "monorepo_rust_library(name = \"test-library\", version = \"1.1.1\")\n",
+      )
+    );
+    // Templates without a replacement are still the built-in ones
+    let root_build_file = "/some/bazel/root/./some_render_prefix/BUILD.bazel";
+    assert_eq!(
+      extract_contents_matching_path(&file_outputs, root_build_file),
+      extract_contents_matching_path(
+        &render_crates_for_test(vec![dummy_library_crate()]),
+        root_build_file
+      )
+    );
+
+    assert!(BazelRenderer::new()
+      .add_templates_dir(&templates_dir.as_ref().join("missing"))
+      .is_err());
+  }
+
   #[test]
   fn crates_generate_build_files_bazel() {
     let file_outputs = render_crates_for_test_with_name(
@@ -906,8 +1902,13 @@ mod tests {
     planned_build.workspace_context = WorkspaceContext {
       workspace_path: "//cargo".to_owned(),
       gen_workspace_prefix: "raze".to_owned(),
+      repositories_function_name: "raze_fetch_remote_crates".to_owned(),
       output_buildfile_suffix: "BUILD.bazel".to_owned(),
       workspace_members: vec![PathBuf::from("lib_a"), PathBuf::from("lib_b")],
+      group_deps: false,
+      proc_macro_tags: Vec::new(),
+      proc_macro_toolchains: Vec::new(),
+      license_rules: false,
     };
 
     let file_outputs = BazelRenderer::new()
@@ -945,4 +1946,1869 @@ mod tests {
     )
     .unwrap();
   }
+
+  fn dummy_buildable_dependency(name: &str) -> BuildableDependency {
+    BuildableDependency {
+      buildable_target: format!("@raze__{}__1_0_0//:{}", name, name),
+      name: name.to_owned(),
+      version: Version::parse("1.0.0").unwrap(),
+      is_proc_macro: false,
+      is_first_party: false,
+    }
+  }
+
+  fn dummy_targeted_deps(
+    target: &str,
+    conditions: Vec<&str>,
+    dependencies: Vec<&str>,
+  ) -> CrateTargetedDepContext {
+    CrateTargetedDepContext {
+      target: target.to_owned(),
+      deps: CrateDependencyContext {
+        dependencies: dependencies
+          .into_iter()
+          .map(dummy_buildable_dependency)
+          .collect(),
+        proc_macro_dependencies: Vec::new(),
+        data_dependencies: Vec::new(),
+        build_dependencies: Vec::new(),
+        build_proc_macro_dependencies: Vec::new(),
+        build_data_dependencies: Vec::new(),
+        dev_dependencies: Vec::new(),
+        aliased_dependencies: Vec::new(),
+        build_aliased_dependencies: Vec::new(),
+      },
+      conditions: conditions.into_iter().map(str::to_owned).collect(),
+      features: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn targeted_deps_render_deterministically() {
+    let mut library_a = dummy_library_crate();
+    library_a.targeted_deps = vec![
+      dummy_targeted_deps(
+        "cfg(windows)",
+        vec![
+          "@rules_rust//rust/platform:x86_64-pc-windows-msvc",
+          "@rules_rust//rust/platform:i686-pc-windows-msvc",
+        ],
+        vec!["winapi", "kernel32"],
+      ),
+      dummy_targeted_deps(
+        "cfg(unix)",
+        vec![
+          "@rules_rust//rust/platform:x86_64-unknown-linux-gnu",
+          "@rules_rust//rust/platform:x86_64-apple-darwin",
+        ],
+        vec!["libc", "errno"],
+      ),
+    ];
+
+    let mut library_b = dummy_library_crate();
+    library_b.targeted_deps = vec![
+      dummy_targeted_deps(
+        "cfg(unix)",
+        vec![
+          "@rules_rust//rust/platform:x86_64-apple-darwin",
+          "@rules_rust//rust/platform:x86_64-unknown-linux-gnu",
+        ],
+        vec!["errno", "libc"],
+      ),
+      dummy_targeted_deps(
+        "cfg(windows)",
+        vec![
+          "@rules_rust//rust/platform:i686-pc-windows-msvc",
+          "@rules_rust//rust/platform:x86_64-pc-windows-msvc",
+        ],
+        vec!["kernel32", "winapi"],
+      ),
+    ];
+
+    let file_name = "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD";
+    let contents_a =
+      extract_contents_matching_path(&render_crates_for_test(vec![library_a]), file_name);
+    let contents_b =
+      extract_contents_matching_path(&render_crates_for_test(vec![library_b]), file_name);
+
+    assert_eq!(contents_a, contents_b);
+
+    // Branches are ordered by their target and labels are ordered within each branch
+    let unix_idx = contents_a.find("# cfg(unix)").unwrap();
+    let windows_idx = contents_a.find("# cfg(windows)").unwrap();
+    assert!(unix_idx < windows_idx);
+    assert!(
+      contents_a.find("@raze__errno__1_0_0//:errno").unwrap()
+        < contents_a.find("@raze__libc__1_0_0//:libc").unwrap()
+    );
+    assert!(
+      contents_a
+        .find("@rules_rust//rust/platform:x86_64-apple-darwin")
+        .unwrap()
+        < contents_a
+          .find("@rules_rust//rust/platform:x86_64-unknown-linux-gnu")
+          .unwrap()
+    );
+  }
+
+  #[test]
+  fn targeted_features_are_rendered_as_selects() {
+    let alias = DependencyAlias {
+      target: "@raze__libc__1_0_0//:libc".to_owned(),
+      alias: "c".to_owned(),
+    };
+    let mut unix_branch = dummy_targeted_deps(
+      "cfg(unix)",
+      vec!["@rules_rust//rust/platform:x86_64-unknown-linux-gnu"],
+      vec!["libc"],
+    );
+    unix_branch.deps.aliased_dependencies = vec![alias.clone()];
+    let mut windows_branch = dummy_targeted_deps(
+      "cfg(windows)",
+      vec!["@rules_rust//rust/platform:x86_64-pc-windows-msvc"],
+      vec!["libc"],
+    );
+    windows_branch.deps.aliased_dependencies = vec![alias];
+    windows_branch.features = vec!["windows_feature".to_owned()];
+    let mut library = dummy_library_crate();
+    library.targeted_deps = vec![unix_branch, windows_branch];
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    assert!(crate_build_contents.contains(concat!(
+      "    ] + selects.with_or({\n",
+      "        # cfg(windows)\n",
+      "        _CONDITIONS_1: [\n",
+      "            \"windows_feature\",\n",
+      "        ],\n",
+      "        \"//conditions:default\": [],\n",
+      "    }),\n",
+      "    crate_root = \"path/lib.rs\",\n",
+    )));
+    // Only branches with features produce a select, while aliases are gated along with the
+    // dependencies of each branch
+    let crate_features = crate_build_contents
+      .split("crate_features = [")
+      .nth(1)
+      .and_then(|rest| rest.split("crate_root =").next())
+      .unwrap();
+    assert!(!crate_features.contains("# cfg(unix)"));
+    assert!(crate_build_contents.contains(concat!(
+      "    aliases = {\n",
+      "    } | selects.with_or({\n",
+      "        # cfg(unix)\n",
+      "        (\n",
+      "            \"@rules_rust//rust/platform:x86_64-unknown-linux-gnu\",\n",
+      "        ): {\n",
+      "            \"@raze__libc__1_0_0//:libc\": \"c\",\n",
+      "        },\n",
+      "        \"//conditions:default\": {},\n",
+      "    }) | selects.with_or({\n",
+      "        # cfg(windows)\n",
+    )));
+  }
+
+  #[test]
+  fn feature_gated_deps_are_rendered_under_their_config_setting() {
+    let mut fancy_branch = dummy_targeted_deps(
+      "feature = \"fancy\"",
+      vec!["//features:fancy_enabled"],
+      vec!["fancy-dep"],
+    );
+    fancy_branch.features = vec!["fancy".to_owned()];
+    let mut library = dummy_library_crate();
+    library.targeted_deps = vec![fancy_branch];
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    let expected_select = concat!(
+      " + selects.with_or({\n",
+      "        # feature = \"fancy\"\n",
+      "        _CONDITIONS_1: [\n",
+      "            \"{}\",\n",
+      "        ],\n",
+      "        \"//conditions:default\": [],\n",
+      "    }),\n",
+    );
+    assert!(crate_build_contents.contains(&expected_select.replace("{}", "fancy")));
+    assert!(crate_build_contents
+      .contains(&expected_select.replace("{}", "@raze__fancy-dep__1_0_0//:fancy-dep")));
+  }
+
+  #[test]
+  fn conditions_shared_by_deps_and_features_are_hoisted_into_a_variable() {
+    let windows_conditions = vec![
+      "@rules_rust//rust/platform:x86_64-pc-windows-msvc",
+      "@rules_rust//rust/platform:i686-pc-windows-msvc",
+    ];
+    let mut windows_branch =
+      dummy_targeted_deps("cfg(windows)", windows_conditions, vec!["winapi"]);
+    windows_branch.features = vec!["windows_feature".to_owned()];
+    let unix_branch = dummy_targeted_deps(
+      "cfg(unix)",
+      vec!["@rules_rust//rust/platform:x86_64-unknown-linux-gnu"],
+      vec!["libc"],
+    );
+    let mut library = dummy_library_crate();
+    library.targeted_deps = vec![windows_branch, unix_branch];
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert_eq!(crate_build_contents.matches("_CONDITIONS_1 = (").count(), 1);
+    assert!(crate_build_contents.contains(concat!(
+      "])\n",
+      "\n",
+      "# cfg(windows)\n",
+      "_CONDITIONS_1 = (\n",
+      "    \"@rules_rust//rust/platform:i686-pc-windows-msvc\",\n",
+      "    \"@rules_rust//rust/platform:x86_64-pc-windows-msvc\",\n",
+      ")\n",
+      "\n",
+      "# Generated Targets\n",
+    )));
+    // Both the features and the deps of the windows branch refer to the shared conditions
+    assert!(crate_build_contents.contains(concat!(
+      "        # cfg(windows)\n",
+      "        _CONDITIONS_1: [\n",
+      "            \"windows_feature\",\n",
+    )));
+    assert!(crate_build_contents.contains(concat!(
+      "        # cfg(windows)\n",
+      "        _CONDITIONS_1: [\n",
+      "            \"@raze__winapi__1_0_0//:winapi\",\n",
+    )));
+    assert_eq!(
+      crate_build_contents
+        .matches("\"@rules_rust//rust/platform:x86_64-pc-windows-msvc\"")
+        .count(),
+      1
+    );
+    // Conditions only the deps branch on are kept inline
+    assert!(crate_build_contents.contains(concat!(
+      "        # cfg(unix)\n",
+      "        (\n",
+      "            \"@rules_rust//rust/platform:x86_64-unknown-linux-gnu\",\n",
+      "        ): [\n",
+    )));
+    assert!(!crate_build_contents.contains("_CONDITIONS_2"));
+  }
+
+  #[test]
+  fn crates_with_identical_archives_share_a_download() {
+    let mut library = dummy_library_crate();
+    library.sha256 = Some("abc123".to_owned());
+    library.registry_url = "https://crates.io/api/v1/crates/test-library/1.1.1/download".to_owned();
+    let mut republished = library.clone();
+    republished.pkg_name = "republished-library".to_owned();
+    republished.registry_url =
+      "https://crates.io/api/v1/crates/republished-library/1.1.1/download".to_owned();
+    let mut other = dummy_binary_crate();
+    other.sha256 = Some("def456".to_owned());
+
+    let file_outputs = BazelRenderer::new()
+      .render_remote_planned_build(
+        &dummy_render_details("BUILD.bazel"),
+        &dummy_planned_build(vec![library, republished, other]),
+      )
+      .unwrap();
+    let crates_bzl = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/crates.bzl",
+    );
+    let repository = |name: &str| {
+      let name_attr = format!("        name = \"{}\",\n", name);
+      crates_bzl
+        .split("\n\n")
+        .find(|rule| rule.contains(&name_attr))
+        .unwrap_or_else(|| panic!("No repository named {} in {}", name, crates_bzl))
+    };
+
+    // Both repositories fetch the same url and checksum, so the archive is downloaded once
+    let shared_url =
+      "        url = \"https://crates.io/api/v1/crates/republished-library/1.1.1/download\",\n";
+    for name in &["__republished_library__1_1_1", "__test_library__1_1_1"] {
+      let rule = repository(name);
+      assert!(rule.contains(shared_url), "{}", rule);
+      assert!(rule.contains("        sha256 = \"abc123\",\n"), "{}", rule);
+      assert!(rule.contains("        strip_prefix = \"republished-library-1.1.1\",\n"));
+    }
+    // Each still builds with its own BUILD file
+    assert!(repository("__test_library__1_1_1").contains("BUILD.test-library-1.1.1.bazel"));
+    assert!(!repository("__test_binary__1_1_1").contains("identical"));
+  }
+
+  #[test]
+  fn crates_bzl_is_byte_stable_across_input_orders() {
+    let mut library = dummy_library_crate();
+    library.workspace_member_dependents = vec![PathBuf::from("lib_a"), PathBuf::from("lib_b")];
+    let mut old_library = library.clone();
+    old_library.pkg_version = Version::parse("1.0.0").unwrap();
+    let binary = dummy_binary_crate();
+
+    let render_crates_bzl = |crates: Vec<CrateContext>, members: Vec<&str>, hosts: Vec<&str>| {
+      let mut render_details = dummy_render_details("BUILD.bazel");
+      for host in hosts {
+        render_details.source_auth.insert(
+          host.to_owned(),
+          SourceAuth {
+            netrc: Some(format!("/netrc/{}", host)),
+            auth_pattern: None,
+          },
+        );
+      }
+      let mut planned_build = dummy_planned_build(crates);
+      planned_build.workspace_context.workspace_members =
+        members.into_iter().map(PathBuf::from).collect();
+
+      let file_outputs = BazelRenderer::new()
+        .render_remote_planned_build(&render_details, &planned_build)
+        .unwrap();
+      extract_contents_matching_path(
+        &file_outputs,
+        "/some/bazel/root/./some_render_prefix/crates.bzl",
+      )
+    };
+
+    let crates_bzl = render_crates_bzl(
+      vec![library.clone(), binary.clone(), old_library.clone()],
+      vec!["lib_a", "lib_b"],
+      vec!["a.example.com", "crates.io"],
+    );
+    assert_eq!(
+      crates_bzl,
+      render_crates_bzl(
+        vec![old_library, binary, library],
+        vec!["lib_b", "lib_a"],
+        vec!["crates.io", "a.example.com"],
+      )
+    );
+
+    let position = |needle: &str| crates_bzl.find(needle).unwrap();
+    let repo_position = |repo: &str| position(&format!("name = \"{}\"", repo));
+    assert!(repo_position("__test_binary__1_1_1") < repo_position("__test_library__1_0_0"));
+    assert!(repo_position("__test_library__1_0_0") < repo_position("__test_library__1_1_1"));
+    assert!(position("/lib_a\": {") < position("/lib_b\": {"));
+  }
+
+  #[test]
+  fn checksum_manifest_lists_every_crate() {
+    let mut render_details = dummy_render_details("BUILD.bazel");
+    render_details.checksum_manifest = true;
+    let mut library = dummy_library_crate();
+    library.sha256 = Some("abc123".to_owned());
+    library.registry_url = "https://crates.io/api/v1/crates/test-library/1.1.1/download".to_owned();
+
+    let file_outputs = BazelRenderer::new()
+      .render_remote_planned_build(
+        &render_details,
+        &dummy_planned_build(vec![library, dummy_binary_crate()]),
+      )
+      .unwrap();
+    let checksums_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/checksums.txt",
+    );
+
+    assert_eq!(
+      checksums_contents,
+      indoc::indoc! {"
+        # crate version sha256 source-url
+        test-binary 1.1.1 - https://crates.io/api/v1/crates/test-binary/1.1.1/download
+        test-library 1.1.1 abc123 https://crates.io/api/v1/crates/test-library/1.1.1/download
+      "}
+    );
+  }
+
+  #[test]
+  fn defs_bzl_is_rendered_once_and_loaded_by_crates() {
+    let mut render_details = dummy_render_details("BUILD");
+    render_details.defs_bzl = true;
+    let planned_build = dummy_planned_build(vec![dummy_library_crate(), dummy_binary_crate()]);
+
+    let file_outputs = BazelRenderer::new()
+      .render_planned_build(&render_details, &planned_build)
+      .unwrap();
+    let defs_bzl_outputs: Vec<&FileOutputs> = file_outputs
+      .iter()
+      .filter(|output| output.path.ends_with("defs.bzl"))
+      .collect();
+    assert_eq!(defs_bzl_outputs.len(), 1);
+    assert_eq!(
+      defs_bzl_outputs[0].path,
+      PathBuf::from("/some/bazel/root/./some_render_prefix/defs.bzl")
+    );
+    assert!(defs_bzl_outputs[0]
+      .contents
+      .contains("rust_library = _rust_library"));
+
+    for path in &[
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+      "/some/bazel/root/./some_render_prefix/vendor/test-binary-1.1.1/BUILD",
+    ] {
+      let build_contents = extract_contents_matching_path(&file_outputs, path);
+      assert!(build_contents.contains("\"//workspace/prefix:defs.bzl\""));
+      assert!(!build_contents.contains("@rules_rust//rust:rust.bzl"));
+      assert!(!build_contents.contains("@bazel_skylib//lib:selects.bzl"));
+    }
+
+    // Remote BUILD files are loaded from external repositories, so must name the main one
+    let file_outputs = BazelRenderer::new()
+      .render_remote_planned_build(&render_details, &planned_build)
+      .unwrap();
+    assert_eq!(
+      file_outputs
+        .iter()
+        .filter(|output| output.path.ends_with("defs.bzl"))
+        .count(),
+      1
+    );
+    let build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert!(build_contents.contains("\"@//workspace/prefix:defs.bzl\""));
+  }
+
+  #[test]
+  fn rules_rust_loads_and_rule_names_are_configurable() {
+    let mut render_details = dummy_render_details("BUILD");
+    render_details.rust_rules_bzl = Some("@my_rules_rust//rust:wrappers.bzl".to_owned());
+    render_details.cargo_build_script_bzl = Some("@my_rules_rust//cargo:wrappers.bzl".to_owned());
+    render_details
+      .rule_names
+      .insert("rust_library".to_owned(), "my_rust_library".to_owned());
+    let planned_build = dummy_planned_build(vec![dummy_library_crate()]);
+
+    let file_outputs = BazelRenderer::new()
+      .render_planned_build(&render_details, &planned_build)
+      .unwrap();
+    let build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert!(build_contents.contains(indoc::indoc! { r#"
+      load(
+          "@my_rules_rust//rust:wrappers.bzl",
+          "my_rust_library",
+          "rust_binary",
+          "rust_test",
+      )
+    "# }));
+    assert!(build_contents.contains("\nmy_rust_library(\n"));
+    assert!(!build_contents.contains("\nrust_library("));
+
+    render_details.defs_bzl = true;
+    let file_outputs = BazelRenderer::new()
+      .render_planned_build(&render_details, &planned_build)
+      .unwrap();
+    let defs_bzl = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/defs.bzl",
+    );
+    assert!(defs_bzl.contains(indoc::indoc! { r#"
+      load(
+          "@my_rules_rust//cargo:wrappers.bzl",
+          _cargo_build_script = "cargo_build_script",
+      )
+    "# }));
+    assert!(defs_bzl.contains("    _my_rust_library = \"my_rust_library\",\n"));
+    assert!(defs_bzl.contains("\nmy_rust_library = _my_rust_library\n"));
+    let build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert!(build_contents.contains("\nmy_rust_library(\n"));
+  }
+
+  #[test]
+  fn disallowed_source_hosts_fail_rendering() {
+    let mut render_details = dummy_render_details("BUILD.bazel");
+    render_details.allowed_source_hosts = vec!["mirror.example.com".to_owned()];
+
+    let render_result = BazelRenderer::new().render_remote_planned_build(
+      &render_details,
+      &dummy_planned_build(vec![dummy_library_crate()]),
+    );
+
+    let message = render_result.unwrap_err().to_string();
+    assert!(message.contains("test-library"));
+    assert!(message.contains("https://crates.io/api/v1/crates/"));
+  }
+
+  #[test]
+  fn allowed_source_hosts_render() {
+    let mut render_details = dummy_render_details("BUILD.bazel");
+    render_details.allowed_source_hosts = vec!["crates.io".to_owned()];
+
+    BazelRenderer::new()
+      .render_remote_planned_build(
+        &render_details,
+        &dummy_planned_build(vec![dummy_library_crate()]),
+      )
+      .unwrap();
+  }
+
+  #[test]
+  fn build_metadata_is_dropped_from_repository_names() {
+    let mut library = dummy_library_crate();
+    library.pkg_version = Version::parse("1.2.3+build").unwrap();
+    let file_outputs = BazelRenderer::new()
+      .render_remote_planned_build(
+        &dummy_render_details("BUILD.bazel"),
+        &dummy_planned_build(vec![library]),
+      )
+      .unwrap();
+
+    let crates_bzl_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/crates.bzl",
+    );
+    assert!(crates_bzl_contents.contains("name = \"__test_library__1_2_3\","));
+    assert!(crates_bzl_contents.contains("strip_prefix = \"test-library-1.2.3+build\","));
+
+    let build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert!(build_contents.contains("version = \"1.2.3+build\","));
+  }
+
+  #[test]
+  fn archives_without_prefix_omit_strip_prefix() {
+    let render_crates_bzl = |library: CrateContext| {
+      let file_outputs = BazelRenderer::new()
+        .render_remote_planned_build(
+          &dummy_render_details("BUILD.bazel"),
+          &dummy_planned_build(vec![library]),
+        )
+        .unwrap();
+      extract_contents_matching_path(
+        &file_outputs,
+        "/some/bazel/root/./some_render_prefix/crates.bzl",
+      )
+    };
+
+    let crates_bzl_contents = render_crates_bzl(dummy_library_crate());
+    assert!(crates_bzl_contents.contains("strip_prefix = \"test-library-1.1.1\","));
+
+    let mut library = dummy_library_crate();
+    library.raze_settings.archive_has_no_prefix = true;
+    let crates_bzl_contents = render_crates_bzl(library);
+    assert!(!crates_bzl_contents.contains("strip_prefix"));
+  }
+
+  #[test]
+  fn source_auth_is_only_rendered_for_its_host() {
+    let mut render_details = dummy_render_details("BUILD.bazel");
+    render_details.source_auth.insert(
+      "mirror.example.com".to_owned(),
+      SourceAuth {
+        netrc: Some("/etc/bazel.netrc".to_owned()),
+        auth_pattern: Some("Bearer <password>".to_owned()),
+      },
+    );
+    let mut library = dummy_library_crate();
+    library.registry_url =
+      "https://mirror.example.com/api/v1/crates/test-library/1.1.1/download".to_owned();
+
+    let file_outputs = BazelRenderer::new()
+      .render_remote_planned_build(
+        &render_details,
+        &dummy_planned_build(vec![library, dummy_binary_crate()]),
+      )
+      .unwrap();
+    let crates_bzl_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/crates.bzl",
+    );
+    let repository_rule = |name: &str| {
+      crates_bzl_contents
+        .split("maybe(")
+        .find(|rule| rule.contains(name))
+        .unwrap()
+        .to_owned()
+    };
+
+    let library_rule = repository_rule("name = \"__test_library__1_1_1\",");
+    assert!(library_rule.contains("netrc = \"/etc/bazel.netrc\","));
+    assert!(library_rule.contains(concat!(
+      "        auth_patterns = {\n",
+      "            \"mirror.example.com\": \"Bearer <password>\",\n",
+      "        },\n",
+    )));
+
+    let binary_rule = repository_rule("name = \"__test_binary__1_1_1\",");
+    assert!(!binary_rule.contains("netrc"));
+    assert!(!binary_rule.contains("auth_patterns"));
+  }
+
+  #[test]
+  fn crates_overridden_to_vendored_are_not_fetched() {
+    let mut vendored_library = dummy_library_crate();
+    vendored_library.raze_settings.gen_mode = Some(GenMode::Vendored);
+    let mut remote_binary = dummy_binary_crate();
+    remote_binary.raze_settings.gen_mode = Some(GenMode::Remote);
+    remote_binary.expected_build_path = "remote/BUILD.test-binary-1.1.1.bazel".to_owned();
+
+    let file_outputs = BazelRenderer::new()
+      .render_remote_planned_build(
+        &dummy_render_details("BUILD.bazel"),
+        &dummy_planned_build(vec![vendored_library, remote_binary]),
+      )
+      .unwrap();
+    let crates_bzl_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/crates.bzl",
+    );
+
+    assert!(crates_bzl_contents.contains("name = \"__test_binary__1_1_1\","));
+    assert!(!crates_bzl_contents.contains("test_library"));
+    assert!(file_outputs.iter().any(|output| output.path
+      == Path::new("/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD")));
+  }
+
+  #[test]
+  fn repositories_function_is_rendered_under_the_configured_name() {
+    let mut planned_build = dummy_planned_build(vec![dummy_binary_crate()]);
+    planned_build.workspace_context.repositories_function_name = "other_repositories".to_owned();
+
+    let file_outputs = BazelRenderer::new()
+      .render_remote_planned_build(&dummy_render_details("BUILD.bazel"), &planned_build)
+      .unwrap();
+    let crates_bzl_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/crates.bzl",
+    );
+
+    assert!(crates_bzl_contents.contains("\ndef other_repositories():\n"));
+    assert!(!crates_bzl_contents.contains("fetch_remote_crates"));
+  }
+
+  #[test]
+  fn inlined_build_files_are_rendered_into_the_git_repository_rule() {
+    let mut library = dummy_library_crate();
+    library.source_details.git_data = Some(GitRepo {
+      remote: "https://github.com/example/test-library.git".to_owned(),
+      commit: "0123456789abcdef".to_owned(),
+      path_to_crate_root: None,
+    });
+    library.raze_settings.inline_build_file = true;
+
+    let file_outputs = BazelRenderer::new()
+      .render_remote_planned_build(
+        &dummy_render_details("BUILD.bazel"),
+        &dummy_planned_build(vec![library]),
+      )
+      .unwrap();
+    let crates_bzl_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/crates.bzl",
+    );
+
+    assert!(crates_bzl_contents.contains("new_git_repository,"));
+    assert!(crates_bzl_contents.contains("build_file_content = '''"));
+    assert!(crates_bzl_contents.contains("rust_library("));
+    assert!(crates_bzl_contents.contains("name = \"test_library\","));
+    assert!(!crates_bzl_contents.contains("build_file = Label("));
+    assert!(!file_outputs.iter().any(|output| output.path
+      == Path::new("/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD")));
+  }
+
+  #[test]
+  fn git_crates_are_rendered_as_pinned_git_repositories() {
+    let mut library = dummy_library_crate();
+    library.source_details.git_data = Some(GitRepo {
+      remote: "https://github.com/example/test-library.git".to_owned(),
+      commit: "0123456789abcdef".to_owned(),
+      path_to_crate_root: None,
+    });
+    let mut shallow_library = library.clone();
+    shallow_library.pkg_version = Version::new(1, 2, 0);
+    shallow_library.raze_settings.git_shallow_since = Some("2021-03-14".to_owned());
+
+    let file_outputs = BazelRenderer::new()
+      .render_remote_planned_build(
+        &dummy_render_details("BUILD.bazel"),
+        &dummy_planned_build(vec![library, shallow_library]),
+      )
+      .unwrap();
+    let crates_bzl_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/crates.bzl",
+    );
+
+    assert!(crates_bzl_contents.contains(concat!(
+      "        name = \"__test_library__1_1_1\",\n",
+      "        remote = \"https://github.com/example/test-library.git\",\n",
+      "        commit = \"0123456789abcdef\",\n",
+      "        build_file = Label(",
+    )));
+    assert!(crates_bzl_contents.contains(concat!(
+      "        name = \"__test_library__1_2_0\",\n",
+      "        remote = \"https://github.com/example/test-library.git\",\n",
+      "        commit = \"0123456789abcdef\",\n",
+      "        shallow_since = \"2021-03-14\",\n",
+      "        build_file = Label(",
+    )));
+    assert!(!crates_bzl_contents.contains("http_archive,"));
+  }
+
+  #[test]
+  fn module_extensions_declare_the_repositories_of_remote_crates() {
+    let mut vendored_binary = dummy_binary_crate();
+    vendored_binary.raze_settings.gen_mode = Some(GenMode::Vendored);
+    let mut render_details = dummy_render_details("BUILD.bazel");
+
+    let render = |render_details: &RenderDetails| {
+      BazelRenderer::new()
+        .render_remote_planned_build(
+          render_details,
+          &dummy_planned_build(vec![dummy_library_crate(), vendored_binary.clone()]),
+        )
+        .unwrap()
+    };
+    let file_outputs = render(&render_details);
+    assert!(!file_outputs
+      .iter()
+      .any(|output| output.path.ends_with("extensions.bzl")));
+
+    render_details.bzlmod = true;
+    let file_outputs = render(&render_details);
+    let extension_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/extensions.bzl",
+    );
+    assert!(extension_contents.contains("def _crates_impl(_module_ctx):\n"));
+    assert!(extension_contents.contains(concat!(
+      "    http_archive(\n",
+      "        name = \"__test_library__1_1_1\",\n",
+    )));
+    assert!(extension_contents.contains("crates = module_extension(\n"));
+    assert!(!extension_contents.contains("maybe"));
+    assert!(!extension_contents.contains("test_binary"));
+
+    let module_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/crates.MODULE.bazel",
+    );
+    assert!(module_contents.ends_with(concat!(
+      "crates = use_extension(\"//workspace/prefix:extensions.bzl\", \"crates\")\n",
+      "use_repo(\n",
+      "    crates,\n",
+      "    \"__test_library__1_1_1\",\n",
+      ")\n",
+    )));
+  }
+
+  #[test]
+  fn crates_overridden_to_remote_are_fetched_in_vendored_mode() {
+    let mut remote_binary = dummy_binary_crate();
+    remote_binary.raze_settings.gen_mode = Some(GenMode::Remote);
+    remote_binary.expected_build_path = "remote/BUILD.test-binary-1.1.1.bazel".to_owned();
+    let mut vendored_library = dummy_library_crate();
+    vendored_library.raze_settings.gen_mode = Some(GenMode::Vendored);
+
+    let file_outputs = render_crates_for_test(vec![remote_binary, vendored_library]);
+    let crates_bzl_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/crates.bzl",
+    );
+
+    assert!(crates_bzl_contents.contains("http_archive,"));
+    assert!(crates_bzl_contents.contains("name = \"__test_binary__1_1_1\","));
+    assert!(!crates_bzl_contents.contains("test_library"));
+  }
+
+  #[test]
+  fn proc_macro_crates_keep_their_library_label() {
+    let mut library = dummy_library_crate();
+    library.is_proc_macro = true;
+    library.lib_crate_types = vec!["proc-macro".to_owned()];
+    library.targets[0].kind = "proc-macro".to_owned();
+    library.targets[0].name = "test-library".to_owned();
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    // Proc-macros are rendered as `rust_library` rules, so references to `:test_library` remain
+    // valid without an alias.
+    assert!(crate_build_contents.contains("rust_library(\n    name = \"test_library\","));
+    assert!(crate_build_contents.contains("crate_type = \"proc-macro\","));
+    assert!(!crate_build_contents.contains("alias("));
+  }
+
+  #[test]
+  fn proc_macro_hints_are_only_rendered_on_proc_macro_rules() {
+    let mut proc_macro = dummy_library_crate();
+    proc_macro.is_proc_macro = true;
+    proc_macro.lib_crate_types = vec!["proc-macro".to_owned()];
+    proc_macro.targets[0].kind = "proc-macro".to_owned();
+
+    let mut planned_build = dummy_planned_build(vec![proc_macro, dummy_binary_crate()]);
+    planned_build.workspace_context.proc_macro_tags = vec!["proc-macro-srv".to_owned()];
+    planned_build.workspace_context.proc_macro_toolchains =
+      vec!["//toolchains:proc_macro_srv".to_owned()];
+    let file_outputs = BazelRenderer::new()
+      .render_planned_build(&dummy_render_details("BUILD"), &planned_build)
+      .unwrap();
+
+    let proc_macro_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert!(proc_macro_build_contents.contains(concat!(
+      "        \"manual\",\n",
+      "        \"proc-macro-srv\",\n",
+      "    ],\n",
+    )));
+    assert!(proc_macro_build_contents.contains(concat!(
+      "    toolchains = [\n",
+      "        \"//toolchains:proc_macro_srv\",\n",
+      "    ],\n",
+    )));
+
+    let binary_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-binary-1.1.1/BUILD",
+    );
+    assert!(!binary_build_contents.contains("proc-macro-srv"));
+    assert!(!binary_build_contents.contains("toolchains = ["));
+  }
+
+  #[test]
+  fn crate_linkopts_are_rendered() {
+    let mut binary = dummy_binary_crate();
+    binary.raze_settings.linkopts = vec!["-lpthread".to_owned(), "-Wl,--as-needed".to_owned()];
+
+    let file_outputs = render_crates_for_test(vec![binary]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-binary-1.1.1/BUILD",
+    );
+
+    assert!(crate_build_contents.contains(
+      "    linkopts = [\n        \"-lpthread\",\n        \"-Wl,--as-needed\",\n    ],\n"
+    ));
+  }
+
+  #[test]
+  fn crate_linkopts_are_escaped() {
+    let mut binary = dummy_binary_crate();
+    binary.raze_settings.linkopts = vec!["-Wl,-rpath,\"C:\\lib\"".to_owned()];
+
+    let file_outputs = render_crates_for_test(vec![binary]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-binary-1.1.1/BUILD",
+    );
+
+    assert!(crate_build_contents.contains("        \"-Wl,-rpath,\\\"C:\\\\lib\\\"\",\n"));
+  }
+
+  #[test]
+  fn crate_srcs_excludes_are_rendered() {
+    let mut binary = dummy_binary_crate();
+    binary.raze_settings.srcs_exclude = vec!["**/*.rs~".to_owned(), "src/scratch/**".to_owned()];
+
+    let file_outputs = render_crates_for_test(vec![binary, dummy_library_crate()]);
+    let binary_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-binary-1.1.1/BUILD",
+    );
+    let library_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    assert!(binary_build_contents.contains(concat!(
+      "    name = \"cargo_bin_some_binary\",\n",
+      "    srcs = glob(\n",
+      "        [\"**/*.rs\"],\n",
+      "        exclude = [\n",
+      "            \"**/*.rs~\",\n",
+      "            \"src/scratch/**\",\n",
+      "        ],\n",
+      "    ),\n",
+    )));
+    assert!(library_build_contents.contains("    srcs = glob([\"**/*.rs\"]),\n"));
+  }
+
+  #[test]
+  fn globs_which_may_match_nothing_allow_being_empty() {
+    let mut library = dummy_library_crate();
+    library.raze_settings.manifest_dir_data = vec!["fixtures/**".to_owned()];
+
+    let file_outputs = render_crates_for_test(vec![library, dummy_binary_crate()]);
+    let library_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    let binary_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-binary-1.1.1/BUILD",
+    );
+
+    // Crates without data files have no data glob at all
+    assert!(binary_build_contents.contains("    data = [],\n"));
+    assert_eq!(binary_build_contents.matches("glob(").count(), 1);
+    assert!(binary_build_contents.contains("    srcs = glob([\"**/*.rs\"]),\n"));
+
+    let data_glob = concat!(
+      "glob(\n",
+      "        [\n",
+      "            \"fixtures/**\",\n",
+      "        ],\n",
+      "        allow_empty = True,\n",
+      "    ),\n",
+    );
+    assert!(library_build_contents.contains(&format!("    data = [] + {}", data_glob)));
+    assert!(library_build_contents.contains(&format!("    compile_data = {}", data_glob)));
+  }
+
+  #[test]
+  fn binary_args_are_rendered_on_binaries() {
+    let mut binary = dummy_binary_crate();
+    binary.raze_settings.binary_args = vec!["--config=\"tool.toml\"".to_owned(), "-v".to_owned()];
+    binary.lib_target_name = Some("test_binary".to_owned());
+    binary.lib_crate_types = vec!["lib".to_owned()];
+    binary.targets.push(BuildableTarget {
+      name: "test_binary".to_owned(),
+      kind: "lib".to_owned(),
+      path: "src/lib.rs".to_owned(),
+      edition: "2015".to_owned(),
+      test: true,
+      doctest: true,
+    });
+
+    let file_outputs = render_crates_for_test(vec![binary]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-binary-1.1.1/BUILD",
+    );
+    let rule = |kind: &str| {
+      crate_build_contents
+        .split("\n\n")
+        .find(|rule| rule.starts_with(kind))
+        .unwrap_or_else(|| panic!("No {} in {}", kind, crate_build_contents))
+    };
+
+    assert!(rule("rust_binary(").contains(concat!(
+      "    args = [\n",
+      "        \"--config=\\\"tool.toml\\\"\",\n",
+      "        \"-v\",\n",
+      "    ],\n",
+      ")",
+    )));
+    assert!(!rule("rust_library(").contains("args = ["));
+  }
+
+  #[test]
+  fn loads_are_consolidated_per_source() {
+    let mut library = dummy_library_crate();
+    library.build_script_target = Some(BuildableTarget {
+      kind: "custom-build".to_owned(),
+      name: "build-script-build".to_owned(),
+      path: "build.rs".to_owned(),
+      edition: "2015".to_owned(),
+      test: true,
+      doctest: true,
+    });
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    expect(
+      crate_build_contents.contains(indoc::indoc! {r#"
+        load(
+            "@rules_rust//rust:rust.bzl",
+            "rust_binary",
+            "rust_library",
+            "rust_test",
+        )
+      "#}),
+      format!(
+        "expected a single sorted rust.bzl load in:\n{}",
+        crate_build_contents
+      ),
+    )
+    .unwrap();
+    assert_eq!(crate_build_contents.matches("load(").count(), 3);
+    assert_eq!(
+      crate_build_contents
+        .matches("//cargo:cargo_build_script.bzl")
+        .count(),
+      1
+    );
+    let last_load = crate_build_contents.rfind("load(").unwrap();
+    assert!(last_load < crate_build_contents.find("package(").unwrap());
+  }
+
+  #[test]
+  fn build_scripts_can_use_the_default_shell_env() {
+    let render_crate_build = |library: CrateContext| {
+      let file_outputs = render_crates_for_test(vec![library]);
+      extract_contents_matching_path(
+        &file_outputs,
+        "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+      )
+    };
+    let mut library = dummy_library_crate();
+    library.build_script_target = Some(BuildableTarget {
+      kind: "custom-build".to_owned(),
+      name: "build-script-build".to_owned(),
+      path: "build.rs".to_owned(),
+      edition: "2015".to_owned(),
+      test: true,
+      doctest: true,
+    });
+
+    assert!(!render_crate_build(library.clone()).contains("use_default_shell_env"));
+
+    library.raze_settings.build_script_use_default_shell_env = true;
+    let crate_build_contents = render_crate_build(library);
+    assert!(crate_build_contents.contains(concat!(
+      "    use_default_shell_env = True,\n",
+      "    version = \"1.1.1\",\n",
+      "    visibility = [\"//visibility:private\"],\n",
+    )));
+  }
+
+  #[test]
+  fn build_script_srcs_render_their_excludes() {
+    let mut library = dummy_library_crate();
+    library.build_script_target = Some(BuildableTarget {
+      kind: "custom-build".to_owned(),
+      name: "build-script-build".to_owned(),
+      path: "build.rs".to_owned(),
+      edition: "2015".to_owned(),
+      test: true,
+      doctest: true,
+    });
+    library.build_script_srcs = vec!["build.rs".to_owned(), "src/**".to_owned()];
+    library.build_script_srcs_exclude = vec!["src/tests/**".to_owned()];
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    assert!(crate_build_contents.contains(concat!(
+      "    srcs = glob(\n",
+      "        [\n",
+      "            \"build.rs\",\n",
+      "            \"src/**\",\n",
+      "        ],\n",
+      "        exclude = [\n",
+      "            \"src/tests/**\",\n",
+      "        ],\n",
+      "        allow_empty = True,\n",
+      "    ),\n",
+    )));
+  }
+
+  #[test]
+  fn build_script_srcs_from_manifest_includes_may_match_nothing() {
+    let render_crate_build = |library: CrateContext| {
+      let file_outputs = render_crates_for_test(vec![library]);
+      extract_contents_matching_path(
+        &file_outputs,
+        "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+      )
+    };
+    let mut library = dummy_library_crate();
+    library.build_script_target = Some(BuildableTarget {
+      kind: "custom-build".to_owned(),
+      name: "build-script-build".to_owned(),
+      path: "build.rs".to_owned(),
+      edition: "2015".to_owned(),
+      test: true,
+      doctest: true,
+    });
+
+    // The build script itself always matches
+    library.build_script_srcs = vec!["build.rs".to_owned()];
+    let crate_build_contents = render_crate_build(library.clone());
+    assert!(crate_build_contents.contains(concat!(
+      "    srcs = glob([\n",
+      "        \"build.rs\",\n",
+      "    ]),\n",
+    )));
+    assert!(!crate_build_contents.contains("allow_empty"));
+
+    library.build_script_srcs = vec!["build.rs".to_owned(), "**/include/**".to_owned()];
+    assert!(render_crate_build(library).contains(concat!(
+      "    srcs = glob(\n",
+      "        [\n",
+      "            \"build.rs\",\n",
+      "            \"**/include/**\",\n",
+      "        ],\n",
+      "        allow_empty = True,\n",
+      "    ),\n",
+    )));
+  }
+
+  #[test]
+  fn crate_categories_are_rendered_as_tags() {
+    let mut library = dummy_library_crate();
+    library.categories = vec!["embedded".to_owned(), "no-std".to_owned()];
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    assert!(crate_build_contents.contains("\"category=embedded\","));
+    assert!(crate_build_contents.contains("\"category=no-std\","));
+  }
+
+  #[test]
+  fn crate_categories_are_escaped() {
+    let mut library = dummy_library_crate();
+    library.categories = vec!["\"quoted\" \\ category".to_owned()];
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    assert!(crate_build_contents.contains("\"category=\\\"quoted\\\" \\\\ category\","));
+  }
+
+  #[test]
+  fn sibling_crate_data_references_render_as_labels() {
+    let mut binary = dummy_binary_crate();
+    binary.raze_settings.data_dependencies = vec![
+      "../test-library-1.1.1/assets/data.txt".to_owned(),
+      "//some/other:target".to_owned(),
+    ];
+
+    let file_outputs = render_crates_for_test(vec![binary, dummy_library_crate()]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-binary-1.1.1/BUILD",
+    );
+
+    assert!(crate_build_contents.contains("\"@raze__test_library__1_1_1//:assets/data.txt\","));
+    assert!(crate_build_contents.contains("\"//some/other:target\","));
+    assert!(!crate_build_contents.contains("../test-library-1.1.1"));
+  }
+
+  #[test]
+  fn extra_rule_attributes_are_rendered() {
+    let mut library = dummy_library_crate();
+    library
+      .raze_settings
+      .extra_rule_attributes
+      .insert("foo".to_owned(), "True".to_owned());
+    library.raze_settings.gen_tests = true;
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    // Only the rules under test get the attributes, not their tests
+    let (library_rule, test_rule) =
+      crate_build_contents.split_at(crate_build_contents.find("rust_test(").unwrap());
+    assert!(library_rule.contains("    foo = True,\n"));
+    assert!(!test_rule.contains("foo = True"));
+  }
+
+  #[test]
+  fn test_targets_are_rendered_with_shard_count() {
+    let mut library = dummy_library_crate();
+    library.targets.push(BuildableTarget {
+      name: "integration".to_owned(),
+      kind: "test".to_owned(),
+      path: "tests/integration.rs".to_owned(),
+      edition: "2015".to_owned(),
+      test: true,
+      doctest: true,
+    });
+
+    let file_outputs = render_crates_for_test(vec![library.clone()]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert!(!crate_build_contents.contains("rust_test("));
+
+    library.raze_settings.test_shard_count = Some(4);
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert!(crate_build_contents.contains("rust_test("));
+    assert!(crate_build_contents.contains("name = \"cargo_test_integration\","));
+    assert!(crate_build_contents.contains("shard_count = 4,"));
+    // The lib only gets a unit test rule when `gen_tests` asks for one
+    assert!(!crate_build_contents.contains("cargo_unit_test"));
+  }
+
+  #[test]
+  fn doc_tests_are_rendered_independently_of_unit_tests() {
+    let crate_build_path = "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD";
+    let mut library = dummy_library_crate();
+    library.raze_settings.gen_doc_tests = Some(true);
+    let file_outputs = render_crates_for_test(vec![library.clone()]);
+    let crate_build_contents = extract_contents_matching_path(&file_outputs, crate_build_path);
+    assert!(crate_build_contents.contains("name = \"cargo_doc_test_some_library\","));
+    assert!(crate_build_contents.contains("\"rust_doc_test\","));
+    assert!(!crate_build_contents.contains("rust_test("));
+    let root_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/BUILD.bazel",
+    );
+    assert!(root_build_contents.contains("//:cargo_doc_test_some_library\","));
+    assert!(!root_build_contents.contains("cargo_unit_test"));
+
+    library.raze_settings.gen_tests = true;
+    library.raze_settings.gen_doc_tests = Some(false);
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(&file_outputs, crate_build_path);
+    assert!(crate_build_contents.contains("name = \"cargo_unit_test_some_library\","));
+    assert!(!crate_build_contents.contains("rust_doc_test"));
+  }
+
+  #[test]
+  fn test_rules_refer_to_the_lib_in_the_form_of_the_rules_rust_version() {
+    let mut library = dummy_library_crate();
+    library.raze_settings.gen_tests = true;
+    library.raze_settings.gen_doc_tests = Some(true);
+    let planned_build = dummy_planned_build(vec![library]);
+    let render = |rules_rust_version: &str| {
+      let mut render_details = dummy_render_details("BUILD");
+      render_details.rules_rust_version = Some(Version::parse(rules_rust_version).unwrap());
+      let file_outputs = BazelRenderer::new()
+        .render_planned_build(&render_details, &planned_build)
+        .unwrap();
+      let crate_build_contents = extract_contents_matching_path(
+        &file_outputs,
+        "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+      );
+      let rule_containing = |name: &str| {
+        crate_build_contents
+          .split("\n)\n")
+          .find(|rule| rule.contains(name))
+          .unwrap()
+          .to_owned()
+      };
+      (
+        rule_containing("name = \"cargo_unit_test_some_library\","),
+        rule_containing("name = \"cargo_doc_test_some_library\","),
+      )
+    };
+
+    let (unit_test, doc_test) = render("0.2.1");
+    assert!(!unit_test.contains("    crate = "));
+    assert!(unit_test.contains("    crate_root = \"path/lib.rs\",\n"));
+    assert!(doc_test.contains("    dep = \":some_library\",\n"));
+    assert!(!doc_test.contains("    crate = "));
+
+    let (unit_test, doc_test) = render("0.3.0");
+    assert!(unit_test.contains("    crate = \":some_library\",\n"));
+    assert!(!unit_test.contains("crate_root"));
+    assert!(doc_test.contains("    crate = \":some_library\",\n"));
+    assert!(!doc_test.contains("    dep = "));
+  }
+
+  #[test]
+  fn targeted_proc_macro_deps_are_rendered_as_selects() {
+    let mut windows_branch = dummy_targeted_deps(
+      "cfg(windows)",
+      vec!["@rules_rust//rust/platform:x86_64-pc-windows-msvc"],
+      vec![],
+    );
+    windows_branch.deps.proc_macro_dependencies = vec![dummy_buildable_dependency("win-macro")];
+    let mut library = dummy_library_crate();
+    library.targeted_deps = vec![windows_branch];
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    assert!(crate_build_contents.contains(concat!(
+      "    proc_macro_deps = [\n",
+      "    ] + selects.with_or({\n",
+      "        # cfg(windows)\n",
+      "        (\n",
+      "            \"@rules_rust//rust/platform:x86_64-pc-windows-msvc\",\n",
+      "        ): [\n",
+      "            \"@raze__win-macro__1_0_0//:win-macro\",\n",
+      "        ],\n",
+      "        \"//conditions:default\": [],\n",
+      "    }),\n",
+    )));
+  }
+
+  #[test]
+  fn unusual_file_names_are_escaped() {
+    let mut library = dummy_library_crate();
+    library.targets[0].path = "src/we\"ird na\\me.rs".to_owned();
+    library.build_script_target = Some(BuildableTarget {
+      name: "build_script_build".to_owned(),
+      kind: "custom-build".to_owned(),
+      path: "build dir/\"main\".rs".to_owned(),
+      edition: "2015".to_owned(),
+      test: true,
+      doctest: true,
+    });
+    library.build_script_srcs = vec!["build dir/**/*.rs".to_owned()];
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    assert!(crate_build_contents.contains("    crate_root = \"src/we\\\"ird na\\\\me.rs\",\n"));
+    assert!(crate_build_contents.contains("    crate_root = \"build dir/\\\"main\\\".rs\",\n"));
+    assert!(crate_build_contents.contains("        \"build dir/**/*.rs\",\n"));
+  }
+
+  #[test]
+  fn env_values_are_escaped() {
+    let mut library = dummy_library_crate();
+    library.raze_settings.additional_env.insert(
+      "GREETING".to_owned(),
+      "say \"hi\"\nthen C:\\leave".to_owned(),
+    );
+    library
+      .raze_settings
+      .buildrs_additional_environment_variables
+      .insert(
+        "BUILD_GREETING".to_owned(),
+        "a \"quoted\"\nvalue".to_owned(),
+      );
+    library.build_script_target = Some(BuildableTarget {
+      name: "build_script_build".to_owned(),
+      kind: "custom-build".to_owned(),
+      path: "build.rs".to_owned(),
+      edition: "2015".to_owned(),
+      test: true,
+      doctest: true,
+    });
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    assert!(crate_build_contents.contains(concat!(
+      "    rustc_env = {\n",
+      "        \"GREETING\": \"say \\\"hi\\\"\\nthen C:\\\\leave\",\n",
+      "    },\n",
+    )));
+    assert!(crate_build_contents.contains(concat!(
+      "    build_script_env = {\n",
+      "        \"BUILD_GREETING\": \"a \\\"quoted\\\"\\nvalue\",\n",
+      "    },\n",
+    )));
+    // No string literal is broken across lines
+    assert!(!crate_build_contents.contains("\nthen"));
+    assert!(!crate_build_contents.contains("\nvalue"));
+  }
+
+  #[test]
+  fn starlark_strings_escape_special_characters() {
+    assert_eq!(starlark_escape("plain/path.rs"), "plain/path.rs");
+    assert_eq!(starlark_escape("a\"b\\c"), "a\\\"b\\\\c");
+    assert_eq!(starlark_escape("tab\tnew\nline"), "tab\\tnew\\nline");
+    assert_eq!(starlark_escape("bell\u{7}"), "bell\\007");
+  }
+
+  #[test]
+  fn compilation_mode_rustc_flags_are_rendered_as_selects() {
+    let mut library = dummy_library_crate();
+    library
+      .raze_settings
+      .compilation_mode_rustc_flags
+      .insert("dbg".to_owned(), vec!["-Cdebug-assertions=on".to_owned()]);
+    library
+      .raze_settings
+      .compilation_mode_rustc_flags
+      .insert("opt".to_owned(), vec!["-Cdebug-assertions=off".to_owned()]);
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    assert!(crate_build_contents.contains(concat!(
+      "    rustc_flags = [\n",
+      "        \"--cap-lints=allow\",\n",
+      "    ] + select({\n",
+      "        \":compilation_mode_dbg\": [\n",
+      "            \"-Cdebug-assertions=on\",\n",
+      "        ],\n",
+      "        \":compilation_mode_opt\": [\n",
+      "            \"-Cdebug-assertions=off\",\n",
+      "        ],\n",
+      "        \"//conditions:default\": [],\n",
+      "    }),\n",
+    )));
+    assert!(crate_build_contents.contains(concat!(
+      "config_setting(\n",
+      "    name = \"compilation_mode_dbg\",\n",
+      "    values = {\"compilation_mode\": \"dbg\"},\n",
+      ")\n",
+    )));
+  }
+
+  #[test]
+  fn compilation_mode_rustc_flags_are_escaped() {
+    let mut library = dummy_library_crate();
+    library
+      .raze_settings
+      .compilation_mode_rustc_flags
+      .insert("opt".to_owned(), vec!["--cfg=feature=\"fast\"".to_owned()]);
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+
+    assert!(crate_build_contents.contains(concat!(
+      "        \":compilation_mode_opt\": [\n",
+      "            \"--cfg=feature=\\\"fast\\\"\",\n",
+      "        ],\n",
+    )));
+  }
+
+  #[test]
+  fn deprecation_notices_are_rendered_on_the_crate_rules() {
+    let mut library = dummy_library_crate();
+    library.raze_settings.deprecation = Some("Use the \"other-library\" crate".to_owned());
+    library.raze_settings.gen_tests = true;
+
+    let file_outputs = render_crates_for_test(vec![library, dummy_binary_crate()]);
+    let library_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    let rule_containing = |name: &str| {
+      library_contents
+        .split("\n)\n")
+        .find(|rule| rule.contains(name))
+        .unwrap()
+        .to_owned()
+    };
+
+    let deprecation = "    deprecation = \"Use the \\\"other-library\\\" crate\",\n";
+    assert!(rule_containing("name = \"test_library\",").contains(deprecation));
+    assert!(rule_containing("name = \"some_library\",").contains(deprecation));
+    assert!(!rule_containing("name = \"cargo_unit_test_some_library\",").contains("deprecation"));
+
+    let binary_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-binary-1.1.1/BUILD",
+    );
+    assert!(!binary_contents.contains("deprecation"));
+  }
+
+  #[test]
+  fn static_rustc_cfgs_are_rendered_into_rustc_flags() {
+    let mut library = dummy_library_crate();
+    library.raze_settings.static_rustc_cfgs =
+      vec!["has_foo".to_owned(), "feature=\"bar\"".to_owned()];
+
+    let file_outputs = render_crates_for_test(vec![library, dummy_binary_crate()]);
+    let library_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert!(library_contents.contains(concat!(
+      "    rustc_flags = [\n",
+      "        \"--cap-lints=allow\",\n",
+      "        \"--cfg=has_foo\",\n",
+      "        \"--cfg=feature=\\\"bar\\\"\",\n",
+      "    ],\n",
+    )));
+
+    let binary_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-binary-1.1.1/BUILD",
+    );
+    assert!(!binary_contents.contains("--cfg="));
+  }
+
+  #[test]
+  fn targeted_editions_are_rendered_as_a_select() {
+    let mut library = dummy_library_crate();
+    library.targeted_editions = vec![
+      (
+        "@rules_rust//rust/platform:x86_64-unknown-linux-gnu".to_owned(),
+        "2021".to_owned(),
+      ),
+      (
+        "@rules_rust//rust/platform:aarch64-apple-darwin".to_owned(),
+        "2018".to_owned(),
+      ),
+    ]
+    .into_iter()
+    .collect();
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let library_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert!(library_contents.contains(concat!(
+      "    edition = select({\n",
+      "        \"@rules_rust//rust/platform:aarch64-apple-darwin\": \"2018\",\n",
+      "        \"@rules_rust//rust/platform:x86_64-unknown-linux-gnu\": \"2021\",\n",
+      "        \"//conditions:default\": \"2015\",\n",
+      "    }),\n",
+    )));
+  }
+
+  #[test]
+  fn targeted_compile_data_and_env_are_rendered_as_selects() {
+    let linux = "@rules_rust//rust/platform:x86_64-unknown-linux-gnu".to_owned();
+    let mut library = dummy_library_crate();
+    library.raze_settings.additional_env = vec![
+      ("LIB_DIR".to_owned(), "lib".to_owned()),
+      ("STATIC".to_owned(), "0".to_owned()),
+    ]
+    .into_iter()
+    .collect();
+    library.targeted_rustc_env.insert(
+      linux.clone(),
+      vec![("STATIC".to_owned(), "1".to_owned())]
+        .into_iter()
+        .collect(),
+    );
+    library.targeted_build_script_env.insert(
+      linux.clone(),
+      vec![("OPENSSL_STATIC".to_owned(), "1".to_owned())]
+        .into_iter()
+        .collect(),
+    );
+    library
+      .targeted_compile_data
+      .insert(linux, "glob([\"linux/**\"])".to_owned());
+    library.build_script_target = Some(BuildableTarget {
+      name: "build_script_build".to_owned(),
+      kind: "custom-build".to_owned(),
+      path: "build.rs".to_owned(),
+      edition: "2015".to_owned(),
+      test: true,
+      doctest: true,
+    });
+
+    let file_outputs = render_crates_for_test(vec![library]);
+    let library_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert!(library_contents.contains(concat!(
+      "    compile_data = select({\n",
+      "        \"@rules_rust//rust/platform:x86_64-unknown-linux-gnu\": glob([\"linux/**\"]),\n",
+      "        \"//conditions:default\": [],\n",
+      "    }),\n",
+    )));
+    assert!(library_contents.contains(concat!(
+      "    rustc_env = select({\n",
+      "        \"@rules_rust//rust/platform:x86_64-unknown-linux-gnu\": {\n",
+      "            \"LIB_DIR\": \"lib\",\n",
+      "            \"STATIC\": \"1\",\n",
+      "        },\n",
+      "        \"//conditions:default\": {\n",
+      "            \"LIB_DIR\": \"lib\",\n",
+      "            \"STATIC\": \"0\",\n",
+      "        },\n",
+      "    }),\n",
+    )));
+    assert!(library_contents.contains(concat!(
+      "    build_script_env = select({\n",
+      "        \"@rules_rust//rust/platform:x86_64-unknown-linux-gnu\": {\n",
+      "            \"OPENSSL_STATIC\": \"1\",\n",
+      "        },\n",
+      "        \"//conditions:default\": {\n",
+      "        },\n",
+      "    }),\n",
+    )));
+  }
+
+  #[test]
+  fn license_rules_are_generated_for_every_crate() {
+    let mut library = dummy_library_crate();
+    library.license = LicenseData {
+      name: "MIT from expression \"MIT OR Apache-2.0\"".to_owned(),
+      rating: "notice".to_owned(),
+      spdx_ids: vec!["Apache-2.0".to_owned(), "MIT".to_owned()],
+    };
+    let mut planned_build = dummy_planned_build(vec![library]);
+    planned_build.workspace_context.license_rules = true;
+
+    let file_outputs = BazelRenderer::new()
+      .render_planned_build(&dummy_render_details("BUILD"), &planned_build)
+      .unwrap();
+    let library_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert!(library_contents.contains(concat!(
+      "    applicable_licenses = [\n",
+      "        \"//workspace/prefix/licenses:test-library-1.1.1\",\n",
+      "        \"//workspace/prefix/licenses:test-library-1.1.1_package_info\",\n",
+      "    ],\n",
+    )));
+
+    let license_rules = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/licenses/BUILD.bazel",
+    );
+    assert!(license_rules.contains(indoc::indoc! { r#"
+      # test-library-1.1.1: MIT from expression "MIT OR Apache-2.0"
+      license(
+          name = "test-library-1.1.1",
+          license_kinds = [
+              "@rules_license//licenses/spdx:Apache-2.0",
+              "@rules_license//licenses/spdx:MIT",
+          ],
+      )
+    "# }));
+    assert!(license_rules.contains(indoc::indoc! { r#"
+      package_info(
+          name = "test-library-1.1.1_package_info",
+          package_name = "test-library",
+    "# }));
+  }
+
+  #[test]
+  fn test_suite_references_all_generated_test_rules() {
+    let mut library = dummy_library_crate();
+    library.raze_settings.gen_tests = true;
+    library.raze_settings.test_shard_count = Some(2);
+    library.targets.push(BuildableTarget {
+      name: "integration-tests".to_owned(),
+      kind: "test".to_owned(),
+      path: "tests/integration.rs".to_owned(),
+      edition: "2015".to_owned(),
+      test: true,
+      doctest: true,
+    });
+
+    let file_outputs = render_crates_for_test(vec![library, dummy_binary_crate()]);
+    let output_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/BUILD.bazel",
+    );
+
+    assert!(output_build_contents.contains(indoc::indoc! { r#"
+      test_suite(
+          name = "all_tests",
+          tests = [
+              "@raze__test_library__1_1_1//:cargo_doc_test_some_library",
+              "@raze__test_library__1_1_1//:cargo_test_integration_tests",
+              "@raze__test_library__1_1_1//:cargo_unit_test_some_library",
+          ],
+      )
+    "# }));
+  }
+
+  #[test]
+  fn test_suite_is_omitted_without_test_rules() {
+    let file_outputs = render_crates_for_test(vec![dummy_library_crate()]);
+
+    assert!(!file_outputs
+      .iter()
+      .any(|output| output.contents.contains("test_suite(")));
+  }
+
+  #[test]
+  fn unit_tests_share_the_library_features_and_integration_tests_use_dev_features() {
+    let mut library = dummy_library_crate();
+    library.raze_settings.gen_tests = true;
+    library.features = vec!["std".to_owned()];
+    library.dev_features = vec!["std".to_owned(), "test-utils".to_owned()];
+    library.targets.push(BuildableTarget {
+      name: "integration".to_owned(),
+      kind: "test".to_owned(),
+      path: "tests/integration.rs".to_owned(),
+      edition: "2015".to_owned(),
+      test: true,
+      doctest: true,
+    });
+
+    let file_outputs = render_crates_for_test(vec![library.clone()]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    let rule_containing = |name: &str| {
+      crate_build_contents
+        .split("\n)\n")
+        .find(|rule| rule.contains(name))
+        .unwrap()
+        .to_owned()
+    };
+
+    let library_features = "    crate_features = [\n        \"std\",\n    ],\n";
+    let unit_test = rule_containing("name = \"cargo_unit_test_some_library\",");
+    assert!(rule_containing("name = \"some_library\",").contains(library_features));
+    assert!(unit_test.contains(library_features));
+    assert!(!unit_test.contains("crate_type"));
+    assert!(!unit_test.contains("\":test_library\","));
+
+    // Integration tests link a variant of the lib built with the dev-resolved features
+    let dev_features = concat!(
+      "    crate_features = [\n",
+      "        \"std\",\n",
+      "        \"test-utils\",\n",
+      "    ],\n",
+    );
+    let dev_library = rule_containing("name = \"test_library_dev_features\",");
+    assert!(dev_library.contains(dev_features));
+    assert!(dev_library.contains("    crate_name = \"some_library\",\n"));
+    assert!(!dev_library.contains("alias("));
+    let integration_test = rule_containing("name = \"cargo_test_integration\",");
+    assert!(integration_test.contains(dev_features));
+    assert!(integration_test.contains("\":test_library_dev_features\","));
+    assert!(!integration_test.contains("\":test_library\","));
+
+    // Without features of their own, integration tests link the lib itself
+    library.dev_features = library.features.clone();
+    let file_outputs = render_crates_for_test(vec![library]);
+    let crate_build_contents = extract_contents_matching_path(
+      &file_outputs,
+      "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+    );
+    assert!(!crate_build_contents.contains("_dev_features"));
+    assert!(crate_build_contents.contains("\":test_library\","));
+  }
+
+  #[test]
+  fn self_aliases_are_rendered_on_the_targets_linking_the_lib() {
+    let mut library = dummy_library_crate();
+    library.raze_settings.gen_tests = true;
+    library.self_alias = Some("test_library_self".to_owned());
+    for (name, kind) in &[("some_binary", "bin"), ("integration", "test")] {
+      library.targets.push(BuildableTarget {
+        name: (*name).to_owned(),
+        kind: (*kind).to_owned(),
+        path: format!("src/{}.rs", name),
+        edition: "2015".to_owned(),
+        test: true,
+        doctest: true,
+      });
+    }
+
+    let render = |library: &CrateContext| {
+      extract_contents_matching_path(
+        &render_crates_for_test(vec![library.clone()]),
+        "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+      )
+    };
+    let rule_containing = |contents: &str, name: &str| {
+      contents
+        .split("\n)\n")
+        .find(|rule| rule.contains(name))
+        .unwrap()
+        .to_owned()
+    };
+
+    let crate_build_contents = render(&library);
+    let self_alias = "    aliases = {\n        \":test_library\": \"test_library_self\",\n    },\n";
+    for name in &["cargo_bin_some_binary", "cargo_test_integration"] {
+      assert!(
+        rule_containing(&crate_build_contents, &format!("name = \"{}\",", name))
+          .contains(self_alias)
+      );
+    }
+    // The lib and its unit tests can't refer to the lib itself
+    for name in &["some_library", "cargo_unit_test_some_library"] {
+      assert!(
+        !rule_containing(&crate_build_contents, &format!("name = \"{}\",", name))
+          .contains("aliases = {")
+      );
+    }
+
+    // Integration tests alias the variant of the lib they link
+    library.dev_features = vec!["feature1".to_owned()];
+    let crate_build_contents = render(&library);
+    assert!(
+      rule_containing(&crate_build_contents, "name = \"cargo_test_integration\",")
+        .contains("        \":test_library_dev_features\": \"test_library_self\",\n")
+    );
+    assert!(
+      rule_containing(&crate_build_contents, "name = \"cargo_bin_some_binary\",")
+        .contains(self_alias)
+    );
+  }
+
+  #[test]
+  fn grouped_deps_separate_first_party_from_third_party() {
+    let mut library = dummy_library_crate();
+    let mut local_utils = dummy_buildable_dependency("local-utils");
+    local_utils.buildable_target = "//libs/local_utils:local_utils".to_owned();
+    local_utils.is_first_party = true;
+    library.default_deps.dependencies = vec![dummy_buildable_dependency("serde"), local_utils];
+    library.raze_settings.additional_deps =
+      vec!["@other//:extra".to_owned(), "//tools:helper".to_owned()];
+
+    let render = |group_deps: bool| {
+      let mut planned_build = dummy_planned_build(vec![library.clone()]);
+      planned_build.workspace_context.group_deps = group_deps;
+      let file_outputs = BazelRenderer::new()
+        .render_planned_build(&dummy_render_details("BUILD"), &planned_build)
+        .unwrap();
+      extract_contents_matching_path(
+        &file_outputs,
+        "/some/bazel/root/./some_render_prefix/vendor/test-library-1.1.1/BUILD",
+      )
+    };
+
+    assert!(render(true).contains(concat!(
+      "    deps = [\n",
+      "        # First-party dependencies\n",
+      "        \"//libs/local_utils:local_utils\",\n",
+      "        \"//tools:helper\",\n",
+      "\n",
+      "        # Third-party dependencies\n",
+      "        \"@other//:extra\",\n",
+      "        \"@raze__serde__1_0_0//:serde\",\n",
+      "    ],\n",
+    )));
+
+    let ungrouped = render(false);
+    assert!(!ungrouped.contains("# First-party dependencies"));
+    assert!(!ungrouped.contains("# Third-party dependencies"));
+    assert!(ungrouped.contains(concat!(
+      "    deps = [\n",
+      "        \"//libs/local_utils:local_utils\",\n",
+      "        \"//tools:helper\",\n",
+      "        \"@other//:extra\",\n",
+      "        \"@raze__serde__1_0_0//:serde\",\n",
+      "    ],\n",
+    )));
+  }
 }
@@ -0,0 +1,668 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+  collections::{BTreeMap, BTreeSet, HashSet},
+  fs,
+  path::Path,
+};
+
+use anyhow::{Context, Result};
+use cargo_metadata::{Metadata, Package};
+use itertools::Itertools;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+  metadata::RazeMetadata,
+  planning::PlannedBuild,
+  settings::{matching_version_entries, CrateSettings, RazeSettings},
+};
+
+/// A summary of what cargo-raze did with every crate cargo resolved, eg: for `--report`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanReport {
+  pub crates: Vec<CrateDecision>,
+  /// Dependencies shared by workspace members whose unified features none of them requested alone
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub feature_unifications: Vec<FeatureUnification>,
+}
+
+/// The decisions cargo-raze made for a single resolved crate.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct CrateDecision {
+  pub name: String,
+  pub version: String,
+  /// Whether BUILD rules are generated for the crate
+  pub included: bool,
+  /// Why the crate is included or excluded
+  pub reason: String,
+  /// The version requirement forcing the crate's version, eg: that of a binary dependency
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub forced_version: Option<String>,
+  /// The version requirement of the `crates` settings entry matching the crate
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub settings_version: Option<String>,
+  /// The settings of the crate which differ from their defaults
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub settings: BTreeMap<String, Value>,
+  /// Where the crate comes from: "registry", "git" or "path"
+  pub source: String,
+  /// The features the crate is built with, if it is included
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub features: Vec<String>,
+  /// The sha256 checksum of the crate's archive, if it is included and downloaded from a registry
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub checksum: Option<String>,
+}
+
+/// A difference between the crates included by two plans, eg: one of those listed for
+/// `--compare-to`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PlanChange {
+  pub name: String,
+  /// One of "added", "removed", "upgraded", "downgraded", "features" or "checksum"
+  pub kind: &'static str,
+  /// The crate's version, or a description of what changed, in the old plan
+  pub old: String,
+  /// The crate's version, or a description of what changed, in the new plan
+  pub new: String,
+}
+
+/// A dependency shared by several workspace members which cargo built with the union of the
+/// features they requested, a superset of what any one of them asked for.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct FeatureUnification {
+  pub name: String,
+  pub version: String,
+  /// The features activated by unifying the requests of all workspace members
+  pub features: Vec<String>,
+  /// The features each workspace member requested, keyed by the member's name
+  pub requested_by: BTreeMap<String, Vec<String>>,
+}
+
+/// Gathers the decisions made for every resolved crate while planning `planned_build`.
+pub fn produce_report(
+  raze_metadata: &RazeMetadata,
+  settings: &RazeSettings,
+  planned_build: &PlannedBuild,
+) -> Result<PlanReport> {
+  let metadata = &raze_metadata.metadata;
+  let resolved_ids: HashSet<_> = metadata
+    .resolve
+    .iter()
+    .flat_map(|resolve| resolve.nodes.iter().map(|node| &node.id))
+    .collect();
+  let default_settings = serde_json::to_value(CrateSettings::default())?;
+
+  let mut crates = Vec::new();
+  for package in metadata
+    .packages
+    .iter()
+    .filter(|package| resolved_ids.contains(&package.id))
+  {
+    let context = planned_build
+      .crate_contexts
+      .iter()
+      .find(|ctx| ctx.pkg_name == package.name && ctx.pkg_version == package.version);
+    let is_binary_dep = settings.binary_deps.contains_key(&package.name);
+    let matching_settings: Vec<_> = settings
+      .crates
+      .get(&package.name)
+      .map(|per_version| matching_version_entries(per_version, &package.version))
+      .unwrap_or_default();
+
+    let reason = match context {
+      Some(_) if is_binary_dep => "binary dependency",
+      Some(_) => "dependency of a workspace member",
+      None if metadata.workspace_members.contains(&package.id) => "workspace member",
+      None if matching_settings.len() > 1 => "multiple `crates` settings entries match its version",
+      None => "optional dependency no resolved feature activates",
+    };
+
+    let (settings_version, overridden_settings) = match matching_settings.as_slice() {
+      [(req, crate_settings)] => (
+        Some(req.to_string()),
+        changed_settings(&default_settings, serde_json::to_value(crate_settings)?),
+      ),
+      _ => (None, BTreeMap::new()),
+    };
+
+    crates.push(CrateDecision {
+      name: package.name.clone(),
+      version: package.version.to_string(),
+      included: context.is_some(),
+      reason: reason.to_owned(),
+      forced_version: settings
+        .binary_deps
+        .get(&package.name)
+        .map(|dep| dep.req().to_owned()),
+      settings_version,
+      settings: overridden_settings,
+      source: source_type(package).to_owned(),
+      features: context.map(|ctx| ctx.features.clone()).unwrap_or_default(),
+      checksum: context.and_then(|ctx| ctx.sha256.clone()),
+    });
+  }
+  crates.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+
+  Ok(PlanReport {
+    crates,
+    feature_unifications: feature_unifications(metadata),
+  })
+}
+
+/// Lists the dependencies of several workspace members whose unified features are not all
+/// requested by any single one of them. Requested features are expanded through the features
+/// they enable, so that eg: `default` implying `std` is not reported.
+pub fn feature_unifications(metadata: &Metadata) -> Vec<FeatureUnification> {
+  let nodes = match &metadata.resolve {
+    Some(resolve) => &resolve.nodes,
+    None => return Vec::new(),
+  };
+  let packages: BTreeMap<_, _> = metadata
+    .packages
+    .iter()
+    .map(|package| (&package.id, package))
+    .collect();
+
+  // The features each workspace member requests of each of its dependencies
+  let mut requests: BTreeMap<_, BTreeMap<String, BTreeSet<String>>> = BTreeMap::new();
+  for node in nodes
+    .iter()
+    .filter(|node| metadata.workspace_members.contains(&node.id))
+  {
+    let member = match packages.get(&node.id) {
+      Some(member) => member,
+      None => continue,
+    };
+    for dep_id in node.deps.iter().map(|dep| &dep.pkg) {
+      let dep_package = match packages.get(dep_id) {
+        Some(dep_package) => dep_package,
+        None => continue,
+      };
+      let requested = requests
+        .entry(dep_id)
+        .or_default()
+        .entry(member.name.clone())
+        .or_default();
+      for dependency in member.dependencies.iter().filter(|dependency| {
+        dependency.name == dep_package.name && dependency.req.matches(&dep_package.version)
+      }) {
+        requested.extend(dependency.features.iter().cloned());
+        if dependency.uses_default_features && dep_package.features.contains_key("default") {
+          requested.insert("default".to_owned());
+        }
+      }
+    }
+  }
+
+  let mut unifications = Vec::new();
+  for (dep_id, requested_by) in requests {
+    if requested_by.len() < 2 {
+      continue;
+    }
+    let dep_package = packages[dep_id];
+    let expanded: Vec<BTreeSet<String>> = requested_by
+      .values()
+      .map(|features| expand_features(dep_package, features))
+      .collect();
+    let unified: BTreeSet<String> = expanded.iter().flatten().cloned().collect();
+    if expanded.iter().any(|features| features == &unified) {
+      continue;
+    }
+
+    unifications.push(FeatureUnification {
+      name: dep_package.name.clone(),
+      version: dep_package.version.to_string(),
+      features: unified.into_iter().collect(),
+      requested_by: requested_by
+        .into_iter()
+        .map(|(member, features)| (member, features.into_iter().collect()))
+        .collect(),
+    });
+  }
+  unifications.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+  unifications
+}
+
+/// Collects `features` along with every feature of `package` they transitively enable.
+fn expand_features(package: &Package, features: &BTreeSet<String>) -> BTreeSet<String> {
+  let mut expanded = BTreeSet::new();
+  let mut pending: Vec<&str> = features.iter().map(String::as_str).collect();
+  while let Some(feature) = pending.pop() {
+    if !expanded.insert(feature.to_owned()) {
+      continue;
+    }
+    if let Some(enabled) = package.features.get(feature) {
+      pending.extend(
+        enabled
+          .iter()
+          .map(String::as_str)
+          .filter(|enabled| package.features.contains_key(*enabled)),
+      );
+    }
+  }
+  expanded
+}
+
+/// Writes `report` as JSON to `path`.
+pub fn write_report(report: &PlanReport, path: &Path) -> Result<()> {
+  fs::write(path, serde_json::to_string_pretty(report)? + "\n")
+    .with_context(|| format!("Failed to write report to {}", path.display()))
+}
+
+/// Reads a report written by `write_report` from `path`.
+pub fn read_report(path: &Path) -> Result<PlanReport> {
+  let contents = fs::read_to_string(path)
+    .with_context(|| format!("Failed to read report from {}", path.display()))?;
+  serde_json::from_str(&contents)
+    .with_context(|| format!("Failed to parse report from {}", path.display()))
+}
+
+/// Lists the crates added, removed or moved to another version by the plan of `new_report`
+/// compared to that of `old_report`, along with those whose features or checksum changed. A crate
+/// with a single included version in both plans is reported as upgraded or downgraded, any other
+/// change of its versions as the removal and addition of individual versions.
+pub fn compare_reports(old_report: &PlanReport, new_report: &PlanReport) -> Vec<PlanChange> {
+  let old_crates = included_crates(old_report);
+  let new_crates = included_crates(new_report);
+  let no_versions = BTreeMap::new();
+
+  let mut changes = Vec::new();
+  for name in old_crates
+    .keys()
+    .chain(new_crates.keys())
+    .collect::<BTreeSet<_>>()
+  {
+    let old_versions = old_crates.get(name).unwrap_or(&no_versions);
+    let new_versions = new_crates.get(name).unwrap_or(&no_versions);
+    let change = |kind: &'static str, old: &str, new: &str| PlanChange {
+      name: (*name).to_owned(),
+      kind,
+      old: old.to_owned(),
+      new: new.to_owned(),
+    };
+
+    let removed: Vec<_> = old_versions
+      .iter()
+      .filter(|(version, _)| !new_versions.contains_key(*version))
+      .collect();
+    let added: Vec<_> = new_versions
+      .iter()
+      .filter(|(version, _)| !old_versions.contains_key(*version))
+      .collect();
+    match (removed.as_slice(), added.as_slice()) {
+      ([(_, old)], [(_, new)]) if old_versions.len() == 1 && new_versions.len() == 1 => {
+        let is_downgrade = matches!(
+          (Version::parse(&old.version), Version::parse(&new.version)),
+          (Ok(old_version), Ok(new_version)) if new_version < old_version
+        );
+        let kind = if is_downgrade {
+          "downgraded"
+        } else {
+          "upgraded"
+        };
+        changes.push(change(kind, &old.version, &new.version));
+      },
+      _ => {
+        changes.extend(
+          removed
+            .iter()
+            .map(|(_, old)| change("removed", &old.version, "")),
+        );
+        changes.extend(
+          added
+            .iter()
+            .map(|(_, new)| change("added", "", &new.version)),
+        );
+      },
+    }
+
+    for (version, old) in old_versions {
+      let new = match new_versions.get(version) {
+        Some(new) => new,
+        None => continue,
+      };
+      if old.features != new.features {
+        let dropped: Vec<_> = old
+          .features
+          .iter()
+          .filter(|f| !new.features.contains(f))
+          .collect();
+        let enabled: Vec<_> = new
+          .features
+          .iter()
+          .filter(|f| !old.features.contains(f))
+          .collect();
+        changes.push(change(
+          "features",
+          &old.version,
+          &dropped
+            .iter()
+            .map(|feature| format!("-{}", feature))
+            .chain(enabled.iter().map(|feature| format!("+{}", feature)))
+            .join(" "),
+        ));
+      }
+      if old.checksum != new.checksum {
+        let checksum = |decision: &CrateDecision| decision.checksum.clone().unwrap_or_default();
+        changes.push(change("checksum", &checksum(old), &checksum(new)));
+      }
+    }
+  }
+  changes
+}
+
+/// The decisions of the crates included by the plan of `report`, by name and version.
+fn included_crates(report: &PlanReport) -> BTreeMap<&str, BTreeMap<&str, &CrateDecision>> {
+  let mut crates: BTreeMap<&str, BTreeMap<&str, &CrateDecision>> = BTreeMap::new();
+  for decision in report.crates.iter().filter(|decision| decision.included) {
+    crates
+      .entry(&decision.name)
+      .or_default()
+      .insert(&decision.version, decision);
+  }
+  crates
+}
+
+/// Formats `changes` as a table with a row per change, eg: for printing to the terminal.
+pub fn format_changes(changes: &[PlanChange]) -> String {
+  if changes.is_empty() {
+    return "No crates changed\n".to_owned();
+  }
+
+  let header = ["CHANGE", "CRATE", "OLD", "NEW"];
+  let rows: Vec<[&str; 4]> = changes
+    .iter()
+    .map(|change| [change.kind, &change.name, &change.old, &change.new])
+    .collect();
+  let widths: Vec<usize> = (0..header.len())
+    .map(|column| {
+      rows
+        .iter()
+        .chain(std::iter::once(&header))
+        .map(|row| row[column].len())
+        .max()
+        .unwrap_or_default()
+    })
+    .collect();
+
+  let mut table = String::new();
+  for row in std::iter::once(&header).chain(rows.iter()) {
+    let line = row
+      .iter()
+      .zip(&widths)
+      .map(|(cell, width)| format!("{:width$}", cell, width = width))
+      .join("  ");
+    table.push_str(line.trim_end());
+    table.push('\n');
+  }
+  table
+}
+
+/// Collects the fields of `settings` which differ from `default_settings`.
+fn changed_settings(default_settings: &Value, settings: Value) -> BTreeMap<String, Value> {
+  match settings {
+    Value::Object(fields) => fields
+      .into_iter()
+      .filter(|(key, value)| default_settings.get(key) != Some(value))
+      .collect(),
+    _ => BTreeMap::new(),
+  }
+}
+
+fn source_type(package: &Package) -> &'static str {
+  match &package.source {
+    Some(source) if source.repr.starts_with("git+") => "git",
+    Some(_) => "registry",
+    None => "path",
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use semver::VersionReq;
+
+  use super::*;
+  use crate::{
+    planning::{BuildPlanner, BuildPlannerImpl},
+    settings::tests::dummy_raze_settings,
+    testing::*,
+    util::PlatformDetails,
+  };
+
+  #[test]
+  fn test_report_records_excluded_and_overridden_crates() {
+    // Make the resolved `cfg-if` dependency of `log 0.4.13` optional without enabling it
+    let mut raze_metadata =
+      template_raze_metadata(templates::PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES);
+    for package in raze_metadata.metadata.packages.iter_mut() {
+      if package.name == "log" && package.version.to_string() == "0.4.13" {
+        for dep in package.dependencies.iter_mut() {
+          if dep.name == "cfg-if" {
+            dep.optional = true;
+          }
+        }
+      }
+    }
+
+    let mut settings = dummy_raze_settings();
+    let log_settings = CrateSettings {
+      additional_flags: vec!["--cfg=log_overridden".to_owned()],
+      gen_buildrs: Some(false),
+      ..Default::default()
+    };
+    settings.crates.insert(
+      "log".to_owned(),
+      vec![(VersionReq::parse("0.4").unwrap(), log_settings)]
+        .into_iter()
+        .collect(),
+    );
+
+    let planned_build = BuildPlannerImpl::new(raze_metadata.clone(), settings.clone())
+      .plan_build(Some(PlatformDetails::new(
+        "some_target_triple".to_owned(),
+        Vec::new(), /* attrs */
+      )))
+      .unwrap();
+    let report = produce_report(&raze_metadata, &settings, &planned_build).unwrap();
+
+    let decision = |name: &str, version: &str| {
+      report
+        .crates
+        .iter()
+        .find(|decision| decision.name == name && decision.version == version)
+        .unwrap()
+    };
+
+    assert_eq!(
+      decision("cfg-if", "0.1.10"),
+      &CrateDecision {
+        name: "cfg-if".to_owned(),
+        version: "0.1.10".to_owned(),
+        included: false,
+        reason: "optional dependency no resolved feature activates".to_owned(),
+        forced_version: None,
+        settings_version: None,
+        settings: BTreeMap::new(),
+        source: "registry".to_owned(),
+        features: Vec::new(),
+        checksum: None,
+      }
+    );
+    assert_eq!(
+      decision("log", "0.4.13"),
+      &CrateDecision {
+        name: "log".to_owned(),
+        version: "0.4.13".to_owned(),
+        included: true,
+        reason: "dependency of a workspace member".to_owned(),
+        forced_version: None,
+        settings_version: Some(">=0.4.0, <0.5.0".to_owned()),
+        settings: vec![
+          (
+            "additional_flags".to_owned(),
+            serde_json::json!(["--cfg=log_overridden"])
+          ),
+          ("gen_buildrs".to_owned(), serde_json::json!(false)),
+        ]
+        .into_iter()
+        .collect(),
+        source: "registry".to_owned(),
+        features: vec!["std".to_owned()],
+        checksum: None,
+      }
+    );
+    assert_eq!(
+      decision("build_produces_aliased_dependencies", "0.1.0").reason,
+      "workspace member"
+    );
+    // Settings only apply to the versions they match
+    assert!(decision("log", "0.3.9").settings.is_empty());
+  }
+
+  #[test]
+  fn test_feature_unifications_list_disjoint_member_requests() {
+    // Make both workspace members depend on `unicode-xid 0.2.1`, each with a different feature
+    let mut metadata = template_metadata(templates::DUMMY_WORKSPACE_MEMBERS_METADATA);
+    for package in metadata.packages.iter_mut() {
+      let feature = match package.name.as_str() {
+        "lib_a" => "bench",
+        "lib_b" => "no_std",
+        _ => continue,
+      };
+      let dependency = &mut package.dependencies[0];
+      dependency.req = "^0.2.1".parse().unwrap();
+      dependency.features = vec![feature.to_owned()];
+      dependency.uses_default_features = false;
+    }
+    let resolve = metadata.resolve.as_mut().unwrap();
+    let unicode_xid_0_2_1 = resolve
+      .nodes
+      .iter()
+      .find(|node| node.id.repr.starts_with("unicode-xid 0.2.1"))
+      .unwrap()
+      .id
+      .clone();
+    for node in resolve.nodes.iter_mut() {
+      if node.id.repr.starts_with("lib_b") {
+        node.deps[0].pkg = unicode_xid_0_2_1.clone();
+      }
+    }
+
+    assert_eq!(
+      feature_unifications(&metadata),
+      vec![FeatureUnification {
+        name: "unicode-xid".to_owned(),
+        version: "0.2.1".to_owned(),
+        features: vec!["bench".to_owned(), "no_std".to_owned()],
+        requested_by: vec![
+          ("lib_a".to_owned(), vec!["bench".to_owned()]),
+          ("lib_b".to_owned(), vec!["no_std".to_owned()]),
+        ]
+        .into_iter()
+        .collect(),
+      }]
+    );
+
+    // Members requesting the same features are not reported
+    for package in metadata.packages.iter_mut() {
+      if package.name == "lib_b" {
+        package.dependencies[0].features = vec!["bench".to_owned()];
+      }
+    }
+    assert!(feature_unifications(&metadata).is_empty());
+  }
+
+  #[test]
+  fn test_compared_reports_list_upgraded_and_added_crates() {
+    let decision = |name: &str, version: &str, checksum: &str| CrateDecision {
+      name: name.to_owned(),
+      version: version.to_owned(),
+      included: true,
+      reason: "dependency of a workspace member".to_owned(),
+      forced_version: None,
+      settings_version: None,
+      settings: BTreeMap::new(),
+      source: "registry".to_owned(),
+      features: vec!["std".to_owned()],
+      checksum: Some(checksum.to_owned()),
+    };
+    let old_report = PlanReport {
+      crates: vec![
+        decision("cfg-if", "1.0.0", "aaaa"),
+        decision("log", "0.4.13", "bbbb"),
+      ],
+      feature_unifications: Vec::new(),
+    };
+
+    // The old plan is read back from its exported form
+    let dir = tempfile::TempDir::new().unwrap();
+    let old_report_path = dir.as_ref().join("old-plan.json");
+    write_report(&old_report, &old_report_path).unwrap();
+    let old_report = read_report(&old_report_path).unwrap();
+
+    let new_report = PlanReport {
+      crates: vec![
+        decision("cfg-if", "1.0.0", "aaaa"),
+        decision("log", "0.4.20", "cccc"),
+        decision("value-bag", "1.4.1", "dddd"),
+      ],
+      feature_unifications: Vec::new(),
+    };
+    let changes = compare_reports(&old_report, &new_report);
+    assert_eq!(
+      changes,
+      vec![
+        PlanChange {
+          name: "log".to_owned(),
+          kind: "upgraded",
+          old: "0.4.13".to_owned(),
+          new: "0.4.20".to_owned(),
+        },
+        PlanChange {
+          name: "value-bag".to_owned(),
+          kind: "added",
+          old: "".to_owned(),
+          new: "1.4.1".to_owned(),
+        },
+      ]
+    );
+    assert_eq!(
+      format_changes(&changes),
+      concat!(
+        "CHANGE    CRATE      OLD     NEW\n",
+        "upgraded  log        0.4.13  0.4.20\n",
+        "added     value-bag          1.4.1\n",
+      )
+    );
+
+    // Changes within a version are listed too
+    let mut rebuilt_report = read_report(&old_report_path).unwrap();
+    rebuilt_report.crates[1].features = vec!["kv_unstable".to_owned()];
+    rebuilt_report.crates[1].checksum = Some("eeee".to_owned());
+    assert_eq!(
+      compare_reports(&old_report, &rebuilt_report)
+        .into_iter()
+        .map(|change| (change.kind, change.new))
+        .collect::<Vec<_>>(),
+      vec![
+        ("features", "-std +kv_unstable".to_owned()),
+        ("checksum", "eeee".to_owned())
+      ]
+    );
+  }
+}
@@ -14,15 +14,18 @@
 
 use crate::{
   error::RazeError,
-  metadata::{MetadataFetcher, DEFAULT_CRATE_INDEX_URL, DEFAULT_CRATE_REGISTRY_URL},
+  metadata::{
+    exec_metadata_command, MetadataFetcher, DEFAULT_CRATE_INDEX_URL, DEFAULT_CRATE_REGISTRY_URL,
+    DEFAULT_MAX_CONCURRENT_DOWNLOADS,
+  },
   util,
 };
 use anyhow::{anyhow, bail, Context, Result};
 use cargo_metadata::{Metadata, MetadataCommand, Package};
-use semver::VersionReq;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
 use std::{
-  collections::HashMap,
+  collections::{BTreeMap, HashMap},
   hash::Hash,
   path::{Path, PathBuf},
 };
@@ -30,7 +33,7 @@ use std::{
 pub type CrateSettingsPerVersion = HashMap<VersionReq, CrateSettings>;
 
 /// The configuration settings for `cargo-raze`, included in a projects Cargo metadata
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct RazeSettings {
   /// The path to write BUILD file outputs to.
   ///
@@ -69,7 +72,7 @@ pub struct RazeSettings {
   pub binary_deps: HashMap<String, cargo_toml::Dependency>,
 
   /// Any crate-specific configuration. See CrateSettings for details.
-  #[serde(default)]
+  #[serde(default, deserialize_with = "deserialize_per_version")]
   pub crates: HashMap<String, CrateSettingsPerVersion>,
 
   // TODO(acmcarther): Does this have a non-bazel analogue?
@@ -93,6 +96,9 @@ pub struct RazeSettings {
 
   /// Default value for per-crate gen_buildrs setting if it's not explicitly for a crate.
   ///
+  /// Build scripts are generated regardless for crates whose library includes files from
+  /// `OUT_DIR`, as these can't be compiled otherwise.
+  ///
   /// See [crate::settings::CrateSettings::gen_buildrs] for more information.
   #[serde(default = "default_raze_settings_field_gen_buildrs")]
   pub default_gen_buildrs: bool,
@@ -102,10 +108,17 @@ pub struct RazeSettings {
   /// The patterns `{crate}` and `{version}` will be used to fill
   /// in the package's name (eg: rand) and version (eg: 0.7.1).
   /// See https://doc.rust-lang.org/cargo/reference/registries.html#index-format
+  ///
+  /// Crates from any other registry are downloaded from the `dl` endpoint in the `config.json` of
+  /// that registry's index.
   #[serde(default = "default_raze_settings_registry")]
   pub registry: String,
 
   /// The index url to use for Binary dependencies
+  ///
+  /// Indexes served over the sparse HTTP protocol are prefixed with `sparse+`, eg:
+  /// `sparse+https://index.crates.io/`. Only the files for the crates which are needed are fetched
+  /// from those, and they are cached between runs unless the metadata cache is disabled.
   #[serde(default = "default_raze_settings_index_url")]
   pub index_url: String,
 
@@ -114,6 +127,28 @@ pub struct RazeSettings {
   #[serde(default = "default_raze_settings_rust_rules_workspace_name")]
   pub rust_rules_workspace_name: String,
 
+  /// The label of the `.bzl` file the generated files load the `rust_*` rules from, eg: one
+  /// exporting wrapper macros. Default: `@{rust_rules_workspace_name}//rust:rust.bzl`
+  #[serde(default)]
+  pub rust_rules_bzl: Option<String>,
+
+  /// The label of the `.bzl` file the generated files load `cargo_build_script` from. Default:
+  /// `@{rust_rules_workspace_name}//cargo:cargo_build_script.bzl`
+  #[serde(default)]
+  pub cargo_build_script_bzl: Option<String>,
+
+  /// A mapping of the rules of `rules_rust` the generated files use to the names they are loaded
+  /// and called by instead, eg: `{ rust_library = "my_rust_library" }`.
+  #[serde(default)]
+  pub rule_names: HashMap<String, String>,
+
+  /// The version of `rules_rust` the generated files are used with, eg: `0.3.0`. From `0.3.0`,
+  /// the unit and doc tests of a lib refer to it through the `crate` attribute of `rust_test` and
+  /// `rust_doc_test`. Earlier versions take it as the `dep` of `rust_doc_test`, and compile unit
+  /// tests from the lib's sources. Default: an earlier version
+  #[serde(default)]
+  pub rules_rust_version: Option<Version>,
+
   /// The expected path relative to the `Cargo.toml` file where vendored sources can
   /// be found. This should match the path passed to the `cargo vendor` command. eg:
   /// `cargo vendor -q --versioned-dirs "cargo/vendor"
@@ -126,6 +161,289 @@ pub struct RazeSettings {
    */
   #[serde(default = "default_raze_settings_experimental_api")]
   pub experimental_api: bool,
+
+  /// A list of hosts generated source urls are allowed to point at.
+  ///
+  /// When non-empty, rendering fails for any crate whose `http_archive` or `new_git_repository`
+  /// url has a host outside of this list. An empty list allows all hosts.
+  #[serde(default)]
+  pub allowed_source_hosts: Vec<String>,
+
+  /// Environment variables to be added to the compilation process of every crate.
+  ///
+  /// Variables set by a crate's own `additional_env` take precedence over these.
+  #[serde(default)]
+  pub global_rustc_env: HashMap<String, String>,
+
+  /// If true, a `checksums.txt` file listing the version, sha256 and source url of every crate in
+  /// the plan is written next to the generated files.
+  #[serde(default)]
+  pub checksum_manifest: bool,
+
+  /// If true, a `NOTICE` file aggregating the license texts shipped by every crate in the plan is
+  /// written next to the generated files, for attribution when distributing the build's outputs.
+  #[serde(default)]
+  pub notice_file: bool,
+
+  /// If true, `rules_license` rules are generated for every third-party crate from the SPDX
+  /// expression of its license, in a `licenses/BUILD.bazel` file next to the generated files. The
+  /// crates' rules list them as their `applicable_licenses`.
+  #[serde(default)]
+  pub license_rules: bool,
+
+  /// A mapping of crate names to the SPDX expression used in place of the license declared by the
+  /// crate, eg: `{ ring = "ISC AND MIT AND OpenSSL" }` for crates with nonstandard license strings.
+  #[serde(default)]
+  pub license_overrides: HashMap<String, String>,
+
+  /// If true, a `defs.bzl` file re-exporting the rules and macros the generated BUILD files use is
+  /// written to the output directory, and each generated BUILD file loads everything from it
+  /// instead of from `rules_rust` and `bazel_skylib` directly.
+  #[serde(default)]
+  pub defs_bzl: bool,
+
+  /// If true, Remote genmode additionally writes a module extension declaring the repositories of
+  /// the fetched crates to `extensions.bzl`, along with a `crates.MODULE.bazel` snippet bringing
+  /// them into scope with `use_repo`, so they can be consumed from a `MODULE.bazel` file rather
+  /// than by calling the repositories function from a WORKSPACE file.
+  #[serde(default)]
+  pub bzlmod: bool,
+
+  /// If true, a BUILD file is also generated in the package of each member of the cargo workspace,
+  /// defining the member's targets with dependencies on the other members and on the crates
+  /// generated into `workspace_path`. This overwrites any BUILD file the members already have.
+  ///
+  /// Members are labeled by their path relative to the Bazel workspace containing the cargo
+  /// workspace, or relative to the cargo workspace when it is not inside a Bazel workspace.
+  #[serde(default)]
+  pub render_workspace_members: bool,
+
+  /// If set, every crate's rules pass rustc a `--remap-path-prefix` mapping the crate's sources in
+  /// the Bazel execution root to this placeholder, so paths embedded in compiled artifacts are
+  /// deterministic. `{name}` and `{version}` are replaced with those of the crate, eg:
+  /// `"/cargo-raze/{name}-{version}"`.
+  #[serde(default)]
+  pub remap_path_prefix: Option<String>,
+
+  /// If true, the `deps` of each generated rule are split into commented first-party and
+  /// third-party sections, the former holding the crate's own targets and path or workspace member
+  /// crates, so the two kinds of edges are easy to tell apart during review.
+  #[serde(default)]
+  pub group_deps: bool,
+
+  /// Tags added to the rules of every proc-macro crate, eg: to have them built with the host
+  /// toolchain a custom proc-macro server expects.
+  #[serde(default)]
+  pub proc_macro_tags: Vec<String>,
+
+  /// Labels of toolchains the rules of every proc-macro crate require through their `toolchains`
+  /// attribute, eg: a toolchain providing the proc-macro server they are expanded with.
+  #[serde(default)]
+  pub proc_macro_toolchains: Vec<String>,
+
+  /// The name of the rule the library of every crate is rendered as, which dependents refer to it
+  /// by, eg: `lib` for labels like `@raze__foo__1_0_0//:lib`. Any `{name}` in the value is
+  /// replaced by the crate's sanitized name. Libraries are named after the crate if unset.
+  #[serde(default)]
+  pub library_target_name: Option<String>,
+
+  /// The path, relative to the cargo workspace root, of a TOML file holding further per-crate
+  /// `additional_deps` and `skipped_deps` to merge into `crates`.
+  ///
+  /// Entries are keyed the same way as `crates`, eg: `[crates.openssl-sys."0.9.24"]`, which allows
+  /// large sets of dependency overrides to be maintained outside of the manifest.
+  #[serde(default)]
+  pub crate_overrides_file: Option<String>,
+
+  /// The path, relative to the cargo workspace root, of a directory of Tera templates to render
+  /// with instead of the built-in ones.
+  ///
+  /// Templates are matched by their path within the directory, eg: `crate.BUILD.template`,
+  /// `remote_crates.bzl.template`, `workspace.BUILD.template` or
+  /// `partials/common_attrs.template`. Built-in templates without a replacement are still used,
+  /// and further templates may be added for the others to include as
+  /// `templates/<path within the directory>`. Templates are passed the same context as the
+  /// built-in ones, eg: the full crate context as `crate`.
+  #[serde(default)]
+  pub templates_dir: Option<String>,
+
+  /// The number of crates the longest chain of dependencies may span before a warning listing
+  /// that chain is emitted. This gives an early signal of dependency graph bloat.
+  #[serde(default)]
+  pub max_dependency_depth: Option<usize>,
+
+  /// A mapping of source hosts to the authentication Bazel should use when downloading crates
+  /// from them, eg: `[package.metadata.raze.source_auth."mirror.example.com"]`.
+  ///
+  /// Crates fetched from any other host are downloaded without authentication.
+  #[serde(default)]
+  pub source_auth: HashMap<String, SourceAuth>,
+
+  /// A mapping of target triples to the sysroot crates are compiled against on those platforms,
+  /// eg: `[package.metadata.raze.target_sysroot.thumbv7em-none-eabihf]`.
+  ///
+  /// The flags are rendered into a `select` on the platform within every crate's `rustc_flags`.
+  #[serde(default)]
+  pub target_sysroot: HashMap<String, TargetSysroot>,
+
+  /// Custom targets described by target-spec JSON files rather than built into rustc, keyed by the
+  /// name they are referred to by like a triple, eg: in `targets` or `target_sysroot`.
+  ///
+  /// Platform specific dependencies and features are gated on each custom target according to the
+  /// properties its spec declares, like `target_os`, `target_arch` or `target_pointer_width`.
+  #[serde(default)]
+  pub custom_targets: BTreeMap<String, CustomTarget>,
+
+  /// A list of the only crates which may appear in the dependency graph, each in the form
+  /// "{name}" or "{name} {version requirement}", eg: `"log 0.4"`.
+  ///
+  /// When non-empty, planning fails for any resolved crate outside of this list. Workspace members
+  /// are always allowed. An empty list allows all crates.
+  #[serde(default)]
+  pub allowed_crates: Vec<String>,
+
+  /// If true, the archives of binary dependencies downloaded from the registry are checked
+  /// against the sha256 checksum listed for them in `index_url`, and in the reused lockfile if it
+  /// lists one, failing on any mismatch. The `--allow-unverified` flag skips these checks.
+  #[serde(default = "default_raze_settings_verify_download_checksums")]
+  pub verify_download_checksums: bool,
+
+  /// The number of binary dependency archives downloaded from the registry at a time. The
+  /// `--jobs` flag overrides this.
+  #[serde(default = "default_raze_settings_max_concurrent_downloads")]
+  pub max_concurrent_downloads: usize,
+
+  /// Registry urls, in the form of `registry`, which the archives of binary dependencies are
+  /// downloaded from in order whenever the download from `registry` fails, including when the
+  /// archive does not match its checksum. Downloads only fail once every mirror was tried.
+  #[serde(default)]
+  pub download_mirrors: Vec<String>,
+
+  /// If true, a binary dependency whose archive can't be downloaded from `registry` or any of
+  /// the `download_mirrors` is cloned from the `repository` the registry lists for it, at the tag
+  /// of its version, eg: `v1.2.3`. It is then generated as a `new_git_repository` rule, and its
+  /// source is not checked against its checksum.
+  #[serde(default)]
+  pub git_fallback: bool,
+
+  /// A command, as a program followed by its arguments, yielding the bearer token of the registry
+  /// or index host it is given as its last argument. Requests to hosts it prints nothing for are
+  /// authenticated with the credentials of the netrc file, `$NETRC` or else `~/.netrc`, if any.
+  #[serde(default)]
+  pub credential_command: Vec<String>,
+
+  /// The number of seconds after which the `cargo metadata` run resolving the workspace is
+  /// aborted. By default, it may run for as long as it takes.
+  #[serde(default)]
+  pub metadata_timeout_secs: Option<u64>,
+
+  /// If true, the checksums the lockfile and `index_url` list for crates from crates.io are
+  /// cross-checked against crates.io's own index, failing on any mismatch. This catches a
+  /// tampered mirror even if the lockfile was altered to match it.
+  #[serde(default)]
+  pub verify_against_upstream_index: bool,
+
+  /// If true, cargo-raze fails instead of accessing the network. `index_url` is then read from
+  /// disk if it is a local `file://` index, or else from cargo's own cache of it in
+  /// `$CARGO_HOME/registry/index`. Binary dependencies are read from `crate_cache_dir`, or else
+  /// from `$CARGO_HOME/registry/cache`, as populated by `cargo fetch`.
+  #[serde(default)]
+  pub offline: bool,
+
+  /// A directory of `{name}-{version}.crate` archives, such as cargo's `registry/cache`
+  /// directories, which binary dependencies are read from when `offline`. Relative paths are
+  /// relative to the cargo workspace root.
+  #[serde(default)]
+  pub crate_cache_dir: Option<String>,
+
+  /// The name of the function generated in crates.bzl which defines the crate repositories, so
+  /// crates.bzl files of several raze workspaces can be loaded together.
+  ///
+  /// Default: {gen_workspace_prefix}_fetch_remote_crates
+  #[serde(default)]
+  pub repositories_function_name: Option<String>,
+
+  /// Named sets of features to resolve the workspace with, selected with `--feature-profile`, eg:
+  /// `[package.metadata.raze.feature_profiles] embedded = ["mycrate/no_std"]`.
+  ///
+  /// Entries take the same form as those of `--features`.
+  #[serde(default)]
+  pub feature_profiles: HashMap<String, Vec<String>>,
+
+  /// If true, the crates of the plan are checked against the RustSec advisory database and any
+  /// yanked versions are reported. The `--audit` flag enables this as well.
+  ///
+  /// Yanked versions are found in cargo's own cache of the registry index, as kept up to date by
+  /// cargo itself.
+  #[serde(default)]
+  pub audit: bool,
+
+  /// Whether crates with advisories or yanked versions found by `audit` fail planning (`Deny`) or
+  /// are only reported (`Warn`). Informational advisories, eg: of unmaintained crates, are always
+  /// only reported.
+  #[serde(default = "default_raze_settings_audit_policy")]
+  pub audit_policy: AuditPolicy,
+
+  /// A local checkout of the RustSec advisory database (https://github.com/RustSec/advisory-db)
+  /// for `audit` to read, which is used as is. Relative paths are relative to the cargo workspace
+  /// root.
+  ///
+  /// Default: `$CARGO_HOME/advisory-db`, as used by cargo-audit, which is cloned or updated on
+  /// every run unless `offline`
+  #[serde(default)]
+  pub advisory_db: Option<String>,
+
+  /// If true, the sources of vendored crates are stripped of files which are not needed to build
+  /// them, ie: their `tests/`, `benches/` and `examples/` directories, along with the files
+  /// matching each crate's `exclude_globs`. Directories holding the sources of a generated
+  /// target, eg: the `[[test]]` targets of crates with `gen_tests`, are kept.
+  #[serde(default)]
+  pub filter_vendored_sources: bool,
+}
+
+/// The sysroot to cross-compile crates for a target triple against (as part of `RazeSettings`).
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TargetSysroot {
+  /// The path of the sysroot, passed to rustc as `--sysroot=<path>`.
+  pub sysroot: String,
+
+  /// Arguments passed on to the linker through `-C link-arg=<arg>`, eg: `-L<sysroot>/lib`.
+  #[serde(default)]
+  pub link_args: Vec<String>,
+}
+
+/// A target described by a target-spec JSON file instead of being built into rustc (as part of
+/// `RazeSettings`), eg: for bare-metal platforms.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CustomTarget {
+  /// The path of the target-spec JSON, relative to the cargo workspace root, eg: as written by
+  /// `rustc --print target-spec-json`.
+  pub spec: String,
+
+  /// The label of the `config_setting` matching the target's Bazel platform, which `select`
+  /// statements gate the target's dependencies and features on.
+  pub condition: String,
+
+  /// The properties read from `spec` when the settings are loaded.
+  #[serde(skip)]
+  pub properties: util::TargetSpec,
+}
+
+/// How Bazel authenticates the `http_archive` downloads of a source host (as part of
+/// `RazeSettings`).
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SourceAuth {
+  /// The path of a `.netrc` file holding the credentials for the host.
+  #[serde(default)]
+  pub netrc: Option<String>,
+
+  /// The `auth_patterns` entry of the host, eg: `Bearer <password>`, where `<password>` is
+  /// substituted with the password of the host's `.netrc` entry.
+  #[serde(default)]
+  pub auth_pattern: Option<String>,
 }
 
 /// Override settings for individual crates (as part of `RazeSettings`).
@@ -176,6 +494,9 @@ pub struct CrateSettings {
   pub data_attr: Option<String>,
 
   /// A list of targets for the `data` attribute of a Rust target
+  ///
+  /// Paths to files of another generated crate, in the form `../{name}-{version}/{path}`, are
+  /// rendered as labels on that crate's package.
   #[serde(default)]
   pub data_dependencies: Vec<String>,
 
@@ -183,6 +504,16 @@ pub struct CrateSettings {
   #[serde(default)]
   pub compile_data_attr: Option<String>,
 
+  /// Globs, relative to the crate's manifest, of files the crate accesses through paths based on
+  /// `CARGO_MANIFEST_DIR`, eg: `["assets/**"]` for `include_bytes!(concat!(env!(
+  /// "CARGO_MANIFEST_DIR"), "/assets/x"))`.
+  ///
+  /// Matching files are added to both the `data` and `compile_data` of the generated targets, and
+  /// `CARGO_MANIFEST_DIR` is set to the crate's directory in Bazel's execution root, where they are
+  /// found at the same relative paths.
+  #[serde(default)]
+  pub manifest_dir_data: Vec<String>,
+
   /// The `data` attribute for buildrs targets
   #[serde(default)]
   pub build_data_dependencies: Vec<String>,
@@ -223,6 +554,8 @@ pub struct CrateSettings {
   ///
   /// If this is specified, Bazel will use the specifed patch tool instead of the Bazel-native patch
   /// implementation.
+  ///
+  /// cargo-raze uses this tool (or `patch` if unset) to apply `patches` to vendored crates.
   #[serde(default)]
   pub patch_tool: Option<String>,
 
@@ -231,6 +564,10 @@ pub struct CrateSettings {
   /// By default, it uses the Bazel-native patch implementation which doesn't support fuzz match and
   /// binary patch, but Bazel will fall back to use patch command line tool if `patch_tool`
   /// attribute is specified or there are arguments other than `-p` in `patch_args` attribute.
+  ///
+  /// In Vendored genmode, cargo-raze applies these with `patch_tool` and `patch_args` to the
+  /// sources in the vendor directory instead, skipping patches which are applied already. These
+  /// must be labels of files in the main repository, eg: `//patches:fix.patch`.
   #[serde(default)]
   pub patches: Vec<String>,
 
@@ -244,10 +581,173 @@ pub struct CrateSettings {
   /// context, see https://doc.rust-lang.org/cargo/reference/workspaces.html#root-package
   #[serde(default)]
   pub additional_build_file: Option<PathBuf>,
+
+  /// Categories to tag the generated targets with, in addition to the `categories` declared in
+  /// the crate's manifest.
+  ///
+  /// Each category is rendered as a `category={name}` tag.
+  #[serde(default)]
+  pub categories: Vec<String>,
+
+  /// Whether to generate `rust_test` targets for the crate's lib unit tests and `[[test]]`
+  /// targets, along with a `rust_doc_test` target for its lib.
+  ///
+  /// The test targets additionally depend on the crate's dev-dependencies.
+  #[serde(default)]
+  pub gen_tests: bool,
+
+  /// Whether to generate a `rust_doc_test` target for the crate's lib, running the examples of
+  /// its documentation. This allows rendering doc tests without unit tests, or the other way
+  /// around.
+  ///
+  /// Default: the value of `gen_tests`
+  #[serde(default)]
+  pub gen_doc_tests: Option<bool>,
+
+  /// The number of shards to split the crate's generated `rust_test` targets into.
+  ///
+  /// Setting this value also renders the integration test targets of crates which do not set
+  /// `gen_tests`, as `rust_test` rules with a matching `shard_count` attribute. Unit tests of the
+  /// crate's lib are only rendered with `gen_tests`.
+  #[serde(default)]
+  pub test_shard_count: Option<u32>,
+
+  /// Additional attributes to set on the generated `rust_library` and `rust_binary` rules, but not
+  /// their `rust_test` rules, mapping attribute names to verbatim single line Starlark values.
+  ///
+  /// This allows setting rules_rust attributes that cargo-raze does not otherwise know about.
+  /// Attributes cargo-raze already sets, such as `srcs` or `deps`, can't be set this way.
+  #[serde(default)]
+  pub extra_rule_attributes: HashMap<String, String>,
+
+  /// Whether or not the crate's archive extracts its contents directly, without the usual
+  /// `{name}-{version}` top level directory.
+  ///
+  /// When set, no `strip_prefix` is rendered for the crate's `http_archive`.
+  #[serde(default)]
+  pub archive_has_no_prefix: bool,
+
+  /// Linker flags to add to the generated Rust targets as `linkopts`.
+  #[serde(default)]
+  pub linkopts: Vec<String>,
+
+  /// Glob patterns of files to leave out of the `srcs` of the generated Rust targets, such as
+  /// editor backups or generated scratch files matching `**/*.rs`.
+  #[serde(default)]
+  pub srcs_exclude: Vec<String>,
+
+  /// Glob patterns of files to remove from the crate's vendored sources, relative to the crate's
+  /// directory, eg: `testdata/**`. These are removed with or without `filter_vendored_sources`.
+  #[serde(default)]
+  pub exclude_globs: Vec<String>,
+
+  /// If true, none of the crate's vendored sources are removed by `filter_vendored_sources` or
+  /// `exclude_globs`, eg: for crates whose build script reads their test data.
+  #[serde(default)]
+  pub vendor_everything: bool,
+
+  /// Overrides the global `genmode` for this crate, allowing a mix of vendored and remote crates
+  /// in a single dependency graph.
+  #[serde(default)]
+  pub gen_mode: Option<GenMode>,
+
+  /// Whether or not the build script should run with Bazel's default shell environment, for build
+  /// scripts which are unable to run with the minimal environment.
+  #[serde(default)]
+  pub build_script_use_default_shell_env: bool,
+
+  /// Features which are toggled at build time, mapped to the label of the `config_setting` which
+  /// enables them.
+  ///
+  /// Rather than being enabled unconditionally, these features (and the optional dependencies
+  /// only they pull in) are rendered in a `select` keyed on their `config_setting`.
+  #[serde(default)]
+  pub feature_config_settings: HashMap<String, String>,
+
+  /// Additional rustc flags to use for each of Bazel's compilation modes (`dbg`, `fastbuild` or
+  /// `opt`), eg: `{ dbg = ["-Cdebug-assertions=on"] }`.
+  ///
+  /// These are rendered into a `select` on `config_setting`s matching the `compilation_mode`.
+  #[serde(default)]
+  pub compilation_mode_rustc_flags: HashMap<String, Vec<String>>,
+
+  /// Whether or not the crate's generated BUILD file should be inlined into the
+  /// `build_file_content` of its `http_archive` or `new_git_repository` rule instead of being
+  /// written to a separate file.
+  ///
+  /// This only applies in Remote genmode, where it allows for compact, single file repository
+  /// definitions.
+  #[serde(default)]
+  pub inline_build_file: bool,
+
+  /// A notice rendered as the `deprecation` attribute of the crate's rules, making Bazel warn
+  /// whenever they are depended upon. This helps migrating a workspace off of a crate.
+  #[serde(default)]
+  pub deprecation: Option<String>,
+
+  /// Configuration options passed to rustc as `--cfg={cfg}`, eg: `has_foo` or `feature="bar"`.
+  ///
+  /// This replicates the `cargo:rustc-cfg` output of a crate's build script, which is skipped
+  /// when these are set unless `gen_buildrs` is explicitly enabled for the crate.
+  #[serde(default)]
+  pub static_rustc_cfgs: Vec<String>,
+
+  /// A mapping of target triples to the Rust edition the crate is compiled with on those
+  /// platforms, eg: `{ "x86_64-unknown-linux-gnu" = "2021" }`.
+  ///
+  /// When any of these differ from the crate's own edition, the `edition` of its rules becomes a
+  /// `select` on the platform, defaulting to the crate's own edition.
+  #[serde(default)]
+  pub edition_per_target: HashMap<String, String>,
+
+  /// A mapping of target triples to the verbatim `compile_data` clause added to the crate's rules
+  /// on those platforms, eg: `{ "x86_64-pc-windows-msvc" = "glob([\"lib/windows/**\"])" }`.
+  #[serde(default)]
+  pub compile_data_attrs: HashMap<String, String>,
+
+  /// A mapping of target triples to the rustc flags added to the crate's rules on those
+  /// platforms, in addition to `additional_flags`, eg: `{ "x86_64-apple-darwin" = ["--cfg=mac"] }`.
+  #[serde(default)]
+  pub rustc_flags: HashMap<String, Vec<String>>,
+
+  /// A mapping of target triples to the environment variables set when compiling the crate's
+  /// rules on those platforms. These take precedence over `additional_env`.
+  #[serde(default)]
+  pub rustc_env: HashMap<String, HashMap<String, String>>,
+
+  /// A mapping of target triples to the environment variables set when running the crate's build
+  /// script on those platforms, eg: `{ "x86_64-unknown-linux-gnu" = { OPENSSL_STATIC = "1" } }`.
+  /// These take precedence over `buildrs_additional_environment_variables`.
+  #[serde(default)]
+  pub build_script_env: HashMap<String, HashMap<String, String>>,
+
+  /// Default arguments rendered as the `args` of the crate's `rust_binary` rules, which are
+  /// passed to the binaries on `bazel run`.
+  #[serde(default)]
+  pub binary_args: Vec<String>,
+
+  /// The registry the crate is downloaded from in Remote genmode, in the form of the global
+  /// `registry`, eg: for crates mirrored on an internal registry. This overrides the download url
+  /// of the registry the lockfile resolved the crate from.
+  ///
+  /// Requests to the registry's host are authenticated per the matching `source_auth` entry.
+  #[serde(default)]
+  pub registry: Option<String>,
+
+  /// The `shallow_since` date of the crate's `new_git_repository` rule in Remote genmode, for
+  /// crates fetched from git. This allows Bazel to shallow clone the repository up to the pinned
+  /// commit rather than fetching its full history, eg: "2021-03-14".
+  #[serde(default)]
+  pub git_shallow_since: Option<String>,
+
+  /// The ids of RustSec advisories which `audit` ignores for the crate, eg: `RUSTSEC-2020-0071`.
+  /// `yanked` ignores the crate's version having been yanked.
+  #[serde(default)]
+  pub audit_ignore: Vec<String>,
 }
 
 /// Describes how dependencies should be managed in tree.
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub enum GenMode {
   /// This mode assumes that files are vendored (into vendor/), and generates BUILD files
   /// accordingly
@@ -259,6 +759,15 @@ pub enum GenMode {
   Unspecified,
 }
 
+/// How the findings of `audit` are handled (as part of `RazeSettings`).
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum AuditPolicy {
+  /// Findings are printed as warnings
+  Warn,
+  /// Findings fail planning
+  Deny,
+}
+
 impl Default for CrateSettings {
   fn default() -> Self {
     Self {
@@ -271,6 +780,7 @@ impl Default for CrateSettings {
       data_attr: default_crate_settings_field_data_attr(),
       data_dependencies: Vec::new(),
       compile_data_attr: None,
+      manifest_dir_data: Vec::new(),
       build_data_dependencies: Vec::new(),
       buildrs_additional_deps: Vec::new(),
       buildrs_additional_environment_variables: HashMap::new(),
@@ -280,6 +790,32 @@ impl Default for CrateSettings {
       patch_tool: None,
       patches: Vec::new(),
       additional_build_file: None,
+      categories: Vec::new(),
+      gen_tests: false,
+      gen_doc_tests: None,
+      test_shard_count: None,
+      extra_rule_attributes: HashMap::new(),
+      archive_has_no_prefix: false,
+      linkopts: Vec::new(),
+      srcs_exclude: Vec::new(),
+      exclude_globs: Vec::new(),
+      vendor_everything: false,
+      gen_mode: None,
+      build_script_use_default_shell_env: false,
+      feature_config_settings: HashMap::new(),
+      compilation_mode_rustc_flags: HashMap::new(),
+      inline_build_file: false,
+      deprecation: None,
+      static_rustc_cfgs: Vec::new(),
+      edition_per_target: HashMap::new(),
+      compile_data_attrs: HashMap::new(),
+      rustc_flags: HashMap::new(),
+      rustc_env: HashMap::new(),
+      build_script_env: HashMap::new(),
+      binary_args: Vec::new(),
+      registry: None,
+      git_shallow_since: None,
+      audit_ignore: Vec::new(),
     }
   }
 }
@@ -323,6 +859,18 @@ fn default_raze_settings_experimental_api() -> bool {
   false
 }
 
+fn default_raze_settings_verify_download_checksums() -> bool {
+  true
+}
+
+fn default_raze_settings_max_concurrent_downloads() -> usize {
+  DEFAULT_MAX_CONCURRENT_DOWNLOADS
+}
+
+fn default_raze_settings_audit_policy() -> AuditPolicy {
+  AuditPolicy::Warn
+}
+
 fn default_crate_settings_field_gen_buildrs() -> Option<bool> {
   None
 }
@@ -346,6 +894,43 @@ pub fn format_registry_url(registry_url: &str, name: &str, version: &str) -> Str
     .replace("{version}", version)
 }
 
+/// Yields the genmode for the given package, honoring any `gen_mode` override of the crate settings
+/// selected for its version by `matching_version_entries`
+pub fn crate_genmode(settings: &RazeSettings, package: &Package) -> GenMode {
+  settings
+    .crates
+    .get(&package.name)
+    .and_then(|per_version| {
+      matching_version_entries(per_version, &package.version)
+        .into_iter()
+        .find_map(|(_, crate_settings)| crate_settings.gen_mode.clone())
+    })
+    .unwrap_or_else(|| settings.genmode.clone())
+}
+
+/// Yields the features of the named entry of `feature_profiles`
+pub fn feature_profile<'a>(
+  settings: &'a RazeSettings,
+  profile: &str,
+) -> Result<&'a Vec<String>, RazeError> {
+  settings.feature_profiles.get(profile).ok_or_else(|| {
+    let mut profiles: Vec<&str> = settings
+      .feature_profiles
+      .keys()
+      .map(String::as_str)
+      .collect();
+    profiles.sort_unstable();
+    RazeError::Config {
+      field_path_opt: Some("raze.feature_profiles".to_owned()),
+      message: format!(
+        "Unknown feature profile `{}`, the available profiles are: [{}]",
+        profile,
+        profiles.join(", ")
+      ),
+    }
+  })
+}
+
 /// Check that the the `additional_build_file` represents a path to a file from the cargo workspace root
 fn validate_crate_setting_additional_build_file(
   additional_build_file: &Path,
@@ -363,6 +948,135 @@ fn validate_crate_setting_additional_build_file(
   Ok(())
 }
 
+/// The values of Bazel's `--compilation_mode` option
+const BAZEL_COMPILATION_MODES: &[&str] = &["dbg", "fastbuild", "opt"];
+
+const RUST_EDITIONS: &[&str] = &["2015", "2018", "2021"];
+
+/// The rules of `rules_rust` the generated files use, which `rule_names` can map to other names
+pub const RUST_RULES: &[&str] = &[
+  "cargo_build_script",
+  "rust_binary",
+  "rust_doc_test",
+  "rust_library",
+  "rust_test",
+];
+
+/// The attributes the generated Rust rules may already set, which `extra_rule_attributes` can't
+/// set a second time
+const GENERATED_RULE_ATTRIBUTES: &[&str] = &[
+  "aliases",
+  "applicable_licenses",
+  "args",
+  "compile_data",
+  "crate",
+  "crate_features",
+  "crate_name",
+  "crate_root",
+  "crate_type",
+  "data",
+  "dep",
+  "deprecation",
+  "deps",
+  "edition",
+  "linkopts",
+  "name",
+  "proc_macro_deps",
+  "rustc_env",
+  "rustc_flags",
+  "shard_count",
+  "srcs",
+  "tags",
+  "target_compatible_with",
+  "toolchains",
+  "version",
+];
+
+/// Whether `name` can name a function or attribute in a .bzl file
+fn is_starlark_identifier(name: &str) -> bool {
+  let mut chars = name.chars();
+  match chars.next() {
+    Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+      chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    },
+    _ => false,
+  }
+}
+
+/// Check that an `extra_rule_attributes` entry is a valid Starlark attribute assignment
+fn validate_crate_setting_extra_rule_attribute(name: &str, value: &str) -> Result<()> {
+  if !is_starlark_identifier(name) {
+    return Err(anyhow!("`{}` is not a valid attribute name", name));
+  }
+
+  if GENERATED_RULE_ATTRIBUTES.contains(&name) {
+    return Err(anyhow!(
+      "`{}` is already set by cargo-raze on the generated rules, use the crate setting for it \
+       instead",
+      name
+    ));
+  }
+
+  if value.trim().is_empty() {
+    return Err(anyhow!("The value for `{}` must not be empty", name));
+  }
+
+  // A comment or line break could swallow or inject the attributes which follow it in the rule
+  if value.contains(&['#', '\n', '\r'][..]) {
+    return Err(anyhow!(
+      "The value for `{}` must be a single line without a `#`: {:?}",
+      name,
+      value
+    ));
+  }
+
+  // Track open brackets and string literals to ensure the value can be safely pasted into a rule
+  let mut open_brackets = Vec::new();
+  let mut open_quote: Option<char> = None;
+  let mut chars = value.chars();
+  while let Some(c) = chars.next() {
+    if let Some(quote) = open_quote {
+      if c == '\\' {
+        chars.next();
+      } else if c == quote {
+        open_quote = None;
+      }
+      continue;
+    }
+
+    match c {
+      '"' | '\'' => open_quote = Some(c),
+      '(' | '[' | '{' => open_brackets.push(c),
+      ')' | ']' | '}' => {
+        let expected = match c {
+          ')' => '(',
+          ']' => '[',
+          _ => '{',
+        };
+        if open_brackets.pop() != Some(expected) {
+          return Err(anyhow!(
+            "The value for `{}` has an unbalanced `{}`: {}",
+            name,
+            c,
+            value
+          ));
+        }
+      },
+      _ => {},
+    }
+  }
+
+  if open_quote.is_some() || !open_brackets.is_empty() {
+    return Err(anyhow!(
+      "The value for `{}` is not balanced Starlark: {}",
+      name,
+      value
+    ));
+  }
+
+  Ok(())
+}
+
 /// Ensures crate settings associatd with the parsed [RazeSettings](crate::settings::RazeSettings) have valid crate settings
 fn validate_crate_settings(
   settings: &RazeSettings,
@@ -372,26 +1086,103 @@ fn validate_crate_settings(
 
   for (crate_name, crate_settings) in settings.crates.iter() {
     for (version, crate_settings) in crate_settings.iter() {
-      if crate_settings.additional_build_file.is_none() {
-        continue;
+      if let Some(additional_build_file) = &crate_settings.additional_build_file {
+        let result =
+          validate_crate_setting_additional_build_file(additional_build_file, cargo_workspace_root);
+
+        if let Some(err) = result.err() {
+          errors.push(RazeError::Config {
+            field_path_opt: Some(format!(
+              "raze.crates.{}.{}.additional_build_file",
+              crate_name, version
+            )),
+            message: err.to_string(),
+          });
+        }
       }
 
-      let result = validate_crate_setting_additional_build_file(
-        // UNWRAP: Safe due to check above
-        crate_settings.additional_build_file.as_ref().unwrap(),
-        cargo_workspace_root,
-      );
-
-      if let Some(err) = result.err() {
+      if crate_settings.gen_mode == Some(GenMode::Unspecified) {
         errors.push(RazeError::Config {
-          field_path_opt: Some(format!(
-            "raze.crates.{}.{}.additional_build_file",
-            crate_name,
-            version.to_string()
-          )),
-          message: err.to_string(),
+          field_path_opt: Some(format!("raze.crates.{}.{}.gen_mode", crate_name, version)),
+          message: "Expected one of `Vendored` or `Remote`".into(),
         });
       }
+
+      for mode in crate_settings.compilation_mode_rustc_flags.keys() {
+        if !BAZEL_COMPILATION_MODES.contains(&mode.as_str()) {
+          errors.push(RazeError::Config {
+            field_path_opt: Some(format!(
+              "raze.crates.{}.{}.compilation_mode_rustc_flags.{}",
+              crate_name, version, mode
+            )),
+            message: format!("Expected one of {:?}", BAZEL_COMPILATION_MODES),
+          });
+        }
+      }
+
+      for (triple, edition) in crate_settings.edition_per_target.iter() {
+        let field_path_opt = Some(format!(
+          "raze.crates.{}.{}.edition_per_target.{}",
+          crate_name, version, triple
+        ));
+        if !util::is_known_target_triple(triple, &settings.custom_targets) {
+          errors.push(RazeError::Config {
+            field_path_opt,
+            message: format!("Unrecognized target triple: \"{}\"", triple),
+          });
+        } else if !RUST_EDITIONS.contains(&edition.as_str()) {
+          errors.push(RazeError::Config {
+            field_path_opt,
+            message: format!("Expected one of {:?}", RUST_EDITIONS),
+          });
+        }
+      }
+
+      let targeted_settings = [
+        (
+          "compile_data_attrs",
+          crate_settings.compile_data_attrs.keys().collect::<Vec<_>>(),
+        ),
+        (
+          "rustc_flags",
+          crate_settings.rustc_flags.keys().collect::<Vec<_>>(),
+        ),
+        (
+          "rustc_env",
+          crate_settings.rustc_env.keys().collect::<Vec<_>>(),
+        ),
+        (
+          "build_script_env",
+          crate_settings.build_script_env.keys().collect::<Vec<_>>(),
+        ),
+      ];
+      for (field, triples) in targeted_settings.iter() {
+        for triple in triples.iter() {
+          if !util::is_known_target_triple(triple, &settings.custom_targets) {
+            errors.push(RazeError::Config {
+              field_path_opt: Some(format!(
+                "raze.crates.{}.{}.{}.{}",
+                crate_name, version, field, triple
+              )),
+              message: format!("Unrecognized target triple: \"{}\"", triple),
+            });
+          }
+        }
+      }
+
+      for (name, value) in crate_settings.extra_rule_attributes.iter() {
+        let result = validate_crate_setting_extra_rule_attribute(name, value);
+
+        if let Some(err) = result.err() {
+          errors.push(RazeError::Config {
+            field_path_opt: Some(format!(
+              "raze.crates.{}.{}.extra_rule_attributes.{}",
+              crate_name, version, name
+            )),
+            message: err.to_string(),
+          });
+        }
+      }
     }
   }
 
@@ -406,6 +1197,233 @@ fn validate_crate_settings(
   Ok(())
 }
 
+/// The dependency overrides of a single crate within a `crate_overrides_file`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CrateDependencyOverrides {
+  #[serde(default)]
+  additional_deps: Vec<String>,
+  #[serde(default)]
+  skipped_deps: Vec<String>,
+}
+
+/// The contents of a `crate_overrides_file`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CrateOverridesFile {
+  #[serde(default, deserialize_with = "deserialize_per_version")]
+  crates: HashMap<String, HashMap<VersionReq, CrateDependencyOverrides>>,
+}
+
+/// Merges the dependency overrides of the `crate_overrides_file`, if any, into the crate settings
+fn merge_crate_overrides_file(
+  settings: &mut RazeSettings,
+  cargo_workspace_root: &Path,
+) -> Result<(), RazeError> {
+  let overrides_path = match &settings.crate_overrides_file {
+    Some(path) => cargo_workspace_root.join(path),
+    None => return Ok(()),
+  };
+  let config_error = |message: String| RazeError::Config {
+    field_path_opt: Some("raze.crate_overrides_file".to_owned()),
+    message,
+  };
+
+  let contents = std::fs::read_to_string(&overrides_path).map_err(|err| {
+    config_error(format!(
+      "Failed to read {}: {}",
+      overrides_path.display(),
+      err
+    ))
+  })?;
+  let overrides: CrateOverridesFile = toml::from_str(&contents).map_err(|err| {
+    config_error(format!(
+      "Failed to parse {}: {}",
+      overrides_path.display(),
+      err
+    ))
+  })?;
+
+  for (crate_name, versions) in overrides.crates.into_iter() {
+    let crate_settings = settings.crates.entry(crate_name).or_default();
+    for (version, overrides) in versions.into_iter() {
+      let version_settings = crate_settings.entry(version).or_default();
+      version_settings
+        .additional_deps
+        .extend(overrides.additional_deps);
+      version_settings.skipped_deps.extend(overrides.skipped_deps);
+    }
+  }
+
+  Ok(())
+}
+
+/// Reads the target-spec JSON of each custom target, relative to the cargo workspace root.
+fn load_custom_targets(
+  settings: &mut RazeSettings,
+  cargo_workspace_path: &Path,
+) -> Result<(), RazeError> {
+  for (name, custom_target) in settings.custom_targets.iter_mut() {
+    let field_path_opt = Some(format!("raze.custom_targets.{}", name));
+    if util::is_known_target_triple(name, &BTreeMap::new()) {
+      return Err(RazeError::Config {
+        field_path_opt,
+        message: format!("\"{}\" is a target built into rustc", name),
+      });
+    }
+
+    let spec_path = cargo_workspace_path.join(&custom_target.spec);
+    custom_target.properties =
+      util::read_target_spec(&spec_path).map_err(|err| RazeError::Config {
+        field_path_opt,
+        message: format!(
+          "Failed to read target spec {}: {}",
+          spec_path.display(),
+          err
+        ),
+      })?;
+  }
+
+  Ok(())
+}
+
+/// Ensures the configured target triples are recognized, as unknown triples never match anything
+fn validate_target_triples(settings: &RazeSettings) -> Result<(), RazeError> {
+  let configured_triples = settings
+    .target
+    .iter()
+    .map(|triple| ("raze.target", triple))
+    .chain(
+      settings
+        .targets
+        .iter()
+        .flatten()
+        .map(|triple| ("raze.targets", triple)),
+    )
+    .chain(
+      settings
+        .target_sysroot
+        .keys()
+        .map(|triple| ("raze.target_sysroot", triple)),
+    );
+
+  for (field_path, triple) in configured_triples {
+    if !util::is_known_target_triple(triple, &settings.custom_targets) {
+      return Err(RazeError::Config {
+        field_path_opt: Some(field_path.to_owned()),
+        message: format!("Unrecognized target triple: \"{}\"", triple),
+      });
+    }
+  }
+
+  Ok(())
+}
+
+/// Splits an entry of `allowed_crates` into the crate name and the version requirement it allows,
+/// which is any version when omitted.
+pub(crate) fn parse_allowed_crate(entry: &str) -> Result<(&str, VersionReq), RazeError> {
+  let (name, version_req) = match entry.trim().split_once(' ') {
+    Some((name, version_req)) => (name, version_req.trim()),
+    None => (entry.trim(), "*"),
+  };
+
+  let version_req = parse_version_req(version_req).map_err(|err| RazeError::Config {
+    field_path_opt: Some("raze.allowed_crates".to_owned()),
+    message: format!("Invalid version requirement in \"{}\": {}", entry, err),
+  })?;
+
+  Ok((name, version_req))
+}
+
+/// Parses a version requirement, dropping any build metadata instead of mistaking it for a
+/// pre-release like `VersionReq::parse` does. Build metadata has no part in semver precedence, so
+/// `=1.0.0+vendored` matches `1.0.0` just like it matches `1.0.0+upstream`.
+pub fn parse_version_req(version_req: &str) -> Result<VersionReq, semver::ReqParseError> {
+  let mut stripped = String::with_capacity(version_req.len());
+  let mut in_build_metadata = false;
+  for c in version_req.chars() {
+    match c {
+      '+' => in_build_metadata = true,
+      c if in_build_metadata && (c.is_ascii_alphanumeric() || c == '.' || c == '-') => {},
+      c => {
+        in_build_metadata = false;
+        stripped.push(c);
+      },
+    }
+  }
+  VersionReq::parse(&stripped)
+}
+
+/// Deserializes settings keyed by crate name and then by version requirement, such as `crates`.
+fn deserialize_per_version<'de, D, T>(
+  deserializer: D,
+) -> Result<HashMap<String, HashMap<VersionReq, T>>, D::Error>
+where
+  D: serde::Deserializer<'de>,
+  T: Deserialize<'de>,
+{
+  HashMap::<String, HashMap<String, T>>::deserialize(deserializer)?
+    .into_iter()
+    .map(|(name, per_version)| {
+      let per_version = per_version
+        .into_iter()
+        .map(
+          |(version_req, value)| match parse_version_req(&version_req) {
+            Ok(parsed) => Ok((parsed, value)),
+            Err(err) => Err(serde::de::Error::custom(format!(
+              "Invalid version requirement `{}` for crate `{}`: {}",
+              version_req, name, err
+            ))),
+          },
+        )
+        .collect::<Result<_, _>>()?;
+      Ok((name, per_version))
+    })
+    .collect()
+}
+
+/// The lowest version `version_req` allows, if any, eg: `1.0.0-alpha.2` for `^1.0.0-alpha.2`.
+fn version_req_lower_bound(version_req: &VersionReq) -> Option<Version> {
+  let version_req = version_req.to_string();
+  if version_req.contains("||") {
+    return None;
+  }
+  version_req
+    .split(',')
+    .filter_map(|predicate| {
+      predicate
+        .trim()
+        .strip_prefix(">=")
+        .or_else(|| predicate.trim().strip_prefix('>'))
+        .or_else(|| predicate.trim().strip_prefix('='))
+    })
+    .filter_map(|version| Version::parse(version.trim()).ok())
+    .max()
+}
+
+/// Finds the entries of `per_version` which apply to `version`. When several of their requirements
+/// match, only the most specific ones are kept: those with the greatest lower bound by semver
+/// precedence, so that eg: `1.0.0-alpha.10` picks `1.0.0-alpha.10` over `1.0.0-alpha.2` and `*`.
+pub fn matching_version_entries<'a, T>(
+  per_version: &'a HashMap<VersionReq, T>,
+  version: &Version,
+) -> Vec<(&'a VersionReq, &'a T)> {
+  let matching: Vec<(&VersionReq, &T, Option<Version>)> = per_version
+    .iter()
+    .filter(|(version_req, _)| version_req.matches(version))
+    .map(|(version_req, value)| (version_req, value, version_req_lower_bound(version_req)))
+    .collect();
+  let greatest_lower_bound = matching.iter().map(|(_, _, bound)| bound).max().cloned();
+
+  let mut entries: Vec<(&VersionReq, &T)> = matching
+    .into_iter()
+    .filter(|(_, _, bound)| Some(bound) == greatest_lower_bound.as_ref())
+    .map(|(version_req, value, _)| (version_req, value))
+    .collect();
+  entries.sort_by_key(|(version_req, _)| version_req.to_string());
+  entries
+}
+
 /// Verifies that the provided settings make sense.
 fn validate_settings(
   settings: &mut RazeSettings,
@@ -434,6 +1452,74 @@ fn validate_settings(
     settings.genmode = GenMode::Vendored;
   }
 
+  if settings.bzlmod && settings.genmode != GenMode::Remote {
+    return Err(RazeError::Config {
+      field_path_opt: Some("raze.bzlmod".to_owned()),
+      message: "Module extensions can only be generated in Remote genmode".to_owned(),
+    });
+  }
+
+  load_custom_targets(settings, cargo_workspace_path)?;
+  validate_target_triples(settings)?;
+
+  for entry in settings.allowed_crates.iter() {
+    parse_allowed_crate(entry)?;
+  }
+
+  if let Some(library_target_name) = &settings.library_target_name {
+    if library_target_name.is_empty() || library_target_name.contains(&['/', ':', '@'][..]) {
+      return Err(RazeError::Config {
+        field_path_opt: Some("raze.library_target_name".to_owned()),
+        message: format!(
+          "'{}' is not a valid name for a target within a crate's package",
+          library_target_name
+        ),
+      });
+    }
+  }
+
+  if settings.max_concurrent_downloads == 0 {
+    return Err(RazeError::Config {
+      field_path_opt: Some("raze.max_concurrent_downloads".to_owned()),
+      message: "At least one download must be allowed at a time".to_owned(),
+    });
+  }
+
+  if settings.metadata_timeout_secs == Some(0) {
+    return Err(RazeError::Config {
+      field_path_opt: Some("raze.metadata_timeout_secs".to_owned()),
+      message: "The timeout must be at least one second".to_owned(),
+    });
+  }
+
+  if let Some(name) = &settings.repositories_function_name {
+    if !is_starlark_identifier(name) {
+      return Err(RazeError::Config {
+        field_path_opt: Some("raze.repositories_function_name".to_owned()),
+        message: format!("`{}` is not a valid Starlark function name", name),
+      });
+    }
+  }
+
+  for (rule, name) in settings.rule_names.iter() {
+    if !RUST_RULES.contains(&rule.as_str()) {
+      return Err(RazeError::Config {
+        field_path_opt: Some(format!("raze.rule_names.{}", rule)),
+        message: format!(
+          "`{}` is not a rule the generated files use, expected one of: {}",
+          rule,
+          RUST_RULES.join(", ")
+        ),
+      });
+    }
+    if !is_starlark_identifier(name) {
+      return Err(RazeError::Config {
+        field_path_opt: Some(format!("raze.rule_names.{}", rule)),
+        message: format!("`{}` is not a valid Starlark rule name", name),
+      });
+    }
+  }
+
   validate_crate_settings(settings, cargo_workspace_path)?;
 
   Ok(())
@@ -458,7 +1544,7 @@ struct RawRazeSettings {
   pub targets: Option<Vec<String>>,
   #[serde(default)]
   pub binary_deps: HashMap<String, cargo_toml::Dependency>,
-  #[serde(default)]
+  #[serde(default, deserialize_with = "deserialize_per_version")]
   pub crates: HashMap<String, CrateSettingsPerVersion>,
   #[serde(default)]
   pub gen_workspace_prefix: Option<String>,
@@ -475,9 +1561,89 @@ struct RawRazeSettings {
   #[serde(default)]
   pub rust_rules_workspace_name: Option<String>,
   #[serde(default)]
+  pub rust_rules_bzl: Option<String>,
+  #[serde(default)]
+  pub cargo_build_script_bzl: Option<String>,
+  #[serde(default)]
+  pub rule_names: Option<HashMap<String, String>>,
+  #[serde(default)]
+  pub rules_rust_version: Option<Version>,
+  #[serde(default)]
   pub vendor_dir: Option<String>,
   #[serde(default)]
   pub experimental_api: Option<bool>,
+  #[serde(default)]
+  pub allowed_source_hosts: Option<Vec<String>>,
+  #[serde(default)]
+  pub global_rustc_env: Option<HashMap<String, String>>,
+  #[serde(default)]
+  pub checksum_manifest: Option<bool>,
+  #[serde(default)]
+  pub notice_file: Option<bool>,
+  #[serde(default)]
+  pub license_rules: Option<bool>,
+  #[serde(default)]
+  pub license_overrides: Option<HashMap<String, String>>,
+  #[serde(default)]
+  pub defs_bzl: Option<bool>,
+  #[serde(default)]
+  pub bzlmod: Option<bool>,
+  #[serde(default)]
+  pub render_workspace_members: Option<bool>,
+  #[serde(default)]
+  pub remap_path_prefix: Option<String>,
+  #[serde(default)]
+  pub group_deps: Option<bool>,
+  #[serde(default)]
+  pub proc_macro_tags: Option<Vec<String>>,
+  #[serde(default)]
+  pub proc_macro_toolchains: Option<Vec<String>>,
+  #[serde(default)]
+  pub library_target_name: Option<String>,
+  #[serde(default)]
+  pub crate_overrides_file: Option<String>,
+  #[serde(default)]
+  pub templates_dir: Option<String>,
+  #[serde(default)]
+  pub max_dependency_depth: Option<usize>,
+  #[serde(default)]
+  pub source_auth: Option<HashMap<String, SourceAuth>>,
+  #[serde(default)]
+  pub target_sysroot: Option<HashMap<String, TargetSysroot>>,
+  #[serde(default)]
+  pub custom_targets: Option<BTreeMap<String, CustomTarget>>,
+  #[serde(default)]
+  pub allowed_crates: Option<Vec<String>>,
+  #[serde(default)]
+  pub verify_download_checksums: Option<bool>,
+  #[serde(default)]
+  pub max_concurrent_downloads: Option<usize>,
+  #[serde(default)]
+  pub download_mirrors: Option<Vec<String>>,
+  #[serde(default)]
+  pub git_fallback: Option<bool>,
+  #[serde(default)]
+  pub credential_command: Option<Vec<String>>,
+  #[serde(default)]
+  pub metadata_timeout_secs: Option<u64>,
+  #[serde(default)]
+  pub verify_against_upstream_index: Option<bool>,
+  #[serde(default)]
+  pub offline: Option<bool>,
+  #[serde(default)]
+  pub crate_cache_dir: Option<String>,
+  #[serde(default)]
+  pub repositories_function_name: Option<String>,
+  #[serde(default)]
+  pub feature_profiles: Option<HashMap<String, Vec<String>>>,
+  #[serde(default)]
+  pub audit: Option<bool>,
+  #[serde(default)]
+  pub audit_policy: Option<AuditPolicy>,
+  #[serde(default)]
+  pub advisory_db: Option<String>,
+  #[serde(default)]
+  pub filter_vendored_sources: Option<bool>,
 }
 
 impl RawRazeSettings {
@@ -495,8 +1661,48 @@ impl RawRazeSettings {
       || self.registry.is_some()
       || self.index_url.is_some()
       || self.rust_rules_workspace_name.is_some()
+      || self.rust_rules_bzl.is_some()
+      || self.cargo_build_script_bzl.is_some()
+      || self.rule_names.is_some()
+      || self.rules_rust_version.is_some()
       || self.vendor_dir.is_some()
       || self.experimental_api.is_some()
+      || self.allowed_source_hosts.is_some()
+      || self.global_rustc_env.is_some()
+      || self.checksum_manifest.is_some()
+      || self.notice_file.is_some()
+      || self.license_rules.is_some()
+      || self.license_overrides.is_some()
+      || self.defs_bzl.is_some()
+      || self.bzlmod.is_some()
+      || self.render_workspace_members.is_some()
+      || self.remap_path_prefix.is_some()
+      || self.group_deps.is_some()
+      || self.proc_macro_tags.is_some()
+      || self.proc_macro_toolchains.is_some()
+      || self.library_target_name.is_some()
+      || self.crate_overrides_file.is_some()
+      || self.templates_dir.is_some()
+      || self.max_dependency_depth.is_some()
+      || self.source_auth.is_some()
+      || self.target_sysroot.is_some()
+      || self.custom_targets.is_some()
+      || self.allowed_crates.is_some()
+      || self.verify_download_checksums.is_some()
+      || self.max_concurrent_downloads.is_some()
+      || self.download_mirrors.is_some()
+      || self.git_fallback.is_some()
+      || self.credential_command.is_some()
+      || self.metadata_timeout_secs.is_some()
+      || self.verify_against_upstream_index.is_some()
+      || self.offline.is_some()
+      || self.crate_cache_dir.is_some()
+      || self.repositories_function_name.is_some()
+      || self.feature_profiles.is_some()
+      || self.audit.is_some()
+      || self.audit_policy.is_some()
+      || self.advisory_db.is_some()
+      || self.filter_vendored_sources.is_some()
   }
 
   fn print_notices_and_warnings(&self) {
@@ -699,12 +1905,14 @@ fn parse_raze_settings(metadata: &Metadata) -> Result<RazeSettings> {
 /// A cargo command wrapper for gathering cargo metadata used to parse [RazeSettings](crate::settings::RazeSettings)
 pub struct SettingsMetadataFetcher {
   pub cargo_bin_path: PathBuf,
+  pub cargo_target_dir: Option<PathBuf>,
 }
 
 impl Default for SettingsMetadataFetcher {
   fn default() -> SettingsMetadataFetcher {
     SettingsMetadataFetcher {
       cargo_bin_path: util::cargo_bin_path(),
+      cargo_target_dir: None,
     }
   }
 }
@@ -712,19 +1920,19 @@ impl Default for SettingsMetadataFetcher {
 impl MetadataFetcher for SettingsMetadataFetcher {
   fn fetch_metadata(&self, working_dir: &Path, _include_deps: bool) -> Result<Metadata> {
     // This fetch does not require network access.
-    MetadataCommand::new()
+    let mut command = MetadataCommand::new();
+    command
       .cargo_path(&self.cargo_bin_path)
       .no_deps()
       .current_dir(working_dir)
-      .other_options(vec!["--offline".to_owned()])
-      .exec()
-      .with_context(|| {
-        format!(
-          "Failed to fetch Metadata with `{}` from `{}`",
-          &self.cargo_bin_path.display(),
-          working_dir.display()
-        )
-      })
+      .other_options(vec!["--offline".to_owned()]);
+    exec_metadata_command(&command, self.cargo_target_dir.as_deref(), None).with_context(|| {
+      format!(
+        "Failed to fetch Metadata with `{}` from `{}`",
+        &self.cargo_bin_path.display(),
+        working_dir.display()
+      )
+    })
   }
 }
 
@@ -744,6 +1952,7 @@ pub fn load_settings_from_manifest<T: AsRef<Path>>(
   // Create a MetadataFetcher
   let fetcher = SettingsMetadataFetcher {
     cargo_bin_path: bin_path,
+    cargo_target_dir: None,
   };
 
   let cargo_toml_dir = cargo_toml_path.as_ref().parent().ok_or_else(|| {
@@ -764,8 +1973,19 @@ pub fn load_settings_from_manifest<T: AsRef<Path>>(
   load_settings(&metadata)
 }
 
+/// The prefix of the environment variables overriding top-level raze settings, eg:
+/// `CARGO_RAZE_GENMODE=Remote` for `genmode`
+pub const SETTINGS_ENV_PREFIX: &str = "CARGO_RAZE_";
+
 /// Load settings used to configure the functionality of Cargo Raze
 pub fn load_settings(metadata: &Metadata) -> Result<RazeSettings, RazeError> {
+  load_settings_with_env(metadata, std::env::vars())
+}
+
+fn load_settings_with_env(
+  metadata: &Metadata,
+  vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<RazeSettings, RazeError> {
   let mut settings = {
     let result = parse_raze_settings(metadata);
     if result.is_err() {
@@ -775,11 +1995,77 @@ pub fn load_settings(metadata: &Metadata) -> Result<RazeSettings, RazeError> {
     result.unwrap()
   };
 
+  apply_env_overrides(&mut settings, vars)?;
+  merge_crate_overrides_file(&mut settings, &metadata.workspace_root)?;
   validate_settings(&mut settings, &metadata.workspace_root)?;
 
   Ok(settings)
 }
 
+/// Overrides the top-level settings named by the `CARGO_RAZE_*` variables of `vars`, ignoring
+/// variables which name no setting with a warning. Values of settings which are not strings are
+/// parsed as TOML, eg: `CARGO_RAZE_TARGETS='["a", "b"]'`.
+fn apply_env_overrides(
+  settings: &mut RazeSettings,
+  vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), RazeError> {
+  let mut overrides: Vec<(String, String)> = vars
+    .into_iter()
+    .filter_map(|(key, value)| {
+      key
+        .strip_prefix(SETTINGS_ENV_PREFIX)
+        .map(|name| (name.to_lowercase(), value))
+    })
+    .collect();
+  if overrides.is_empty() {
+    return Ok(());
+  }
+  overrides.sort();
+
+  let config_error = |name: &str, message: String| RazeError::Config {
+    field_path_opt: Some(format!("raze.{}", name)),
+    message,
+  };
+  let mut value = serde_json::to_value(&*settings)
+    .map_err(|err| RazeError::Generic(format!("Failed to serialize raze settings: {}", err)))?;
+  for (name, env_value) in overrides.into_iter() {
+    let env_var = format!("{}{}", SETTINGS_ENV_PREFIX, name.to_uppercase());
+    // UNWRAP: Settings are always serialized as a map
+    let field = match value.as_object_mut().unwrap().get_mut(&name) {
+      Some(field) => field,
+      None => {
+        eprintln!(
+          "WARNING: Ignoring {} as it does not name a raze setting",
+          env_var
+        );
+        continue;
+      },
+    };
+    *field = match field {
+      serde_json::Value::String(_) => serde_json::Value::String(env_value),
+      _ => match toml::from_str::<toml::Value>(&format!("value = {}", env_value)) {
+        Ok(parsed) => serde_json::to_value(&parsed["value"])
+          .map_err(|err| config_error(&name, format!("Invalid {}: {}", env_var, err)))?,
+        // Unquoted strings, eg: the values of optional settings, are kept as they are
+        Err(_) => serde_json::Value::String(env_value),
+      },
+    };
+    // Report invalid values by the variable which set them
+    RazeSettings::deserialize(&value)
+      .map_err(|err| config_error(&name, format!("Invalid {}: {}", env_var, err)))?;
+  }
+
+  *settings = RazeSettings::deserialize(&value)
+    .map_err(|err| RazeError::Generic(format!("Failed to override raze settings: {}", err)))?;
+  Ok(())
+}
+
+/// Formats fully-resolved settings as pretty-printed JSON
+pub fn format_settings(settings: &RazeSettings) -> Result<String, RazeError> {
+  serde_json::to_string_pretty(settings)
+    .map_err(|e| RazeError::Generic(format!("Failed to serialize raze settings: {}", e)))
+}
+
 #[cfg(test)]
 pub mod tests {
   use crate::testing::{make_workspace, named_toml_contents};
@@ -804,8 +2090,48 @@ pub mod tests {
       registry: default_raze_settings_registry(),
       index_url: default_raze_settings_index_url(),
       rust_rules_workspace_name: default_raze_settings_rust_rules_workspace_name(),
+      rust_rules_bzl: None,
+      cargo_build_script_bzl: None,
+      rule_names: HashMap::new(),
+      rules_rust_version: None,
       vendor_dir: default_raze_settings_vendor_dir(),
       experimental_api: default_raze_settings_experimental_api(),
+      allowed_source_hosts: Vec::new(),
+      global_rustc_env: HashMap::new(),
+      checksum_manifest: false,
+      notice_file: false,
+      license_rules: false,
+      license_overrides: HashMap::new(),
+      defs_bzl: false,
+      bzlmod: false,
+      render_workspace_members: false,
+      remap_path_prefix: None,
+      group_deps: false,
+      proc_macro_tags: Vec::new(),
+      proc_macro_toolchains: Vec::new(),
+      library_target_name: None,
+      crate_overrides_file: None,
+      templates_dir: None,
+      max_dependency_depth: None,
+      source_auth: HashMap::new(),
+      target_sysroot: HashMap::new(),
+      custom_targets: BTreeMap::new(),
+      allowed_crates: Vec::new(),
+      verify_download_checksums: default_raze_settings_verify_download_checksums(),
+      max_concurrent_downloads: default_raze_settings_max_concurrent_downloads(),
+      download_mirrors: Vec::new(),
+      git_fallback: false,
+      credential_command: Vec::new(),
+      metadata_timeout_secs: None,
+      verify_against_upstream_index: false,
+      offline: false,
+      crate_cache_dir: None,
+      repositories_function_name: None,
+      feature_profiles: HashMap::new(),
+      audit: false,
+      audit_policy: default_raze_settings_audit_policy(),
+      advisory_db: None,
+      filter_vendored_sources: false,
     }
   }
 
@@ -935,6 +2261,268 @@ pub mod tests {
     assert_eq!(settings.crates.len(), 2);
   }
 
+  #[test]
+  fn test_formatting_resolved_settings() {
+    let toml_contents = indoc! { r#"
+      [workspace]
+      members = [
+        "crate_a",
+      ]
+
+      [workspace.metadata.raze]
+      workspace_path = "//workspace_path/raze/"
+    "# };
+
+    let dir = make_workspace(toml_contents, None);
+    let crate_toml = dir.as_ref().join("crate_a").join("Cargo.toml");
+    std::fs::create_dir_all(crate_toml.parent().unwrap()).unwrap();
+    let toml_contents = formatdoc! { r#"
+      {named_contents}
+
+      [package.metadata.raze.crates.settings-test.'*']
+      additional_flags = [
+      "--cfg=crate_a"
+      ]
+    "#, named_contents = named_toml_contents("crate_a", "0.0.1") };
+    std::fs::write(crate_toml, toml_contents).unwrap();
+
+    let settings = load_settings_from_manifest(dir.as_ref().join("Cargo.toml"), None).unwrap();
+    let formatted: serde_json::Value =
+      serde_json::from_str(&format_settings(&settings).unwrap()).unwrap();
+
+    // Values are reported after validation has normalized them
+    assert_eq!(formatted["workspace_path"], "//workspace_path/raze");
+    assert_eq!(formatted["genmode"], "Vendored");
+    // Settings merged in from workspace members are included
+    let crate_settings = formatted["crates"]["settings-test"].as_object().unwrap();
+    assert_eq!(crate_settings.len(), 1);
+    assert_eq!(
+      crate_settings.values().next().unwrap()["additional_flags"],
+      serde_json::json!(["--cfg=crate_a"])
+    );
+  }
+
+  #[test]
+  fn test_formatting_settings_overridden_by_the_environment() {
+    let toml_contents = indoc! { r#"
+      [package]
+      name = "settings-env-test"
+      version = "0.1.0"
+
+      [lib]
+      path = "not_a_file.rs"
+
+      [package.metadata.raze]
+      workspace_path = "//workspace_path/raze"
+      genmode = "Remote"
+      default_gen_buildrs = false
+
+      [package.metadata.raze.crates.some-crate.'1.0.0']
+      gen_buildrs = true
+    "# };
+    let dir = make_workspace(toml_contents, None);
+    let metadata = SettingsMetadataFetcher::default()
+      .fetch_metadata(dir.as_ref(), false)
+      .unwrap();
+
+    let vars = vec![
+      ("CARGO_RAZE_WORKSPACE_PATH", "//from_env/raze/"),
+      ("CARGO_RAZE_DEFAULT_GEN_BUILDRS", "true"),
+      ("CARGO_RAZE_TARGETS", r#"["aarch64-apple-darwin"]"#),
+      ("UNRELATED", "value"),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_owned(), value.to_owned()));
+    let settings = load_settings_with_env(&metadata, vars).unwrap();
+    let formatted: serde_json::Value =
+      serde_json::from_str(&format_settings(&settings).unwrap()).unwrap();
+
+    // The environment wins over the manifest, and is validated as it is
+    assert_eq!(formatted["workspace_path"], "//from_env/raze");
+    assert_eq!(formatted["default_gen_buildrs"], true);
+    assert_eq!(
+      formatted["targets"],
+      serde_json::json!(["aarch64-apple-darwin"])
+    );
+    // The settings which are not overridden are kept
+    assert_eq!(formatted["genmode"], "Remote");
+    let crate_settings = formatted["crates"]["some-crate"].as_object().unwrap();
+    assert_eq!(crate_settings.len(), 1);
+    assert_eq!(crate_settings.values().next().unwrap()["gen_buildrs"], true);
+
+    // Variables which name no setting are ignored
+    let vars = vec![
+      ("CARGO_RAZE_NOT_A_SETTING".to_owned(), "1".to_owned()),
+      ("CARGO_RAZE_GENMODE".to_owned(), "Vendored".to_owned()),
+    ];
+    let settings = load_settings_with_env(&metadata, vars).unwrap();
+    assert_eq!(settings.genmode, GenMode::Vendored);
+    assert_eq!(settings.workspace_path, "//workspace_path/raze");
+  }
+
+  #[test]
+  fn test_validating_target_triples() {
+    let mut settings = dummy_raze_settings();
+    settings.targets = Some(vec![
+      "x86_64-unknown-linux-gnu".to_owned(),
+      "x86_64-apple-darwin".to_owned(),
+    ]);
+    assert!(validate_settings(&mut settings, Path::new("/some/workspace")).is_ok());
+
+    settings.targets = Some(vec![
+      "x86_64-apple-darwin".to_owned(),
+      "x86_64-unkown-linux-gnu".to_owned(),
+    ]);
+    let message = validate_settings(&mut settings, Path::new("/some/workspace"))
+      .unwrap_err()
+      .to_string();
+    assert!(message.contains("raze.targets"));
+    assert!(message.contains("x86_64-unkown-linux-gnu"));
+  }
+
+  #[test]
+  fn test_loading_custom_targets() {
+    let temp_workspace_dir = TempDir::new().expect("Failed to set up temporary directory");
+    std::fs::write(
+      temp_workspace_dir.path().join("thumb-custom.json"),
+      r#"{ "arch": "arm", "target-pointer-width": "32", "max-atomic-width": 32 }"#,
+    )
+    .unwrap();
+
+    let mut settings = dummy_raze_settings();
+    settings.custom_targets.insert(
+      "thumb-custom".to_owned(),
+      CustomTarget {
+        spec: "thumb-custom.json".to_owned(),
+        condition: "//platforms:thumb_custom".to_owned(),
+        ..Default::default()
+      },
+    );
+    settings.targets = Some(vec!["thumb-custom".to_owned()]);
+    validate_settings(&mut settings, temp_workspace_dir.path()).unwrap();
+
+    let properties = &settings.custom_targets["thumb-custom"].properties;
+    assert_eq!(properties.arch, "arm");
+    assert_eq!(properties.os, "none");
+    assert_eq!(properties.target_pointer_width, 32);
+    assert_eq!(properties.max_atomic_width, Some(32));
+
+    // Custom targets may not shadow the targets built into rustc
+    let custom_target = settings.custom_targets.remove("thumb-custom").unwrap();
+    settings
+      .custom_targets
+      .insert("x86_64-unknown-linux-gnu".to_owned(), custom_target);
+    let message = validate_settings(&mut settings, temp_workspace_dir.path())
+      .unwrap_err()
+      .to_string();
+    assert!(message.contains("raze.custom_targets.x86_64-unknown-linux-gnu"));
+  }
+
+  #[test]
+  fn test_validating_repositories_function_name() {
+    let mut settings = dummy_raze_settings();
+    settings.repositories_function_name = Some("other_fetch_remote_crates".to_owned());
+    assert!(validate_settings(&mut settings, Path::new("/some/workspace")).is_ok());
+
+    settings.repositories_function_name = Some("other-repositories".to_owned());
+    let message = validate_settings(&mut settings, Path::new("/some/workspace"))
+      .unwrap_err()
+      .to_string();
+    assert!(message.contains("raze.repositories_function_name"));
+  }
+
+  #[test]
+  fn test_validating_rule_names() {
+    let mut settings = dummy_raze_settings();
+    settings
+      .rule_names
+      .insert("rust_library".to_owned(), "my_rust_library".to_owned());
+    assert!(validate_settings(&mut settings, Path::new("/some/workspace")).is_ok());
+
+    settings
+      .rule_names
+      .insert("rust_library".to_owned(), "//my:rust_library".to_owned());
+    assert!(validate_settings(&mut settings, Path::new("/some/workspace")).is_err());
+
+    settings.rule_names.clear();
+    settings.rule_names.insert(
+      "rust_proc_macro".to_owned(),
+      "my_rust_proc_macro".to_owned(),
+    );
+    let message = validate_settings(&mut settings, Path::new("/some/workspace"))
+      .unwrap_err()
+      .to_string();
+    assert!(message.contains("raze.rule_names.rust_proc_macro"));
+  }
+
+  #[test]
+  fn test_validating_bzlmod_requires_remote_genmode() {
+    let mut settings = dummy_raze_settings();
+    settings.bzlmod = true;
+    assert!(validate_settings(&mut settings, Path::new("/some/workspace")).is_ok());
+
+    settings.genmode = GenMode::Vendored;
+    let message = validate_settings(&mut settings, Path::new("/some/workspace"))
+      .unwrap_err()
+      .to_string();
+    assert!(message.contains("raze.bzlmod"));
+  }
+
+  #[test]
+  fn test_validating_extra_rule_attributes() {
+    assert!(validate_crate_setting_extra_rule_attribute("foo", "True").is_ok());
+    assert!(validate_crate_setting_extra_rule_attribute("_foo_2", "[\"a\", (1, 2)]").is_ok());
+    assert!(validate_crate_setting_extra_rule_attribute("foo", "{\"key\": \"}\"}").is_ok());
+
+    assert!(validate_crate_setting_extra_rule_attribute("2foo", "True").is_err());
+    assert!(validate_crate_setting_extra_rule_attribute("foo-bar", "True").is_err());
+    assert!(validate_crate_setting_extra_rule_attribute("foo", "").is_err());
+    assert!(validate_crate_setting_extra_rule_attribute("foo", "[1, 2").is_err());
+    assert!(validate_crate_setting_extra_rule_attribute("foo", "(1, 2]").is_err());
+    assert!(validate_crate_setting_extra_rule_attribute("foo", "\"unterminated").is_err());
+  }
+
+  #[test]
+  fn test_validating_extra_rule_attributes_rejects_generated_attributes() {
+    for name in &["srcs", "deps", "tags", "rustc_flags", "crate_features"] {
+      let message = validate_crate_setting_extra_rule_attribute(name, "[]")
+        .unwrap_err()
+        .to_string();
+      assert!(message.starts_with(&format!("`{}` is already set by cargo-raze", name)));
+    }
+    assert!(validate_crate_setting_extra_rule_attribute("stamp", "0").is_ok());
+  }
+
+  #[test]
+  fn test_validating_extra_rule_attributes_rejects_comments_and_line_breaks() {
+    for value in &[
+      "True # trailing",
+      "\"a#b\"",
+      "True,\n    srcs = []",
+      "[\r\n]",
+    ] {
+      let message = validate_crate_setting_extra_rule_attribute("foo", value)
+        .unwrap_err()
+        .to_string();
+      assert!(message.starts_with("The value for `foo` must be a single line without a `#`"));
+    }
+  }
+
+  #[test]
+  fn test_validating_compilation_mode_rustc_flags() {
+    let mut settings = dummy_raze_settings();
+    let mut crate_settings = CrateSettings::default();
+    crate_settings
+      .compilation_mode_rustc_flags
+      .insert("debug".to_owned(), vec!["-Cdebug-assertions=on".to_owned()]);
+    let mut versions = HashMap::new();
+    versions.insert(VersionReq::parse("*").unwrap(), crate_settings);
+    settings.crates.insert("foo".to_owned(), versions);
+
+    let err = validate_crate_settings(&settings, Path::new("/")).unwrap_err();
+    assert!(format!("{:?}", err).contains("compilation_mode_rustc_flags.debug"));
+  }
+
   #[test]
   fn test_formatting_registry_url() {
     assert_eq!(
@@ -955,4 +2543,67 @@ pub mod tests {
       "https://registry.io/foo/foo/0.0.1/0.0.1"
     );
   }
+
+  #[test]
+  fn test_selecting_crate_settings_by_semver_precedence() {
+    let toml_contents = indoc! { r#"
+      [workspace]
+      members = []
+
+      [workspace.metadata.raze]
+      workspace_path = "//workspace_path/raze"
+      genmode = "Remote"
+
+      [workspace.metadata.raze.crates.prerelease.'1.0.0-alpha.2']
+      additional_flags = ["--cfg=alpha_2"]
+
+      [workspace.metadata.raze.crates.prerelease.'1.0.0-alpha.10']
+      additional_flags = ["--cfg=alpha_10"]
+
+      [workspace.metadata.raze.crates.prerelease.'1.0.0-beta']
+      additional_flags = ["--cfg=beta"]
+
+      [workspace.metadata.raze.crates.metadata.'=2.0.0+vendored']
+      additional_flags = ["--cfg=vendored"]
+    "# };
+    let dir = make_workspace(toml_contents, None);
+    let settings = load_settings_from_manifest(dir.as_ref().join("Cargo.toml"), None).unwrap();
+
+    let selected_flags = |name: &str, version: &str| -> Vec<Vec<String>> {
+      matching_version_entries(&settings.crates[name], &Version::parse(version).unwrap())
+        .iter()
+        .map(|(_, crate_settings)| crate_settings.additional_flags.clone())
+        .collect()
+    };
+    // Numeric pre-release identifiers are compared numerically, not lexically
+    assert_eq!(
+      selected_flags("prerelease", "1.0.0-alpha.10"),
+      vec![vec!["--cfg=alpha_10"]]
+    );
+    assert_eq!(
+      selected_flags("prerelease", "1.0.0-alpha.9"),
+      vec![vec!["--cfg=alpha_2"]]
+    );
+    assert_eq!(
+      selected_flags("prerelease", "1.0.0-beta.1"),
+      vec![vec!["--cfg=beta"]]
+    );
+    assert_eq!(
+      selected_flags("prerelease", "1.0.0-alpha.1"),
+      Vec::<Vec<String>>::new()
+    );
+    // Build metadata is ignored rather than treated as a pre-release
+    assert_eq!(
+      selected_flags("metadata", "2.0.0"),
+      vec![vec!["--cfg=vendored"]]
+    );
+    assert_eq!(
+      selected_flags("metadata", "2.0.0+upstream"),
+      vec![vec!["--cfg=vendored"]]
+    );
+    assert_eq!(
+      selected_flags("metadata", "2.0.0-vendored"),
+      Vec::<Vec<String>>::new()
+    );
+  }
 }
@@ -16,18 +16,22 @@ use cargo_metadata::Metadata;
 use flate2::Compression;
 use httpmock::{Method::GET, MockRef, MockServer};
 use indoc::{formatdoc, indoc};
+use rustc_serialize::hex::ToHex;
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
+use url::Url;
 
 use std::{
   collections::HashMap,
-  fs::{create_dir_all, write, File},
+  fs::{self, create_dir_all, write, File},
   io::Write,
   path::Path,
 };
 
 use crate::{
   metadata::{
+    index_entry_path,
     tests::{dummy_raze_metadata_fetcher, DummyCargoMetadataFetcher},
     RazeMetadata,
   },
@@ -43,10 +47,14 @@ pub mod templates {
     "dummy_workspace_members_metadata.json.template";
   pub const PLAN_BUILD_PRODUCES_ALIASED_DEPENDENCIES: &str =
     "plan_build_produces_aliased_dependencies.json.template";
+  pub const PLAN_BUILD_PRODUCES_GIT_AND_PATH_DEPENDENCIES: &str =
+    "plan_build_produces_git_and_path_dependencies.json.template";
   pub const PLAN_BUILD_PRODUCES_BUILD_PROC_MACRO_DEPENDENCIES: &str =
     "plan_build_produces_build_proc_macro_dependencies.json.template";
   pub const PLAN_BUILD_PRODUCES_PROC_MACRO_DEPENDENCIES: &str =
     "plan_build_produces_proc_macro_dependencies.json.template";
+  pub const PLAN_BUILD_PRODUCES_TEST_DEPENDENCIES: &str =
+    "plan_build_produces_test_dependencies.json.template";
   pub const SEMVER_MATCHING: &str = "semver_matching.json.template";
   pub const SUBPLAN_PRODUCES_CRATE_ROOT_WITH_FORWARD_SLASH: &str =
     "subplan_produces_crate_root_with_forward_slash.json.template";
@@ -172,6 +180,8 @@ pub struct MockRemoteCrateInfo<'http_mock_server> {
   pub data_dir: TempDir,
   // mocked endpoints
   pub endpoints: Vec<MockRef<'http_mock_server>>,
+  // The sha256 checksum of the served crate archive
+  pub checksum: String,
 }
 
 /// Configures the given mock_server (representing a crates.io remote) to return
@@ -180,10 +190,23 @@ pub fn mock_remote_crate<'server>(
   name: &str,
   version: &str,
   mock_server: &'server MockServer,
+) -> MockRemoteCrateInfo<'server> {
+  mock_remote_crate_at("", name, version, mock_server)
+}
+
+/// Like `mock_remote_crate`, but serves the registry api under the given base path, eg: `/private`,
+/// as registries other than crates.io may do.
+pub fn mock_remote_crate_at<'server>(
+  base_path: &str,
+  name: &str,
+  version: &str,
+  mock_server: &'server MockServer,
 ) -> MockRemoteCrateInfo<'server> {
   // Crate info mock response
   let mock_metadata = mock_server.mock(|when, then| {
-    when.method(GET).path(format!("/api/v1/crates/{}", name));
+    when
+      .method(GET)
+      .path(format!("{}/api/v1/crates/{}", base_path, name));
     // Note that `crate[versions]` is an arbitrary value that must only match a `versions[id]`
     then.status(200).json_body(json!({
         "crate": {
@@ -231,18 +254,22 @@ pub fn mock_remote_crate<'server>(
 
   // Create download mock response
   let mock_download = mock_server.mock(|when, then| {
-    when
-      .method(GET)
-      .path(format!("/api/v1/crates/{}/{}/download", name, version));
+    when.method(GET).path(format!(
+      "{}/api/v1/crates/{}/{}/download",
+      base_path, name, version
+    ));
     then
       .status(200)
       .header("content-type", "application/x-tar")
       .body_from_file(&tar_path.display().to_string());
   });
 
+  let checksum = Sha256::digest(&fs::read(&tar_path).unwrap()).to_hex();
+
   MockRemoteCrateInfo {
     data_dir: dir,
     endpoints: vec![mock_metadata, mock_download],
+    checksum,
   }
 }
 
@@ -268,30 +295,12 @@ pub fn mock_crate_index(
   };
 
   for (name, version) in crates {
-    let crate_index_path = if name.len() < 4 {
-      index_dir.join(name.len().to_string()).join(name)
-    } else {
-      index_dir
-        .join(&name.as_str()[0..2])
-        .join(&name.as_str()[2..4])
-        .join(name)
-    };
-
-    create_dir_all(crate_index_path.parent().unwrap()).unwrap();
-    write(
-      crate_index_path,
-      json!({
-        "name": name,
-        "vers": version,
-        "deps": [],
-        "cksum": "8a648e87a02fa31d9d9a3b7c76dbfee469402fbb4af3ae98b36c099d8a82bb18",
-        "features": {},
-        "yanked": false,
-        "links": null
-      })
-      .to_string(),
-    )
-    .unwrap();
+    mock_crate_index_entry(
+      index_dir,
+      name,
+      version,
+      "8a648e87a02fa31d9d9a3b7c76dbfee469402fbb4af3ae98b36c099d8a82bb18",
+    );
   }
 
   // Return the generated TempDir in the event that `mock_dir` was not provided
@@ -302,6 +311,65 @@ pub fn mock_crate_index(
   }
 }
 
+/// The line listing a version of a crate with the given sha256 checksum in a mock crate index
+pub fn mock_crate_index_line(name: &str, version: &str, checksum: &str) -> String {
+  json!({
+    "name": name,
+    "vers": version,
+    "deps": [],
+    "cksum": checksum,
+    "features": {},
+    "yanked": false,
+    "links": null
+  })
+  .to_string()
+}
+
+/// Writes the index entry of a crate with the given sha256 checksum into a mock crate index
+pub fn mock_crate_index_entry(index_dir: &Path, name: &str, version: &str, checksum: &str) {
+  let crate_index_path = index_dir.join(index_entry_path(name));
+  create_dir_all(crate_index_path.parent().unwrap()).unwrap();
+  write(
+    crate_index_path,
+    mock_crate_index_line(name, version, checksum),
+  )
+  .unwrap();
+}
+
+/// The url of a mock crate index served over cargo's sparse protocol by the given mock_server
+pub fn mock_sparse_index_url(mock_server: &MockServer) -> Url {
+  Url::parse(&format!("sparse+{}/index/", mock_server.base_url())).unwrap()
+}
+
+/// Configures the given mock_server to serve the index entry of a crate with the given sha256
+/// checksum from the sparse index at `mock_sparse_index_url`.
+pub fn mock_sparse_crate_index_entry<'server>(
+  name: &str,
+  version: &str,
+  checksum: &str,
+  mock_server: &'server MockServer,
+) -> MockRef<'server> {
+  let line = mock_crate_index_line(name, version, checksum);
+  mock_server.mock(|when, then| {
+    when
+      .method(GET)
+      .path(format!("/index/{}", index_entry_path(name)));
+    then.status(200).body(format!("{}\n", line));
+  })
+}
+
+/// Configures the given mock_server to serve the `config.json` of the sparse index at
+/// `mock_sparse_index_url`, holding the given `dl` endpoint.
+pub fn mock_sparse_index_config<'server>(
+  dl: &str,
+  mock_server: &'server MockServer,
+) -> MockRef<'server> {
+  mock_server.mock(|when, then| {
+    when.method(GET).path("/index/config.json");
+    then.status(200).json_body(json!({ "dl": dl, "api": null }));
+  })
+}
+
 /// Generate RazeMetadata from a cargo metadata template
 pub fn template_raze_metadata(template_path: &str) -> RazeMetadata {
   let dir = make_basic_workspace();
@@ -319,3 +387,25 @@ pub fn template_raze_metadata(template_path: &str) -> RazeMetadata {
 pub fn template_metadata(template_path: &str) -> Metadata {
   template_raze_metadata(template_path).metadata
 }
+
+/// Moves the root of the package `name` `version` of `raze_metadata` to `crate_dir`, so its
+/// manifest and the sources of its targets are read from there.
+pub fn relocate_package_root(
+  raze_metadata: &mut RazeMetadata,
+  name: &str,
+  version: &str,
+  crate_dir: &Path,
+) {
+  let package = raze_metadata
+    .metadata
+    .packages
+    .iter_mut()
+    .find(|package| package.name == name && package.version.to_string() == version)
+    .unwrap();
+  let package_root = package.manifest_path.parent().unwrap().to_path_buf();
+  package.manifest_path = crate_dir.join("Cargo.toml");
+  for target in package.targets.iter_mut() {
+    let relative_path = target.src_path.strip_prefix(&package_root).unwrap();
+    target.src_path = crate_dir.join(relative_path);
+  }
+}
@@ -12,14 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{env, fmt, iter::Iterator, path::Path, path::PathBuf, process::Command, str::FromStr};
+use std::{
+  collections::BTreeMap, convert::TryFrom, env, fmt, iter::Iterator, path::Path, path::PathBuf,
+  process::Command, str::FromStr,
+};
 
 use anyhow::{anyhow, Result};
 
 use cargo_platform::Cfg;
 
-use cfg_expr::{targets::get_builtin_target_by_triple, Expression, Predicate};
+use cfg_expr::{
+  targets::{get_builtin_target_by_triple, Arch, Endian, Env, Os, TargetInfo, Vendor},
+  Expression, Predicate,
+};
 use pathdiff::diff_paths;
+use serde::{Deserialize, Deserializer};
+
+use crate::settings::CustomTarget;
 
 pub(crate) const SYSTEM_CARGO_BIN_PATH: &str = "cargo";
 pub(crate) const RAZE_LOCKFILE_NAME: &str = "Cargo.raze.lock";
@@ -62,13 +71,11 @@ static SUPPORTED_PLATFORM_TRIPLES: &[&str] = &[
 /// | `unknown-unknown-unknown`             | `(false, false)` | This will not match any triple.                  |
 /// | `cfg(foo)`                            | `(false, false)` | `foo` is not a strongly defined cfg value.       |
 /// | `cfg(target_os = "redox")`            | `(false, false)` | `redox` is not a supported platform.             |
-pub fn is_bazel_supported_platform(target: &str) -> (bool, bool) {
-  // Ensure the target is represented as an expression
-  let target_exp = match target.starts_with("cfg(") {
-    true => target.to_owned(),
-    false => format!("cfg(target = \"{}\")", target),
-  };
-
+pub fn is_bazel_supported_platform(
+  target: &str,
+  custom_targets: &BTreeMap<String, CustomTarget>,
+) -> (bool, bool) {
+  let target_exp = target_expression(target);
   let expression = match Expression::parse(&target_exp) {
     Ok(exp) => exp,
     // If the target expression cannot be parsed it is not considered a Bazel platform
@@ -81,21 +88,8 @@ pub fn is_bazel_supported_platform(target: &str) -> (bool, bool) {
   let mut matches_all = true;
 
   // Attempt to match the expression
-  for target_info in SUPPORTED_PLATFORM_TRIPLES
-    .iter()
-    .map(|x| get_builtin_target_by_triple(x).unwrap())
-  {
-    let target_matches = expression.eval(|pred| {
-      match pred {
-        Predicate::Target(tp) => tp.matches(target_info),
-        Predicate::KeyValue {
-          key,
-          val,
-        } => (*key == "target") && (*val == target_info.triple),
-        // For now there is no other kind of matching
-        _ => false,
-      }
-    });
+  for platform in platforms(custom_targets) {
+    let target_matches = expression.eval(|pred| matches_predicate(pred, &platform));
     if target_matches {
       is_supported = true;
     } else {
@@ -106,41 +100,207 @@ pub fn is_bazel_supported_platform(target: &str) -> (bool, bool) {
   (is_supported, matches_all)
 }
 
+/// The key `target_has_atomic` predicates are renamed to, as `cfg_expr` fails to parse any
+/// `target_*` key it does not know of
+const HAS_ATOMIC_KEY: &str = "raze_has_atomic";
+
+/// Represents a Rust cfg or triple target as a cfg expression `cfg_expr` can parse.
+fn target_expression(target: &str) -> String {
+  match target.starts_with("cfg(") {
+    true => target.replace("target_has_atomic", HAS_ATOMIC_KEY),
+    false => format!("cfg(target = \"{}\")", target),
+  }
+}
+
+/// The properties of a custom target which cfg expressions are evaluated against, as described by
+/// its target-spec JSON, eg: as printed by `rustc --print target-spec-json`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TargetSpec {
+  pub arch: String,
+  #[serde(default = "default_target_spec_os")]
+  pub os: String,
+  #[serde(default)]
+  pub env: String,
+  #[serde(default = "default_target_spec_vendor")]
+  pub vendor: String,
+  #[serde(default)]
+  pub target_family: Vec<String>,
+  #[serde(deserialize_with = "deserialize_pointer_width")]
+  pub target_pointer_width: u8,
+  #[serde(default)]
+  pub target_endian: String,
+  #[serde(default)]
+  pub max_atomic_width: Option<u8>,
+}
+
+impl Default for TargetSpec {
+  fn default() -> Self {
+    TargetSpec {
+      arch: String::new(),
+      os: default_target_spec_os(),
+      env: String::new(),
+      vendor: default_target_spec_vendor(),
+      target_family: Vec::new(),
+      target_pointer_width: 64,
+      target_endian: String::new(),
+      max_atomic_width: None,
+    }
+  }
+}
+
+fn default_target_spec_os() -> String {
+  "none".to_owned()
+}
+
+fn default_target_spec_vendor() -> String {
+  "unknown".to_owned()
+}
+
+/// Target specs hold their pointer width as a string, or as a number in those of newer rustcs.
+fn deserialize_pointer_width<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+  match serde_json::Value::deserialize(deserializer)? {
+    serde_json::Value::String(width) => width.parse().map_err(serde::de::Error::custom),
+    serde_json::Value::Number(width) => width
+      .as_u64()
+      .and_then(|width| u8::try_from(width).ok())
+      .ok_or_else(|| serde::de::Error::custom(format!("invalid pointer width {}", width))),
+    value => Err(serde::de::Error::custom(format!(
+      "invalid pointer width {}",
+      value
+    ))),
+  }
+}
+
+/// Reads the target-spec JSON of a custom target.
+pub fn read_target_spec(path: &Path) -> Result<TargetSpec> {
+  let contents = std::fs::read_to_string(path)?;
+  Ok(serde_json::from_str(&contents)?)
+}
+
+/// A platform cfg expressions are evaluated against, which is either one of the
+/// `SUPPORTED_PLATFORM_TRIPLES` or a custom target.
+struct PlatformInfo<'a> {
+  target_info: TargetInfo<'a>,
+  // The width, in bits, of the widest atomic operations the platform supports
+  max_atomic_width: u8,
+}
+
+/// Yields the supported triples followed by the custom targets, in the order of their names.
+fn platforms<'a>(
+  custom_targets: &'a BTreeMap<String, CustomTarget>,
+) -> impl Iterator<Item = PlatformInfo<'a>> {
+  let builtins = SUPPORTED_PLATFORM_TRIPLES.iter().map(|triple| {
+    let target_info = get_builtin_target_by_triple(triple).unwrap().clone();
+    PlatformInfo {
+      max_atomic_width: max_atomic_width(&target_info),
+      target_info,
+    }
+  });
+  let customs = custom_targets.iter().map(|(name, custom_target)| {
+    let spec = &custom_target.properties;
+    PlatformInfo {
+      target_info: TargetInfo {
+        triple: name,
+        os: Some(Os(&spec.os)),
+        arch: Arch(&spec.arch),
+        env: Some(Env(&spec.env)).filter(|env| !env.0.is_empty()),
+        vendor: Some(Vendor(&spec.vendor)),
+        family: spec
+          .target_family
+          .iter()
+          .find_map(|family| family.parse().ok()),
+        pointer_width: spec.target_pointer_width,
+        endian: match spec.target_endian.as_str() {
+          "big" => Endian::big,
+          _ => Endian::little,
+        },
+      },
+      max_atomic_width: spec.max_atomic_width.unwrap_or(spec.target_pointer_width),
+    }
+  });
+  builtins.chain(customs)
+}
+
+/// Evaluates a single predicate of a cfg expression against a platform.
+fn matches_predicate(pred: &Predicate, platform: &PlatformInfo) -> bool {
+  let target_info = &platform.target_info;
+  match pred {
+    Predicate::Target(tp) => tp.matches(target_info),
+    Predicate::KeyValue { key: "target", val } => *val == target_info.triple,
+    Predicate::KeyValue { key, val } if *key == HAS_ATOMIC_KEY => {
+      let width = match *val {
+        "ptr" => Some(target_info.pointer_width),
+        width => width.parse::<u8>().ok(),
+      };
+      matches!(width, Some(width) if width <= platform.max_atomic_width)
+    },
+    // For now there is no other kind of matching
+    _ => false,
+  }
+}
+
+/// The width, in bits, of the widest atomic operations a supported triple supports, as rustc
+/// reports them through `cfg(target_has_atomic = "...")`.
+fn max_atomic_width(target_info: &TargetInfo) -> u8 {
+  match target_info.arch.0 {
+    "aarch64" => 128,
+    "arm" | "s390x" | "wasm32" | "x86" | "x86_64" => 64,
+    "powerpc" => 32,
+    _ => target_info.pointer_width,
+  }
+}
+
 /// Maps a Rust cfg or triple target to Bazel supported triples.
 ///
 /// Note, the Bazel triples must be defined in:
 /// https://github.com/bazelbuild/rules_rust/blob/master/rust/platform/platform.bzl
-pub fn get_matching_bazel_triples(target: &str) -> Result<Vec<String>> {
-  let target_exp = match target.starts_with("cfg(") {
-    true => target.to_owned(),
-    false => format!("cfg(target = \"{}\")", target),
-  };
-
+pub fn get_matching_bazel_triples(
+  target: &str,
+  custom_targets: &BTreeMap<String, CustomTarget>,
+) -> Result<Vec<String>> {
+  let target_exp = target_expression(target);
   let expression = Expression::parse(&target_exp)?;
-  let triples: Vec<String> = SUPPORTED_PLATFORM_TRIPLES
-    .iter()
-    .filter_map(|triple| {
-      let target_info = get_builtin_target_by_triple(triple).unwrap();
-      match expression.eval(|pred| {
-        match pred {
-          Predicate::Target(tp) => tp.matches(target_info),
-          Predicate::KeyValue {
-            key,
-            val,
-          } => (*key == "target") && (*val == target_info.triple),
-          // For now there is no other kind of matching
-          _ => false,
-        }
-      }) {
-        true => Some(String::from((*target_info).triple)),
-        false => None,
-      }
-    })
+  let triples: Vec<String> = platforms(custom_targets)
+    .filter(|platform| expression.eval(|pred| matches_predicate(pred, platform)))
+    .map(|platform| platform.target_info.triple.to_owned())
     .collect();
 
   Ok(triples)
 }
 
+/// Returns the list of all triples supported by rules_rust, followed by the custom targets.
+pub fn get_supported_bazel_triples(custom_targets: &BTreeMap<String, CustomTarget>) -> Vec<String> {
+  SUPPORTED_PLATFORM_TRIPLES
+    .iter()
+    .map(|triple| triple.to_string())
+    .chain(custom_targets.keys().cloned())
+    .collect()
+}
+
+/// Returns the `target_os` of the given triple or custom target, eg: `windows` or `macos`, if it
+/// has one
+pub fn get_triple_os(
+  triple: &str,
+  custom_targets: &BTreeMap<String, CustomTarget>,
+) -> Option<String> {
+  if let Some(custom_target) = custom_targets.get(triple) {
+    return Some(custom_target.properties.os.clone());
+  }
+  get_builtin_target_by_triple(triple)?
+    .os
+    .as_ref()
+    .map(|os| os.0.to_owned())
+}
+
+/// Returns whether or not the given triple is a target known to Rust, or one of the custom targets
+pub fn is_known_target_triple(
+  triple: &str,
+  custom_targets: &BTreeMap<String, CustomTarget>,
+) -> bool {
+  custom_targets.contains_key(triple) || get_builtin_target_by_triple(triple).is_some()
+}
+
 /// Produces a list of triples based on a provided whitelist
 pub fn filter_bazel_triples(triples: &mut Vec<String>, triples_whitelist: &[String]) {
   // Early-out if the filter list is empty
@@ -159,17 +319,22 @@ pub fn filter_bazel_triples(triples: &mut Vec<String>, triples_whitelist: &[Stri
 pub fn generate_bazel_conditions(
   rust_rules_workspace_name: &str,
   triples: &[String],
+  custom_targets: &BTreeMap<String, CustomTarget>,
 ) -> Result<Vec<String>> {
   // Sanity check ensuring all strings represent real triples
   for triple in triples.iter() {
-    if get_builtin_target_by_triple(triple).is_none() {
+    if !is_known_target_triple(triple, custom_targets) {
       return Err(anyhow!("Not a triple: '{}'", triple));
     }
   }
 
+  // Custom targets are matched by the condition configured for them
   let mut bazel_triples: Vec<String> = triples
     .iter()
-    .map(|triple| format!("@{}//rust/platform:{}", rust_rules_workspace_name, triple))
+    .map(|triple| match custom_targets.get(triple) {
+      Some(custom_target) => custom_target.condition.clone(),
+      None => format!("@{}//rust/platform:{}", rust_rules_workspace_name, triple),
+    })
     .collect();
 
   bazel_triples.sort();
@@ -284,6 +449,18 @@ pub fn sanitize_ident(ident: &str) -> String {
   slug::slugify(&ident).replace("-", "_")
 }
 
+/// Sanitizes a crate version for use within Bazel repository names. Build metadata is dropped as
+/// cargo ignores it when comparing versions, so it can't distinguish two resolved versions of a
+/// crate, and would otherwise collide with a pre-release of the same name (eg: `1.2.3+beta` and
+/// `1.2.3-beta`).
+pub fn sanitize_version(version: &str) -> String {
+  let version = match version.find('+') {
+    Some(idx) => &version[..idx],
+    None => version,
+  };
+  sanitize_ident(version)
+}
+
 /// Gets the proper system attributes for the provided platform triple using rustc.
 fn fetch_attrs(target: &str) -> Result<Vec<Cfg>> {
   let args = vec![format!("--target={}", target), "--print=cfg".to_owned()];
@@ -355,6 +532,103 @@ mod tests {
 
   use super::*;
 
+  #[test]
+  fn test_matching_bazel_triples_for_target_property_cfgs() {
+    let matches = |cfg: &str, triple: &str| {
+      get_matching_bazel_triples(cfg, &BTreeMap::new())
+        .unwrap()
+        .contains(&triple.to_owned())
+    };
+
+    assert!(matches(
+      "cfg(target_has_atomic = \"ptr\")",
+      "powerpc-unknown-linux-gnu"
+    ));
+    assert!(matches(
+      "cfg(target_has_atomic = \"64\")",
+      "i686-unknown-linux-gnu"
+    ));
+    assert!(!matches(
+      "cfg(target_has_atomic = \"64\")",
+      "powerpc-unknown-linux-gnu"
+    ));
+    assert!(matches(
+      "cfg(target_has_atomic = \"128\")",
+      "aarch64-unknown-linux-gnu"
+    ));
+    assert!(!matches(
+      "cfg(target_has_atomic = \"128\")",
+      "x86_64-unknown-linux-gnu"
+    ));
+    assert!(!matches(
+      "cfg(target_has_atomic = \"huge\")",
+      "x86_64-unknown-linux-gnu"
+    ));
+    assert!(matches(
+      "cfg(target_pointer_width = \"32\")",
+      "wasm32-unknown-unknown"
+    ));
+    assert!(!matches(
+      "cfg(target_pointer_width = \"32\")",
+      "x86_64-unknown-linux-gnu"
+    ));
+    assert!(matches(
+      "cfg(target_endian = \"big\")",
+      "s390x-unknown-linux-gnu"
+    ));
+    assert!(!matches(
+      "cfg(target_endian = \"big\")",
+      "x86_64-unknown-linux-gnu"
+    ));
+  }
+
+  #[test]
+  fn test_matching_custom_targets_by_their_target_spec() {
+    let properties: TargetSpec = serde_json::from_str(
+      r#"{ "arch": "arm", "os": "none", "target-pointer-width": 32, "max-atomic-width": 0 }"#,
+    )
+    .unwrap();
+    let mut custom_targets = BTreeMap::new();
+    custom_targets.insert(
+      "thumb-custom".to_owned(),
+      CustomTarget {
+        spec: "thumb-custom.json".to_owned(),
+        condition: "//platforms:thumb_custom".to_owned(),
+        properties,
+      },
+    );
+
+    let matches = |cfg: &str| {
+      get_matching_bazel_triples(cfg, &custom_targets)
+        .unwrap()
+        .contains(&"thumb-custom".to_owned())
+    };
+    assert!(matches("cfg(target_os = \"none\")"));
+    assert!(matches("cfg(target_arch = \"arm\")"));
+    assert!(matches("cfg(target_pointer_width = \"32\")"));
+    assert!(matches("cfg(not(unix))"));
+    assert!(matches("thumb-custom"));
+    assert!(!matches("cfg(target_has_atomic = \"8\")"));
+    assert!(!matches("cfg(target_os = \"linux\")"));
+
+    assert_eq!(
+      get_matching_bazel_triples("cfg(target_os = \"none\")", &custom_targets).unwrap(),
+      vec!["thumb-custom"]
+    );
+    assert_eq!(
+      generate_bazel_conditions("rules_rust", &["thumb-custom".to_owned()], &custom_targets)
+        .unwrap(),
+      vec!["//platforms:thumb_custom"]
+    );
+  }
+
+  #[test]
+  fn test_sanitize_version_drops_build_metadata() {
+    assert_eq!(sanitize_version("1.2.3"), "1_2_3");
+    assert_eq!(sanitize_version("1.2.3+build.5"), "1_2_3");
+    assert_eq!(sanitize_version("1.2.3-beta.1+build"), "1_2_3_beta_1");
+  }
+
   #[test]
   fn test_collect_up_to_works_for_zero() {
     let test_items: Vec<u32> = Vec::new();
@@ -403,35 +677,61 @@ mod tests {
   #[test]
   fn detect_bazel_platforms() {
     assert_eq!(
-      is_bazel_supported_platform("cfg(not(fuchsia))"),
+      is_bazel_supported_platform("cfg(not(fuchsia))", &BTreeMap::new()),
       (true, true)
     );
     assert_eq!(
-      is_bazel_supported_platform("cfg(not(target_os = \"redox\"))"),
+      is_bazel_supported_platform("cfg(not(target_os = \"redox\"))", &BTreeMap::new()),
       (true, true)
     );
-    assert_eq!(is_bazel_supported_platform("cfg(unix)"), (true, false));
     assert_eq!(
-      is_bazel_supported_platform("cfg(not(windows))"),
+      is_bazel_supported_platform("cfg(unix)", &BTreeMap::new()),
       (true, false)
     );
     assert_eq!(
-      is_bazel_supported_platform("cfg(target = \"x86_64-apple-darwin\")"),
+      is_bazel_supported_platform("cfg(not(windows))", &BTreeMap::new()),
       (true, false)
     );
     assert_eq!(
-      is_bazel_supported_platform("x86_64-apple-darwin"),
+      is_bazel_supported_platform("cfg(target = \"x86_64-apple-darwin\")", &BTreeMap::new()),
       (true, false)
     );
     assert_eq!(
-      is_bazel_supported_platform("unknown-unknown-unknown"),
+      is_bazel_supported_platform("x86_64-apple-darwin", &BTreeMap::new()),
+      (true, false)
+    );
+    assert_eq!(
+      is_bazel_supported_platform("unknown-unknown-unknown", &BTreeMap::new()),
       (false, false)
     );
-    assert_eq!(is_bazel_supported_platform("cfg(foo)"), (false, false));
     assert_eq!(
-      is_bazel_supported_platform("cfg(target_os = \"redox\")"),
+      is_bazel_supported_platform("cfg(foo)", &BTreeMap::new()),
       (false, false)
     );
+    assert_eq!(
+      is_bazel_supported_platform("cfg(target_os = \"redox\")", &BTreeMap::new()),
+      (false, false)
+    );
+  }
+
+  #[test]
+  fn match_bazel_triples_of_cfg_predicates() {
+    assert_eq!(
+      get_matching_bazel_triples("cfg(windows)", &BTreeMap::new()).unwrap(),
+      vec!["i686-pc-windows-msvc", "x86_64-pc-windows-msvc"]
+    );
+    assert_eq!(
+      get_matching_bazel_triples("cfg(target_arch = \"wasm32\")", &BTreeMap::new()).unwrap(),
+      vec!["wasm32-unknown-unknown", "wasm32-wasi"]
+    );
+    assert_eq!(
+      get_matching_bazel_triples("x86_64-unknown-linux-gnu", &BTreeMap::new()).unwrap(),
+      vec!["x86_64-unknown-linux-gnu"]
+    );
+    assert!(get_matching_bazel_triples("cfg(unix)", &BTreeMap::new())
+      .unwrap()
+      .iter()
+      .all(|triple| !triple.contains("windows") && !triple.starts_with("wasm32")));
   }
 
   #[test]
@@ -449,7 +749,8 @@ mod tests {
         &vec![
           "aarch64-unknown-linux-gnu".to_string(),
           "aarch64-apple-ios".to_string(),
-        ]
+        ],
+        &BTreeMap::new(),
       )
       .unwrap(),
       vec![
@@ -459,8 +760,12 @@ mod tests {
     );
 
     assert_eq!(
-      generate_bazel_conditions("rules_rust", &vec!["aarch64-unknown-linux-gnu".to_string()])
-        .unwrap(),
+      generate_bazel_conditions(
+        "rules_rust",
+        &["aarch64-unknown-linux-gnu".to_string()],
+        &BTreeMap::new(),
+      )
+      .unwrap(),
       vec!["@rules_rust//rust/platform:aarch64-unknown-linux-gnu"]
     );
 
@@ -469,18 +774,22 @@ mod tests {
       &vec![
         "aarch64-unknown-linux-gnu".to_string(),
         "unknown-unknown-unknown".to_string(),
-      ]
+      ],
+      &BTreeMap::new(),
     )
     .is_err());
 
-    assert!(
-      generate_bazel_conditions("rules_rust", &vec!["unknown-unknown-unknown".to_string()])
-        .is_err()
-    );
+    assert!(generate_bazel_conditions(
+      "rules_rust",
+      &["unknown-unknown-unknown".to_string()],
+      &BTreeMap::new(),
+    )
+    .is_err());
 
     assert!(generate_bazel_conditions(
       "rules_rust",
-      &vec!["foo".to_string(), "bar".to_string(), "baz".to_string()]
+      &["foo".to_string(), "bar".to_string(), "baz".to_string()],
+      &BTreeMap::new(),
     )
     .is_err());
   }
@@ -13,7 +13,6 @@ load("@bazel_tools//tools/build_defs/repo:utils.bzl", "maybe")  # buildifier: di
 _DEPENDENCIES = {
     "impl": {
         "anyhow": "@cargo_raze__anyhow__1_0_38//:anyhow",
-        "cargo-clone-crate": "@cargo_raze__cargo_clone_crate__0_1_6//:cargo_clone_crate",
         "cargo-lock": "@cargo_raze__cargo_lock__6_0_0//:cargo_lock",
         "cargo-platform": "@cargo_raze__cargo_platform__0_1_1//:cargo_platform",
         "cargo_metadata": "@cargo_raze__cargo_metadata__0_12_3//:cargo_metadata",
@@ -21,17 +20,21 @@ _DEPENDENCIES = {
         "cfg-expr": "@cargo_raze__cfg_expr__0_6_0//:cfg_expr",
         "crates-index": "@cargo_raze__crates_index__0_16_2//:crates_index",
         "docopt": "@cargo_raze__docopt__1_1_0//:docopt",
+        "flate2": "@cargo_raze__flate2__1_0_19//:flate2",
         "glob": "@cargo_raze__glob__0_3_0//:glob",
         "itertools": "@cargo_raze__itertools__0_10_0//:itertools",
         "log": "@cargo_raze__log__0_4_13//:log",
         "pathdiff": "@cargo_raze__pathdiff__0_2_0//:pathdiff",
         "regex": "@cargo_raze__regex__1_4_3//:regex",
+        "reqwest": "@cargo_raze__reqwest__0_11_0//:reqwest",
         "rustc-serialize": "@cargo_raze__rustc_serialize__0_3_24//:rustc_serialize",
         "semver": "@cargo_raze__semver__0_11_0//:semver",
         "serde": "@cargo_raze__serde__1_0_120//:serde",
         "serde_json": "@cargo_raze__serde_json__1_0_61//:serde_json",
+        "sha2": "@cargo_raze__sha2__0_8_2//:sha2",
         "slug": "@cargo_raze__slug__0_1_4//:slug",
         "spdx": "@cargo_raze__spdx__0_3_4//:spdx",
+        "tar": "@cargo_raze__tar__0_4_30//:tar",
         "tempfile": "@cargo_raze__tempfile__3_2_0//:tempfile",
         "tera": "@cargo_raze__tera__1_6_1//:tera",
         "toml": "@cargo_raze__toml__0_5_8//:toml",
@@ -49,11 +52,9 @@ _PROC_MACRO_DEPENDENCIES = {
 # EXPERIMENTAL -- MAY CHANGE AT ANY TIME: A mapping of package names to a set of normal dev dependencies for the Rust targets of that package.
 _DEV_DEPENDENCIES = {
     "impl": {
-        "flate2": "@cargo_raze__flate2__1_0_19//:flate2",
         "hamcrest2": "@cargo_raze__hamcrest2__0_3_0//:hamcrest2",
         "httpmock": "@cargo_raze__httpmock__0_5_4//:httpmock",
         "lazy_static": "@cargo_raze__lazy_static__1_4_0//:lazy_static",
-        "tar": "@cargo_raze__tar__0_4_30//:tar",
     },
 }
 
@@ -579,16 +580,6 @@ def cargo_raze_fetch_remote_crates():
         build_file = Label("//third_party/cargo/remote:BUILD.cache-padded-1.1.1.bazel"),
     )
 
-    maybe(
-        http_archive,
-        name = "cargo_raze__cargo_clone_crate__0_1_6",
-        url = "https://crates.io/api/v1/crates/cargo-clone-crate/0.1.6/download",
-        type = "tar.gz",
-        sha256 = "6b78a45c9c653977a5f6513261370501ce16de5ddcef970adbff135cf63540fe",
-        strip_prefix = "cargo-clone-crate-0.1.6",
-        build_file = Label("//third_party/cargo/remote:BUILD.cargo-clone-crate-0.1.6.bazel"),
-    )
-
     maybe(
         http_archive,
         name = "cargo_raze__cargo_lock__6_0_0",
@@ -781,7 +772,7 @@ def cargo_raze_fetch_remote_crates():
 
     maybe(
         http_archive,
-        name = "cargo_raze__curl_sys__0_4_39_curl_7_74_0",
+        name = "cargo_raze__curl_sys__0_4_39",
         url = "https://crates.io/api/v1/crates/curl-sys/0.4.39+curl-7.74.0/download",
         type = "tar.gz",
         sha256 = "07a8ce861e7b68a0b394e814d7ee9f1b2750ff8bd10372c6ad3bacc10e86f874",
@@ -1369,16 +1360,6 @@ def cargo_raze_fetch_remote_crates():
         build_file = Label("//third_party/cargo/remote:BUILD.isahc-1.0.3.bazel"),
     )
 
-    maybe(
-        http_archive,
-        name = "cargo_raze__itertools__0_10_0",
-        url = "https://crates.io/api/v1/crates/itertools/0.10.0/download",
-        type = "tar.gz",
-        sha256 = "37d572918e350e82412fe766d24b15e6682fb2ed2bbe018280caa810397cb319",
-        strip_prefix = "itertools-0.10.0",
-        build_file = Label("//third_party/cargo/remote:BUILD.itertools-0.10.0.bazel"),
-    )
-
     maybe(
         http_archive,
         name = "cargo_raze__itertools__0_9_0",
@@ -1389,6 +1370,16 @@ def cargo_raze_fetch_remote_crates():
         build_file = Label("//third_party/cargo/remote:BUILD.itertools-0.9.0.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "cargo_raze__itertools__0_10_0",
+        url = "https://crates.io/api/v1/crates/itertools/0.10.0/download",
+        type = "tar.gz",
+        sha256 = "37d572918e350e82412fe766d24b15e6682fb2ed2bbe018280caa810397cb319",
+        strip_prefix = "itertools-0.10.0",
+        build_file = Label("//third_party/cargo/remote:BUILD.itertools-0.10.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "cargo_raze__itoa__0_4_7",
@@ -1481,7 +1472,7 @@ def cargo_raze_fetch_remote_crates():
 
     maybe(
         http_archive,
-        name = "cargo_raze__libgit2_sys__0_12_18_1_1_0",
+        name = "cargo_raze__libgit2_sys__0_12_18",
         url = "https://crates.io/api/v1/crates/libgit2-sys/0.12.18+1.1.0/download",
         type = "tar.gz",
         sha256 = "3da6a42da88fc37ee1ecda212ffa254c25713532980005d5f7c0b0fbe7e6e885",
@@ -1491,7 +1482,7 @@ def cargo_raze_fetch_remote_crates():
 
     maybe(
         http_archive,
-        name = "cargo_raze__libnghttp2_sys__0_1_5_1_42_0",
+        name = "cargo_raze__libnghttp2_sys__0_1_5",
         url = "https://crates.io/api/v1/crates/libnghttp2-sys/0.1.5+1.42.0/download",
         type = "tar.gz",
         sha256 = "9657455ff47889b70ffd37c3e118e8cdd23fd1f9f3293a285f141070621c4c79",
@@ -2329,6 +2320,16 @@ def cargo_raze_fetch_remote_crates():
         build_file = Label("//third_party/cargo/remote:BUILD.sha-1-0.8.2.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "cargo_raze__sha2__0_8_2",
+        url = "https://crates.io/api/v1/crates/sha2/0.8.2/download",
+        type = "tar.gz",
+        sha256 = "a256f46ea78a0c0d9ff00077504903ac881a1dafdc20da66545699e7776b3e69",
+        strip_prefix = "sha2-0.8.2",
+        build_file = Label("//third_party/cargo/remote:BUILD.sha2-0.8.2.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "cargo_raze__signal_hook__0_1_17",
@@ -2881,22 +2882,22 @@ def cargo_raze_fetch_remote_crates():
 
     maybe(
         http_archive,
-        name = "cargo_raze__wasi__0_10_1_wasi_snapshot_preview1",
-        url = "https://crates.io/api/v1/crates/wasi/0.10.1+wasi-snapshot-preview1/download",
+        name = "cargo_raze__wasi__0_9_0",
+        url = "https://crates.io/api/v1/crates/wasi/0.9.0+wasi-snapshot-preview1/download",
         type = "tar.gz",
-        sha256 = "93c6c3420963c5c64bca373b25e77acb562081b9bb4dd5bb864187742186cea9",
-        strip_prefix = "wasi-0.10.1+wasi-snapshot-preview1",
-        build_file = Label("//third_party/cargo/remote:BUILD.wasi-0.10.1+wasi-snapshot-preview1.bazel"),
+        sha256 = "cccddf32554fecc6acb585f82a32a72e28b48f8c4c1883ddfeeeaa96f7d8e519",
+        strip_prefix = "wasi-0.9.0+wasi-snapshot-preview1",
+        build_file = Label("//third_party/cargo/remote:BUILD.wasi-0.9.0+wasi-snapshot-preview1.bazel"),
     )
 
     maybe(
         http_archive,
-        name = "cargo_raze__wasi__0_9_0_wasi_snapshot_preview1",
-        url = "https://crates.io/api/v1/crates/wasi/0.9.0+wasi-snapshot-preview1/download",
+        name = "cargo_raze__wasi__0_10_1",
+        url = "https://crates.io/api/v1/crates/wasi/0.10.1+wasi-snapshot-preview1/download",
         type = "tar.gz",
-        sha256 = "cccddf32554fecc6acb585f82a32a72e28b48f8c4c1883ddfeeeaa96f7d8e519",
-        strip_prefix = "wasi-0.9.0+wasi-snapshot-preview1",
-        build_file = Label("//third_party/cargo/remote:BUILD.wasi-0.9.0+wasi-snapshot-preview1.bazel"),
+        sha256 = "93c6c3420963c5c64bca373b25e77acb562081b9bb4dd5bb864187742186cea9",
+        strip_prefix = "wasi-0.10.1+wasi-snapshot-preview1",
+        build_file = Label("//third_party/cargo/remote:BUILD.wasi-0.10.1+wasi-snapshot-preview1.bazel"),
     )
 
     maybe(